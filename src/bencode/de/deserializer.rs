@@ -79,6 +79,12 @@ impl<R: Read> Deserializer<R> {
         Ok(buf)
     }
 
+    pub(crate) fn read_to_end(&mut self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.rdr.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
     fn read_until(&mut self, byte: u8) -> Result<Vec<u8>> {
         let mut buf = Vec::new();
         self.rdr.read_until(byte, &mut buf)?;
@@ -229,9 +235,17 @@ impl<'de, R: Read> de::Deserializer<'de> for &mut Deserializer<R> {
         self.deserialize_map(visitor)
     }
 
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        // Bencode has no null value, so a present value is always `Some`.
+        visitor.visit_some(self)
+    }
+
     deserialize_int! { i8 i16 i32 i64 u8 u16 u32 u64 }
 
-    not_supported! { f32 f64 bool unit option }
+    not_supported! { f32 f64 bool unit }
 
     forward_to_deserialize_any! {
         unit_struct identifier
@@ -405,6 +419,28 @@ mod tests {
         assert!(value.is_empty());
     }
 
+    #[test]
+    fn test_deserialize_optional_fields() {
+        use serde::Deserialize;
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct TestStruct {
+            foo: Option<String>,
+            bar: Option<i32>,
+        }
+
+        let data = b"d3:foo5:helloe";
+        let mut deserializer = Deserializer::new(&data[..]);
+        let value: TestStruct = de::Deserialize::deserialize(&mut deserializer).unwrap();
+        assert_eq!(
+            value,
+            TestStruct {
+                foo: Some("hello".to_string()),
+                bar: None
+            }
+        );
+    }
+
     #[test]
     fn test_deserialize_struct() {
         use serde::Deserialize;
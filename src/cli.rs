@@ -1,12 +1,26 @@
-use crate::cmd;
+use crate::{
+    cmd,
+    meta::MagnetLink,
+    net::{DEFAULT_READ_AHEAD, PiecePicker, Priority, mse::EncryptionPolicy},
+};
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use std::error::Error;
 
 #[derive(Parser)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Command,
+    /// Encrypts peer connections with message stream encryption: disabled,
+    /// enabled when the peer supports it, or required.
+    #[arg(long, global = true, default_value_t = EncryptionPolicy::Disabled)]
+    pub encryption: EncryptionPolicy,
+}
+
+impl Cli {
+    pub async fn run(self) -> Result<(), Box<dyn Error>> {
+        self.command.run(self.encryption).await
+    }
 }
 
 #[derive(Subcommand)]
@@ -18,6 +32,13 @@ pub enum Command {
     Info {
         path: String,
     },
+    /// Prints a magnet link for a torrent file.
+    Magnet {
+        path: String,
+        /// Selects only the given files in the link.
+        #[arg(long, value_name = "FILE")]
+        only: Vec<usize>,
+    },
     Peers {
         path: String,
     },
@@ -35,6 +56,10 @@ pub enum Command {
         #[arg(short, long)]
         output: String,
         path: String,
+        #[command(flatten)]
+        files: FileSelection,
+        #[command(flatten)]
+        order: PieceOrder,
     },
     MagnetParse {
         uri: String,
@@ -55,11 +80,121 @@ pub enum Command {
         #[arg(short, long)]
         output: String,
         uri: String,
+        #[command(flatten)]
+        files: FileSelection,
+        #[command(flatten)]
+        order: PieceOrder,
+    },
+    /// Serves the files of a torrent file or magnet link over HTTP while they
+    /// download.
+    Serve {
+        #[arg(short, long)]
+        output: String,
+        /// A torrent file or magnet link.
+        source: String,
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+        /// How many pieces to request ahead of what is being read.
+        #[arg(long, value_name = "PIECES", default_value_t = DEFAULT_READ_AHEAD)]
+        read_ahead: usize,
+        #[command(flatten)]
+        files: FileSelection,
+    },
+    /// Fetches a magnet link's metadata and saves it as a torrent file.
+    MagnetToTorrent {
+        #[arg(short, long)]
+        output: String,
+        uri: String,
     },
 }
 
+// Which files of a torrent to download, by their index in the file list. Not
+// a doc comment, which clap would take for the help of the subcommand.
+#[derive(Args, Debug, Clone, Default)]
+pub struct FileSelection {
+    /// Sets the priority of a file: skip, low, normal or high.
+    #[arg(long = "priority", value_name = "FILE=PRIORITY", value_parser = parse_file_priority)]
+    pub priorities: Vec<(usize, Priority)>,
+    /// Downloads only the given files, skipping the others.
+    #[arg(long, value_name = "FILE")]
+    pub only: Vec<usize>,
+}
+
+impl FileSelection {
+    /// Downloads the files a magnet link selects, unless files were chosen on
+    /// the command line.
+    pub fn select_magnet_files(
+        &mut self,
+        magnet_link: &MagnetLink,
+        num_files: usize,
+    ) -> Result<(), String> {
+        if !self.only.is_empty() || magnet_link.select_only().is_empty() {
+            return Ok(());
+        }
+
+        self.only = (0..num_files)
+            .filter(|i| magnet_link.is_selected(*i))
+            .collect();
+        if self.only.is_empty() {
+            return Err(format!(
+                "The magnet link selects none of the {num_files} files"
+            ));
+        }
+        Ok(())
+    }
+
+    /// The priority of each of `num_files` files.
+    pub fn file_priorities(&self, num_files: usize) -> Result<Vec<Priority>, String> {
+        let default = if self.only.is_empty() {
+            Priority::Normal
+        } else {
+            Priority::Skip
+        };
+        let mut priorities = vec![default; num_files];
+
+        let only = self.only.iter().map(|file| (*file, Priority::Normal));
+        for (file, priority) in only.chain(self.priorities.iter().copied()) {
+            *priorities
+                .get_mut(file)
+                .ok_or_else(|| format!("No file {file}, the torrent has {num_files}"))? = priority;
+        }
+
+        Ok(priorities)
+    }
+}
+
+// The order pieces are downloaded in.
+#[derive(Args, Debug, Clone, Default)]
+pub struct PieceOrder {
+    /// Downloads the pieces in order, so the data can be used as it arrives.
+    #[arg(long)]
+    pub sequential: bool,
+    /// How many pieces a sequential download requests ahead.
+    #[arg(long, value_name = "PIECES", default_value_t = DEFAULT_READ_AHEAD, requires = "sequential")]
+    pub read_ahead: usize,
+}
+
+impl PieceOrder {
+    pub fn apply(&self, picker: &mut PiecePicker) {
+        if self.sequential {
+            picker.set_sequential(self.read_ahead);
+        }
+    }
+}
+
+fn parse_file_priority(s: &str) -> Result<(usize, Priority), String> {
+    let (file, priority) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected FILE=PRIORITY, got {s}"))?;
+    let file = file
+        .parse()
+        .map_err(|_| format!("Invalid file index {file}"))?;
+    let priority = priority.parse().map_err(|e| format!("{e}"))?;
+    Ok((file, priority))
+}
+
 impl Command {
-    pub async fn run(self) -> Result<(), Box<dyn Error>> {
+    pub async fn run(self, encryption: EncryptionPolicy) -> Result<(), Box<dyn Error>> {
         match self {
             Self::Decode { token } => cmd::decode::run(token).await?,
             Self::Info { path } => cmd::info::run(path).await?,
@@ -69,15 +204,36 @@ impl Command {
                 output,
                 path,
                 index,
-            } => cmd::download_piece::run(output, path, index).await?,
-            Self::Download { output, path } => cmd::download::run(output, path).await?,
+            } => cmd::download_piece::run(output, path, index, encryption).await?,
+            Self::Download {
+                output,
+                path,
+                files,
+                order,
+            } => cmd::download::run(output, path, files, order, encryption).await?,
+            Self::Magnet { path, only } => cmd::magnet::run(path, only).await?,
             Self::MagnetParse { uri } => cmd::magnet_parse::run(uri).await?,
             Self::MagnetHandshake { uri } => cmd::magnet_handshake::run(uri).await?,
-            Self::MagnetInfo { uri } => cmd::magnet_info::run(uri).await?,
+            Self::MagnetInfo { uri } => cmd::magnet_info::run(uri, encryption).await?,
             Self::MagnetDownloadPiece { output, uri, index } => {
-                cmd::magnet_download_piece::run(output, uri, index).await?
+                cmd::magnet_download_piece::run(output, uri, index, encryption).await?
+            }
+            Self::MagnetDownload {
+                output,
+                uri,
+                files,
+                order,
+            } => cmd::magnet_download::run(output, uri, files, order, encryption).await?,
+            Self::Serve {
+                output,
+                source,
+                listen,
+                read_ahead,
+                files,
+            } => cmd::serve::run(output, source, listen, read_ahead, files, encryption).await?,
+            Self::MagnetToTorrent { output, uri } => {
+                cmd::magnet_to_torrent::run(output, uri, encryption).await?
             }
-            Self::MagnetDownload { output, uri } => cmd::magnet_download::run(output, uri).await?,
         }
 
         Ok(())
@@ -1,42 +1,32 @@
-use crate::{meta::Meta, net::Piece};
+use crate::{
+    cli::{FileSelection, PieceOrder},
+    meta::Meta,
+    net::{PiecePicker, mse::EncryptionPolicy},
+    storage::Storage,
+};
 
 use super::utils;
 use std::error::Error;
-use tracing::debug;
-
-pub(crate) async fn run(output: String, path: String) -> Result<(), Box<dyn Error>> {
+use std::sync::Arc;
+
+pub(crate) async fn run(
+    output: String,
+    path: String,
+    files: FileSelection,
+    order: PieceOrder,
+    encryption: EncryptionPolicy,
+) -> Result<(), Box<dyn Error>> {
     let meta = Meta::from_path(&path)?;
-    let info_hash = meta.info.hash()?;
-
-    let resp = utils::get_response(&meta).await?;
-    let peers = resp.peers.as_ref();
-
-    let streams = utils::connect(peers, info_hash).await?;
-    let (mut brokers, mut piece_rx) = utils::broker_channels(streams).await?;
-
-    let hashes = meta.piece_hashes();
-    let mut pieces: Vec<Piece> = Vec::with_capacity(hashes.len());
-
-    for (index, _) in hashes.iter().enumerate() {
-        let broker = brokers.get_item();
-        let length = meta.piece_length(index);
-        broker.request_piece(index, length).await;
-    }
-
-    while let Some(piece) = piece_rx.recv().await {
-        pieces.push(piece);
-        debug!("Downloaded piece {}/{}", pieces.len(), hashes.len());
-
-        if pieces.len() == hashes.len() {
-            break;
-        }
-    }
+    let mut swarm = utils::meta_swarm(&meta, encryption).await?;
 
-    pieces.sort_by_key(|p| p.index);
+    let priorities = files.file_priorities(meta.info.files().len())?;
+    let mut picker = PiecePicker::for_files(&meta.info, &priorities);
+    order.apply(&mut picker);
 
-    let file_data = pieces.into_iter().flat_map(|d| d.data).collect::<Vec<u8>>();
+    let mut storage = Storage::new(Arc::new(meta.info.clone()), output)?;
+    storage.set_file_priorities(&priorities);
 
-    std::fs::write(output, file_data)?;
+    utils::download(&mut swarm, &storage, &meta.piece_layers, picker).await?;
 
     Ok(())
 }
@@ -0,0 +1,40 @@
+use crate::{
+    BitTorrentError, Result,
+    meta::{MagnetLinkBuilder, Meta},
+};
+
+use std::ops::RangeInclusive;
+
+pub(crate) async fn run(path: String, only: Vec<usize>) -> Result<()> {
+    let meta = Meta::from_path(&path)?;
+
+    let num_files = meta.info.files().len();
+    if let Some(file) = only.iter().find(|file| **file >= num_files) {
+        return Err(BitTorrentError::Other(format!(
+            "No file {file}, the torrent has {num_files}"
+        )));
+    }
+
+    let link = MagnetLinkBuilder::from_meta(&meta)?
+        .select_only(ranges(only))
+        .build()?;
+    println!("{link}");
+
+    Ok(())
+}
+
+// Collapses file indices into the fewest ranges.
+fn ranges(mut indices: Vec<usize>) -> Vec<RangeInclusive<usize>> {
+    indices.sort_unstable();
+    indices.dedup();
+
+    let mut ranges: Vec<RangeInclusive<usize>> = Vec::new();
+    for index in indices {
+        match ranges.last_mut() {
+            Some(range) if *range.end() + 1 == index => *range = *range.start()..=index,
+            _ => ranges.push(index..=index),
+        }
+    }
+
+    ranges
+}
@@ -6,9 +6,9 @@ use std::str::FromStr;
 
 pub(crate) async fn run(url: String) -> Result<(), Box<dyn Error>> {
     let magnet_link = MagnetLink::from_str(&url)?;
-    let resp = utils::get_response(&magnet_link).await?;
+    let peers = utils::magnet_peers(&magnet_link).await?;
 
-    for peer in resp.peers {
+    for peer in peers {
         let info_hash = magnet_link.info_hash();
         let peer_id = Bytes20::new(*b"-CT0001-012345678901");
 
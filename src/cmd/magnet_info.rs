@@ -1,20 +1,19 @@
-use crate::meta::MagnetLink;
+use crate::{meta::MagnetLink, net::mse::EncryptionPolicy};
 
 use super::utils;
 use std::error::Error;
 use std::str::FromStr;
 
-pub(crate) async fn run(url: String) -> Result<(), Box<dyn Error>> {
+pub(crate) async fn run(url: String, encryption: EncryptionPolicy) -> Result<(), Box<dyn Error>> {
     let magnet_link = MagnetLink::from_str(&url)?;
     println!("Tracker URL: {}", magnet_link.tracker().unwrap_or("N/A"));
 
-    let resp = utils::get_response(&magnet_link).await?;
-    let peers = resp.peers.as_ref();
+    let peers = utils::magnet_peers(&magnet_link).await?;
 
     let info_hash = magnet_link.info_hash();
-    let mut streams = utils::connect(peers, info_hash).await?;
+    let mut streams = utils::connect(&peers, info_hash, encryption).await?;
 
-    let info = utils::get_ext_info(&mut streams).await?;
+    let (info, _) = utils::get_ext_info(&mut streams, info_hash).await?;
     utils::print_info(&info)?;
 
     Ok(())
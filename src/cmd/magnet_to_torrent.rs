@@ -0,0 +1,24 @@
+use crate::{meta::MagnetLink, net::mse::EncryptionPolicy};
+
+use super::utils;
+use std::error::Error;
+use std::fs;
+use std::str::FromStr;
+
+pub(crate) async fn run(
+    output: String,
+    url: String,
+    encryption: EncryptionPolicy,
+) -> Result<(), Box<dyn Error>> {
+    let magnet_link = MagnetLink::from_str(&url)?;
+
+    let peers = utils::magnet_peers(&magnet_link).await?;
+
+    let info_hash = magnet_link.info_hash();
+    let mut streams = utils::connect(&peers, info_hash, encryption).await?;
+
+    let (_, metadata) = utils::get_ext_info(&mut streams, info_hash).await?;
+    fs::write(output, magnet_link.to_torrent(&metadata)?)?;
+
+    Ok(())
+}
@@ -3,11 +3,14 @@ pub(crate) mod download;
 pub(crate) mod download_piece;
 pub(crate) mod handshake;
 pub(crate) mod info;
+pub(crate) mod magnet;
 pub(crate) mod magnet_download;
 pub(crate) mod magnet_download_piece;
 pub(crate) mod magnet_handshake;
 pub(crate) mod magnet_info;
 pub(crate) mod magnet_parse;
+pub(crate) mod magnet_to_torrent;
 pub(crate) mod peers;
+pub(crate) mod serve;
 
 mod utils;
@@ -0,0 +1,47 @@
+use crate::{
+    cli::FileSelection,
+    meta::{MagnetLink, Meta, PieceLayers},
+    net::{PiecePicker, mse::EncryptionPolicy},
+    serve::FileServer,
+    storage::Storage,
+    stream::StreamingDownload,
+};
+
+use super::utils;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Arc;
+
+pub(crate) async fn run(
+    output: String,
+    source: String,
+    listen: String,
+    read_ahead: usize,
+    mut files: FileSelection,
+    encryption: EncryptionPolicy,
+) -> Result<(), Box<dyn Error>> {
+    let (swarm, info, piece_layers) = if source.starts_with("magnet:") {
+        let magnet_link = MagnetLink::from_str(&source)?;
+        let (swarm, info) = utils::magnet_swarm(&magnet_link, encryption).await?;
+        files.select_magnet_files(&magnet_link, info.files().len())?;
+        (swarm, info, PieceLayers::default())
+    } else {
+        let meta = Meta::from_path(&source)?;
+        let swarm = utils::meta_swarm(&meta, encryption).await?;
+        (swarm, meta.info, meta.piece_layers)
+    };
+
+    let priorities = files.file_priorities(info.files().len())?;
+    let mut picker = PiecePicker::for_files(&info, &priorities);
+    picker.set_sequential(read_ahead);
+
+    let mut storage = Storage::new(Arc::new(info), output)?;
+    storage.set_file_priorities(&priorities);
+
+    let download = StreamingDownload::start(swarm, storage, piece_layers, picker);
+    let server = FileServer::bind(listen, download).await?;
+    println!("Serving on http://{}/", server.local_addr()?);
+
+    server.run().await?;
+    Ok(())
+}
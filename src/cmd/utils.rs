@@ -1,33 +1,115 @@
 use crate::{
     BitTorrentError, Result,
     bencode::Deserializer,
-    meta::{AsTrackerRequest, Info, TrackerResponse},
+    meta::{AsTrackerRequest, Info, MagnetLink, Meta, PieceLayers, TrackerResponse},
     net::{
-        Extension, Peer, PeerStream, Piece,
-        broker::{self, Broker},
+        Peer, PeerStream, PiecePicker, Swarm, broker,
+        dht::{self, Dht, RoutingTable},
+        fetch_metadata,
+        lsd::{Lsd, LsdConfig},
+        mse::EncryptionPolicy,
+        utp::UtpSocket,
+        webseed::WebSeed,
     },
-    util::{Bytes20, RotationPool},
+    storage::Storage,
+    util::Bytes20,
 };
+use bytes::Bytes;
 use serde::Deserialize;
-use tokio::sync::mpsc::{self, Receiver};
-use tracing::warn;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, lookup_host};
+use tokio::sync::mpsc::Sender;
+use tracing::{debug, warn};
 
-macro_rules! err {
-    ($msg:expr) => {
-        BitTorrentError::Other($msg.into())
-    };
+const PEER_ID: [u8; 20] = *b"-CT0001-012345678901";
+const DHT_STATE_FILE: &str = "dht.dat";
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Requests the pieces the picker wants, no more at once than it allows, and
+/// writes the pieces to `storage` as they arrive and verify, completing the
+/// files at the end. Pieces failing verification are requested again, and
+/// sequential ones that are late are asked of another source.
+pub(crate) async fn download(
+    swarm: &mut Swarm,
+    storage: &Storage,
+    piece_layers: &PieceLayers,
+    mut picker: PiecePicker,
+) -> Result<()> {
+    let info = storage.info();
+    let wanted = picker.num_wanted();
+    let mut in_flight = 0;
+
+    loop {
+        for index in swarm.take_suggestions() {
+            picker.suggest(index);
+        }
+        while in_flight < picker.max_in_flight() {
+            let Some(index) = picker.pick() else {
+                break;
+            };
+            let length = info.piece_length(index);
+            swarm.request_piece(index, length).await?;
+            if let Some(due) = picker.due(index, Instant::now()) {
+                swarm.set_deadline(index, length, due);
+            }
+            in_flight += 1;
+        }
+
+        if picker.is_complete() {
+            break;
+        }
+        let Some(piece) = swarm.next_piece().await? else {
+            break;
+        };
+        if picker.is_done(piece.index) {
+            continue;
+        }
+        in_flight -= 1;
+
+        if !info.verify_piece(piece.index, &piece.data, piece_layers) {
+            warn!("Piece {} failed verification", piece.index);
+            picker.failed(piece.index);
+            continue;
+        }
+
+        storage.write_piece(piece.index, &piece.data)?;
+        picker.done(piece.index);
+        swarm.have(piece.index).await;
+        debug!("Downloaded piece {}/{wanted}", picker.num_done());
+    }
+
+    if !picker.is_complete() {
+        return Err(BitTorrentError::Other(format!(
+            "Downloaded {}/{wanted} pieces",
+            picker.num_done()
+        )));
+    }
+
+    storage.finish()
 }
 
 pub fn print_info(info: &Info) -> Result<()> {
-    println!("Length: {}", info.length);
+    println!("Length: {}", info.total_length());
     println!("Info Hash: {}", info.hash()?.hex_encoded());
     println!("Piece Length: {}", info.piece_length);
+    if info.is_v2() {
+        println!("Info Hash v2: {}", info.hash_v2()?.hex_encoded());
+    }
     println!("Piece Hashes:");
 
     for hash in info.piece_hashes() {
         println!("{}", hash.hex_encoded());
     }
 
+    if info.is_multi_file() {
+        println!("Files:");
+        for (index, file) in info.files().iter().enumerate() {
+            println!("{index}: {} ({} bytes)", file.path.join("/"), file.length);
+        }
+    }
+
     Ok(())
 }
 
@@ -36,12 +118,174 @@ pub(crate) async fn get_response<R: AsTrackerRequest>(req: &R) -> Result<Tracker
     Ok(resp)
 }
 
-pub(crate) async fn connect(peers: &[Peer], info_hash: Bytes20) -> Result<Vec<PeerStream>> {
-    let peer_id = Bytes20::new(*b"-CT0001-012345678901");
+/// Asks the torrent's tracker for peers, or the DHT when it has none. A
+/// torrent with web seeds can do without peers, so a failing tracker is only
+/// fatal without them.
+pub(crate) async fn meta_peers(meta: &Meta) -> Result<Vec<Peer>> {
+    if meta.announce.is_empty() && !meta.info.is_private() {
+        return dht_peers(meta.info.hash()?).await;
+    }
+
+    match get_response(meta).await {
+        Ok(resp) => Ok(resp.peers.into_iter().collect()),
+        Err(err) if !meta.url_list.is_empty() => {
+            warn!("Tracker request failed, relying on web seeds: {err}");
+            Ok(Vec::new())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Finds the torrent's peers, connects to them and builds the swarm, with the
+/// torrent's web seeds.
+pub(crate) async fn meta_swarm(meta: &Meta, encryption: EncryptionPolicy) -> Result<Swarm> {
+    let info_hash = meta.info.hash()?;
+
+    let peers = meta_peers(meta).await?;
+    let streams = connect(&peers, info_hash, encryption).await?;
+    let mut swarm = swarm(
+        streams,
+        info_hash,
+        meta.info.num_pieces(),
+        None,
+        meta.info.is_private(),
+        encryption,
+    )
+    .await?;
+    add_web_seeds(&mut swarm, meta);
+
+    Ok(swarm)
+}
+
+/// Finds the magnet link's peers, fetches the metadata from them and builds
+/// the swarm, with the link's web seeds. Metadata whose pieces cannot be
+/// verified without piece layers is refused, as peers do not send them.
+pub(crate) async fn magnet_swarm(
+    magnet_link: &MagnetLink,
+    encryption: EncryptionPolicy,
+) -> Result<(Swarm, Info)> {
+    let info_hash = magnet_link.info_hash();
+
+    let peers = magnet_peers(magnet_link).await?;
+    let mut streams = connect(&peers, info_hash, encryption).await?;
+    let (info, metadata) = get_ext_info(&mut streams, info_hash).await?;
+    info.check_without_piece_layers()?;
+
+    let mut swarm = swarm(
+        streams,
+        info_hash,
+        info.num_pieces(),
+        Some(metadata),
+        info.is_private(),
+        encryption,
+    )
+    .await?;
+    add_magnet_web_seeds(&mut swarm, magnet_link, &info);
+
+    Ok((swarm, info))
+}
+
+/// Adds the torrent's web seeds to the swarm, skipping invalid URLs.
+fn add_web_seeds(swarm: &mut Swarm, meta: &Meta) {
+    add_web_seed_urls(
+        swarm,
+        Arc::new(meta.info.clone()),
+        Arc::new(meta.piece_layers.clone()),
+        &meta.url_list,
+    );
+}
+
+/// Adds the web seeds of a magnet link once its metadata is known.
+fn add_magnet_web_seeds(swarm: &mut Swarm, magnet_link: &MagnetLink, info: &Info) {
+    add_web_seed_urls(
+        swarm,
+        Arc::new(info.clone()),
+        Arc::default(),
+        magnet_link.web_seeds(),
+    );
+}
+
+fn add_web_seed_urls(
+    swarm: &mut Swarm,
+    info: Arc<Info>,
+    piece_layers: Arc<PieceLayers>,
+    urls: &[String],
+) {
+    for url in urls {
+        match WebSeed::new(url, Arc::clone(&info)) {
+            Ok(seed) => swarm.add_web_seed(seed.with_piece_layers(Arc::clone(&piece_layers))),
+            Err(err) => warn!("Skipping web seed: {err}"),
+        }
+    }
+}
+
+/// Finds peers for a magnet link from its tracker, or over the DHT when the
+/// link has none, along with the peers the link names itself. Those are
+/// enough to go on when the tracker fails.
+pub(crate) async fn magnet_peers(magnet_link: &MagnetLink) -> Result<Vec<Peer>> {
+    let mut peers = Vec::new();
+    for addr in magnet_link.peers() {
+        match lookup_host(addr.as_str()).await {
+            Ok(addrs) => peers.extend(addrs.map(Peer::new)),
+            Err(err) => warn!("Skipping peer {addr}: {err}"),
+        }
+    }
+
+    if magnet_link.tracker().is_some() {
+        match get_response(magnet_link).await {
+            Ok(resp) => peers.extend(resp.peers),
+            Err(err) if !peers.is_empty() => {
+                warn!("Tracker request failed, relying on the link's peers: {err}")
+            }
+            Err(err) => return Err(err),
+        }
+    } else {
+        peers.extend(dht_peers(magnet_link.info_hash()).await?);
+    }
+
+    Ok(peers)
+}
+
+async fn dht_peers(info_hash: Bytes20) -> Result<Vec<Peer>> {
+    let path = dht_state_path();
+
+    let dht = match RoutingTable::load(&path) {
+        Ok(table) => Dht::with_routing_table("0.0.0.0:0", table).await?,
+        Err(err) => {
+            debug!("No saved DHT routing table: {err}");
+            Dht::bind("0.0.0.0:0").await?
+        }
+    };
+
+    let bootstrap = dht::resolve_nodes(dht::DEFAULT_BOOTSTRAP_NODES).await;
+    let nodes = dht.bootstrap(&bootstrap).await;
+    debug!("DHT routing table has {nodes} nodes");
+
+    let peers = dht.get_peers(info_hash).await;
+
+    if let Err(err) = dht.save_routing_table(&path).await {
+        warn!("Failed to save DHT routing table: {err}");
+    }
+
+    Ok(peers)
+}
+
+fn dht_state_path() -> PathBuf {
+    std::env::temp_dir()
+        .join(env!("CARGO_PKG_NAME"))
+        .join(DHT_STATE_FILE)
+}
+
+pub(crate) async fn connect(
+    peers: &[Peer],
+    info_hash: Bytes20,
+    encryption: EncryptionPolicy,
+) -> Result<Vec<PeerStream>> {
+    let peer_id = Bytes20::new(PEER_ID);
     let mut streams: Vec<PeerStream> = Vec::new();
 
     for peer in peers {
-        match peer.connect(info_hash, peer_id).await {
+        match peer.connect_with(info_hash, peer_id, encryption).await {
             Ok(stream) => streams.push(stream),
             Err(err) => {
                 warn!("Failed to connect to peer {peer}: {err}");
@@ -53,62 +297,121 @@ pub(crate) async fn connect(peers: &[Peer], info_hash: Bytes20) -> Result<Vec<Pe
     Ok(streams)
 }
 
-pub(crate) async fn broker_channels<S>(
+/// Builds the swarm for a download from the connected peers, skipping the ones
+/// that never unchoke us. Local peers are looked for unless the torrent is
+/// private.
+async fn swarm<S>(
     streams: S,
-) -> Result<(RotationPool<Broker>, Receiver<Piece>)>
+    info_hash: Bytes20,
+    num_pieces: usize,
+    metadata: Option<Bytes>,
+    private: bool,
+    encryption: EncryptionPolicy,
+) -> Result<Swarm>
 where
     S: IntoIterator<Item = PeerStream>,
 {
-    let mut brokers: Vec<Broker> = Vec::new();
-    let mut rxs: Vec<Receiver<Piece>> = Vec::new();
+    let config = broker::Config {
+        metadata,
+        ..broker::Config::default()
+    };
+    let mut swarm = Swarm::new(info_hash, Bytes20::new(PEER_ID), num_pieces, config);
+    swarm.set_private(private);
+    swarm.set_encryption_policy(encryption);
 
-    for mut stream in streams {
-        stream.ready().await?;
+    // Peers found later are tried over uTP first.
+    match UtpSocket::bind("0.0.0.0:0").await {
+        Ok(socket) => swarm.set_utp_socket(socket),
+        Err(err) => debug!("uTP unavailable: {err}"),
+    }
 
-        let (b, piece_rx) = broker::create(stream);
-        brokers.push(b);
-        rxs.push(piece_rx);
+    if !private {
+        let (peers, incoming) = (swarm.peer_sender(), swarm.incoming_sender());
+        tokio::spawn(discover_local_peers(info_hash, peers, incoming, encryption));
     }
 
-    let brokers = RotationPool::from_iter(brokers);
-    let (merged_tx, merged_rx) = mpsc::channel::<Piece>(100);
+    for stream in streams {
+        let peer_id = stream.peer_id();
 
-    for mut rx in rxs {
-        let tx = merged_tx.clone();
+        if let Err(err) = swarm.add_stream(stream).await {
+            warn!("Peer {} is not ready: {err}", peer_id.hex_encoded());
+        }
+    }
+
+    Ok(swarm)
+}
+
+/// Announces the torrent over LSD and listens for local peers until the swarm
+/// is gone. The announced port accepts the local peers' connections, which are
+/// handed to the swarm.
+async fn discover_local_peers(
+    info_hash: Bytes20,
+    peers: Sender<Peer>,
+    incoming: Sender<PeerStream>,
+    encryption: EncryptionPolicy,
+) {
+    let (listener, lsd) = match bind_local_discovery().await {
+        Ok(bound) => bound,
+        Err(err) => {
+            debug!("Local service discovery unavailable: {err}");
+            return;
+        }
+    };
 
+    lsd.add_torrent(info_hash, peers.clone()).await;
+
+    loop {
+        let (socket, addr) = tokio::select! {
+            _ = peers.closed() => return,
+            conn = listener.accept() => match conn {
+                Ok(conn) => conn,
+                Err(err) => {
+                    warn!("Failed to accept a connection: {err}");
+                    continue;
+                }
+            },
+        };
+
+        let incoming = incoming.clone();
         tokio::spawn(async move {
-            while let Some(piece) = rx.recv().await {
-                if tx.send(piece).await.is_err() {
-                    break;
+            let info_hashes = [info_hash];
+            let accept = PeerStream::accept(
+                socket,
+                Some(addr),
+                &info_hashes,
+                Bytes20::new(PEER_ID),
+                encryption,
+            );
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, accept).await {
+                Ok(Ok((_, stream))) => {
+                    let _ = incoming.send(stream).await;
                 }
+                Ok(Err(err)) => debug!("Dropping incoming peer {addr}: {err}"),
+                Err(_) => debug!("Dropping incoming peer {addr}: handshake timed out"),
             }
         });
     }
-
-    Ok((brokers, merged_rx))
 }
 
-pub(crate) async fn get_ext_info(streams: &mut [PeerStream]) -> Result<Info> {
-    for stream in streams.iter_mut() {
-        let ext_id = stream
-            .extension_handshake()
-            .await?
-            .metadata_ext_id()
-            .ok_or(err!("Peer did not advertise ut_metadata extension"))?;
+async fn bind_local_discovery() -> Result<(TcpListener, Lsd)> {
+    let listener = TcpListener::bind("0.0.0.0:0").await?;
+    let config = LsdConfig {
+        port: Some(listener.local_addr()?.port()),
+        ..LsdConfig::default()
+    };
+    Ok((listener, Lsd::bind(config).await?))
+}
 
-        stream
-            .send_message(crate::net::Extension::RequestMetadata { ext_id, piece: 0 })
-            .await?;
+/// Fetches the info dictionary over ut_metadata, returning it parsed along with
+/// its raw bytes.
+pub(crate) async fn get_ext_info(
+    streams: &mut [PeerStream],
+    info_hash: Bytes20,
+) -> Result<(Info, Bytes)> {
+    let metadata = fetch_metadata(streams, info_hash).await?;
 
-        match stream.wait_extention().await? {
-            Extension::Metadata { data, .. } => {
-                let mut deserializer = Deserializer::new(data.as_ref());
-                let info = Info::deserialize(&mut deserializer)?;
-                return Ok(info);
-            }
-            _ => continue,
-        };
-    }
+    let mut deserializer = Deserializer::new(metadata.as_ref());
+    let info = Info::deserialize(&mut deserializer)?;
 
-    Err(err!("Failed to retrieve metadata from any peer"))
+    Ok((info, metadata))
 }
@@ -1,3 +1,5 @@
+use crate::meta::MagnetLinkError;
+
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -44,14 +46,17 @@ pub enum BitTorrentError {
     #[error("Invalid peer message: {0}")]
     InvalidPeerMessage(String),
 
+    #[error("Protocol violation: {0}")]
+    ProtocolViolation(String),
+
     #[error("Connection closed unexpectedly")]
     ConnectionClosed,
 
     #[error("Unexpected channel closed")]
     ChannelClosed,
 
-    #[error("Invalid magnet link")]
-    InvalidMagnetLink,
+    #[error("Invalid magnet link: {0}")]
+    InvalidMagnetLink(#[from] MagnetLinkError),
 
     #[error("Hex decode error: {0}")]
     FromHexError(#[from] hex::FromHexError),
@@ -4,6 +4,10 @@ mod cmd;
 mod error;
 pub mod meta;
 pub mod net;
+pub mod serve;
+pub mod session;
+pub mod storage;
+pub mod stream;
 pub mod util;
 
 pub use cli::{Cli, Command};
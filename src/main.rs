@@ -12,7 +12,7 @@ async fn main() {
 
     let cli = Cli::parse();
 
-    if let Err(err) = cli.command.run().await {
+    if let Err(err) = cli.run().await {
         eprintln!("{err}");
         std::process::exit(1);
     }
@@ -1,25 +1,38 @@
 use crate::{
     BitTorrentError,
-    bencode::{ByteSeqVisitor, Deserializer, Serializer},
-    util::{Bytes20, HASH_SIZE},
+    bencode::{Bencode, ByteSeqVisitor, Deserializer, Serializer},
+    util::{Bytes20, Bytes32, HASH_SIZE},
 };
 
-use super::{AsTrackerRequest, TrackerRequest};
+use super::{AsTrackerRequest, FileTree, PieceLayers, TrackerRequest, merkle};
 
 use serde::{Deserialize, Serialize, de, ser};
+use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
 use std::fs;
 use std::path::Path;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Hashes(Vec<Bytes20>);
 
+impl Hashes {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
 impl AsRef<[Bytes20]> for Hashes {
     fn as_ref(&self) -> &[Bytes20] {
         &self.0
     }
 }
 
+impl From<Vec<Bytes20>> for Hashes {
+    fn from(hashes: Vec<Bytes20>) -> Self {
+        Self(hashes)
+    }
+}
+
 impl ser::Serialize for Hashes {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -45,21 +58,232 @@ impl<'de> de::Deserialize<'de> for Hashes {
     }
 }
 
+/// A file of a multi-file torrent, its path relative to the torrent's
+/// directory given as components.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileInfo {
+    /// Attribute flags (BEP 47), see [`FileAttributes`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+    pub length: u64,
+    pub path: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<ByteBuf>,
+    /// The target of a symlink, relative to the torrent's directory.
+    #[serde(
+        rename = "symlink path",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub symlink_path: Option<Vec<String>>,
+}
+
+/// The attributes of a file (BEP 47). Padding files only align the next file
+/// to a piece boundary and are never written, and symlinks are created
+/// rather than downloaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+    pub padding: bool,
+    pub executable: bool,
+    pub hidden: bool,
+    pub symlink: bool,
+}
+
+impl FileAttributes {
+    /// Reads the `attr` flags, ignoring the ones we do not know.
+    pub fn parse(attr: &str) -> Self {
+        Self {
+            padding: attr.contains('p'),
+            executable: attr.contains('x'),
+            hidden: attr.contains('h'),
+            symlink: attr.contains('l'),
+        }
+    }
+}
+
+/// Where a file lies in the torrent's data: the path starts with the torrent
+/// name for multi-file torrents.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileSpan {
+    pub path: Vec<String>,
+    pub offset: u64,
+    pub length: u64,
+    pub attributes: FileAttributes,
+    pub symlink_path: Option<Vec<String>>,
+}
+
+impl FileSpan {
+    /// Whether the file holds data to write: padding files and symlinks do not.
+    pub fn has_data(&self) -> bool {
+        !self.attributes.padding && !self.attributes.symlink
+    }
+}
+
+/// The part of a file covering some range of the torrent's data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileRange {
+    pub file: usize,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// The info dictionary. v1 torrents describe their files with `length` or
+/// `files` and hash pieces with SHA-1, v2 torrents (BEP 52) have a `file tree`
+/// of merkle roots instead, and hybrid torrents carry both.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Info {
+    /// Attribute flags of the file of a single-file torrent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<FileTree>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileInfo>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u8>,
+    pub name: String,
     #[serde(rename = "piece length")]
     pub piece_length: u32,
+    #[serde(default, skip_serializing_if = "Hashes::is_empty")]
     pub pieces: Hashes,
-    pub name: String,
-    pub length: u64,
+    /// Set to 1 for private torrents (BEP 27), whose peers come from their
+    /// trackers only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+    /// Set by private trackers to give their torrents a distinct info hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
 }
 
 impl Info {
+    /// Whether the torrent has v1 metadata, which hybrid torrents do as well.
+    pub fn is_v1(&self) -> bool {
+        !self.pieces.is_empty()
+    }
+
+    /// Whether the torrent has v2 metadata, which hybrid torrents do as well.
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2) && self.file_tree.is_some()
+    }
+
+    pub fn is_hybrid(&self) -> bool {
+        self.is_v1() && self.is_v2()
+    }
+
+    /// Whether peers may only be found through the torrent's trackers, with
+    /// DHT, PEX and local peer discovery turned off.
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    /// The size of all the torrent's data.
+    pub fn total_length(&self) -> u64 {
+        if !self.is_v1() {
+            return self.tree_files().iter().map(|(f, _)| f.length).sum();
+        }
+
+        match &self.files {
+            Some(files) => files.iter().map(|f| f.length).sum(),
+            None => self.length.unwrap_or_default(),
+        }
+    }
+
+    pub fn is_multi_file(&self) -> bool {
+        if !self.is_v1() {
+            return self.tree_files().len() != 1 || self.file_tree_is_directory();
+        }
+
+        self.files.is_some()
+    }
+
+    /// The torrent's files in order, a single one named after the torrent for
+    /// single-file torrents. In v2-only torrents every file starts on a piece
+    /// boundary, leaving gaps between them.
+    pub fn files(&self) -> Vec<FileSpan> {
+        if !self.is_v1() {
+            return self.tree_files().into_iter().map(|(f, _)| f).collect();
+        }
+
+        let Some(files) = &self.files else {
+            return vec![FileSpan {
+                path: vec![self.name.clone()],
+                offset: 0,
+                length: self.total_length(),
+                attributes: FileAttributes::parse(self.attr.as_deref().unwrap_or_default()),
+                symlink_path: None,
+            }];
+        };
+
+        let mut offset = 0;
+        files
+            .iter()
+            .map(|file| {
+                let mut attributes =
+                    FileAttributes::parse(file.attr.as_deref().unwrap_or_default());
+                // Padding files from before BEP 47 only have a telling name.
+                attributes.padding |= file.path.first().is_some_and(|p| p == ".pad");
+
+                let span = FileSpan {
+                    path: std::iter::once(self.name.clone())
+                        .chain(file.path.iter().cloned())
+                        .collect(),
+                    offset,
+                    length: file.length,
+                    attributes,
+                    symlink_path: file.symlink_path.clone(),
+                };
+                offset += file.length;
+                span
+            })
+            .collect()
+    }
+
+    /// Maps `length` bytes of the torrent's data starting at `offset` to the
+    /// files they belong to.
+    pub fn file_ranges(&self, offset: u64, length: u64) -> Vec<FileRange> {
+        let end = offset + length;
+
+        self.files()
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.length > 0 && f.offset < end && offset < f.offset + f.length)
+            .map(|(i, f)| {
+                let start = offset.max(f.offset);
+                let stop = end.min(f.offset + f.length);
+                FileRange {
+                    file: i,
+                    offset: start - f.offset,
+                    length: stop - start,
+                }
+            })
+            .collect()
+    }
+
+    /// The files holding piece `index`.
+    pub fn piece_file_ranges(&self, index: usize) -> Vec<FileRange> {
+        let offset = index as u64 * self.piece_length as u64;
+        self.file_ranges(offset, self.piece_length(index) as u64)
+    }
+
     pub fn piece_hashes(&self) -> &[Bytes20] {
         self.pieces.as_ref()
     }
 
     pub fn num_pieces(&self) -> usize {
+        if !self.is_v1() {
+            let end = self
+                .tree_files()
+                .last()
+                .map_or(0, |(f, _)| f.offset + f.length);
+            return end.div_ceil(self.piece_length as u64) as usize;
+        }
+
         self.pieces.as_ref().len()
     }
 
@@ -67,30 +291,186 @@ impl Info {
         self.piece_hashes().get(index).is_some_and(|h| h == hash)
     }
 
+    /// The info hash used with peers and trackers: the SHA-1 of the info
+    /// dictionary, or the truncated v2 info hash for v2-only torrents.
     pub fn hash(&self) -> Result<Bytes20, BitTorrentError> {
+        if !self.is_v1() {
+            return Ok(self.hash_v2()?.truncated());
+        }
+
+        let digest = Sha1::digest(self.to_bytes()?);
+        Ok(Bytes20::from(digest.as_ref()))
+    }
+
+    /// The v2 info hash, the SHA-256 of the info dictionary.
+    pub fn hash_v2(&self) -> Result<Bytes32, BitTorrentError> {
+        Ok(Bytes32::sha256_hash(&self.to_bytes()?))
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, BitTorrentError> {
         let mut bytes = Vec::new();
         self.serialize(&mut Serializer::new(&mut bytes))?;
-        let digest = Sha1::digest(&bytes);
-        Ok(Bytes20::from(digest.as_ref()))
+        Ok(bytes)
     }
 
     pub fn piece_length(&self, index: usize) -> usize {
-        let piece_length = self.piece_length as usize;
-        let last_piece_length = (self.length % piece_length as u64) as usize;
-        let is_last_piece = index == (self.num_pieces() - 1);
+        let piece_length = self.piece_length as u64;
+        let start = index as u64 * piece_length;
 
-        if is_last_piece {
-            last_piece_length
-        } else {
-            piece_length
+        if !self.is_v1() {
+            let ranges = self.file_ranges(start, piece_length);
+            return ranges.iter().map(|r| r.length).sum::<u64>() as usize;
         }
+
+        self.total_length().saturating_sub(start).min(piece_length) as usize
+    }
+
+    /// Checks the data of piece `index`: against its SHA-1 hash when the
+    /// torrent has v1 metadata, otherwise against the merkle tree of the file
+    /// it belongs to, using the piece layers for files larger than a piece.
+    pub fn verify_piece(&self, index: usize, data: &[u8], layers: &PieceLayers) -> bool {
+        if self.is_v1() {
+            return self.match_hash(index, &Bytes20::sha1_hash(data));
+        }
+
+        let piece_length = self.piece_length as u64;
+        let start = index as u64 * piece_length;
+
+        let Some((file, entry)) = self
+            .tree_files()
+            .into_iter()
+            .find(|(f, _)| f.offset <= start && start < f.offset + f.length)
+        else {
+            return false;
+        };
+        let Some(pieces_root) = entry.pieces_root else {
+            return false;
+        };
+
+        if data.len() != self.piece_length(index) {
+            return false;
+        }
+
+        if file.length <= piece_length {
+            return merkle::file_root(data) == pieces_root;
+        }
+
+        let piece = ((start - file.offset) / piece_length) as usize;
+        layers
+            .get(&pieces_root)
+            .and_then(|layer| layer.get(piece))
+            .is_some_and(|hash| *hash == merkle::piece_root(data, self.piece_length as usize))
+    }
+
+    /// Fails for v2-only metadata with files larger than a piece, whose pieces
+    /// cannot be verified without piece layers. Metadata fetched from peers
+    /// does not carry them.
+    pub fn check_without_piece_layers(&self) -> Result<(), BitTorrentError> {
+        if self.is_v1() {
+            return Ok(());
+        }
+
+        let piece_length = self.piece_length as u64;
+        match self
+            .tree_files()
+            .iter()
+            .find(|(f, _)| f.length > piece_length)
+        {
+            Some((file, _)) => Err(BitTorrentError::Other(format!(
+                "The v2-only metadata needs piece layers to verify {}, which peers do not send",
+                file.path.join("/")
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn file_tree_is_directory(&self) -> bool {
+        self.tree_files()
+            .first()
+            .is_some_and(|(f, _)| f.path.len() > 1)
+    }
+
+    // The files of the v2 file tree with their entries. Paths start with the
+    // torrent name unless the tree holds a single file.
+    fn tree_files(&self) -> Vec<(FileSpan, super::FileEntry)> {
+        let Some(tree) = &self.file_tree else {
+            return Vec::new();
+        };
+
+        let files = tree.files();
+        let single = files.len() == 1 && files[0].0.len() == 1;
+        let piece_length = self.piece_length as u64;
+        let mut offset = 0;
+
+        files
+            .into_iter()
+            .map(|(path, entry)| {
+                let path = if single {
+                    path
+                } else {
+                    std::iter::once(self.name.clone()).chain(path).collect()
+                };
+                let span = FileSpan {
+                    path,
+                    offset,
+                    length: entry.length,
+                    attributes: FileAttributes::parse(entry.attr.as_deref().unwrap_or_default()),
+                    symlink_path: entry.symlink_path.clone(),
+                };
+                offset += entry.length.div_ceil(piece_length) * piece_length;
+                (span, entry.clone())
+            })
+            .collect()
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Meta {
+    /// The tracker, which trackerless torrents made from magnet links lack.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub announce: String,
+    /// Tiers of trackers (BEP 12).
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub announce_list: Vec<Vec<String>>,
     pub info: Info,
+    /// Web seeds (BEP 19), given as a single URL or a list.
+    #[serde(
+        rename = "url-list",
+        default,
+        deserialize_with = "url_list",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub url_list: Vec<String>,
+    #[serde(
+        rename = "piece layers",
+        default,
+        skip_serializing_if = "PieceLayers::is_empty"
+    )]
+    pub piece_layers: PieceLayers,
+}
+
+fn url_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let to_string = |b: Bencode| match b {
+        Bencode::Str(s) => String::from_utf8(s).map_err(de::Error::custom),
+        _ => Err(de::Error::custom("expected a URL string in url-list")),
+    };
+
+    match Bencode::deserialize(deserializer)? {
+        Bencode::List(urls) => urls
+            .into_iter()
+            .map(to_string)
+            .filter(|url| url.as_ref().map_or(true, |u| !u.is_empty()))
+            .collect(),
+        Bencode::Str(url) if url.is_empty() => Ok(Vec::new()),
+        url => Ok(vec![to_string(url)?]),
+    }
 }
 
 impl Meta {
@@ -98,9 +478,62 @@ impl Meta {
         let f = fs::File::open(path)?;
         let mut de = Deserializer::new(&f);
         let meta = Meta::deserialize(&mut de)?;
+        meta.check_piece_layers()?;
         Ok(meta)
     }
 
+    /// Checks that the piece layers hash up to the pieces roots of the files
+    /// they belong to, and that every file larger than a piece has one.
+    pub fn check_piece_layers(&self) -> Result<(), BitTorrentError> {
+        let Some(tree) = self.info.file_tree.as_ref().filter(|_| self.info.is_v2()) else {
+            return Ok(());
+        };
+
+        let piece_length = self.info.piece_length as usize;
+
+        for (path, entry) in tree.files() {
+            let Some(root) = entry.pieces_root else {
+                continue;
+            };
+            if entry.length <= piece_length as u64 {
+                continue;
+            }
+
+            let layer = self.piece_layers.get(&root).ok_or_else(|| {
+                BitTorrentError::Other(format!("Missing piece layer for {}", path.join("/")))
+            })?;
+
+            if layer.len() as u64 != entry.length.div_ceil(piece_length as u64)
+                || merkle::layer_root(layer, piece_length) != root
+            {
+                return Err(BitTorrentError::Other(format!(
+                    "Invalid piece layer for {}",
+                    path.join("/")
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every tracker of the torrent once, the announce URL first.
+    pub fn trackers(&self) -> Vec<&str> {
+        let mut trackers = Vec::new();
+        let urls = std::iter::once(&self.announce).chain(self.announce_list.iter().flatten());
+
+        for url in urls {
+            if !url.is_empty() && !trackers.contains(&url.as_str()) {
+                trackers.push(url.as_str());
+            }
+        }
+
+        trackers
+    }
+
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> bool {
+        self.info.verify_piece(index, data, &self.piece_layers)
+    }
+
     pub fn piece_hashes(&self) -> &[Bytes20] {
         self.info.piece_hashes()
     }
@@ -115,7 +548,7 @@ impl AsTrackerRequest for Meta {
         TrackerRequest::builder()
             .url(&self.announce)
             .info_hash(self.info.hash()?)
-            .left(self.info.length)
+            .left(self.info.total_length())
             .build()
     }
 }
@@ -129,13 +562,19 @@ mod tests {
     #[test]
     fn test_info_serialization() {
         let info = Info {
+            attr: None,
             piece_length: 16384,
             pieces: Hashes(vec![
                 Bytes20::from(&hash("hello")[..]),
                 Bytes20::from(&hash("world")[..]),
             ]),
             name: "test_file.txt".to_string(),
-            length: 32768,
+            length: Some(32768),
+            files: None,
+            file_tree: None,
+            meta_version: None,
+            private: None,
+            source: None,
         };
 
         let mut bytes = Vec::new();
@@ -164,18 +603,245 @@ mod tests {
         let mut de = Deserializer::new(&data[..]);
         let info = Info::deserialize(&mut de).unwrap();
         let expected = Info {
+            attr: None,
             piece_length: 16384,
             pieces: Hashes(vec![
                 Bytes20::from(&hash("hello")[..]),
                 Bytes20::from(&hash("world")[..]),
             ]),
             name: "test_file.txt".to_string(),
-            length: 32768,
+            length: Some(32768),
+            files: None,
+            file_tree: None,
+            meta_version: None,
+            private: None,
+            source: None,
         };
 
         assert_eq!(info, expected);
     }
 
+    #[test]
+    fn test_multi_file_ranges() {
+        let data = b"d5:filesld6:lengthi5e4:pathl1:aeed6:lengthi0e4:pathl1:beed6:lengthi7e4:pathl3:sub1:ceee4:name3:dir12:piece lengthi4e6:pieces60:"
+            .iter()
+            .chain(&[0u8; 60])
+            .chain(b"e")
+            .cloned()
+            .collect::<Vec<u8>>();
+        let info = Info::deserialize(&mut Deserializer::new(&data[..])).unwrap();
+
+        assert!(info.is_multi_file());
+        assert_eq!(info.total_length(), 12);
+        assert_eq!(info.files()[2].path, ["dir", "sub", "c"]);
+        assert_eq!(info.files()[2].offset, 5);
+        assert_eq!(info.piece_length(2), 4);
+        assert_eq!(
+            info.piece_file_ranges(1),
+            [
+                FileRange {
+                    file: 0,
+                    offset: 4,
+                    length: 1
+                },
+                FileRange {
+                    file: 2,
+                    offset: 0,
+                    length: 3
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_url_list() {
+        let meta = |url_list: &[u8]| {
+            let data = b"d8:announce0:4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:"
+                .iter()
+                .chain(&[0u8; 20])
+                .chain(b"e")
+                .chain(url_list)
+                .chain(b"e")
+                .cloned()
+                .collect::<Vec<u8>>();
+            Meta::deserialize(&mut Deserializer::new(&data[..])).unwrap()
+        };
+
+        assert!(meta(b"").url_list.is_empty());
+        assert_eq!(meta(b"8:url-list8:http://a").url_list, ["http://a"]);
+        assert_eq!(
+            meta(b"8:url-listl8:http://a0:8:http://be").url_list,
+            ["http://a", "http://b"]
+        );
+    }
+
+    // A v2 torrent with a two-piece file and a small one, optionally with
+    // hybrid v1 metadata, returned with the data of both files.
+    fn v2_meta(hybrid: bool) -> (Meta, Vec<u8>, Vec<u8>) {
+        use crate::meta::{FileEntry, FileTreeNode, merkle::BLOCK_SIZE};
+        use std::collections::BTreeMap;
+
+        let piece_length = 2 * BLOCK_SIZE;
+        let big = (0..piece_length + 100)
+            .map(|i| i as u8)
+            .collect::<Vec<u8>>();
+        let small = vec![9u8; 1000];
+
+        let big_root = merkle::file_root(&big);
+        let mut piece_layers = PieceLayers::default();
+        piece_layers.insert(big_root, merkle::piece_layer(&big, piece_length));
+
+        let entry = |data: &[u8], root| {
+            FileTreeNode::File(FileEntry {
+                length: data.len() as u64,
+                pieces_root: Some(root),
+                ..FileEntry::default()
+            })
+        };
+        let sub = FileTree(BTreeMap::from([(
+            "small".into(),
+            entry(&small, merkle::file_root(&small)),
+        )]));
+        let tree = FileTree(BTreeMap::from([
+            ("big".into(), entry(&big, big_root)),
+            ("sub".into(), FileTreeNode::Directory(sub)),
+        ]));
+
+        let mut info = Info {
+            attr: None,
+            file_tree: Some(tree),
+            files: None,
+            length: None,
+            meta_version: Some(2),
+            name: "dir".into(),
+            piece_length: piece_length as u32,
+            pieces: Hashes::default(),
+            private: None,
+            source: None,
+        };
+
+        if hybrid {
+            // v1 data is aligned like v2 data by a padding file.
+            let padding = piece_length - 100;
+            let mut data = big.clone();
+            data.extend(vec![0; padding]);
+            data.extend(&small);
+
+            info.files = Some(vec![
+                FileInfo {
+                    length: big.len() as u64,
+                    path: vec!["big".into()],
+                    ..FileInfo::default()
+                },
+                FileInfo {
+                    attr: Some("p".into()),
+                    length: padding as u64,
+                    path: vec![".pad".into(), padding.to_string()],
+                    ..FileInfo::default()
+                },
+                FileInfo {
+                    length: small.len() as u64,
+                    path: vec!["sub".into(), "small".into()],
+                    ..FileInfo::default()
+                },
+            ]);
+            info.pieces = Hashes(data.chunks(piece_length).map(Bytes20::sha1_hash).collect());
+        }
+
+        let meta = Meta {
+            announce: "http://tracker/announce".into(),
+            announce_list: Vec::new(),
+            info,
+            url_list: Vec::new(),
+            piece_layers,
+        };
+        (meta, big, small)
+    }
+
+    #[test]
+    fn test_v2_round_trip_and_layout() {
+        let (meta, big, small) = v2_meta(false);
+
+        let mut bytes = Vec::new();
+        meta.serialize(&mut Serializer::new(&mut bytes)).unwrap();
+        let parsed = Meta::deserialize(&mut Deserializer::new(&bytes[..])).unwrap();
+        assert_eq!(parsed, meta);
+        parsed.check_piece_layers().unwrap();
+
+        let info = &parsed.info;
+        assert!(info.is_v2() && !info.is_v1());
+        assert_eq!(info.hash().unwrap(), info.hash_v2().unwrap().truncated());
+        assert_eq!(info.num_pieces(), 3);
+        assert_eq!(info.piece_length(1), 100);
+        assert_eq!(info.files()[1].path, ["dir", "sub", "small"]);
+        assert_eq!(info.files()[1].offset, 2 * info.piece_length as u64);
+
+        let piece_length = info.piece_length as usize;
+        assert!(parsed.verify_piece(0, &big[..piece_length]));
+        assert!(parsed.verify_piece(1, &big[piece_length..]));
+        assert!(parsed.verify_piece(2, &small));
+        assert!(!parsed.verify_piece(2, &big[..1000]));
+        assert!(!parsed.verify_piece(0, &big[piece_length..]));
+    }
+
+    #[test]
+    fn test_invalid_piece_layers() {
+        let (mut meta, big, _) = v2_meta(false);
+        let root = merkle::file_root(&big);
+        meta.piece_layers.insert(root, vec![root]);
+        assert!(meta.check_piece_layers().is_err());
+
+        meta.piece_layers = PieceLayers::default();
+        assert!(meta.check_piece_layers().is_err());
+    }
+
+    #[test]
+    fn test_hybrid() {
+        let (meta, big, small) = v2_meta(true);
+        let info = &meta.info;
+
+        assert!(info.is_hybrid());
+        assert_ne!(info.hash().unwrap(), info.hash_v2().unwrap().truncated());
+        assert_eq!(info.num_pieces(), 3);
+        assert_eq!(info.piece_length(1), info.piece_length as usize);
+
+        let piece_length = info.piece_length as usize;
+        let mut padded = big[piece_length..].to_vec();
+        padded.resize(piece_length, 0);
+        assert!(meta.verify_piece(1, &padded));
+        assert!(meta.verify_piece(2, &small));
+    }
+
+    #[test]
+    fn test_check_without_piece_layers() {
+        let (meta, _, _) = v2_meta(false);
+        let err = meta.info.check_without_piece_layers().unwrap_err();
+        assert!(err.to_string().contains("big"));
+
+        let (meta, _, _) = v2_meta(true);
+        meta.info.check_without_piece_layers().unwrap();
+
+        // With every file within a piece the pieces roots suffice.
+        let (mut meta, _, _) = v2_meta(false);
+        meta.info.piece_length *= 2;
+        meta.info.check_without_piece_layers().unwrap();
+    }
+
+    #[test]
+    fn test_private_flag_and_source_kept() {
+        let data = b"d6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:"
+            .iter()
+            .chain(&[0u8; 20])
+            .chain(b"7:privatei1e6:source3:ABCe")
+            .cloned()
+            .collect::<Vec<u8>>();
+        let info = Info::deserialize(&mut Deserializer::new(&data[..])).unwrap();
+
+        assert!(info.is_private());
+        assert_eq!(info.source.as_deref(), Some("ABC"));
+        assert_eq!(info.hash().unwrap(), Bytes20::sha1_hash(&data));
+    }
+
     fn hash(v: &str) -> Vec<u8> {
         let mut hasher = Sha1::new();
         hasher.update(v.as_bytes());
@@ -0,0 +1,192 @@
+use crate::util::{Bytes32, HASH_V2_SIZE};
+
+use serde::{Deserialize, Serialize, de, ser};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A file in a v2 file tree.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileEntry {
+    /// Attribute flags (BEP 47).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+    pub length: u64,
+    /// The root of the file's merkle tree, absent for empty files.
+    #[serde(
+        rename = "pieces root",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pieces_root: Option<Bytes32>,
+    #[serde(
+        rename = "symlink path",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub symlink_path: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileTreeNode {
+    File(FileEntry),
+    Directory(FileTree),
+}
+
+/// The `file tree` of a v2 torrent (BEP 52). Directories map names to their
+/// entries and a file is a dictionary holding its entry under an empty key.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FileTree(pub BTreeMap<String, FileTreeNode>);
+
+impl FileTree {
+    /// The files in the tree with their paths, in the tree's order.
+    pub fn files(&self) -> Vec<(Vec<String>, &FileEntry)> {
+        let mut files = Vec::new();
+        self.collect(&mut Vec::new(), &mut files);
+        files
+    }
+
+    fn collect<'a>(
+        &'a self,
+        path: &mut Vec<String>,
+        files: &mut Vec<(Vec<String>, &'a FileEntry)>,
+    ) {
+        for (name, node) in &self.0 {
+            path.push(name.clone());
+            match node {
+                FileTreeNode::File(entry) => files.push((path.clone(), entry)),
+                FileTreeNode::Directory(tree) => tree.collect(path, files),
+            }
+            path.pop();
+        }
+    }
+}
+
+impl ser::Serialize for FileTreeNode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        match self {
+            Self::File(entry) => {
+                let mut map = BTreeMap::new();
+                map.insert("", entry);
+                map.serialize(serializer)
+            }
+            Self::Directory(tree) => tree.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> de::Deserialize<'de> for FileTreeNode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = FileTreeNode;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a file tree entry")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: de::MapAccess<'de>,
+            {
+                let mut file = None;
+                let mut children = BTreeMap::new();
+
+                while let Some(name) = map.next_key::<String>()? {
+                    if name.is_empty() {
+                        file = Some(map.next_value::<FileEntry>()?);
+                    } else {
+                        children.insert(name, map.next_value::<FileTreeNode>()?);
+                    }
+                }
+
+                match file {
+                    Some(_) if !children.is_empty() => Err(de::Error::custom(
+                        "file tree entry is both a file and a directory",
+                    )),
+                    Some(entry) => Ok(FileTreeNode::File(entry)),
+                    None => Ok(FileTreeNode::Directory(FileTree(children))),
+                }
+            }
+        }
+
+        deserializer.deserialize_map(Visitor)
+    }
+}
+
+/// The `piece layers` of a v2 torrent: for every file larger than a piece,
+/// the hashes of its pieces keyed by the file's pieces root.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PieceLayers(BTreeMap<Bytes32, Vec<Bytes32>>);
+
+impl PieceLayers {
+    pub fn get(&self, pieces_root: &Bytes32) -> Option<&[Bytes32]> {
+        self.0.get(pieces_root).map(Vec::as_slice)
+    }
+
+    pub fn insert(&mut self, pieces_root: Bytes32, layer: Vec<Bytes32>) {
+        self.0.insert(pieces_root, layer);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes32, &[Bytes32])> {
+        self.0.iter().map(|(root, layer)| (root, layer.as_slice()))
+    }
+}
+
+impl ser::Serialize for PieceLayers {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        let map = self
+            .0
+            .iter()
+            .map(|(root, layer)| {
+                let key = ByteBuf::from(root.as_ref());
+                let value = ByteBuf::from(
+                    layer
+                        .iter()
+                        .flat_map(|h| h.iter().copied())
+                        .collect::<Vec<u8>>(),
+                );
+                (key, value)
+            })
+            .collect::<BTreeMap<_, _>>();
+        map.serialize(serializer)
+    }
+}
+
+impl<'de> de::Deserialize<'de> for PieceLayers {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let map = BTreeMap::<ByteBuf, ByteBuf>::deserialize(deserializer)?;
+        let mut layers = PieceLayers::default();
+
+        for (root, layer) in map {
+            let root = Bytes32::try_from(root.into_vec()).map_err(de::Error::custom)?;
+            if layer.len() % HASH_V2_SIZE != 0 {
+                return Err(de::Error::custom("piece layer is not a list of hashes"));
+            }
+            layers.insert(
+                root,
+                layer.chunks(HASH_V2_SIZE).map(Bytes32::from).collect(),
+            );
+        }
+
+        Ok(layers)
+    }
+}
@@ -1,28 +1,160 @@
-use crate::{BitTorrentError, util::Bytes20};
+use crate::{
+    BitTorrentError,
+    bencode::Serializer,
+    util::{Bytes20, Bytes32, HASH_SIZE},
+};
 
-use super::{AsTrackerRequest, TrackerRequest};
+use super::{AsTrackerRequest, Meta, TrackerRequest};
 
-use std::collections::HashMap;
+use serde::Serialize;
+use std::fmt;
+use std::ops::RangeInclusive;
 use std::str::FromStr;
+use thiserror::Error;
 
-#[derive(Debug, PartialEq)]
+const SCHEME: &str = "magnet:?";
+
+// The multihash prefix of a SHA-256 digest: the function code and length.
+const SHA256_MULTIHASH: [u8; 2] = [0x12, 0x20];
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Why a magnet link could not be parsed.
+#[derive(Debug, Error, PartialEq)]
+pub enum MagnetLinkError {
+    #[error("not a magnet URI")]
+    NotMagnet,
+
+    #[error("no btih or btmh exact topic")]
+    MissingInfoHash,
+
+    #[error("invalid info hash {0:?}")]
+    InvalidInfoHash(String),
+
+    #[error("conflicting info hashes {0} and {1}")]
+    ConflictingInfoHash(String, String),
+
+    #[error("unsupported multihash {0:?}")]
+    UnsupportedMultihash(String),
+
+    #[error("invalid file selection {0:?}")]
+    InvalidSelectOnly(String),
+
+    #[error("no tracker")]
+    NoTracker,
+
+    #[error("metadata does not match the info hash")]
+    MetadataMismatch,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct MagnetLink {
-    info_hash: Vec<u8>,
+    info_hash: Option<Bytes20>,
+    info_hash_v2: Option<Bytes32>,
+    keywords: Vec<String>,
     name: Option<String>,
-    tracker: Option<String>,
+    peers: Vec<String>,
+    select_only: Vec<RangeInclusive<usize>>,
+    trackers: Vec<String>,
+    web_seeds: Vec<String>,
 }
 
 impl MagnetLink {
+    pub fn builder() -> MagnetLinkBuilder {
+        MagnetLinkBuilder::default()
+    }
+
+    /// The info hash to use with peers and trackers, which is the truncated
+    /// v2 info hash for links to v2-only torrents.
     pub fn info_hash(&self) -> Bytes20 {
-        Bytes20::from(self.info_hash.as_ref())
+        match (self.info_hash, self.info_hash_v2) {
+            (Some(hash), _) => hash,
+            (None, Some(hash)) => hash.truncated(),
+            (None, None) => unreachable!("magnet links have an info hash"),
+        }
+    }
+
+    /// The v1 info hash, given by a `urn:btih:` exact topic.
+    pub fn info_hash_v1(&self) -> Option<Bytes20> {
+        self.info_hash
+    }
+
+    /// The v2 info hash, given by a `urn:btmh:` exact topic.
+    pub fn info_hash_v2(&self) -> Option<Bytes32> {
+        self.info_hash_v2
     }
 
     pub fn name(&self) -> Option<&str> {
         self.name.as_deref()
     }
 
+    /// The first tracker of the link.
     pub fn tracker(&self) -> Option<&str> {
-        self.tracker.as_deref()
+        self.trackers.first().map(String::as_str)
+    }
+
+    /// Every tracker given by `tr`, in the link's order.
+    pub fn trackers(&self) -> &[String] {
+        &self.trackers
+    }
+
+    /// Search keywords given by `kt`.
+    pub fn keywords(&self) -> &[String] {
+        &self.keywords
+    }
+
+    /// Peer addresses given by `x.pe`, as `host:port`.
+    pub fn peers(&self) -> &[String] {
+        &self.peers
+    }
+
+    /// Web seed URLs given by `ws` (BEP 19).
+    pub fn web_seeds(&self) -> &[String] {
+        &self.web_seeds
+    }
+
+    /// The file index ranges given by `so` (BEP 53), empty when the link
+    /// selects every file.
+    pub fn select_only(&self) -> &[RangeInclusive<usize>] {
+        &self.select_only
+    }
+
+    /// Whether the link selects file `index` for download.
+    pub fn is_selected(&self, index: usize) -> bool {
+        self.select_only.is_empty() || self.select_only.iter().any(|r| r.contains(&index))
+    }
+
+    /// A .torrent file for the link, given the raw info dictionary fetched
+    /// from peers. The info dictionary is written as is so that its hash is
+    /// kept, and every tracker of the link gets a tier of its own.
+    pub fn to_torrent(&self, metadata: &[u8]) -> crate::Result<Vec<u8>> {
+        let matches = match (self.info_hash, self.info_hash_v2) {
+            (Some(hash), _) => Bytes20::sha1_hash(metadata) == hash,
+            (None, Some(hash)) => Bytes32::sha256_hash(metadata) == hash,
+            (None, None) => false,
+        };
+        if !matches {
+            return Err(MagnetLinkError::MetadataMismatch.into());
+        }
+
+        let mut bytes = b"d".to_vec();
+
+        // Keys go in sorted order.
+        if let Some(tracker) = self.tracker() {
+            write_entry(&mut bytes, "announce", tracker)?;
+        }
+        if self.trackers.len() > 1 {
+            let tiers = self.trackers.iter().map(|t| [t]).collect::<Vec<_>>();
+            write_entry(&mut bytes, "announce-list", &tiers)?;
+        }
+        "info".serialize(&mut Serializer::new(&mut bytes))?;
+        bytes.extend_from_slice(metadata);
+        if !self.web_seeds.is_empty() {
+            write_entry(&mut bytes, "url-list", &self.web_seeds)?;
+        }
+        bytes.push(b'e');
+
+        Ok(bytes)
     }
 }
 
@@ -30,40 +162,300 @@ impl FromStr for MagnetLink {
     type Err = BitTorrentError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if !s.starts_with("magnet:?") {
-            return Err(BitTorrentError::InvalidMagnetLink);
+        let query = strip_prefix_ignore_case(s.trim(), SCHEME).ok_or(MagnetLinkError::NotMagnet)?;
+        let pairs = serde_urlencoded::from_str::<Vec<(String, String)>>(query)?;
+
+        let mut link = MagnetLink {
+            info_hash: None,
+            info_hash_v2: None,
+            keywords: Vec::new(),
+            name: None,
+            peers: Vec::new(),
+            select_only: Vec::new(),
+            trackers: Vec::new(),
+            web_seeds: Vec::new(),
+        };
+
+        for (key, value) in pairs {
+            let key = key.to_ascii_lowercase();
+
+            // Exact topics may be numbered, as in `xt.1`.
+            let key = match key.split_once('.') {
+                Some(("xt", n)) if n.bytes().all(|b| b.is_ascii_digit()) => "xt",
+                _ => key.as_str(),
+            };
+
+            match key {
+                "xt" => link.add_exact_topic(&value)?,
+                "dn" if link.name.is_none() => link.name = Some(value),
+                "tr" => link.trackers.push(value),
+                "ws" => link.web_seeds.push(value),
+                "x.pe" => link.peers.push(value),
+                "kt" => link.keywords.push(value),
+                "so" => link.select_only.extend(parse_select_only(&value)?),
+                _ => {}
+            }
+        }
+
+        if link.info_hash.is_none() && link.info_hash_v2.is_none() {
+            return Err(MagnetLinkError::MissingInfoHash.into());
         }
 
-        let query = &s[8..];
+        Ok(link)
+    }
+}
+
+/// Builds a magnet link, which needs at least one of the info hashes.
+#[derive(Debug, Clone, Default)]
+pub struct MagnetLinkBuilder {
+    info_hash: Option<Bytes20>,
+    info_hash_v2: Option<Bytes32>,
+    name: Option<String>,
+    select_only: Vec<RangeInclusive<usize>>,
+    trackers: Vec<String>,
+    web_seeds: Vec<String>,
+}
+
+impl MagnetLinkBuilder {
+    /// A builder for a link to the torrent with its name, trackers and web
+    /// seeds. Hybrid torrents get both info hashes.
+    pub fn from_meta(meta: &Meta) -> crate::Result<Self> {
+        let info = &meta.info;
 
-        let params = serde_urlencoded::from_str::<HashMap<String, String>>(query)?;
+        let mut builder = Self::default().name(&info.name);
+        if info.is_v1() {
+            builder = builder.info_hash(info.hash()?);
+        }
+        if info.is_v2() {
+            builder = builder.info_hash_v2(info.hash_v2()?);
+        }
+        for tracker in meta.trackers() {
+            builder = builder.tracker(tracker);
+        }
+        for url in &meta.url_list {
+            builder = builder.web_seed(url);
+        }
 
-        let info_hash = params
-            .get("xt")
-            .and_then(|xt| xt.strip_prefix("urn:btih:"))
-            .map(hex::decode)
-            .transpose()?
-            .ok_or_else(|| BitTorrentError::InvalidMagnetLink)?;
+        Ok(builder)
+    }
 
-        let name = params.get("dn").cloned();
-        let tracker = params.get("tr").cloned();
+    pub fn build(self) -> crate::Result<MagnetLink> {
+        if self.info_hash.is_none() && self.info_hash_v2.is_none() {
+            return Err(MagnetLinkError::MissingInfoHash.into());
+        }
 
         Ok(MagnetLink {
-            info_hash,
-            name,
-            tracker,
+            info_hash: self.info_hash,
+            info_hash_v2: self.info_hash_v2,
+            keywords: Vec::new(),
+            name: self.name,
+            peers: Vec::new(),
+            select_only: self.select_only,
+            trackers: self.trackers,
+            web_seeds: self.web_seeds,
         })
     }
+
+    pub fn info_hash(self, info_hash: Bytes20) -> Self {
+        Self {
+            info_hash: Some(info_hash),
+            ..self
+        }
+    }
+
+    pub fn info_hash_v2(self, info_hash_v2: Bytes32) -> Self {
+        Self {
+            info_hash_v2: Some(info_hash_v2),
+            ..self
+        }
+    }
+
+    pub fn name(self, name: impl Into<String>) -> Self {
+        Self {
+            name: Some(name.into()),
+            ..self
+        }
+    }
+
+    /// Adds a tracker after the ones already added.
+    pub fn tracker(mut self, url: impl Into<String>) -> Self {
+        self.trackers.push(url.into());
+        self
+    }
+
+    pub fn web_seed(mut self, url: impl Into<String>) -> Self {
+        self.web_seeds.push(url.into());
+        self
+    }
+
+    /// Selects only the files in `ranges` for download (BEP 53).
+    pub fn select_only(self, ranges: Vec<RangeInclusive<usize>>) -> Self {
+        Self {
+            select_only: ranges,
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(SCHEME)?;
+
+        let mut params = Vec::new();
+        if let Some(hash) = self.info_hash {
+            params.push(format!("xt=urn:btih:{}", hash.hex_encoded()));
+        }
+        if let Some(hash) = self.info_hash_v2 {
+            let multihash = hex::encode(SHA256_MULTIHASH);
+            params.push(format!("xt=urn:btmh:{multihash}{}", hash.hex_encoded()));
+        }
+
+        let values = [
+            ("dn", self.name.as_slice()),
+            ("tr", &self.trackers),
+            ("ws", &self.web_seeds),
+            ("x.pe", &self.peers),
+            ("kt", &self.keywords),
+        ];
+        for (key, values) in values {
+            for value in values {
+                let value = url::form_urlencoded::byte_serialize(value.as_bytes());
+                params.push(format!("{key}={}", value.collect::<String>()));
+            }
+        }
+
+        if !self.select_only.is_empty() {
+            let ranges = self
+                .select_only
+                .iter()
+                .map(|r| match (r.start(), r.end()) {
+                    (start, end) if start == end => start.to_string(),
+                    (start, end) => format!("{start}-{end}"),
+                })
+                .collect::<Vec<_>>();
+            params.push(format!("so={}", ranges.join(",")));
+        }
+
+        f.write_str(&params.join("&"))
+    }
+}
+
+impl MagnetLink {
+    // Links to hybrid torrents carry an exact topic for both versions. Topics
+    // of other kinds are not ours to handle and are ignored.
+    fn add_exact_topic(&mut self, xt: &str) -> Result<(), MagnetLinkError> {
+        if let Some(hash) = strip_prefix_ignore_case(xt, "urn:btih:") {
+            let hash = parse_btih(hash)?;
+            set_hash(&mut self.info_hash, hash, Bytes20::hex_encoded)
+        } else if let Some(hash) = strip_prefix_ignore_case(xt, "urn:btmh:") {
+            let hash = parse_multihash(hash)?;
+            set_hash(&mut self.info_hash_v2, hash, Bytes32::hex_encoded)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn write_entry<T: Serialize + ?Sized>(
+    bytes: &mut Vec<u8>,
+    key: &str,
+    value: &T,
+) -> crate::Result<()> {
+    let mut serializer = Serializer::new(bytes);
+    key.serialize(&mut serializer)?;
+    value.serialize(&mut serializer)
+}
+
+fn set_hash<T: PartialEq + Copy>(
+    slot: &mut Option<T>,
+    hash: T,
+    hex: fn(&T) -> String,
+) -> Result<(), MagnetLinkError> {
+    match slot {
+        Some(old) if *old != hash => {
+            Err(MagnetLinkError::ConflictingInfoHash(hex(old), hex(&hash)))
+        }
+        _ => {
+            *slot = Some(hash);
+            Ok(())
+        }
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = s.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &s[prefix.len()..])
+}
+
+// A v1 info hash, either 40 hex digits or 32 base32 characters.
+fn parse_btih(hash: &str) -> Result<Bytes20, MagnetLinkError> {
+    let invalid = || MagnetLinkError::InvalidInfoHash(hash.to_string());
+
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).map_err(|_| invalid())?,
+        32 => base32_decode(hash).ok_or_else(invalid)?,
+        _ => return Err(invalid()),
+    };
+
+    Bytes20::try_from(bytes).map_err(|_| invalid())
+}
+
+fn parse_multihash(hash: &str) -> Result<Bytes32, MagnetLinkError> {
+    let bytes =
+        hex::decode(hash).map_err(|_| MagnetLinkError::InvalidInfoHash(hash.to_string()))?;
+
+    bytes
+        .strip_prefix(&SHA256_MULTIHASH[..])
+        .and_then(|digest| Bytes32::try_from(digest.to_vec()).ok())
+        .ok_or_else(|| MagnetLinkError::UnsupportedMultihash(hash.to_string()))
+}
+
+// Unpadded RFC 4648 base32, in either case.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(HASH_SIZE);
+    let mut buffer = 0u64;
+    let mut bits = 0;
+
+    for c in s.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+// A list of file indices and inclusive ranges, like `0,2,4-6`.
+fn parse_select_only(so: &str) -> Result<Vec<RangeInclusive<usize>>, MagnetLinkError> {
+    let invalid = || MagnetLinkError::InvalidSelectOnly(so.to_string());
+    let index = |s: &str| s.trim().parse::<usize>().map_err(|_| invalid());
+
+    so.split(',')
+        .map(|part| match part.split_once('-') {
+            Some((start, end)) => {
+                let range = index(start)?..=index(end)?;
+                if range.is_empty() {
+                    return Err(invalid());
+                }
+                Ok(range)
+            }
+            None => index(part).map(|i| i..=i),
+        })
+        .collect()
 }
 
 impl AsTrackerRequest for MagnetLink {
     fn as_tracker_request(&self) -> crate::Result<TrackerRequest> {
         TrackerRequest::builder()
-            .url(
-                self.tracker
-                    .as_deref()
-                    .ok_or(BitTorrentError::InvalidMagnetLink)?,
-            )
+            .url(self.tracker().ok_or(MagnetLinkError::NoTracker)?)
             .info_hash(self.info_hash())
             .left(999)
             .build()
@@ -74,6 +466,15 @@ impl AsTrackerRequest for MagnetLink {
 mod tests {
     use super::*;
 
+    const V1: &str = "ad42ce8109f54c99613ce38f9b4d87e70f24a165";
+
+    fn error(s: &str) -> MagnetLinkError {
+        match MagnetLink::from_str(s) {
+            Err(BitTorrentError::InvalidMagnetLink(err)) => err,
+            other => panic!("Expected a magnet link error, got {other:?}"),
+        }
+    }
+
     #[test]
     fn test_magnet_link_parsing() {
         let magnet_str = "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&dn=magnet1.gif&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce";
@@ -81,12 +482,235 @@ mod tests {
         assert_eq!(
             magnet_link,
             MagnetLink {
-                info_hash: hex::decode("ad42ce8109f54c99613ce38f9b4d87e70f24a165").unwrap(),
+                info_hash: Some(Bytes20::from(
+                    hex::decode("ad42ce8109f54c99613ce38f9b4d87e70f24a165")
+                        .unwrap()
+                        .as_ref()
+                )),
+                info_hash_v2: None,
+                keywords: Vec::new(),
                 name: Some("magnet1.gif".to_string()),
-                tracker: Some(
+                peers: Vec::new(),
+                select_only: Vec::new(),
+                trackers: vec![
                     "http://bittorrent-test-tracker.codecrafters.io/announce".to_string()
-                ),
+                ],
+                web_seeds: Vec::new(),
             }
         );
     }
+
+    #[test]
+    fn test_btmh_magnet_links() {
+        let v2 = "1220caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e";
+        let digest = Bytes32::try_from(hex::decode(&v2[4..]).unwrap()).unwrap();
+
+        let link = MagnetLink::from_str(&format!("magnet:?xt=urn:btmh:{v2}&dn=test")).unwrap();
+        assert_eq!(link.info_hash_v2(), Some(digest));
+        assert_eq!(link.info_hash(), digest.truncated());
+
+        let v1 = "631a31dd0a46257d5078c0dee4e66e26f73e42ac";
+        let hybrid =
+            MagnetLink::from_str(&format!("magnet:?xt=urn:btih:{v1}&xt=urn:btmh:{v2}")).unwrap();
+        assert_eq!(hybrid.info_hash().hex_encoded(), v1);
+        assert_eq!(hybrid.info_hash_v2(), Some(digest));
+
+        assert!(MagnetLink::from_str("magnet:?xt=urn:btmh:1114aabb").is_err());
+    }
+
+    #[test]
+    fn test_select_only_peers_and_web_seeds() {
+        let link = MagnetLink::from_str(
+            "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&so=0,2,4-6\
+             &x.pe=10.0.0.1:6881&x.pe=%5B::1%5D:51413&ws=http%3A%2F%2Fseed.example%2Fdata",
+        )
+        .unwrap();
+
+        assert_eq!(link.select_only(), [0..=0, 2..=2, 4..=6]);
+        let selected = (0..8).filter(|i| link.is_selected(*i)).collect::<Vec<_>>();
+        assert_eq!(selected, [0, 2, 4, 5, 6]);
+
+        assert_eq!(link.peers(), ["10.0.0.1:6881", "[::1]:51413"]);
+        assert_eq!(link.web_seeds(), ["http://seed.example/data"]);
+
+        let all =
+            MagnetLink::from_str("magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165")
+                .unwrap();
+        assert!(all.is_selected(42));
+
+        for so in ["1,x", "3-1", ""] {
+            let url =
+                format!("magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&so={so}");
+            assert!(MagnetLink::from_str(&url).is_err(), "{so}");
+        }
+    }
+
+    #[test]
+    fn test_base32_info_hash() {
+        // The base32 form of V1.
+        let base32 = "VVBM5AIJ6VGJSYJ44OHZWTMH44HSJILF";
+        let link = MagnetLink::from_str(&format!("magnet:?xt=urn:btih:{base32}")).unwrap();
+        assert_eq!(link.info_hash().hex_encoded(), V1);
+
+        let lower = base32.to_lowercase();
+        let link = MagnetLink::from_str(&format!("magnet:?xt=urn:btih:{lower}")).unwrap();
+        assert_eq!(link.info_hash().hex_encoded(), V1);
+    }
+
+    #[test]
+    fn test_repeated_and_case_insensitive_params() {
+        let link = MagnetLink::from_str(&format!(
+            "MAGNET:?XT=URN:BTIH:{}&TR=udp%3A%2F%2Fone&tr=http%3A%2F%2Ftwo&tr=http%3A%2F%2Fthree\
+             &Ws=http%3A%2F%2Fa&ws=http%3A%2F%2Fb&kt=linux+iso&kt=debian\
+             &xt.1=urn:btih:{V1}&x.foo=ignored",
+            V1.to_uppercase()
+        ))
+        .unwrap();
+
+        assert_eq!(link.info_hash().hex_encoded(), V1);
+        assert_eq!(link.trackers(), ["udp://one", "http://two", "http://three"]);
+        assert_eq!(link.tracker(), Some("udp://one"));
+        assert_eq!(link.web_seeds(), ["http://a", "http://b"]);
+        assert_eq!(link.keywords(), ["linux iso", "debian"]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(error("http://example.com"), MagnetLinkError::NotMagnet);
+        assert_eq!(error("magnet:?dn=name"), MagnetLinkError::MissingInfoHash);
+        assert_eq!(
+            error("magnet:?xt=urn:btih:abcd"),
+            MagnetLinkError::InvalidInfoHash("abcd".into())
+        );
+        assert_eq!(
+            error("magnet:?xt=urn:btih:11111111111111111111111111111111111111!!"),
+            MagnetLinkError::InvalidInfoHash("11111111111111111111111111111111111111!!".into())
+        );
+
+        let other = "631a31dd0a46257d5078c0dee4e66e26f73e42ac";
+        assert_eq!(
+            error(&format!("magnet:?xt=urn:btih:{V1}&xt=urn:btih:{other}")),
+            MagnetLinkError::ConflictingInfoHash(V1.into(), other.into())
+        );
+        assert_eq!(
+            error("magnet:?xt=urn:btmh:1114aabb"),
+            MagnetLinkError::UnsupportedMultihash("1114aabb".into())
+        );
+        assert_eq!(
+            error(&format!("magnet:?xt=urn:btih:{V1}&so=2-1")),
+            MagnetLinkError::InvalidSelectOnly("2-1".into())
+        );
+
+        let link = MagnetLink::from_str(&format!("magnet:?xt=urn:btih:{V1}")).unwrap();
+        assert!(matches!(
+            link.as_tracker_request(),
+            Err(BitTorrentError::InvalidMagnetLink(
+                MagnetLinkError::NoTracker
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        let v2 = Bytes32::sha256_hash(b"info");
+        let link = MagnetLink::builder()
+            .info_hash(Bytes20::sha1_hash(b"info"))
+            .info_hash_v2(v2)
+            .name("two words & more")
+            .tracker("http://one/announce")
+            .tracker("udp://two:80")
+            .web_seed("http://seed/")
+            .select_only(vec![0..=0, 2..=4])
+            .build()
+            .unwrap();
+
+        let uri = link.to_string();
+        assert!(uri.starts_with(&format!(
+            "magnet:?xt=urn:btih:{}&xt=urn:btmh:1220{}&dn=two+words+%26+more&tr=http%3A%2F%2Fone",
+            Bytes20::sha1_hash(b"info").hex_encoded(),
+            v2.hex_encoded()
+        )));
+        assert!(uri.ends_with("&so=0,2-4"));
+        assert_eq!(MagnetLink::from_str(&uri).unwrap(), link);
+
+        assert!(MagnetLink::builder().name("x").build().is_err());
+    }
+
+    #[test]
+    fn test_from_meta() {
+        use crate::meta::Info;
+
+        let meta = Meta {
+            announce: "http://a/announce".into(),
+            announce_list: vec![
+                vec!["http://a/announce".into(), "http://b/announce".into()],
+                vec!["udp://c:80".into()],
+            ],
+            info: Info {
+                attr: None,
+                file_tree: None,
+                files: None,
+                length: Some(3),
+                meta_version: None,
+                name: "file.txt".into(),
+                piece_length: 4,
+                pieces: vec![Bytes20::sha1_hash(b"abc")].into(),
+                private: None,
+                source: None,
+            },
+            url_list: vec!["http://seed/file.txt".into()],
+            piece_layers: Default::default(),
+        };
+
+        let link = MagnetLinkBuilder::from_meta(&meta)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(link.info_hash(), meta.info.hash().unwrap());
+        assert_eq!(link.info_hash_v2(), None);
+        assert_eq!(link.name(), Some("file.txt"));
+        assert_eq!(
+            link.trackers(),
+            ["http://a/announce", "http://b/announce", "udp://c:80"]
+        );
+        assert_eq!(link.web_seeds(), ["http://seed/file.txt"]);
+    }
+
+    #[test]
+    fn test_to_torrent_keeps_raw_info() {
+        use crate::bencode::Deserializer;
+        use serde::Deserialize;
+
+        // Unsorted keys would not survive parsing and serializing again.
+        let metadata =
+            b"d6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaa1:zi1e1:xi1ee";
+        let hash = Bytes20::sha1_hash(metadata);
+
+        let link = MagnetLink::builder()
+            .info_hash(hash)
+            .tracker("http://one/announce")
+            .tracker("http://two/announce")
+            .web_seed("http://seed/a")
+            .build()
+            .unwrap();
+        let bytes = link.to_torrent(metadata).unwrap();
+
+        let start = bytes.windows(6).position(|w| w == b"4:info").unwrap() + 6;
+        assert_eq!(&bytes[start..start + metadata.len()], metadata);
+
+        let meta = Meta::deserialize(&mut Deserializer::new(&bytes[..])).unwrap();
+        assert_eq!(meta.announce, "http://one/announce");
+        assert_eq!(
+            meta.announce_list,
+            [["http://one/announce"], ["http://two/announce"]]
+        );
+        assert_eq!(meta.url_list, ["http://seed/a"]);
+        assert_eq!(meta.info.length, Some(3));
+
+        let other = MagnetLink::builder()
+            .info_hash(Bytes20::sha1_hash(b"other"))
+            .build()
+            .unwrap();
+        assert!(other.to_torrent(metadata).is_err());
+    }
 }
@@ -0,0 +1,117 @@
+//! The SHA-256 merkle trees of v2 torrents (BEP 52). Every file has its own
+//! tree over 16 KiB blocks, padded with zero hashes to a power of two leaves.
+
+use crate::util::Bytes32;
+
+use sha2::{Digest, Sha256};
+
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+/// The leaves of a tree: the hashes of the 16 KiB blocks of `data`.
+pub fn leaf_hashes(data: &[u8]) -> Vec<Bytes32> {
+    data.chunks(BLOCK_SIZE).map(Bytes32::sha256_hash).collect()
+}
+
+/// The root of a tree over `hashes`, padded with `pad` up to `width` leaves.
+pub fn root(hashes: &[Bytes32], width: usize, pad: Bytes32) -> Bytes32 {
+    debug_assert!(width.is_power_of_two() && hashes.len() <= width);
+
+    let mut level = hashes.to_vec();
+    let mut pad = pad;
+    let mut width = width;
+
+    while width > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pad)))
+            .collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+
+    level.first().copied().unwrap_or(pad)
+}
+
+/// The root of a subtree of `width` zero leaves.
+pub fn pad_hash(width: usize) -> Bytes32 {
+    root(&[], width, Bytes32::default())
+}
+
+/// The hash of a piece in its file's piece layer: the root of the subtree
+/// over its blocks. The last piece of a file is padded like a full one.
+pub fn piece_root(data: &[u8], piece_length: usize) -> Bytes32 {
+    root(
+        &leaf_hashes(data),
+        blocks_per_piece(piece_length),
+        Bytes32::default(),
+    )
+}
+
+/// The piece layer of a file holding `data`.
+pub fn piece_layer(data: &[u8], piece_length: usize) -> Vec<Bytes32> {
+    data.chunks(piece_length)
+        .map(|piece| piece_root(piece, piece_length))
+        .collect()
+}
+
+/// The root of a whole file from its data.
+pub fn file_root(data: &[u8]) -> Bytes32 {
+    let leaves = leaf_hashes(data);
+    root(
+        &leaves,
+        leaves.len().next_power_of_two(),
+        Bytes32::default(),
+    )
+}
+
+/// The root of a whole file from its piece layer.
+pub fn layer_root(layer: &[Bytes32], piece_length: usize) -> Bytes32 {
+    root(
+        layer,
+        layer.len().next_power_of_two(),
+        pad_hash(blocks_per_piece(piece_length)),
+    )
+}
+
+fn blocks_per_piece(piece_length: usize) -> usize {
+    (piece_length / BLOCK_SIZE).max(1)
+}
+
+fn hash_pair(left: &Bytes32, right: &Bytes32) -> Bytes32 {
+    let mut hasher = Sha256::new();
+    hasher.update(left.as_ref());
+    hasher.update(right.as_ref());
+    Bytes32::from(hasher.finalize().as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_block_root_is_block_hash() {
+        let data = vec![7u8; 1000];
+        assert_eq!(file_root(&data), Bytes32::sha256_hash(&data));
+    }
+
+    #[test]
+    fn test_layer_root_matches_file_root() {
+        let data = (0..5 * BLOCK_SIZE + 100)
+            .map(|i| i as u8)
+            .collect::<Vec<u8>>();
+
+        for piece_length in [BLOCK_SIZE, 2 * BLOCK_SIZE, 4 * BLOCK_SIZE] {
+            let layer = piece_layer(&data, piece_length);
+            assert_eq!(layer_root(&layer, piece_length), file_root(&data));
+        }
+    }
+
+    #[test]
+    fn test_padding() {
+        let a = Bytes32::sha256_hash(b"a");
+        let zero = Bytes32::default();
+        assert_eq!(root(&[a], 2, zero), hash_pair(&a, &zero));
+        assert_eq!(pad_hash(2), hash_pair(&zero, &zero));
+        assert_eq!(pad_hash(1), zero);
+    }
+}
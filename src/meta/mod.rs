@@ -1,7 +1,12 @@
 mod file;
+mod file_tree;
 mod magnet_link;
+pub mod merkle;
 mod tracker;
 
-pub use file::{Info, Meta};
-pub use magnet_link::MagnetLink;
-pub use tracker::{AsTrackerRequest, TrackerRequest, TrackerRequestBuilder, TrackerResponse};
+pub use file::{FileAttributes, FileInfo, FileRange, FileSpan, Info, Meta};
+pub use file_tree::{FileEntry, FileTree, FileTreeNode, PieceLayers};
+pub use magnet_link::{MagnetLink, MagnetLinkBuilder, MagnetLinkError};
+pub use tracker::{
+    AsTrackerRequest, TrackerEvent, TrackerRequest, TrackerRequestBuilder, TrackerResponse,
+};
@@ -38,6 +38,24 @@ impl TrackerRequest {
     }
 }
 
+/// What an announce tells the tracker about the download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerEvent {
+    Started,
+    Stopped,
+    Completed,
+}
+
+impl TrackerEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Started => "started",
+            Self::Stopped => "stopped",
+            Self::Completed => "completed",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct TrackerRequestBuilder {
     url: Option<String>,
@@ -48,6 +66,7 @@ pub struct TrackerRequestBuilder {
     downloaded: Option<u64>,
     left: Option<u64>,
     compact: Option<u8>,
+    event: Option<TrackerEvent>,
 }
 
 impl TrackerRequestBuilder {
@@ -86,8 +105,8 @@ impl TrackerRequestBuilder {
             }
         });
 
-        let url = url
-            .query_pairs_mut()
+        let mut query = url.query_pairs_mut();
+        query
             .encoding_override(encoding)
             .append_pair("info_hash", unsafe_hash_str)
             .append_pair("peer_id", peer_id)
@@ -95,8 +114,11 @@ impl TrackerRequestBuilder {
             .append_pair("uploaded", &uploaded)
             .append_pair("downloaded", &downloaded)
             .append_pair("left", &left)
-            .append_pair("compact", &compact)
-            .finish();
+            .append_pair("compact", &compact);
+        if let Some(event) = self.event {
+            query.append_pair("event", event.as_str());
+        }
+        let url = query.finish();
 
         let req = reqwest::Client::new().get(url.as_str());
         Ok(TrackerRequest { inner: req })
@@ -116,12 +138,33 @@ impl TrackerRequestBuilder {
         }
     }
 
+    pub fn peer_id(self, peer_id: impl Into<String>) -> Self {
+        Self {
+            peer_id: Some(peer_id.into()),
+            ..self
+        }
+    }
+
+    pub fn port(self, port: u16) -> Self {
+        Self {
+            port: Some(port),
+            ..self
+        }
+    }
+
     pub fn left(self, left: u64) -> Self {
         Self {
             left: Some(left),
             ..self
         }
     }
+
+    pub fn event(self, event: TrackerEvent) -> Self {
+        Self {
+            event: Some(event),
+            ..self
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -1,108 +1,554 @@
-use crate::util::{KeyHash, ThrottleQueue};
+use crate::{
+    BitTorrentError, Result,
+    util::{KeyHash, ThrottleQueue},
+};
 
-use super::{AsBytes, Message, PeerMessage, PeerStream, Piece, PieceManager};
+use super::{
+    AsBytes, Extension, MAX_PEX_PEERS, Message, Peer, PeerMessage, PeerStream, PexFlags, Piece,
+    PieceManager,
+    metadata::metadata_response,
+    peer::BoxedWriter,
+    pex::PexTracker,
+    piece::BLOCK_SIZE,
+    score::{PROTOCOL_VIOLATION_PENALTY, PeerScores},
+};
 
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
+use std::net::SocketAddr;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
-use tokio::net::tcp::OwnedWriteHalf;
 use tokio::sync::{
     Mutex,
     mpsc::{self, Receiver},
 };
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tokio_stream::StreamExt;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
-const BLOCK_SIZE: usize = 16 * 1024;
 const THROTTLE_CAPACITY: usize = 5;
 
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+
 type PeerMessageSender =
     Box<dyn Fn(PeerMessage) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
 
 type Queue = Arc<Mutex<ThrottleQueue<PeerMessage, PeerMessageSender>>>;
 type Pieces = Arc<Mutex<PieceManager>>;
+type AllowedFast = Arc<Mutex<HashSet<usize>>>;
+type Parked = Arc<Mutex<Vec<PeerMessage>>>;
+type SharedWriter = Arc<Mutex<Writer>>;
+type SharedPex = Arc<Mutex<PexTracker>>;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub keep_alive_interval: Duration,
+    pub idle_timeout: Duration,
+    /// Raw info dictionary served to peers requesting it over ut_metadata.
+    pub metadata: Option<Bytes>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            keep_alive_interval: KEEP_ALIVE_INTERVAL,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            metadata: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Piece(Piece),
+    /// The connection was closed. `pending` lists the index and length of every
+    /// piece that was requested from the peer but not completed.
+    Disconnected {
+        pending: Vec<(usize, usize)>,
+    },
+    /// Peers the remote side told us about over ut_pex.
+    Peers(Vec<Peer>),
+    /// The peer rejected a block of the piece while not choking us, so it
+    /// will not send it. The piece is handed back for another peer.
+    Rejected {
+        index: usize,
+        length: usize,
+    },
+    /// The peer suggested downloading the piece.
+    Suggest(usize),
+}
 
 pub struct Broker {
+    peer: Option<Peer>,
+    outbound: bool,
     queue: Queue,
     pieces: Pieces,
+    allowed_fast: AllowedFast,
+    // Pieces the peer may request from us while choked, once we have them.
+    fast_set: Vec<usize>,
+    // Requests held back while the peer chokes us, sent once it unchokes.
+    parked: Parked,
+    choked: Arc<AtomicBool>,
+    rejected: Arc<Mutex<HashSet<usize>>>,
+    connected: Arc<AtomicBool>,
+    writer: SharedWriter,
+    pex_ext_id: Option<u8>,
+    pex: SharedPex,
+    // The reader and keep-alive tasks, stopped when the broker is dropped.
+    tasks: Vec<JoinHandle<()>>,
 }
 
-pub fn create(stream: PeerStream) -> (Broker, Receiver<Piece>) {
+impl Drop for Broker {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        self.connected.store(false, Ordering::SeqCst);
+
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let writer = Arc::clone(&self.writer);
+            runtime.spawn(async move {
+                if let Err(err) = writer.lock().await.shutdown().await {
+                    debug!("Failed to shut down connection: {err}");
+                }
+            });
+        }
+    }
+}
+
+pub fn create(stream: PeerStream, scores: PeerScores) -> (Broker, Receiver<Event>) {
+    create_with_config(stream, scores, Config::default())
+}
+
+pub fn create_with_config(
+    stream: PeerStream,
+    scores: PeerScores,
+    config: Config,
+) -> (Broker, Receiver<Event>) {
+    let addr = stream.peer_addr();
+    let metadata_ext_id = stream.metadata_ext_id();
+    let pex_ext_id = stream.pex_ext_id();
+    let outbound = stream.is_outbound();
+    let fast_extension = stream.supports_fast_extension();
+
     let PeerStream {
         mut reader, writer, ..
     } = stream;
 
-    let writer = Arc::new(Mutex::new(writer));
+    let writer = Arc::new(Mutex::new(Writer::new(writer)));
+    let writer_pointer = Arc::clone(&writer);
 
     let queue = Arc::new(Mutex::new(ThrottleQueue::new(
         THROTTLE_CAPACITY,
-        send_message(writer),
+        send_message(Arc::clone(&writer)),
     )));
 
-    let (piece_tx, piece_rx) = mpsc::channel::<Piece>(100);
-    let pieces = Arc::new(Mutex::new(PieceManager::new(piece_tx)));
+    let (event_tx, event_rx) = mpsc::channel::<Event>(100);
+    let pieces = Arc::new(Mutex::new(PieceManager::new()));
 
-    let broker = Broker { queue, pieces };
+    let allowed_fast = Arc::new(Mutex::new(HashSet::new()));
+    let connected = Arc::new(AtomicBool::new(true));
+    let pex = Arc::new(Mutex::new(PexTracker::new()));
+
+    let mut broker = Broker {
+        peer: addr.map(Peer::new),
+        outbound,
+        queue,
+        pieces,
+        allowed_fast,
+        fast_set: Vec::new(),
+        parked: Arc::default(),
+        choked: Arc::default(),
+        rejected: Arc::default(),
+        connected,
+        writer: Arc::clone(&writer),
+        pex_ext_id,
+        pex,
+        tasks: Vec::new(),
+    };
 
     let queue_pointer = broker.clone_queue();
     let pieces_pointer = broker.clone_pieces();
+    let allowed_fast_pointer = Arc::clone(&broker.allowed_fast);
+    let parked_pointer = Arc::clone(&broker.parked);
+    let choked_pointer = Arc::clone(&broker.choked);
+    let rejected_pointer = Arc::clone(&broker.rejected);
+    let connected_pointer = Arc::clone(&broker.connected);
+    let pex_pointer = Arc::clone(&broker.pex);
+
+    broker.tasks.push(tokio::spawn(keep_alive(
+        writer,
+        Arc::clone(&broker.connected),
+        config.keep_alive_interval,
+    )));
 
-    tokio::spawn(async move {
-        while let Some(msg) = reader.next().await {
-            let msg = match msg {
-                Ok(msg) => msg,
-                Err(e) => {
+    let reader_task = tokio::spawn(async move {
+        loop {
+            let msg = match tokio::time::timeout(config.idle_timeout, reader.next()).await {
+                Ok(Some(Ok(msg))) => msg,
+                Ok(Some(Err(e))) => {
                     error!("Failed to read message: {e}");
+                    penalize(&scores, addr, &e).await;
+                    break;
+                }
+                Ok(None) => {
+                    debug!("Connection closed by peer");
+                    break;
+                }
+                Err(_) => {
+                    warn!(
+                        "No message received for {:?}, disconnecting",
+                        config.idle_timeout
+                    );
                     break;
                 }
             };
 
-            if let Some(peer_msg) = msg.as_peer_message() {
+            if let Some(peer_msg) = msg.as_peer_message().filter(|m| m.is_piece()) {
                 let mut queue = queue_pointer.lock().await;
                 queue.done(peer_msg.key_hash()).await;
             }
 
-            if let Message::PeerMessage(PeerMessage::Piece {
-                index,
-                begin,
-                block,
-            }) = msg
-            {
-                debug!(
-                    "Received piece message: index={}, begin={}, block_length={}",
+            match msg {
+                Message::PeerMessage(PeerMessage::Piece {
                     index,
                     begin,
-                    block.len()
-                );
-                let mut pieces = pieces_pointer.lock().await;
-
-                if let Err(err) = pieces
-                    .insert_block(index as usize, begin as usize, block)
-                    .await
-                {
-                    error!("Failed to insert block: {err}");
-                    break;
+                    block,
+                }) => {
+                    debug!(
+                        "Received piece message: index={}, begin={}, block_length={}",
+                        index,
+                        begin,
+                        block.len()
+                    );
+
+                    let result = pieces_pointer.lock().await.insert_block(
+                        index as usize,
+                        begin as usize,
+                        block,
+                    );
+
+                    match result {
+                        Ok(Some(mut piece)) => {
+                            piece.peer = addr;
+                            if event_tx.send(Event::Piece(piece)).await.is_err() {
+                                error!("{}", BitTorrentError::ChannelClosed);
+                                break;
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(err) => {
+                            error!("Failed to insert block: {err}");
+                            penalize(&scores, addr, &err).await;
+                            break;
+                        }
+                    }
                 }
+                Message::PeerMessage(
+                    msg @ PeerMessage::RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    },
+                ) => {
+                    debug!("Request rejected: index={index}, begin={begin}, length={length}");
+                    queue_pointer.lock().await.done(msg.key_hash()).await;
+
+                    // A choking peer rejects what it would send once it
+                    // unchokes us, unless the piece is allowed fast.
+                    let mut parked = parked_pointer.lock().await;
+                    if choked_pointer.load(Ordering::SeqCst)
+                        && !allowed_fast_pointer
+                            .lock()
+                            .await
+                            .contains(&(index as usize))
+                    {
+                        parked.push(PeerMessage::Request {
+                            index,
+                            begin,
+                            length,
+                        });
+                        continue;
+                    }
+                    drop(parked);
+
+                    let Some(length) = pieces_pointer.lock().await.remove(index as usize) else {
+                        continue;
+                    };
+                    rejected_pointer.lock().await.insert(index as usize);
+                    queue_pointer.lock().await.retain_waiting(
+                        |msg| !matches!(msg, PeerMessage::Request { index: i, .. } if *i == index),
+                    );
+
+                    let index = index as usize;
+                    if event_tx
+                        .send(Event::Rejected { index, length })
+                        .await
+                        .is_err()
+                    {
+                        error!("{}", BitTorrentError::ChannelClosed);
+                        break;
+                    }
+                }
+                Message::PeerMessage(PeerMessage::Choke) => {
+                    debug!("Peer choked us");
+                    let _parked = parked_pointer.lock().await;
+                    choked_pointer.store(true, Ordering::SeqCst);
+                }
+                Message::PeerMessage(PeerMessage::Unchoke) => {
+                    let mut parked = parked_pointer.lock().await;
+                    choked_pointer.store(false, Ordering::SeqCst);
+                    debug!("Peer unchoked us, sending {} held requests", parked.len());
+
+                    let mut queue = queue_pointer.lock().await;
+                    for request in parked.drain(..) {
+                        queue.queue(request).await;
+                    }
+                }
+                Message::PeerMessage(PeerMessage::AllowedFast(index)) => {
+                    debug!("Piece {index} is allowed fast");
+                    allowed_fast_pointer.lock().await.insert(index as usize);
+                }
+                Message::PeerMessage(PeerMessage::SuggestPiece(index)) => {
+                    debug!("Peer suggested piece {index}");
+                    if event_tx.send(Event::Suggest(index as usize)).await.is_err() {
+                        error!("{}", BitTorrentError::ChannelClosed);
+                        break;
+                    }
+                }
+                // We upload nothing. Peers with the fast extension may ask for
+                // allowed fast pieces while choked and must be told so.
+                Message::PeerMessage(PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                }) if fast_extension => {
+                    let reject = PeerMessage::RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    };
+                    if let Err(err) = send(&writer_pointer, reject).await {
+                        error!("Failed to reject request: {err}");
+                        break;
+                    }
+                }
+                Message::PeerMessage(PeerMessage::Port(port)) => {
+                    debug!("Peer listens for DHT on port {port}");
+                }
+                Message::Extension(Extension::RequestMetadata { piece, .. }) => {
+                    let Some(ext_id) = metadata_ext_id else {
+                        debug!("Ignoring metadata request from peer without ut_metadata");
+                        continue;
+                    };
+
+                    let response = metadata_response(config.metadata.as_ref(), ext_id, piece);
+                    let bytes = match response.as_bytes() {
+                        Ok(b) => b,
+                        Err(err) => {
+                            error!("Failed to serialize metadata response: {err}");
+                            continue;
+                        }
+                    };
+
+                    if let Err(err) = writer_pointer.lock().await.send(&bytes).await {
+                        error!("Failed to send metadata response: {err}");
+                        break;
+                    }
+                }
+                Message::Extension(Extension::Pex { message, .. }) => {
+                    if !pex_pointer.lock().await.accept_incoming(Instant::now()) {
+                        debug!("Ignoring PEX message sent too soon after the previous one");
+                        continue;
+                    }
+
+                    debug!(
+                        "Peer exchanged {} added and {} dropped peers",
+                        message.added.len(),
+                        message.dropped.len()
+                    );
+
+                    // BEP 11 caps the peers a message adds; the rest are dropped.
+                    let peers = message
+                        .added
+                        .into_iter()
+                        .take(MAX_PEX_PEERS)
+                        .map(|(peer, _)| peer)
+                        .collect();
+                    if event_tx.send(Event::Peers(peers)).await.is_err() {
+                        error!("{}", BitTorrentError::ChannelClosed);
+                        break;
+                    }
+                }
+                Message::Extension(Extension::Unknown { ext_id, payload }) => {
+                    debug!(
+                        "Skipping unsupported extended message: ext_id={ext_id}, payload_length={}",
+                        payload.len()
+                    );
+                }
+                Message::Unknown { id, payload } => {
+                    warn!(
+                        "Skipping unknown message: id={id}, payload_length={}",
+                        payload.len()
+                    );
+                }
+                _ => {}
             }
         }
+
+        // Mark the broker as disconnected while holding the pieces lock so that
+        // no new piece can be assigned after the pending ones are collected.
+        let pending = {
+            let mut pieces = pieces_pointer.lock().await;
+            connected_pointer.store(false, Ordering::SeqCst);
+            pieces.take_pending()
+        };
+
+        if let Err(err) = writer_pointer.lock().await.shutdown().await {
+            debug!("Failed to shut down connection: {err}");
+        }
+
+        if event_tx
+            .send(Event::Disconnected { pending })
+            .await
+            .is_err()
+        {
+            debug!("Download finished before disconnection was reported");
+        }
     });
+    broker.tasks.push(reader_task);
 
-    (broker, piece_rx)
+    (broker, event_rx)
 }
 
 impl Broker {
-    pub async fn request_piece(&mut self, index: usize, piece_length: usize) {
-        self.new_piece(index, piece_length).await;
+    pub async fn request_piece(&mut self, index: usize, piece_length: usize) -> Result<()> {
+        self.new_piece(index, piece_length).await?;
         self.send_piece_request(index, piece_length).await;
+        Ok(())
+    }
+
+    pub fn peer(&self) -> Option<Peer> {
+        self.peer
     }
 
+    /// Whether we connected to the peer rather than it to us.
+    pub fn is_outbound(&self) -> bool {
+        self.outbound
+    }
+
+    /// Tells the peer about changes in our connected peers over ut_pex, at most
+    /// once a minute. Does nothing if the peer does not support ut_pex.
+    pub async fn send_pex(&self, connected: &HashMap<Peer, PexFlags>) -> Result<()> {
+        let Some(ext_id) = self.pex_ext_id else {
+            return Ok(());
+        };
+
+        if !self.is_connected() {
+            return Err(BitTorrentError::ConnectionClosed);
+        }
+
+        // Never advertise the peer to itself.
+        let mut connected = connected.clone();
+        if let Some(peer) = self.peer {
+            connected.remove(&peer);
+        }
+
+        let Some(message) = self
+            .pex
+            .lock()
+            .await
+            .next_message(&connected, Instant::now())
+        else {
+            return Ok(());
+        };
+
+        let bytes = Extension::Pex { ext_id, message }.as_bytes()?;
+        self.writer.lock().await.send(&bytes).await?;
+        Ok(())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    /// Sets the pieces the peer may request while we choke it (BEP 6), and
+    /// tells it about the ones we `have` already.
+    pub fn offer_allowed_fast(&mut self, fast_set: Vec<usize>, have: &[bool]) {
+        let offered = fast_set
+            .iter()
+            .filter(|index| have.get(**index).copied().unwrap_or_default())
+            .map(|index| PeerMessage::AllowedFast(*index as u32))
+            .collect::<Vec<_>>();
+        self.fast_set = fast_set;
+
+        let writer = Arc::clone(&self.writer);
+        tokio::spawn(async move {
+            for msg in offered {
+                if let Err(err) = send(&writer, msg).await {
+                    debug!("Failed to send allowed fast piece: {err}");
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Tells the peer about a piece we just got, if it is in its allowed
+    /// fast set.
+    pub async fn allow_fast(&self, index: usize) -> Result<()> {
+        if !self.fast_set.contains(&index) || !self.is_connected() {
+            return Ok(());
+        }
+        send(&self.writer, PeerMessage::AllowedFast(index as u32)).await
+    }
+
+    /// Whether the peer allows requesting the piece even while choking us.
+    pub async fn is_allowed_fast(&self, index: usize) -> bool {
+        self.allowed_fast.lock().await.contains(&index)
+    }
+
+    /// Whether the piece was requested from the peer and is still coming.
+    pub async fn is_downloading(&self, index: usize) -> bool {
+        self.pieces.lock().await.contains(index)
+    }
+
+    /// Whether the peer rejected a request for the piece while unchoking us,
+    /// so asking it again is pointless.
+    pub async fn has_rejected(&self, index: usize) -> bool {
+        self.rejected.lock().await.contains(&index)
+    }
+
+    // Sends the request unless the peer chokes us and the piece is not
+    // allowed fast, in which case it waits for the peer to unchoke us.
     async fn queue(&mut self, msg: PeerMessage) {
+        if let PeerMessage::Request { index, .. } = msg {
+            let mut parked = self.parked.lock().await;
+            if self.choked.load(Ordering::SeqCst) && !self.is_allowed_fast(index as usize).await {
+                parked.push(msg);
+                return;
+            }
+        }
+
         self.queue.lock().await.queue(msg).await;
     }
 
-    async fn new_piece(&mut self, index: usize, length: usize) {
-        self.pieces.lock().await.new_block(index, length);
+    async fn new_piece(&mut self, index: usize, length: usize) -> Result<()> {
+        let mut pieces = self.pieces.lock().await;
+
+        if !self.is_connected() {
+            return Err(BitTorrentError::ConnectionClosed);
+        }
+
+        pieces.new_block(index, length);
+        Ok(())
     }
 
     async fn send_piece_request(&mut self, index: usize, piece_length: usize) {
@@ -132,7 +578,78 @@ impl Broker {
     }
 }
 
-fn send_message(writer: Arc<Mutex<OwnedWriteHalf>>) -> PeerMessageSender {
+#[derive(Debug)]
+struct Writer {
+    inner: BoxedWriter,
+    last_sent: Instant,
+}
+
+impl Writer {
+    fn new(inner: BoxedWriter) -> Self {
+        Self {
+            inner,
+            last_sent: Instant::now(),
+        }
+    }
+
+    async fn send(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.inner.write_all(bytes).await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> std::io::Result<()> {
+        self.inner.shutdown().await
+    }
+}
+
+async fn keep_alive(writer: SharedWriter, connected: Arc<AtomicBool>, interval: Duration) {
+    while connected.load(Ordering::SeqCst) {
+        let idle = writer.lock().await.last_sent.elapsed();
+
+        if idle < interval {
+            tokio::time::sleep(interval - idle).await;
+            continue;
+        }
+
+        let bytes = match Message::KeepAlive.as_bytes() {
+            Ok(b) => b,
+            Err(err) => {
+                error!("Failed to serialize keep-alive: {err}");
+                return;
+            }
+        };
+
+        debug!("Sending keep-alive");
+
+        if let Err(err) = writer.lock().await.send(&bytes).await {
+            debug!("Failed to send keep-alive: {err}");
+            return;
+        }
+    }
+}
+
+async fn send(writer: &SharedWriter, msg: PeerMessage) -> Result<()> {
+    let bytes = msg.as_bytes()?;
+    writer.lock().await.send(&bytes).await?;
+    Ok(())
+}
+
+async fn penalize(scores: &PeerScores, addr: Option<SocketAddr>, err: &BitTorrentError) {
+    let is_violation = matches!(
+        err,
+        BitTorrentError::ProtocolViolation(_) | BitTorrentError::InvalidPeerMessage(_)
+    );
+
+    if let Some(addr) = addr
+        && is_violation
+    {
+        warn!("Disconnecting peer {addr}: {err}");
+        scores.penalize(addr, PROTOCOL_VIOLATION_PENALTY).await;
+    }
+}
+
+fn send_message(writer: SharedWriter) -> PeerMessageSender {
     Box::new(move |msg: PeerMessage| {
         let writer = Arc::clone(&writer);
 
@@ -146,9 +663,397 @@ fn send_message(writer: Arc<Mutex<OwnedWriteHalf>>) -> PeerMessageSender {
             };
 
             let mut writer = writer.lock().await;
-            if let Err(err) = writer.write_all(&bytes).await {
+            if let Err(err) = writer.send(&bytes).await {
                 error!("Failed to send message: {err}");
             }
         })
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::{PexFlags, PexMessage, mse::EncryptionPolicy, utp::UtpSocket};
+    use crate::util::Bytes20;
+    use std::str::FromStr;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn stream_pair() -> (PeerStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let stream = PeerStream::new(Bytes20::default(), client.unwrap());
+
+        (stream, server.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_sends_keep_alive_after_outbound_silence() {
+        let (stream, mut remote) = stream_pair().await;
+        let config = Config {
+            keep_alive_interval: Duration::from_millis(50),
+            idle_timeout: Duration::from_secs(5),
+            ..Config::default()
+        };
+
+        let (_broker, _events) = create_with_config(stream, PeerScores::new(), config);
+
+        let mut buf = [0xffu8; 4];
+        tokio::time::timeout(Duration::from_secs(1), remote.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(buf, [0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_reports_pending_pieces_when_idle() {
+        let (stream, _remote) = stream_pair().await;
+        let config = Config {
+            keep_alive_interval: Duration::from_secs(5),
+            idle_timeout: Duration::from_millis(50),
+            ..Config::default()
+        };
+
+        let (mut broker, mut events) = create_with_config(stream, PeerScores::new(), config);
+        broker.request_piece(3, 1024).await.unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            event,
+            Some(Event::Disconnected {
+                pending: vec![(3, 1024)]
+            })
+        );
+        assert!(!broker.is_connected());
+        assert!(broker.request_piece(4, 1024).await.is_err());
+    }
+
+    // A connection that negotiated the fast extension.
+    async fn fast_stream_pair() -> (PeerStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = Peer::new(listener.local_addr().unwrap());
+
+        let remote = tokio::spawn(async move {
+            let (mut remote, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; 68];
+            remote.read_exact(&mut handshake).await.unwrap();
+            remote.write_all(&handshake).await.unwrap();
+            remote
+        });
+
+        let stream = peer
+            .connect_with(
+                Bytes20::sha1_hash(b"info"),
+                Bytes20::default(),
+                EncryptionPolicy::Disabled,
+            )
+            .await
+            .unwrap();
+        assert!(stream.supports_fast_extension());
+
+        (stream, remote.await.unwrap())
+    }
+
+    async fn assert_receives(remote: &mut TcpStream, msg: PeerMessage) {
+        let expected = msg.as_bytes().unwrap();
+        let mut buf = vec![0u8; expected.len()];
+        tokio::time::timeout(Duration::from_secs(1), remote.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf, expected);
+    }
+
+    async fn send(remote: &mut TcpStream, msg: PeerMessage) {
+        remote.write_all(&msg.as_bytes().unwrap()).await.unwrap();
+    }
+
+    async fn read_request(remote: &mut TcpStream) -> PeerMessage {
+        let mut buf = [0u8; 17];
+        tokio::time::timeout(Duration::from_secs(1), remote.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let field = |at: usize| u32::from_be_bytes(buf[at..at + 4].try_into().unwrap());
+        assert_eq!(buf[4], 6);
+        PeerMessage::Request {
+            index: field(5),
+            begin: field(9),
+            length: field(13),
+        }
+    }
+
+    async fn assert_silent(remote: &mut TcpStream) {
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_millis(100), remote.read(&mut buf)).await;
+        assert!(read.is_err());
+    }
+
+    fn request(index: u32, length: u32) -> PeerMessage {
+        PeerMessage::Request {
+            index,
+            begin: 0,
+            length,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reports_pieces_with_their_peer() {
+        let (stream, mut remote) = stream_pair().await;
+        let addr = stream.peer_addr();
+        let (mut broker, mut events) = create(stream, PeerScores::new());
+
+        broker.request_piece(2, 1024).await.unwrap();
+        assert_eq!(read_request(&mut remote).await, request(2, 1024));
+
+        let block = vec![7; 1024];
+        send(
+            &mut remote,
+            PeerMessage::Piece {
+                index: 2,
+                begin: 0,
+                block: block.clone(),
+            },
+        )
+        .await;
+
+        let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+            .await
+            .unwrap();
+        assert_eq!(
+            event,
+            Some(Event::Piece(Piece {
+                index: 2,
+                data: block,
+                peer: addr,
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hands_back_pieces_rejected_while_unchoked() {
+        let (stream, mut remote) = stream_pair().await;
+        let (mut broker, mut events) = create(stream, PeerScores::new());
+
+        broker.request_piece(2, 1024).await.unwrap();
+        assert_eq!(read_request(&mut remote).await, request(2, 1024));
+
+        let reject = PeerMessage::RejectRequest {
+            index: 2,
+            begin: 0,
+            length: 1024,
+        };
+        send(&mut remote, reject).await;
+
+        let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+            .await
+            .unwrap();
+        assert_eq!(
+            event,
+            Some(Event::Rejected {
+                index: 2,
+                length: 1024
+            })
+        );
+        assert!(broker.has_rejected(2).await);
+
+        // The peer is not asked for the block again.
+        assert_silent(&mut remote).await;
+    }
+
+    #[tokio::test]
+    async fn test_holds_requests_while_choked() {
+        let (stream, mut remote) = stream_pair().await;
+        let (mut broker, mut events) = create(stream, PeerScores::new());
+
+        broker.request_piece(2, 1024).await.unwrap();
+        assert_eq!(read_request(&mut remote).await, request(2, 1024));
+
+        send(&mut remote, PeerMessage::Choke).await;
+        send(&mut remote, PeerMessage::AllowedFast(4)).await;
+        let reject = PeerMessage::RejectRequest {
+            index: 2,
+            begin: 0,
+            length: 1024,
+        };
+        send(&mut remote, reject).await;
+
+        let event = tokio::time::timeout(Duration::from_millis(100), events.recv()).await;
+        assert!(event.is_err());
+        assert_silent(&mut remote).await;
+
+        broker.request_piece(3, 512).await.unwrap();
+        broker.request_piece(4, 512).await.unwrap();
+        assert_eq!(read_request(&mut remote).await, request(4, 512));
+        assert_silent(&mut remote).await;
+
+        send(&mut remote, PeerMessage::Unchoke).await;
+        assert_eq!(read_request(&mut remote).await, request(2, 1024));
+        assert_eq!(read_request(&mut remote).await, request(3, 512));
+        assert!(!broker.has_rejected(2).await);
+    }
+
+    #[tokio::test]
+    async fn test_offers_allowed_fast_pieces_we_have() {
+        let (stream, mut remote) = fast_stream_pair().await;
+        let (mut broker, _events) = create(stream, PeerScores::new());
+
+        broker.offer_allowed_fast(vec![3, 5], &[false, false, false, true]);
+        assert_receives(&mut remote, PeerMessage::AllowedFast(3)).await;
+
+        broker.allow_fast(1).await.unwrap();
+        broker.allow_fast(5).await.unwrap();
+        assert_receives(&mut remote, PeerMessage::AllowedFast(5)).await;
+
+        // Nothing is uploaded, so requests are rejected.
+        let request = PeerMessage::Request {
+            index: 3,
+            begin: 0,
+            length: 1024,
+        };
+        send(&mut remote, request).await;
+        let reject = PeerMessage::RejectRequest {
+            index: 3,
+            begin: 0,
+            length: 1024,
+        };
+        assert_receives(&mut remote, reject).await;
+        assert_silent(&mut remote).await;
+    }
+
+    #[tokio::test]
+    async fn test_reports_suggested_pieces() {
+        let (stream, mut remote) = stream_pair().await;
+        let (_broker, mut events) = create(stream, PeerScores::new());
+
+        send(&mut remote, PeerMessage::SuggestPiece(7)).await;
+        let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+            .await
+            .unwrap();
+        assert_eq!(event, Some(Event::Suggest(7)));
+    }
+
+    #[tokio::test]
+    async fn test_reports_exchanged_peers() {
+        let (stream, mut remote) = stream_pair().await;
+        let (_broker, mut events) = create(stream, PeerScores::new());
+
+        let peer = Peer::from_str("10.0.0.1:6881").unwrap();
+        let msg = Extension::Pex {
+            ext_id: 2,
+            message: PexMessage {
+                added: vec![(peer, PexFlags::SEED)],
+                dropped: vec![],
+            },
+        };
+
+        for _ in 0..2 {
+            remote.write_all(&msg.as_bytes().unwrap()).await.unwrap();
+        }
+
+        let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+            .await
+            .unwrap();
+        assert_eq!(event, Some(Event::Peers(vec![peer])));
+
+        // The second message arrived too soon after the first and is ignored.
+        let next = tokio::time::timeout(Duration::from_millis(100), events.recv()).await;
+        assert!(next.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_caps_exchanged_peers() {
+        let (stream, mut remote) = stream_pair().await;
+        let (_broker, mut events) = create(stream, PeerScores::new());
+
+        let added = (0..MAX_PEX_PEERS as u16 + 10)
+            .map(|port| {
+                (
+                    Peer::from_str(&format!("10.0.0.1:{port}")).unwrap(),
+                    PexFlags::default(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let msg = Extension::Pex {
+            ext_id: 2,
+            message: PexMessage {
+                added,
+                dropped: vec![],
+            },
+        };
+        remote.write_all(&msg.as_bytes().unwrap()).await.unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+            .await
+            .unwrap();
+        let Some(Event::Peers(peers)) = event else {
+            panic!("expected peers, got {event:?}");
+        };
+        assert_eq!(peers.len(), MAX_PEX_PEERS);
+    }
+
+    #[tokio::test]
+    async fn test_runs_over_utp() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer = Peer::new(server.local_addr().unwrap());
+        let info_hash = Bytes20::sha1_hash(b"info");
+
+        let remote = tokio::spawn(async move {
+            let mut remote = server.accept().await.unwrap();
+            let mut handshake = [0u8; 68];
+            remote.read_exact(&mut handshake).await.unwrap();
+            remote.write_all(&handshake).await.unwrap();
+            (server, remote)
+        });
+
+        let stream = peer
+            .connect_utp(
+                &client,
+                info_hash,
+                Bytes20::default(),
+                EncryptionPolicy::Disabled,
+            )
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr(), Some(peer.addr()));
+        assert!(stream.supports_fast_extension());
+
+        let (_server, mut remote) = remote.await.unwrap();
+        let config = Config {
+            keep_alive_interval: Duration::from_millis(50),
+            ..Config::default()
+        };
+        let (_broker, mut events) = create_with_config(stream, PeerScores::new(), config);
+
+        let mut buf = [0xffu8; 4];
+        tokio::time::timeout(Duration::from_secs(1), remote.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf, [0, 0, 0, 0]);
+
+        let added = Peer::from_str("10.0.0.1:6881").unwrap();
+        let msg = Extension::Pex {
+            ext_id: 2,
+            message: PexMessage {
+                added: vec![(added, PexFlags::SEED)],
+                dropped: vec![],
+            },
+        };
+        remote.write_all(&msg.as_bytes().unwrap()).await.unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+            .await
+            .unwrap();
+        assert_eq!(event, Some(Event::Peers(vec![added])));
+    }
+}
@@ -0,0 +1,549 @@
+use crate::{BitTorrentError, Result, util::Bytes20};
+
+use super::Peer;
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{
+    Arc, OnceLock, Weak,
+    atomic::{AtomicU16, Ordering},
+};
+use std::time::Duration;
+use tokio::net::{ToSocketAddrs, UdpSocket, lookup_host};
+use tokio::sync::{Mutex, oneshot};
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+mod krpc;
+mod routing;
+mod storage;
+mod token;
+
+pub use krpc::{Body, ERROR_GENERIC, ERROR_PROTOCOL, KrpcMessage, NodeInfo, Query, Response};
+pub use routing::{K, RoutingTable, distance};
+pub use storage::PeerStore;
+pub use token::Tokens;
+
+pub type NodeId = Bytes20;
+
+pub const DEFAULT_BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Number of queries a lookup keeps in flight.
+const ALPHA: usize = 3;
+const MAX_DATAGRAM_SIZE: usize = 1500;
+
+macro_rules! err {
+    ($($arg:tt)*) => {
+        BitTorrentError::Other(format!($($arg)*))
+    };
+}
+
+/// A 160-bit id from the OS random source, used for node ids and token
+/// secrets that remote nodes must not be able to guess.
+pub fn random_id() -> NodeId {
+    let mut id = [0u8; 20];
+    getrandom::getrandom(&mut id).expect("Failed to generate a random id");
+    Bytes20::new(id)
+}
+
+/// Resolves `host:port` bootstrap addresses, skipping the ones that fail.
+pub async fn resolve_nodes(hosts: &[&str]) -> Vec<SocketAddr> {
+    let mut addrs = Vec::new();
+
+    for host in hosts {
+        match lookup_host(host).await {
+            Ok(resolved) => addrs.extend(resolved.filter(SocketAddr::is_ipv4)),
+            Err(err) => warn!("Failed to resolve DHT bootstrap node {host}: {err}"),
+        }
+    }
+
+    addrs
+}
+
+type Pending = HashMap<(SocketAddr, Vec<u8>), oneshot::Sender<Result<Response>>>;
+
+struct Inner {
+    id: NodeId,
+    socket: Arc<UdpSocket>,
+    table: Mutex<RoutingTable>,
+    peers: Mutex<PeerStore>,
+    tokens: Mutex<Tokens>,
+    pending: Mutex<Pending>,
+    next_transaction: AtomicU16,
+    tasks: OnceLock<Vec<AbortHandle>>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        for task in self.tasks.get().into_iter().flatten() {
+            task.abort();
+        }
+    }
+}
+
+/// A mainline DHT node (BEP 5) answering queries on a UDP socket and looking up
+/// peers for info hashes.
+#[derive(Clone)]
+pub struct Dht {
+    inner: Arc<Inner>,
+}
+
+/// The outcome of an iterative lookup: the closest nodes that answered, with
+/// the announce token each of them handed out, and any peers found.
+#[derive(Debug, Clone, Default)]
+struct Lookup {
+    nodes: Vec<(NodeInfo, Option<Vec<u8>>)>,
+    peers: Vec<Peer>,
+}
+
+impl Dht {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Self::with_routing_table(addr, RoutingTable::new(random_id())).await
+    }
+
+    /// Starts a node that keeps the id and nodes of a previously saved table.
+    pub async fn with_routing_table<A: ToSocketAddrs>(
+        addr: A,
+        table: RoutingTable,
+    ) -> Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+
+        let inner = Arc::new(Inner {
+            id: table.id(),
+            socket: Arc::clone(&socket),
+            table: Mutex::new(table),
+            peers: Mutex::new(PeerStore::new()),
+            tokens: Mutex::new(Tokens::new(Instant::now())),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(0),
+            tasks: OnceLock::new(),
+        });
+
+        let receiver = tokio::spawn(receive(socket, Arc::downgrade(&inner)));
+        let refresher = tokio::spawn(refresh(Arc::downgrade(&inner)));
+        let _ = inner
+            .tasks
+            .set(vec![receiver.abort_handle(), refresher.abort_handle()]);
+
+        Ok(Self { inner })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.id
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.inner.socket.local_addr()?)
+    }
+
+    pub async fn routing_table(&self) -> RoutingTable {
+        self.inner.table.lock().await.clone()
+    }
+
+    pub async fn save_routing_table<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.inner.table.lock().await.save(path)
+    }
+
+    /// Joins the network through `nodes`, then looks up our own id to fill the
+    /// routing table. Returns the number of nodes in the table.
+    pub async fn bootstrap(&self, nodes: &[SocketAddr]) -> usize {
+        let mut queries = JoinSet::new();
+
+        for addr in nodes {
+            let dht = self.clone();
+            let addr = *addr;
+            let target = self.id();
+
+            queries.spawn(async move { dht.query(addr, Query::FindNode { target }).await });
+        }
+
+        while let Some(result) = queries.join_next().await {
+            if let Ok(Err(err)) = result {
+                debug!("DHT bootstrap query failed: {err}");
+            }
+        }
+
+        self.find_node(self.id()).await;
+        self.inner.table.lock().await.len()
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> Result<NodeId> {
+        Ok(self.query(addr, Query::Ping).await?.id)
+    }
+
+    /// The closest nodes to `target` that answered an iterative lookup.
+    pub async fn find_node(&self, target: NodeId) -> Vec<NodeInfo> {
+        let lookup = self.lookup(target, Query::FindNode { target }).await;
+        lookup.nodes.into_iter().map(|(node, _)| node).collect()
+    }
+
+    pub async fn get_peers(&self, info_hash: Bytes20) -> Vec<Peer> {
+        self.lookup(info_hash, Query::GetPeers { info_hash })
+            .await
+            .peers
+    }
+
+    /// Looks up peers for `info_hash` and announces ourselves to the closest
+    /// nodes. Without a port the nodes use the source port of our queries.
+    pub async fn announce(&self, info_hash: Bytes20, port: Option<u16>) -> Result<Vec<Peer>> {
+        let lookup = self.lookup(info_hash, Query::GetPeers { info_hash }).await;
+        let mut announces = JoinSet::new();
+
+        for (node, token) in lookup.nodes {
+            let Some(token) = token else {
+                continue;
+            };
+
+            let dht = self.clone();
+            let query = Query::AnnouncePeer {
+                info_hash,
+                port: port.unwrap_or_default(),
+                implied_port: port.is_none(),
+                token,
+            };
+
+            announces.spawn(async move { dht.query(node.addr, query).await });
+        }
+
+        let mut announced = 0;
+        while let Some(result) = announces.join_next().await {
+            match result {
+                Ok(Ok(_)) => announced += 1,
+                Ok(Err(err)) => debug!("DHT announce failed: {err}"),
+                Err(err) => debug!("DHT announce task failed: {err}"),
+            }
+        }
+
+        if announced == 0 {
+            return Err(err!("No DHT node accepted the announce"));
+        }
+
+        Ok(lookup.peers)
+    }
+
+    pub async fn query(&self, addr: SocketAddr, query: Query) -> Result<Response> {
+        let transaction = self
+            .inner
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+
+        let (tx, rx) = oneshot::channel();
+        let key = (addr, transaction.clone());
+        self.inner.pending.lock().await.insert(key.clone(), tx);
+
+        let msg = KrpcMessage::query(transaction, self.id(), query);
+        let sent = match msg.to_bencode() {
+            Ok(bytes) => self
+                .inner
+                .socket
+                .send_to(&bytes, addr)
+                .await
+                .map_err(Into::into),
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = sent {
+            self.inner.pending.lock().await.remove(&key);
+            return Err(err);
+        }
+
+        let result = tokio::time::timeout(QUERY_TIMEOUT, rx).await;
+        self.inner.pending.lock().await.remove(&key);
+
+        match result {
+            Ok(Ok(response)) => response,
+            Ok(Err(_)) => Err(BitTorrentError::ChannelClosed),
+            Err(_) => {
+                self.inner.table.lock().await.failed(addr);
+                Err(err!("DHT query to {addr} timed out"))
+            }
+        }
+    }
+
+    /// Iterative Kademlia lookup: keeps querying the closest nodes not yet
+    /// asked, ALPHA at a time, until the K closest known nodes have answered.
+    async fn lookup(&self, target: NodeId, query: Query) -> Lookup {
+        let mut candidates = self.inner.table.lock().await.closest(&target, K);
+        let mut queried: HashSet<SocketAddr> = HashSet::new();
+        let mut responded: Vec<(NodeInfo, Option<Vec<u8>>)> = Vec::new();
+        let mut peers: HashSet<Peer> = HashSet::new();
+        let mut in_flight = JoinSet::new();
+
+        loop {
+            while in_flight.len() < ALPHA {
+                let Some(node) = candidates
+                    .iter()
+                    .take(K)
+                    .find(|n| !queried.contains(&n.addr))
+                    .copied()
+                else {
+                    break;
+                };
+
+                queried.insert(node.addr);
+
+                let dht = self.clone();
+                let query = query.clone();
+                in_flight.spawn(async move { (node, dht.query(node.addr, query).await) });
+            }
+
+            let Some(result) = in_flight.join_next().await else {
+                break;
+            };
+
+            let Ok((node, result)) = result else {
+                continue;
+            };
+
+            match result {
+                Ok(response) => {
+                    let node = NodeInfo::new(response.id, node.addr);
+                    responded.push((node, response.token));
+                    peers.extend(response.values);
+
+                    for found in response.nodes {
+                        if found.id != self.id() && !candidates.iter().any(|n| n.addr == found.addr)
+                        {
+                            candidates.push(found);
+                        }
+                    }
+
+                    candidates.sort_by_key(|n| distance(&n.id, &target));
+                }
+                Err(err) => {
+                    debug!("DHT lookup query to {} failed: {err}", node.addr);
+                    candidates.retain(|n| n.addr != node.addr);
+                }
+            }
+        }
+
+        responded.sort_by_key(|(n, _)| distance(&n.id, &target));
+        responded.truncate(K);
+
+        Lookup {
+            nodes: responded,
+            peers: peers.into_iter().collect(),
+        }
+    }
+}
+
+impl Inner {
+    async fn handle(&self, bytes: &[u8], from: SocketAddr) -> Result<()> {
+        let msg = KrpcMessage::from_bencode(bytes)?;
+
+        match msg.body {
+            Body::Query { id, query } => {
+                self.table
+                    .lock()
+                    .await
+                    .insert(NodeInfo::new(id, from), Instant::now());
+
+                let reply = self.answer(msg.transaction, query, from).await;
+                self.socket.send_to(&reply.to_bencode()?, from).await?;
+            }
+            Body::Response(response) => {
+                let Some(tx) = self.pending.lock().await.remove(&(from, msg.transaction)) else {
+                    debug!("Dropping unexpected DHT response from {from}");
+                    return Ok(());
+                };
+
+                self.table
+                    .lock()
+                    .await
+                    .insert(NodeInfo::new(response.id, from), Instant::now());
+
+                let _ = tx.send(Ok(response));
+            }
+            Body::Error { code, message } => {
+                if let Some(tx) = self.pending.lock().await.remove(&(from, msg.transaction)) {
+                    let _ = tx.send(Err(err!("DHT error {code}: {message}")));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn answer(&self, transaction: Vec<u8>, query: Query, from: SocketAddr) -> KrpcMessage {
+        let now = Instant::now();
+        let mut response = Response::new(self.id);
+
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => {
+                response.nodes = self.table.lock().await.closest(&target, K);
+            }
+            Query::GetPeers { info_hash } => {
+                response.token = Some(self.tokens.lock().await.generate(from.ip(), now));
+                response.values = self.peers.lock().await.peers(&info_hash, now);
+
+                if response.values.is_empty() {
+                    response.nodes = self.table.lock().await.closest(&info_hash, K);
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if !self.tokens.lock().await.verify(from.ip(), &token, now) {
+                    return KrpcMessage::error(transaction, ERROR_PROTOCOL, "Bad token");
+                }
+
+                let port = if implied_port { from.port() } else { port };
+                let peer = Peer::new(SocketAddr::new(from.ip(), port));
+                self.peers.lock().await.insert(info_hash, peer, now);
+            }
+        }
+
+        KrpcMessage::response(transaction, response)
+    }
+}
+
+async fn receive(socket: Arc<UdpSocket>, inner: Weak<Inner>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                debug!("Failed to receive DHT datagram: {err}");
+                continue;
+            }
+        };
+
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+
+        if let Err(err) = inner.handle(&buf[..len], from).await {
+            debug!("Ignoring DHT message from {from}: {err}");
+        }
+    }
+}
+
+// Pings the nodes that have gone quiet so that dead ones are eventually
+// replaced.
+async fn refresh(inner: Weak<Inner>) {
+    loop {
+        tokio::time::sleep(REFRESH_INTERVAL).await;
+
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+
+        let dht = Dht { inner };
+        let questionable = dht.inner.table.lock().await.questionable(Instant::now());
+
+        for node in questionable {
+            if let Err(err) = dht.ping(node.addr).await {
+                debug!("DHT node {} did not answer ping: {err}", node.addr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn network(size: usize) -> Vec<Dht> {
+        let mut nodes = Vec::new();
+
+        for _ in 0..size {
+            nodes.push(Dht::bind("127.0.0.1:0").await.unwrap());
+        }
+
+        let seed = nodes[0].local_addr().unwrap();
+        for node in &nodes[1..] {
+            node.bootstrap(&[seed]).await;
+        }
+
+        nodes
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let a = Dht::bind("127.0.0.1:0").await.unwrap();
+        let b = Dht::bind("127.0.0.1:0").await.unwrap();
+
+        let id = a.ping(b.local_addr().unwrap()).await.unwrap();
+        assert_eq!(id, b.id());
+
+        // Both sides learn about each other.
+        assert!(a.routing_table().await.contains(&b.id()));
+        assert!(b.routing_table().await.contains(&a.id()));
+    }
+
+    #[tokio::test]
+    async fn test_find_node() {
+        let nodes = network(8).await;
+
+        let target = nodes[7].id();
+        let found = nodes[1].find_node(target).await;
+        assert_eq!(found.first().map(|n| n.id), Some(target));
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_get_peers() {
+        let nodes = network(8).await;
+        let info_hash = Bytes20::sha1_hash(b"torrent");
+
+        nodes[3].announce(info_hash, Some(6881)).await.unwrap();
+        nodes[5].announce(info_hash, None).await.unwrap();
+
+        let mut peers = nodes[6].get_peers(info_hash).await;
+        peers.sort_by_key(|p| p.addr());
+
+        let mut expected = vec![
+            Peer::new(SocketAddr::from(([127, 0, 0, 1], 6881))),
+            Peer::new(nodes[5].local_addr().unwrap()),
+        ];
+        expected.sort_by_key(|p| p.addr());
+
+        assert_eq!(peers, expected);
+    }
+
+    #[tokio::test]
+    async fn test_reject_announce_with_bad_token() {
+        let a = Dht::bind("127.0.0.1:0").await.unwrap();
+        let b = Dht::bind("127.0.0.1:0").await.unwrap();
+
+        let query = Query::AnnouncePeer {
+            info_hash: Bytes20::sha1_hash(b"torrent"),
+            port: 6881,
+            implied_port: false,
+            token: b"forged".to_vec(),
+        };
+
+        assert!(a.query(b.local_addr().unwrap(), query).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_restart_from_saved_routing_table() {
+        let nodes = network(4).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dht.dat");
+
+        nodes[1].save_routing_table(&path).await.unwrap();
+
+        let table = RoutingTable::load(&path).unwrap();
+        let restarted = Dht::with_routing_table("127.0.0.1:0", table).await.unwrap();
+
+        assert_eq!(restarted.id(), nodes[1].id());
+        let found = restarted.find_node(nodes[3].id()).await;
+        assert_eq!(found.first().map(|n| n.id), Some(nodes[3].id()));
+    }
+}
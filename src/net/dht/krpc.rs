@@ -0,0 +1,431 @@
+use crate::{
+    BitTorrentError, Result,
+    bencode::{Bencode, Deserializer, Serializer},
+    net::{PEER_BYTE_SIZE, PEER_V6_BYTE_SIZE, Peer},
+    util::Bytes20,
+};
+
+use super::NodeId;
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::net::SocketAddr;
+
+pub const NODE_BYTE_SIZE: usize = 20 + PEER_BYTE_SIZE;
+pub const NODE_V6_BYTE_SIZE: usize = 20 + PEER_V6_BYTE_SIZE;
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+
+macro_rules! err {
+    ($($arg:tt)*) => {
+        BitTorrentError::DeserdeError(format!($($arg)*))
+    };
+}
+
+/// A DHT node as carried in the compact node info of KRPC responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+impl NodeInfo {
+    pub fn new(id: NodeId, addr: SocketAddr) -> Self {
+        Self { id, addr }
+    }
+
+    pub fn to_compact(&self) -> Vec<u8> {
+        let mut bytes = self.id.to_vec();
+        bytes.extend(Peer::new(self.addr).to_compact());
+        bytes
+    }
+
+    pub(crate) fn from_compact(bytes: &[u8]) -> Result<Self> {
+        let id = Bytes20::from(&bytes[..20]);
+        let peer = Peer::try_from(bytes[20..].to_vec())?;
+        Ok(Self::new(id, peer.addr()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: Bytes20,
+    },
+    AnnouncePeer {
+        info_hash: Bytes20,
+        port: u16,
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+}
+
+impl Query {
+    fn method(&self) -> &'static str {
+        match self {
+            Self::Ping => "ping",
+            Self::FindNode { .. } => "find_node",
+            Self::GetPeers { .. } => "get_peers",
+            Self::AnnouncePeer { .. } => "announce_peer",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<Peer>,
+    pub token: Option<Vec<u8>>,
+}
+
+impl Response {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Body {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+/// A KRPC message (BEP 5): a query, a response or an error, matched up by the
+/// transaction id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KrpcMessage {
+    pub transaction: Vec<u8>,
+    pub body: Body,
+}
+
+// Fields are kept in key order, as bencoded dictionaries require.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RawMessage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<RawBody>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e: Option<Vec<Bencode>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    r: Option<RawBody>,
+    #[serde(with = "serde_bytes")]
+    t: Vec<u8>,
+    y: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RawBody {
+    #[serde(with = "serde_bytes")]
+    id: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    implied_port: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    info_hash: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    nodes: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    nodes6: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    target: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    token: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+}
+
+impl RawBody {
+    fn new(id: NodeId) -> Self {
+        Self {
+            id: id.to_vec(),
+            ..Self::default()
+        }
+    }
+
+    fn id(&self) -> Result<NodeId> {
+        hash(&self.id, "id")
+    }
+}
+
+impl KrpcMessage {
+    pub fn query(transaction: Vec<u8>, id: NodeId, query: Query) -> Self {
+        Self {
+            transaction,
+            body: Body::Query { id, query },
+        }
+    }
+
+    pub fn response(transaction: Vec<u8>, response: Response) -> Self {
+        Self {
+            transaction,
+            body: Body::Response(response),
+        }
+    }
+
+    pub fn error(transaction: Vec<u8>, code: i64, message: impl Into<String>) -> Self {
+        Self {
+            transaction,
+            body: Body::Error {
+                code,
+                message: message.into(),
+            },
+        }
+    }
+
+    pub fn to_bencode(&self) -> Result<Vec<u8>> {
+        let mut raw = RawMessage {
+            t: self.transaction.clone(),
+            ..RawMessage::default()
+        };
+
+        match &self.body {
+            Body::Query { id, query } => {
+                let mut args = RawBody::new(*id);
+
+                match query {
+                    Query::Ping => {}
+                    Query::FindNode { target } => args.target = Some(target.to_vec()),
+                    Query::GetPeers { info_hash } => args.info_hash = Some(info_hash.to_vec()),
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                    } => {
+                        args.info_hash = Some(info_hash.to_vec());
+                        args.port = Some(*port);
+                        args.implied_port = implied_port.then_some(1);
+                        args.token = Some(token.clone());
+                    }
+                }
+
+                raw.y = "q".into();
+                raw.q = Some(query.method().into());
+                raw.a = Some(args);
+            }
+            Body::Response(response) => {
+                let mut values = RawBody::new(response.id);
+                let mut nodes = Vec::new();
+                let mut nodes6 = Vec::new();
+
+                for node in &response.nodes {
+                    match node.addr {
+                        SocketAddr::V4(_) => nodes.extend(node.to_compact()),
+                        SocketAddr::V6(_) => nodes6.extend(node.to_compact()),
+                    }
+                }
+
+                values.nodes = (!nodes.is_empty()).then_some(nodes);
+                values.nodes6 = (!nodes6.is_empty()).then_some(nodes6);
+                values.token = response.token.clone();
+
+                if !response.values.is_empty() {
+                    values.values = Some(
+                        response
+                            .values
+                            .iter()
+                            .map(|peer| ByteBuf::from(peer.to_compact()))
+                            .collect(),
+                    );
+                }
+
+                raw.y = "r".into();
+                raw.r = Some(values);
+            }
+            Body::Error { code, message } => {
+                raw.y = "e".into();
+                raw.e = Some(vec![Bencode::Int(*code), message.as_str().into()]);
+            }
+        }
+
+        let mut bytes = Vec::new();
+        raw.serialize(&mut Serializer::new(&mut bytes))?;
+        Ok(bytes)
+    }
+
+    pub fn from_bencode(bytes: &[u8]) -> Result<Self> {
+        let mut de = Deserializer::new(bytes);
+        let raw = RawMessage::deserialize(&mut de)?;
+        let transaction = raw.t;
+
+        let body = match raw.y.as_str() {
+            "q" => {
+                let method = raw.q.ok_or_else(|| err!("Query without method"))?;
+                let args = raw.a.ok_or_else(|| err!("Query without arguments"))?;
+                let id = args.id()?;
+
+                let query = match method.as_str() {
+                    "ping" => Query::Ping,
+                    "find_node" => Query::FindNode {
+                        target: hash(required(&args.target, "target")?, "target")?,
+                    },
+                    "get_peers" => Query::GetPeers {
+                        info_hash: hash(required(&args.info_hash, "info_hash")?, "info_hash")?,
+                    },
+                    "announce_peer" => Query::AnnouncePeer {
+                        info_hash: hash(required(&args.info_hash, "info_hash")?, "info_hash")?,
+                        port: args.port.ok_or_else(|| err!("Missing port"))?,
+                        implied_port: args.implied_port.is_some_and(|v| v != 0),
+                        token: required(&args.token, "token")?.to_vec(),
+                    },
+                    _ => return Err(err!("Unknown method {method}")),
+                };
+
+                Body::Query { id, query }
+            }
+            "r" => {
+                let values = raw.r.ok_or_else(|| err!("Response without values"))?;
+                let id = values.id()?;
+
+                let mut nodes = compact_nodes(values.nodes.as_deref(), NODE_BYTE_SIZE)?;
+                nodes.extend(compact_nodes(values.nodes6.as_deref(), NODE_V6_BYTE_SIZE)?);
+
+                let peers = values
+                    .values
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|v| Peer::try_from(v.into_vec()))
+                    .collect::<Result<Vec<Peer>>>()?;
+
+                Body::Response(Response {
+                    id,
+                    nodes,
+                    values: peers,
+                    token: values.token,
+                })
+            }
+            "e" => match raw.e.as_deref() {
+                Some([Bencode::Int(code), Bencode::Str(message)]) => Body::Error {
+                    code: *code,
+                    message: String::from_utf8_lossy(message).into_owned(),
+                },
+                _ => return Err(err!("Invalid error details")),
+            },
+            y => return Err(err!("Unknown message type {y}")),
+        };
+
+        Ok(Self { transaction, body })
+    }
+}
+
+fn required<'a>(value: &'a Option<Vec<u8>>, name: &str) -> Result<&'a [u8]> {
+    value.as_deref().ok_or_else(|| err!("Missing {name}"))
+}
+
+fn hash(bytes: &[u8], name: &str) -> Result<Bytes20> {
+    Bytes20::try_from(bytes.to_vec()).map_err(|_| err!("Invalid {name} length {}", bytes.len()))
+}
+
+fn compact_nodes(bytes: Option<&[u8]>, unit_len: usize) -> Result<Vec<NodeInfo>> {
+    let bytes = bytes.unwrap_or_default();
+
+    if !bytes.len().is_multiple_of(unit_len) {
+        return Err(err!("Invalid compact node info length {}", bytes.len()));
+    }
+
+    bytes
+        .chunks_exact(unit_len)
+        .map(NodeInfo::from_compact)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn round_trip(msg: KrpcMessage) {
+        let bytes = msg.to_bencode().unwrap();
+        assert_eq!(KrpcMessage::from_bencode(&bytes).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_ping_matches_bep_example() {
+        let msg = KrpcMessage::query(
+            b"aa".to_vec(),
+            Bytes20::new(*b"abcdefghij0123456789"),
+            Query::Ping,
+        );
+
+        assert_eq!(
+            msg.to_bencode().unwrap(),
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+        );
+    }
+
+    #[test]
+    fn test_queries_round_trip() {
+        let id = Bytes20::sha1_hash(b"node");
+        let info_hash = Bytes20::sha1_hash(b"torrent");
+
+        round_trip(KrpcMessage::query(
+            b"t1".to_vec(),
+            id,
+            Query::FindNode { target: info_hash },
+        ));
+        round_trip(KrpcMessage::query(
+            b"t2".to_vec(),
+            id,
+            Query::GetPeers { info_hash },
+        ));
+        round_trip(KrpcMessage::query(
+            b"t3".to_vec(),
+            id,
+            Query::AnnouncePeer {
+                info_hash,
+                port: 6881,
+                implied_port: true,
+                token: b"token".to_vec(),
+            },
+        ));
+    }
+
+    #[test]
+    fn test_response_and_error_round_trip() {
+        let node = NodeInfo::new(
+            Bytes20::sha1_hash(b"a"),
+            SocketAddr::from_str("10.0.0.1:6881").unwrap(),
+        );
+        let node6 = NodeInfo::new(
+            Bytes20::sha1_hash(b"b"),
+            SocketAddr::from_str("[::1]:6881").unwrap(),
+        );
+
+        round_trip(KrpcMessage::response(
+            b"aa".to_vec(),
+            Response {
+                id: Bytes20::sha1_hash(b"node"),
+                nodes: vec![node, node6],
+                values: vec![Peer::from_str("192.168.0.2:51413").unwrap()],
+                token: Some(b"secret".to_vec()),
+            },
+        ));
+        round_trip(KrpcMessage::error(
+            b"aa".to_vec(),
+            ERROR_GENERIC,
+            "A Generic Error Ocurred",
+        ));
+    }
+
+    #[test]
+    fn test_reject_malformed_messages() {
+        assert!(KrpcMessage::from_bencode(b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe").is_err());
+        assert!(KrpcMessage::from_bencode(b"d1:t2:aa1:y1:xe").is_err());
+        assert!(
+            KrpcMessage::from_bencode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:aa1:y1:qe")
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,337 @@
+use crate::{
+    BitTorrentError, Result,
+    bencode::{Deserializer, Serializer},
+    util::Bytes20,
+};
+
+use super::{
+    NodeId,
+    krpc::{NODE_BYTE_SIZE, NODE_V6_BYTE_SIZE, NodeInfo},
+};
+
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Maximum number of nodes per bucket.
+pub const K: usize = 8;
+
+// A node that has not been heard from for this long is questionable and gets
+// pinged; a node that failed this many queries in a row is bad and replaced.
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+const MAX_FAILURES: u32 = 2;
+
+const NUM_BUCKETS: usize = 160;
+
+pub fn distance(a: &NodeId, b: &NodeId) -> [u8; 20] {
+    let mut d = [0u8; 20];
+    for (i, byte) in d.iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+    d
+}
+
+// Buckets are indexed by the length of the prefix shared with our own id, so
+// the last buckets cover the nodes closest to us.
+fn bucket_index(own: &NodeId, id: &NodeId) -> Option<usize> {
+    let d = distance(own, id);
+
+    d.iter()
+        .position(|b| *b != 0)
+        .map(|i| i * 8 + d[i].leading_zeros() as usize)
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+impl Entry {
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+/// The Kademlia routing table of a DHT node.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedTable {
+    #[serde(with = "serde_bytes")]
+    id: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    nodes: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    nodes6: Vec<u8>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![Vec::new(); NUM_BUCKETS],
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Records that `node` is alive. Returns false when its bucket is full of
+    /// good nodes and the node was not added.
+    pub fn insert(&mut self, node: NodeInfo, now: Instant) -> bool {
+        let Some(index) = bucket_index(&self.id, &node.id) else {
+            return false;
+        };
+
+        let bucket = &mut self.buckets[index];
+
+        if let Some(entry) = bucket.iter_mut().find(|e| e.node.id == node.id) {
+            entry.node.addr = node.addr;
+            entry.last_seen = now;
+            entry.failures = 0;
+            return true;
+        }
+
+        let entry = Entry {
+            node,
+            last_seen: now,
+            failures: 0,
+        };
+
+        if bucket.len() < K {
+            bucket.push(entry);
+            return true;
+        }
+
+        match bucket.iter().position(Entry::is_bad) {
+            Some(i) => {
+                bucket[i] = entry;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records a query to the node at `addr` that went unanswered.
+    pub fn failed(&mut self, addr: SocketAddr) {
+        for entry in self.buckets.iter_mut().flatten() {
+            if entry.node.addr == addr {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    pub fn contains(&self, id: &NodeId) -> bool {
+        bucket_index(&self.id, id).is_some_and(|i| self.buckets[i].iter().any(|e| e.node.id == *id))
+    }
+
+    /// The `n` good nodes closest to `target`, closest first.
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<NodeInfo> {
+        let mut nodes = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|e| !e.is_bad())
+            .map(|e| e.node)
+            .collect::<Vec<NodeInfo>>();
+
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    /// Nodes not heard from recently that should be pinged.
+    pub fn questionable(&self, now: Instant) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|e| now.duration_since(e.last_seen) >= QUESTIONABLE_AFTER || e.failures > 0)
+            .map(|e| e.node)
+            .collect()
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets.iter().flatten().map(|e| e.node).collect()
+    }
+
+    pub fn to_bencode(&self) -> Result<Vec<u8>> {
+        let mut saved = SavedTable {
+            id: self.id.to_vec(),
+            nodes: Vec::new(),
+            nodes6: Vec::new(),
+        };
+
+        for node in self.nodes() {
+            match node.addr {
+                SocketAddr::V4(_) => saved.nodes.extend(node.to_compact()),
+                SocketAddr::V6(_) => saved.nodes6.extend(node.to_compact()),
+            }
+        }
+
+        let mut bytes = Vec::new();
+        saved.serialize(&mut Serializer::new(&mut bytes))?;
+        Ok(bytes)
+    }
+
+    /// Restores a saved table. The nodes are kept as they were but count as
+    /// questionable until they answer again.
+    pub fn from_bencode(bytes: &[u8]) -> Result<Self> {
+        let mut de = Deserializer::new(bytes);
+        let saved = SavedTable::deserialize(&mut de)?;
+
+        let id = Bytes20::try_from(saved.id)?;
+        let mut table = Self::new(id);
+
+        let now = Instant::now();
+        let seen = now.checked_sub(QUESTIONABLE_AFTER).unwrap_or(now);
+
+        let nodes = saved
+            .nodes
+            .chunks_exact(NODE_BYTE_SIZE)
+            .chain(saved.nodes6.chunks_exact(NODE_V6_BYTE_SIZE));
+
+        for chunk in nodes {
+            let node = NodeInfo::from_compact(chunk)
+                .map_err(|e| BitTorrentError::DeserdeError(format!("Invalid saved node: {e}")))?;
+            table.insert(node, seen);
+        }
+
+        Ok(table)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        if let Some(parent) = path.as_ref().parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        std::fs::write(path, self.to_bencode()?)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+        Self::from_bencode(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(seed: u32, port: u16) -> NodeInfo {
+        NodeInfo::new(
+            Bytes20::sha1_hash(&seed.to_be_bytes()),
+            SocketAddr::from(([127, 0, 0, 1], port)),
+        )
+    }
+
+    fn id_with_prefix(own: &NodeId, flip_bit: usize, seed: u8) -> NodeId {
+        let mut bytes = **own;
+        bytes[flip_bit / 8] ^= 0x80 >> (flip_bit % 8);
+        bytes[19] ^= seed;
+        Bytes20::new(bytes)
+    }
+
+    #[test]
+    fn test_bucket_index() {
+        let own = Bytes20::default();
+        assert_eq!(bucket_index(&own, &own), None);
+        assert_eq!(bucket_index(&own, &id_with_prefix(&own, 0, 0)), Some(0));
+        assert_eq!(bucket_index(&own, &id_with_prefix(&own, 13, 0)), Some(13));
+    }
+
+    #[test]
+    fn test_full_bucket_replaces_bad_nodes_only() {
+        let own = Bytes20::default();
+        let mut table = RoutingTable::new(own);
+        let now = Instant::now();
+
+        for i in 0..K as u8 {
+            let id = id_with_prefix(&own, 0, i + 1);
+            let addr = SocketAddr::from(([127, 0, 0, 1], 1000 + i as u16));
+            assert!(table.insert(NodeInfo::new(id, addr), now));
+        }
+
+        let extra = NodeInfo::new(
+            id_with_prefix(&own, 0, 0xff),
+            SocketAddr::from(([127, 0, 0, 1], 2000)),
+        );
+        assert!(!table.insert(extra, now));
+
+        let first = SocketAddr::from(([127, 0, 0, 1], 1000));
+        table.failed(first);
+        table.failed(first);
+        assert!(table.insert(extra, now));
+        assert!(table.contains(&extra.id));
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn test_closest_nodes() {
+        let mut table = RoutingTable::new(Bytes20::sha1_hash(b"own"));
+        let now = Instant::now();
+        let nodes = (0..50)
+            .map(|i| node(i, 1000 + i as u16))
+            .collect::<Vec<_>>();
+
+        for n in &nodes {
+            table.insert(*n, now);
+        }
+
+        let target = Bytes20::sha1_hash(b"target");
+        let closest = table.closest(&target, K);
+        assert_eq!(closest.len(), K);
+
+        let mut expected = table.nodes();
+        expected.sort_by_key(|n| distance(&n.id, &target));
+        assert_eq!(closest, expected[..K]);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut table = RoutingTable::new(Bytes20::sha1_hash(b"own"));
+        let now = Instant::now();
+
+        for i in 0..20 {
+            table.insert(node(i, 1000 + i as u16), now);
+        }
+        table.insert(
+            NodeInfo::new(
+                Bytes20::sha1_hash(b"v6"),
+                SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 6881)),
+            ),
+            now,
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dht").join("table.dat");
+        table.save(&path).unwrap();
+
+        let loaded = RoutingTable::load(&path).unwrap();
+        assert_eq!(loaded.id(), table.id());
+
+        let mut expected = table.nodes();
+        let mut actual = loaded.nodes();
+        expected.sort_by_key(|n| *n.id);
+        actual.sort_by_key(|n| *n.id);
+        assert_eq!(actual, expected);
+        assert_eq!(loaded.questionable(Instant::now()).len(), expected.len());
+    }
+}
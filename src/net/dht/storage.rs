@@ -0,0 +1,56 @@
+use crate::{net::Peer, util::Bytes20};
+
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const MAX_VALUES: usize = 50;
+
+/// Peers announced to this node, kept for 30 minutes after their last announce.
+#[derive(Debug, Clone, Default)]
+pub struct PeerStore {
+    torrents: HashMap<Bytes20, HashMap<Peer, Instant>>,
+}
+
+impl PeerStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, info_hash: Bytes20, peer: Peer, now: Instant) {
+        self.torrents
+            .entry(info_hash)
+            .or_default()
+            .insert(peer, now);
+    }
+
+    /// Up to 50 live peers for `info_hash`, few enough to fit in one datagram.
+    pub fn peers(&mut self, info_hash: &Bytes20, now: Instant) -> Vec<Peer> {
+        let Some(peers) = self.torrents.get_mut(info_hash) else {
+            return Vec::new();
+        };
+
+        peers.retain(|_, announced| now.duration_since(*announced) < PEER_TTL);
+        peers.keys().take(MAX_VALUES).copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_peers_expire() {
+        let mut store = PeerStore::new();
+        let now = Instant::now();
+        let info_hash = Bytes20::sha1_hash(b"torrent");
+        let peer = Peer::from_str("10.0.0.1:6881").unwrap();
+
+        store.insert(info_hash, peer, now);
+        assert_eq!(store.peers(&info_hash, now), vec![peer]);
+        assert!(store.peers(&Bytes20::default(), now).is_empty());
+        assert!(store.peers(&info_hash, now + PEER_TTL).is_empty());
+    }
+}
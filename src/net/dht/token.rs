@@ -0,0 +1,79 @@
+use crate::util::Bytes20;
+
+use super::random_id;
+
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const ROTATE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Hands out announce tokens for get_peers replies. A token is the SHA-1 of the
+/// querying IP and a secret that changes every five minutes; tokens made with
+/// the previous secret are still accepted.
+#[derive(Debug, Clone)]
+pub struct Tokens {
+    current: Bytes20,
+    previous: Bytes20,
+    rotated_at: Instant,
+}
+
+impl Tokens {
+    pub fn new(now: Instant) -> Self {
+        let secret = random_id();
+
+        Self {
+            current: secret,
+            previous: secret,
+            rotated_at: now,
+        }
+    }
+
+    pub fn generate(&mut self, ip: IpAddr, now: Instant) -> Vec<u8> {
+        self.rotate(now);
+        make_token(&self.current, ip)
+    }
+
+    pub fn verify(&mut self, ip: IpAddr, token: &[u8], now: Instant) -> bool {
+        self.rotate(now);
+        [&self.current, &self.previous]
+            .into_iter()
+            .any(|secret| make_token(secret, ip) == token)
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        if now.duration_since(self.rotated_at) >= ROTATE_INTERVAL {
+            self.previous = self.current;
+            self.current = random_id();
+            self.rotated_at = now;
+        }
+    }
+}
+
+fn make_token(secret: &Bytes20, ip: IpAddr) -> Vec<u8> {
+    let mut data = secret.to_vec();
+    match ip {
+        IpAddr::V4(ip) => data.extend(ip.octets()),
+        IpAddr::V6(ip) => data.extend(ip.octets()),
+    }
+    Bytes20::sha1_hash(&data).to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_expire_after_two_rotations() {
+        let now = Instant::now();
+        let mut tokens = Tokens::new(now);
+        let ip = IpAddr::from([10, 0, 0, 1]);
+
+        let token = tokens.generate(ip, now);
+        assert!(tokens.verify(ip, &token, now));
+        assert!(!tokens.verify(IpAddr::from([10, 0, 0, 2]), &token, now));
+
+        assert!(tokens.verify(ip, &token, now + ROTATE_INTERVAL));
+        assert!(!tokens.verify(ip, &token, now + ROTATE_INTERVAL * 2));
+    }
+}
@@ -0,0 +1,69 @@
+use crate::util::Bytes20;
+
+use std::net::Ipv4Addr;
+
+/// Number of pieces offered to a peer in its allowed fast set.
+pub const ALLOWED_FAST_SET_SIZE: usize = 10;
+
+/// Generates the canonical allowed fast set described in BEP 6.
+///
+/// The set depends only on the peer's IPv4 address (masked to its /24), the
+/// torrent's info hash and the number of pieces, so both ends can compute it
+/// independently.
+pub fn allowed_fast_set(k: usize, num_pieces: u32, info_hash: Bytes20, ip: Ipv4Addr) -> Vec<u32> {
+    let k = std::cmp::min(k, num_pieces as usize);
+    let mut set: Vec<u32> = Vec::with_capacity(k);
+
+    let masked = u32::from(ip) & 0xFFFF_FF00;
+    let mut x = masked
+        .to_be_bytes()
+        .into_iter()
+        .chain(info_hash.iter().copied())
+        .collect::<Vec<u8>>();
+
+    while set.len() < k {
+        x = Bytes20::sha1_hash(&x).to_vec();
+
+        for chunk in x.chunks(4) {
+            if set.len() >= k {
+                break;
+            }
+
+            let y = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            let index = y % num_pieces;
+
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_fast_set() {
+        let info_hash = Bytes20::new([0xaa; 20]);
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+
+        let set = allowed_fast_set(7, 1313, info_hash, ip);
+        assert_eq!(set, vec![1059, 431, 808, 1217, 287, 376, 1188]);
+
+        let set = allowed_fast_set(9, 1313, info_hash, ip);
+        assert_eq!(set, vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]);
+    }
+
+    #[test]
+    fn test_allowed_fast_set_is_bounded_by_num_pieces() {
+        let info_hash = Bytes20::new([0xaa; 20]);
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+
+        let mut set = allowed_fast_set(ALLOWED_FAST_SET_SIZE, 3, info_hash, ip);
+        set.sort();
+        assert_eq!(set, vec![0, 1, 2]);
+    }
+}
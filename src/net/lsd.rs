@@ -0,0 +1,415 @@
+use crate::{BitTorrentError, Result, util::Bytes20};
+
+use super::{Peer, dht::random_id};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{Mutex, mpsc::Sender};
+use tokio::task::AbortHandle;
+use tokio::time::Instant;
+use tracing::debug;
+
+pub const LSD_PORT: u16 = 6771;
+pub const LSD_MULTICAST_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// BEP 14 asks for at most one announce per minute.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+// Keeps each announce well within a single datagram.
+const MAX_HASHES_PER_ANNOUNCE: usize = 20;
+const MAX_DATAGRAM_SIZE: usize = 1500;
+
+const REQUEST_LINE: &str = "BT-SEARCH * HTTP/1.1";
+
+macro_rules! err {
+    ($($arg:tt)*) => {
+        BitTorrentError::Other(format!($($arg)*))
+    };
+}
+
+/// A Local Service Discovery announce (BEP 14).
+#[derive(Debug, Clone, PartialEq)]
+pub struct LsdAnnounce {
+    pub port: u16,
+    pub info_hashes: Vec<Bytes20>,
+    pub cookie: Option<String>,
+}
+
+impl LsdAnnounce {
+    pub fn to_bytes(&self, host: SocketAddr) -> Vec<u8> {
+        let mut msg = format!("{REQUEST_LINE}\r\nHost: {host}\r\nPort: {}\r\n", self.port);
+
+        for info_hash in &self.info_hashes {
+            msg.push_str(&format!("Infohash: {}\r\n", info_hash.hex_encoded()));
+        }
+
+        if let Some(cookie) = &self.cookie {
+            msg.push_str(&format!("cookie: {cookie}\r\n"));
+        }
+
+        msg.push_str("\r\n\r\n");
+        msg.into_bytes()
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let text = std::str::from_utf8(bytes)?;
+        let mut lines = text.split("\r\n");
+
+        if lines.next() != Some(REQUEST_LINE) {
+            return Err(err!("Not an LSD announce"));
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(err!("Malformed LSD header {line}"));
+            };
+            let value = value.trim();
+
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = Some(value.parse::<u16>()?),
+                "infohash" => {
+                    let bytes = hex::decode(value)?;
+                    let info_hash = Bytes20::try_from(bytes)?;
+                    info_hashes.push(info_hash);
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        if info_hashes.is_empty() {
+            return Err(err!("LSD announce without info hash"));
+        }
+
+        Ok(Self {
+            port: port.ok_or_else(|| err!("LSD announce without port"))?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LsdConfig {
+    /// Address the announces are received on.
+    pub listen: SocketAddr,
+    /// Address the announces are sent to, normally the multicast group.
+    pub group: SocketAddr,
+    /// Port we accept peer connections on. Without one, we only listen.
+    pub port: Option<u16>,
+}
+
+impl Default for LsdConfig {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_PORT)),
+            group: SocketAddr::from((LSD_MULTICAST_V4, LSD_PORT)),
+            port: None,
+        }
+    }
+}
+
+struct Inner {
+    socket: Arc<UdpSocket>,
+    group: SocketAddr,
+    port: Option<u16>,
+    cookie: String,
+    torrents: Mutex<HashMap<Bytes20, Sender<Peer>>>,
+    last_announce: Mutex<Option<Instant>>,
+    tasks: OnceLock<Vec<AbortHandle>>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        for task in self.tasks.get().into_iter().flatten() {
+            task.abort();
+        }
+    }
+}
+
+/// Finds peers on the local network by announcing our torrents to, and
+/// listening on, the LSD multicast group.
+#[derive(Clone)]
+pub struct Lsd {
+    inner: Arc<Inner>,
+}
+
+impl Lsd {
+    pub async fn bind(config: LsdConfig) -> Result<Self> {
+        let socket = Arc::new(bind_socket(&config)?);
+
+        let inner = Arc::new(Inner {
+            socket: Arc::clone(&socket),
+            group: config.group,
+            port: config.port,
+            cookie: random_id().hex_encoded()[..16].to_string(),
+            torrents: Mutex::new(HashMap::new()),
+            last_announce: Mutex::new(None),
+            tasks: OnceLock::new(),
+        });
+
+        let receiver = tokio::spawn(receive(socket, Arc::downgrade(&inner)));
+        let announcer = tokio::spawn(announce_periodically(Arc::downgrade(&inner)));
+        let _ = inner
+            .tasks
+            .set(vec![receiver.abort_handle(), announcer.abort_handle()]);
+
+        Ok(Self { inner })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.inner.socket.local_addr()?)
+    }
+
+    /// Starts looking for local peers of `info_hash`, sending the ones found to
+    /// `peers`, and announces the torrent.
+    pub async fn add_torrent(&self, info_hash: Bytes20, peers: Sender<Peer>) {
+        self.inner.torrents.lock().await.insert(info_hash, peers);
+
+        if let Err(err) = self.announce().await {
+            debug!("Skipping LSD announce: {err}");
+        }
+    }
+
+    pub async fn remove_torrent(&self, info_hash: &Bytes20) {
+        self.inner.torrents.lock().await.remove(info_hash);
+    }
+
+    /// Announces every torrent, unless we have no port to announce or the last
+    /// announce was less than a minute ago.
+    pub async fn announce(&self) -> Result<()> {
+        self.inner.announce().await
+    }
+}
+
+impl Inner {
+    async fn announce(&self) -> Result<()> {
+        let Some(port) = self.port else {
+            return Ok(());
+        };
+
+        {
+            let mut last = self.last_announce.lock().await;
+            let now = Instant::now();
+
+            if let Some(at) = *last
+                && now.duration_since(at) < MIN_ANNOUNCE_INTERVAL
+            {
+                return Err(err!("LSD announce rate limited"));
+            }
+
+            *last = Some(now);
+        }
+
+        let info_hashes = self
+            .torrents
+            .lock()
+            .await
+            .keys()
+            .copied()
+            .collect::<Vec<Bytes20>>();
+
+        for chunk in info_hashes.chunks(MAX_HASHES_PER_ANNOUNCE) {
+            let msg = LsdAnnounce {
+                port,
+                info_hashes: chunk.to_vec(),
+                cookie: Some(self.cookie.clone()),
+            };
+
+            self.socket
+                .send_to(&msg.to_bytes(self.group), self.group)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle(&self, bytes: &[u8], from: SocketAddr) -> Result<()> {
+        let msg = LsdAnnounce::parse(bytes)?;
+
+        if msg.cookie.as_deref() == Some(self.cookie.as_str()) {
+            return Ok(());
+        }
+
+        let peer = Peer::new(SocketAddr::new(from.ip(), msg.port));
+
+        // The lock is released before sending so a full channel does not
+        // block registering other torrents.
+        let senders = {
+            let torrents = self.torrents.lock().await;
+            msg.info_hashes
+                .into_iter()
+                .filter_map(|info_hash| Some((info_hash, torrents.get(&info_hash)?.clone())))
+                .collect::<Vec<_>>()
+        };
+
+        for (info_hash, peers) in senders {
+            debug!("Found local peer {peer} for {}", info_hash.hex_encoded());
+
+            if peers.send(peer).await.is_err() {
+                let mut torrents = self.torrents.lock().await;
+                if torrents
+                    .get(&info_hash)
+                    .is_some_and(|p| p.same_channel(&peers))
+                {
+                    torrents.remove(&info_hash);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn bind_socket(config: &LsdConfig) -> Result<UdpSocket> {
+    let domain = match config.listen {
+        SocketAddr::V4(_) => Domain::IPV4,
+        SocketAddr::V6(_) => Domain::IPV6,
+    };
+
+    // Other clients on this machine listen on the same port.
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&config.listen.into())?;
+
+    match config.group.ip() {
+        IpAddr::V4(group) if group.is_multicast() => {
+            socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+            socket.set_multicast_loop_v4(true)?;
+        }
+        IpAddr::V6(group) if group.is_multicast() => {
+            socket.join_multicast_v6(&group, 0)?;
+            socket.set_multicast_loop_v6(true)?;
+        }
+        _ => {}
+    }
+
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+async fn receive(socket: Arc<UdpSocket>, inner: Weak<Inner>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                debug!("Failed to receive LSD datagram: {err}");
+                continue;
+            }
+        };
+
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+
+        if let Err(err) = inner.handle(&buf[..len], from).await {
+            debug!("Ignoring LSD datagram from {from}: {err}");
+        }
+    }
+}
+
+async fn announce_periodically(inner: Weak<Inner>) {
+    loop {
+        tokio::time::sleep(ANNOUNCE_INTERVAL).await;
+
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+
+        if let Err(err) = inner.announce().await {
+            debug!("Failed to send LSD announce: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_announce_round_trip() {
+        let msg = LsdAnnounce {
+            port: 6881,
+            info_hashes: vec![Bytes20::sha1_hash(b"a"), Bytes20::sha1_hash(b"b")],
+            cookie: Some("abc123".into()),
+        };
+
+        let bytes = msg.to_bytes(LsdConfig::default().group);
+        assert!(bytes.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+        assert_eq!(LsdAnnounce::parse(&bytes).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_parse_is_case_insensitive() {
+        let data = b"BT-SEARCH * HTTP/1.1\r\nhost: 239.192.152.143:6771\r\nPORT: 7000\r\ninfohash: 0123456789abcdef0123456789abcdef01234567\r\n\r\n\r\n";
+        let msg = LsdAnnounce::parse(data).unwrap();
+
+        assert_eq!(msg.port, 7000);
+        assert_eq!(msg.info_hashes.len(), 1);
+        assert_eq!(msg.cookie, None);
+
+        assert!(LsdAnnounce::parse(b"NOTIFY * HTTP/1.1\r\n\r\n").is_err());
+        assert!(LsdAnnounce::parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n").is_err());
+    }
+
+    async fn unicast(group: SocketAddr, port: Option<u16>) -> Lsd {
+        Lsd::bind(LsdConfig {
+            listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+            group,
+            port,
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_discovers_peers_and_ignores_own_announces() {
+        let info_hash = Bytes20::sha1_hash(b"torrent");
+        let placeholder = SocketAddr::from(([127, 0, 0, 1], 9));
+
+        let listener = unicast(placeholder, None).await;
+        let (tx, mut found) = mpsc::channel(10);
+        listener.add_torrent(info_hash, tx).await;
+
+        let announcer = unicast(listener.local_addr().unwrap(), Some(7000)).await;
+        let (own_tx, mut own) = mpsc::channel(10);
+        announcer.add_torrent(info_hash, own_tx).await;
+
+        let peer = tokio::time::timeout(Duration::from_secs(1), found.recv())
+            .await
+            .unwrap();
+        assert_eq!(
+            peer,
+            Some(Peer::new(SocketAddr::from(([127, 0, 0, 1], 7000))))
+        );
+
+        // An announce that comes back to its sender is filtered by cookie.
+        let looped = unicast(placeholder, Some(7001)).await;
+        let echo = LsdAnnounce {
+            port: 7001,
+            info_hashes: vec![info_hash],
+            cookie: Some(looped.inner.cookie.clone()),
+        };
+        let (echo_tx, mut echoed) = mpsc::channel(10);
+        looped.add_torrent(info_hash, echo_tx).await;
+        looped
+            .inner
+            .handle(&echo.to_bytes(placeholder), placeholder)
+            .await
+            .unwrap();
+
+        assert!(echoed.try_recv().is_err());
+        assert!(own.try_recv().is_err());
+    }
+}
@@ -15,6 +15,15 @@ macro_rules! ensure {
     };
 }
 
+macro_rules! violation {
+    ($msg:expr) => {
+        return Err(BitTorrentError::ProtocolViolation(format!($msg)))
+    };
+    ($msg:expr, $($arg:tt)*) => {
+        return Err(BitTorrentError::ProtocolViolation(format!($msg, $($arg)*)))
+    };
+}
+
 pub mod extension;
 pub mod peer;
 
@@ -23,11 +32,17 @@ use crate::{BitTorrentError, Result};
 use bytes::{Buf, Bytes, BytesMut};
 use tokio_util::codec::Decoder;
 
-pub use extension::Extension;
+pub use extension::{
+    CLIENT_VERSION, DEFAULT_REQQ, Extension, ExtensionHandshake, ExtensionRegistry, PexFlags,
+    PexMessage, UT_METADATA, UT_PEX,
+};
 pub use peer::PeerMessage;
 
 const LENGTH_SIZE: usize = 4;
 
+pub const DEFAULT_MAX_MESSAGE_LENGTH: usize = 1024 * 1024;
+pub const MAX_BLOCK_LENGTH: usize = 16 * 1024;
+
 pub trait AsBytes {
     fn as_bytes(&self) -> Result<Bytes>;
 }
@@ -37,6 +52,7 @@ pub enum Message {
     KeepAlive,
     PeerMessage(PeerMessage),
     Extension(Extension),
+    Unknown { id: u8, payload: Vec<u8> },
 }
 
 impl Message {
@@ -52,6 +68,10 @@ impl Message {
         matches!(self, Self::Extension(_))
     }
 
+    pub fn is_unknown(&self) -> bool {
+        matches!(self, Self::Unknown { .. })
+    }
+
     pub fn as_peer_message(&self) -> Option<&PeerMessage> {
         if let Self::PeerMessage(msg) = self {
             Some(msg)
@@ -69,8 +89,92 @@ impl Message {
     }
 }
 
-#[derive(Debug)]
-pub struct MessageDecoder;
+#[derive(Debug, Clone)]
+pub struct MessageDecoder {
+    max_length: usize,
+    num_pieces: Option<usize>,
+    extensions: ExtensionRegistry,
+}
+
+impl Default for MessageDecoder {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MESSAGE_LENGTH)
+    }
+}
+
+impl MessageDecoder {
+    pub fn new(max_length: usize) -> Self {
+        Self {
+            max_length,
+            num_pieces: None,
+            extensions: ExtensionRegistry::supported(),
+        }
+    }
+
+    /// The extension ids we advertise, used to decode extended messages.
+    pub fn extensions(&self) -> &ExtensionRegistry {
+        &self.extensions
+    }
+
+    pub fn set_extensions(&mut self, extensions: ExtensionRegistry) {
+        self.extensions = extensions;
+    }
+
+    pub fn set_max_length(&mut self, max_length: usize) {
+        self.max_length = max_length;
+    }
+
+    /// Enables the checks that depend on the torrent, such as the bitfield size.
+    pub fn set_num_pieces(&mut self, num_pieces: usize) {
+        self.num_pieces = Some(num_pieces);
+    }
+
+    fn validate(&self, msg: &PeerMessage) -> Result<()> {
+        match msg {
+            PeerMessage::Bitfield(bitfield) => {
+                let Some(num_pieces) = self.num_pieces else {
+                    return Ok(());
+                };
+
+                let expected = num_pieces.div_ceil(8);
+                if bitfield.len() != expected {
+                    violation!(
+                        "Bitfield length {} does not match {num_pieces} pieces",
+                        bitfield.len()
+                    );
+                }
+
+                let spare_bits = expected * 8 - num_pieces;
+                if spare_bits > 0 && bitfield[expected - 1] & ((1u8 << spare_bits) - 1) != 0 {
+                    violation!("Bitfield has spare bits set");
+                }
+            }
+            PeerMessage::Have(index)
+            | PeerMessage::SuggestPiece(index)
+            | PeerMessage::AllowedFast(index) => {
+                if let Some(num_pieces) = self.num_pieces
+                    && *index as usize >= num_pieces
+                {
+                    violation!("Piece index {index} out of range");
+                }
+            }
+            PeerMessage::Piece { index, block, .. } => {
+                if let Some(num_pieces) = self.num_pieces
+                    && *index as usize >= num_pieces
+                {
+                    violation!("Piece index {index} out of range");
+                }
+
+                if block.len() > MAX_BLOCK_LENGTH {
+                    violation!("Block length {} exceeds {MAX_BLOCK_LENGTH}", block.len());
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
 
 impl Decoder for MessageDecoder {
     type Item = Message;
@@ -90,7 +194,15 @@ impl Decoder for MessageDecoder {
             return Ok(Some(Message::KeepAlive));
         }
 
+        if length > self.max_length {
+            violation!(
+                "Message length {length} exceeds maximum {}",
+                self.max_length
+            );
+        }
+
         if src.len() < LENGTH_SIZE + length {
+            src.reserve(LENGTH_SIZE + length - src.len());
             return Ok(None);
         }
 
@@ -101,15 +213,19 @@ impl Decoder for MessageDecoder {
 
         if peer::is_peer_message(msg_id) {
             let msg = PeerMessage::try_from(msg_bytes.as_ref())?;
+            self.validate(&msg)?;
             return Ok(Some(Message::PeerMessage(msg)));
         }
 
         if extension::is_extension_message(msg_id) {
-            let msg = Extension::try_from(msg_bytes.as_ref())?;
+            let msg = Extension::decode(msg_bytes.as_ref(), &self.extensions)?;
             return Ok(Some(Message::Extension(msg)));
         }
 
-        bail!("Unknown message ID: {msg_id}");
+        Ok(Some(Message::Unknown {
+            id: msg_id,
+            payload: msg_bytes[1..].to_vec(),
+        }))
     }
 }
 
@@ -119,6 +235,107 @@ impl AsBytes for Message {
             Self::KeepAlive => Ok(Bytes::from_static(b"\x00\x00\x00\x00")),
             Self::PeerMessage(msg) => msg.as_bytes(),
             Self::Extension(ext) => ext.as_bytes(),
+            Self::Unknown { id, payload } => {
+                let length = 1 + payload.len() as u32;
+                let mut bytes = Vec::with_capacity(4 + length as usize);
+                bytes.extend_from_slice(&length.to_be_bytes());
+                bytes.push(*id);
+                bytes.extend_from_slice(payload);
+                Ok(Bytes::from(bytes))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_unknown_message() {
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&[0, 0, 0, 3, 42, 0xde, 0xad]);
+        src.extend_from_slice(&PeerMessage::Port(6881).as_bytes().unwrap());
+        src.extend_from_slice(&PeerMessage::Unchoke.as_bytes().unwrap());
+
+        let mut decoder = MessageDecoder::default();
+
+        assert_eq!(
+            decoder.decode(&mut src).unwrap(),
+            Some(Message::Unknown {
+                id: 42,
+                payload: vec![0xde, 0xad],
+            })
+        );
+        assert_eq!(
+            decoder.decode(&mut src).unwrap(),
+            Some(Message::PeerMessage(PeerMessage::Port(6881)))
+        );
+        assert_eq!(
+            decoder.decode(&mut src).unwrap(),
+            Some(Message::PeerMessage(PeerMessage::Unchoke))
+        );
+        assert_eq!(decoder.decode(&mut src).unwrap(), None);
+    }
+
+    #[test]
+    fn test_decode_message_too_long() {
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 7]);
+
+        let mut decoder = MessageDecoder::default();
+        let result = decoder.decode(&mut src);
+        assert!(matches!(result, Err(BitTorrentError::ProtocolViolation(_))));
+
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&PeerMessage::Have(1).as_bytes().unwrap());
+
+        let mut decoder = MessageDecoder::new(4);
+        let result = decoder.decode(&mut src);
+        assert!(matches!(result, Err(BitTorrentError::ProtocolViolation(_))));
+    }
+
+    #[test]
+    fn test_decode_validates_against_num_pieces() {
+        let mut decoder = MessageDecoder::default();
+        decoder.set_num_pieces(10);
+
+        let valid = [
+            PeerMessage::Bitfield(vec![0xff, 0xc0]),
+            PeerMessage::Have(9),
+            PeerMessage::Piece {
+                index: 9,
+                begin: 0,
+                block: vec![0; MAX_BLOCK_LENGTH],
+            },
+        ];
+
+        for msg in valid {
+            let mut src = BytesMut::from(msg.as_bytes().unwrap().as_ref());
+            assert_eq!(
+                decoder.decode(&mut src).unwrap(),
+                Some(Message::PeerMessage(msg))
+            );
+        }
+
+        let invalid = [
+            PeerMessage::Bitfield(vec![0xff]),
+            PeerMessage::Bitfield(vec![0xff, 0xff]),
+            PeerMessage::Have(10),
+            PeerMessage::Piece {
+                index: 0,
+                begin: 0,
+                block: vec![0; MAX_BLOCK_LENGTH + 1],
+            },
+        ];
+
+        for msg in invalid {
+            let mut src = BytesMut::from(msg.as_bytes().unwrap().as_ref());
+            let result = decoder.decode(&mut src);
+            assert!(
+                matches!(result, Err(BitTorrentError::ProtocolViolation(_))),
+                "{msg:?} should be rejected"
+            );
         }
     }
 }
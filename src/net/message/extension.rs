@@ -8,6 +8,14 @@ use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+mod handshake;
+mod pex;
+mod registry;
+
+pub use handshake::{CLIENT_VERSION, DEFAULT_REQQ, ExtensionHandshake};
+pub use pex::{PexFlags, PexMessage};
+pub use registry::{ExtensionRegistry, UT_METADATA, UT_PEX};
+
 const MESSAGE_ID_EXTENSION: u8 = 20;
 const MESSAGE_ID_EXTENSION_HANDSHAKE: u8 = 0;
 
@@ -21,27 +29,47 @@ pub fn is_extension_message(id: u8) -> bool {
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Extension {
-    Handshake(HashMap<String, Bencode>),
-    RequestMetadata { ext_id: u8, piece: u32 },
-    Metadata { ext_id: u8, piece: u32, data: Bytes },
-    Rejected { ext_id: u8, piece: u32 },
+    Handshake(ExtensionHandshake),
+    RequestMetadata {
+        ext_id: u8,
+        piece: u32,
+    },
+    Metadata {
+        ext_id: u8,
+        piece: u32,
+        total_size: usize,
+        data: Bytes,
+    },
+    Rejected {
+        ext_id: u8,
+        piece: u32,
+    },
+    Pex {
+        ext_id: u8,
+        message: PexMessage,
+    },
+    Unknown {
+        ext_id: u8,
+        payload: Bytes,
+    },
 }
 
 impl Extension {
     pub fn metadata_ext_id(&self) -> Option<u8> {
         match self {
-            Self::Handshake(dict) => {
-                if let Some(Bencode::Dict(m)) = dict.get("m")
-                    && let Some(Bencode::Int(id)) = m.get("ut_metadata")
-                {
-                    Some(*id as u8)
-                } else {
-                    None
-                }
-            }
+            Self::Handshake(handshake) => handshake.extension_id(UT_METADATA),
             Self::RequestMetadata { ext_id, .. } => Some(*ext_id),
             Self::Metadata { ext_id, .. } => Some(*ext_id),
             Self::Rejected { ext_id, .. } => Some(*ext_id),
+            Self::Pex { .. } | Self::Unknown { .. } => None,
+        }
+    }
+
+    pub fn metadata_size(&self) -> Option<usize> {
+        match self {
+            Self::Handshake(handshake) => handshake.metadata_size,
+            Self::Metadata { total_size, .. } => Some(*total_size),
+            _ => None,
         }
     }
 }
@@ -52,8 +80,8 @@ impl AsBytes for Extension {
         let mut serializer = BencodeSerializer::new(&mut dict_bytes);
 
         match self {
-            Self::Handshake(dict) => {
-                dict.serialize(&mut serializer)?;
+            Self::Handshake(handshake) => {
+                handshake.serialize(&mut serializer)?;
             }
             Self::RequestMetadata { piece, .. } => {
                 let mut dict = HashMap::new();
@@ -61,11 +89,16 @@ impl AsBytes for Extension {
                 dict.insert("piece".to_string(), Bencode::Int(*piece as i64));
                 dict.serialize(&mut serializer)?;
             }
-            Self::Metadata { piece, data, .. } => {
+            Self::Metadata {
+                piece,
+                total_size,
+                data,
+                ..
+            } => {
                 let mut dict = HashMap::new();
                 dict.insert("msg_type".to_string(), MESSAGE_TYPE_DATA); // data
                 dict.insert("piece".to_string(), Bencode::Int(*piece as i64));
-                dict.insert("total_size".to_string(), Bencode::Int(data.len() as i64));
+                dict.insert("total_size".to_string(), Bencode::Int(*total_size as i64));
 
                 dict.serialize(&mut serializer)?;
                 dict_bytes.extend_from_slice(data);
@@ -76,6 +109,12 @@ impl AsBytes for Extension {
                 dict.insert("piece".to_string(), Bencode::Int(*piece as i64));
                 dict.serialize(&mut serializer)?;
             }
+            Self::Pex { message, .. } => {
+                dict_bytes.extend(message.to_bencode()?);
+            }
+            Self::Unknown { payload, .. } => {
+                dict_bytes.extend_from_slice(payload);
+            }
         };
 
         // 1 for message ID
@@ -87,6 +126,8 @@ impl AsBytes for Extension {
             Self::RequestMetadata { ext_id, .. } => *ext_id,
             Self::Metadata { ext_id, .. } => *ext_id,
             Self::Rejected { ext_id, .. } => *ext_id,
+            Self::Pex { ext_id, .. } => *ext_id,
+            Self::Unknown { ext_id, .. } => *ext_id,
         };
 
         Ok(length
@@ -98,10 +139,10 @@ impl AsBytes for Extension {
     }
 }
 
-impl TryFrom<&[u8]> for Extension {
-    type Error = BitTorrentError;
-
-    fn try_from(bytes: &[u8]) -> Result<Self> {
+impl Extension {
+    /// Decodes an extended message, using `registry` (the ids we advertised) to
+    /// tell which extension the message belongs to.
+    pub fn decode(bytes: &[u8], registry: &ExtensionRegistry) -> Result<Self> {
         ensure!(bytes.len() >= 2, "Extension message too short");
 
         let msg_id = bytes[0];
@@ -111,58 +152,64 @@ impl TryFrom<&[u8]> for Extension {
         );
 
         let ext_id = bytes[1];
-        match ext_id {
-            MESSAGE_ID_EXTENSION_HANDSHAKE => {
-                let dict_bytes = &bytes[2..];
-                let mut deserializer = BencodeDeserializer::new(dict_bytes);
-                let dict: HashMap<String, Bencode> = Deserialize::deserialize(&mut deserializer)?;
+        let payload = &bytes[2..];
 
-                Ok(Extension::Handshake(dict))
-            }
-            _ => {
-                let data_bytes = &bytes[2..];
-                let mut deserializer = BencodeDeserializer::new(data_bytes);
-                let dict: HashMap<String, Bencode> = Deserialize::deserialize(&mut deserializer)?;
-
-                let msg_type = dict
-                    .get("msg_type")
-                    .ok_or_else(|| BitTorrentError::DeserdeError("Missing msg_type".to_string()))?;
-
-                let piece = get_int(&dict, "piece")? as u32;
-
-                match *msg_type {
-                    MESSAGE_TYPE_REQUEST => Ok(Extension::RequestMetadata { ext_id, piece }),
-                    MESSAGE_TYPE_DATA => {
-                        let total_size = get_int(&dict, "total_size")? as usize;
-                        let bytes = deserializer.read_exact(total_size)?;
-
-                        Ok(Extension::Metadata {
-                            ext_id,
-                            piece,
-                            data: Bytes::from(bytes),
-                        })
-                    }
-                    MESSAGE_TYPE_REJECTED => Ok(Extension::Rejected { ext_id, piece }),
-                    _ => Err(BitTorrentError::DeserdeError(
-                        "Unknown msg_type".to_string(),
-                    )),
-                }
-            }
+        if ext_id == MESSAGE_ID_EXTENSION_HANDSHAKE {
+            let mut deserializer = BencodeDeserializer::new(payload);
+            let handshake = ExtensionHandshake::deserialize(&mut deserializer)?;
+            return Ok(Extension::Handshake(handshake));
+        }
+
+        match registry.name(ext_id) {
+            Some(UT_METADATA) => decode_metadata(ext_id, payload),
+            Some(UT_PEX) => Ok(Extension::Pex {
+                ext_id,
+                message: PexMessage::from_bencode(payload)?,
+            }),
+            _ => Ok(Extension::Unknown {
+                ext_id,
+                payload: Bytes::copy_from_slice(payload),
+            }),
         }
     }
 }
 
-pub fn handshake() -> Extension {
-    let mut dict = HashMap::new();
-    dict.insert(
-        "m".to_string(),
-        Bencode::Dict({
-            let mut ext_map = HashMap::new();
-            ext_map.insert("ut_metadata".to_string(), Bencode::Int(1));
-            ext_map
-        }),
-    );
-    Extension::Handshake(dict)
+impl TryFrom<&[u8]> for Extension {
+    type Error = BitTorrentError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        Self::decode(bytes, &ExtensionRegistry::supported())
+    }
+}
+
+fn decode_metadata(ext_id: u8, payload: &[u8]) -> Result<Extension> {
+    let mut deserializer = BencodeDeserializer::new(payload);
+    let dict: HashMap<String, Bencode> = Deserialize::deserialize(&mut deserializer)?;
+
+    let msg_type = dict
+        .get("msg_type")
+        .ok_or_else(|| BitTorrentError::DeserdeError("Missing msg_type".to_string()))?;
+
+    let piece = get_int(&dict, "piece")? as u32;
+
+    match *msg_type {
+        MESSAGE_TYPE_REQUEST => Ok(Extension::RequestMetadata { ext_id, piece }),
+        MESSAGE_TYPE_DATA => {
+            let total_size = get_int(&dict, "total_size")? as usize;
+            let bytes = deserializer.read_to_end()?;
+
+            Ok(Extension::Metadata {
+                ext_id,
+                piece,
+                total_size,
+                data: Bytes::from(bytes),
+            })
+        }
+        MESSAGE_TYPE_REJECTED => Ok(Extension::Rejected { ext_id, piece }),
+        _ => Err(BitTorrentError::DeserdeError(
+            "Unknown msg_type".to_string(),
+        )),
+    }
 }
 
 fn get_int(dict: &HashMap<String, Bencode>, key: &str) -> Result<i64> {
@@ -174,3 +221,52 @@ fn get_int(dict: &HashMap<String, Bencode>, key: &str) -> Result<i64> {
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_message_round_trip() {
+        let msg = Extension::Metadata {
+            ext_id: 1,
+            piece: 1,
+            total_size: 16384 + 5,
+            data: Bytes::from_static(b"hello"),
+        };
+
+        let bytes = msg.as_bytes().unwrap();
+        let decoded = Extension::try_from(&bytes[4..]).unwrap();
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn test_handshake_message() {
+        let handshake = ExtensionHandshake {
+            metadata_size: Some(31235),
+            ..ExtensionHandshake::new(&ExtensionRegistry::supported())
+        };
+
+        let bytes = Extension::Handshake(handshake).as_bytes().unwrap();
+        let decoded = Extension::try_from(&bytes[4..]).unwrap();
+
+        assert_eq!(decoded.metadata_ext_id(), Some(1));
+        assert_eq!(decoded.metadata_size(), Some(31235));
+    }
+
+    #[test]
+    fn test_decode_dispatches_by_registered_id() {
+        let msg = Extension::RequestMetadata {
+            ext_id: 9,
+            piece: 0,
+        };
+        let bytes = msg.as_bytes().unwrap();
+
+        let decoded = Extension::try_from(&bytes[4..]).unwrap();
+        assert!(matches!(decoded, Extension::Unknown { ext_id: 9, .. }));
+
+        let mut registry = ExtensionRegistry::new();
+        registry.register(UT_METADATA, 9);
+        assert_eq!(Extension::decode(&bytes[4..], &registry).unwrap(), msg);
+    }
+}
@@ -0,0 +1,132 @@
+use super::ExtensionRegistry;
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+pub const CLIENT_VERSION: &str = concat!("CT ", env!("CARGO_PKG_VERSION"));
+pub const DEFAULT_REQQ: u32 = 250;
+
+/// The dictionary exchanged in the extension protocol handshake (BEP 10).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExtensionHandshake {
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "compact_ip")]
+    pub yourip: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "compact_ip")]
+    pub ipv6: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "compact_ip")]
+    pub ipv4: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_only: Option<i64>,
+}
+
+impl ExtensionHandshake {
+    pub fn new(registry: &ExtensionRegistry) -> Self {
+        Self {
+            m: registry.to_map(),
+            v: Some(CLIENT_VERSION.to_string()),
+            reqq: Some(DEFAULT_REQQ),
+            ..Self::default()
+        }
+    }
+
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.m
+            .get(name)
+            .and_then(|id| u8::try_from(*id).ok())
+            .filter(|id| *id != 0)
+    }
+
+    pub fn is_upload_only(&self) -> bool {
+        self.upload_only.is_some_and(|v| v != 0)
+    }
+}
+
+mod compact_ip {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    pub fn serialize<S>(ip: &Option<IpAddr>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match ip {
+            Some(IpAddr::V4(ip)) => serializer.serialize_bytes(&ip.octets()),
+            Some(IpAddr::V6(ip)) => serializer.serialize_bytes(&ip.octets()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<IpAddr>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let bytes = serde_bytes::ByteBuf::deserialize(deserializer)?;
+
+        // Addresses of any other length are ignored rather than failing the
+        // whole handshake.
+        let ip = match bytes.len() {
+            4 => <[u8; 4]>::try_from(bytes.as_slice())
+                .ok()
+                .map(|b| IpAddr::V4(Ipv4Addr::from(b))),
+            16 => <[u8; 16]>::try_from(bytes.as_slice())
+                .ok()
+                .map(|b| IpAddr::V6(Ipv6Addr::from(b))),
+            _ => None,
+        };
+
+        Ok(ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::{Deserializer, Serializer};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_handshake_round_trip() {
+        let handshake = ExtensionHandshake {
+            p: Some(6881),
+            yourip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            metadata_size: Some(31235),
+            upload_only: Some(1),
+            ..ExtensionHandshake::new(&ExtensionRegistry::supported())
+        };
+
+        let mut bytes = Vec::new();
+        handshake
+            .serialize(&mut Serializer::new(&mut bytes))
+            .unwrap();
+
+        let mut de = Deserializer::new(bytes.as_slice());
+        let decoded = ExtensionHandshake::deserialize(&mut de).unwrap();
+
+        assert_eq!(decoded, handshake);
+        assert_eq!(decoded.extension_id("ut_metadata"), Some(1));
+        assert!(decoded.is_upload_only());
+    }
+
+    #[test]
+    fn test_handshake_ignores_unknown_keys() {
+        let data = b"d1:md11:ut_metadatai3e6:ut_pexi0ee13:metadata_sizei100e1:q3:fooe";
+        let mut de = Deserializer::new(&data[..]);
+        let handshake = ExtensionHandshake::deserialize(&mut de).unwrap();
+
+        assert_eq!(handshake.extension_id("ut_metadata"), Some(3));
+        assert_eq!(handshake.extension_id("ut_pex"), None);
+        assert_eq!(handshake.metadata_size, Some(100));
+        assert_eq!(handshake.v, None);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{
+    Result,
+    bencode::{Deserializer, Serializer},
+    net::{PEER_BYTE_SIZE, PEER_V6_BYTE_SIZE, Peer},
+};
+
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct PexFlags(u8);
+
+impl PexFlags {
+    pub const PREFERS_ENCRYPTION: Self = Self(0x01);
+    pub const SEED: Self = Self(0x02);
+    pub const SUPPORTS_UTP: Self = Self(0x04);
+    pub const SUPPORTS_HOLEPUNCH: Self = Self(0x08);
+    pub const REACHABLE: Self = Self(0x10);
+
+    pub fn new(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for PexFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// A ut_pex message (BEP 11) listing peers connected or disconnected since the
+/// previous message.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PexMessage {
+    pub added: Vec<(Peer, PexFlags)>,
+    pub dropped: Vec<Peer>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RawPexMessage {
+    #[serde(default, with = "serde_bytes")]
+    added: Vec<u8>,
+    #[serde(rename = "added.f", default, with = "serde_bytes")]
+    added_f: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    added6: Vec<u8>,
+    #[serde(rename = "added6.f", default, with = "serde_bytes")]
+    added6_f: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    dropped: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    dropped6: Vec<u8>,
+}
+
+impl PexMessage {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn to_bencode(&self) -> Result<Vec<u8>> {
+        let mut raw = RawPexMessage::default();
+
+        for (peer, flags) in &self.added {
+            match peer.addr() {
+                SocketAddr::V4(_) => {
+                    raw.added.extend(peer.to_compact());
+                    raw.added_f.push(flags.bits());
+                }
+                SocketAddr::V6(_) => {
+                    raw.added6.extend(peer.to_compact());
+                    raw.added6_f.push(flags.bits());
+                }
+            }
+        }
+
+        for peer in &self.dropped {
+            match peer.addr() {
+                SocketAddr::V4(_) => raw.dropped.extend(peer.to_compact()),
+                SocketAddr::V6(_) => raw.dropped6.extend(peer.to_compact()),
+            }
+        }
+
+        let mut bytes = Vec::new();
+        raw.serialize(&mut Serializer::new(&mut bytes))?;
+        Ok(bytes)
+    }
+
+    pub fn from_bencode(bytes: &[u8]) -> Result<Self> {
+        let mut de = Deserializer::new(bytes);
+        let raw = RawPexMessage::deserialize(&mut de)?;
+
+        let mut added = Vec::new();
+        added.extend(with_flags(&raw.added, &raw.added_f, PEER_BYTE_SIZE)?);
+        added.extend(with_flags(&raw.added6, &raw.added6_f, PEER_V6_BYTE_SIZE)?);
+
+        let mut dropped = peers(&raw.dropped, PEER_BYTE_SIZE)?;
+        dropped.extend(peers(&raw.dropped6, PEER_V6_BYTE_SIZE)?);
+
+        Ok(Self { added, dropped })
+    }
+}
+
+fn peers(bytes: &[u8], unit_len: usize) -> Result<Vec<Peer>> {
+    bytes
+        .chunks_exact(unit_len)
+        .map(|chunk| Peer::try_from(chunk.to_vec()))
+        .collect()
+}
+
+fn with_flags(bytes: &[u8], flags: &[u8], unit_len: usize) -> Result<Vec<(Peer, PexFlags)>> {
+    let peers = peers(bytes, unit_len)?;
+
+    // Flags are optional; peers without one are treated as having none set.
+    Ok(peers
+        .into_iter()
+        .enumerate()
+        .map(|(i, peer)| (peer, PexFlags::new(flags.get(i).copied().unwrap_or(0))))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_pex_message_round_trip() {
+        let msg = PexMessage {
+            added: vec![
+                (
+                    Peer::from_str("10.0.0.1:6881").unwrap(),
+                    PexFlags::SEED | PexFlags::REACHABLE,
+                ),
+                (Peer::from_str("[::1]:51413").unwrap(), PexFlags::default()),
+            ],
+            dropped: vec![Peer::from_str("192.168.1.2:80").unwrap()],
+        };
+
+        let bytes = msg.to_bencode().unwrap();
+        let decoded = PexMessage::from_bencode(&bytes).unwrap();
+
+        assert_eq!(decoded, msg);
+        assert!(decoded.added[0].1.contains(PexFlags::SEED));
+    }
+
+    #[test]
+    fn test_pex_message_without_flags() {
+        let data = b"d5:added6:\x0a\x00\x00\x01\x1a\xe1e";
+        let msg = PexMessage::from_bencode(data).unwrap();
+
+        assert_eq!(
+            msg.added,
+            vec![(
+                Peer::from_str("10.0.0.1:6881").unwrap(),
+                PexFlags::default()
+            )]
+        );
+        assert!(msg.dropped.is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+use super::ExtensionHandshake;
+
+use std::collections::BTreeMap;
+
+pub const UT_METADATA: &str = "ut_metadata";
+pub const UT_PEX: &str = "ut_pex";
+
+const SUPPORTED_EXTENSIONS: &[(&str, u8)] = &[(UT_METADATA, 1), (UT_PEX, 2)];
+
+/// Maps extension message names to the ids negotiated in the BEP 10 handshake.
+///
+/// Our own registry decides how incoming extended messages are decoded, while
+/// the registry built from a peer's handshake gives the ids to send with.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtensionRegistry {
+    ids: BTreeMap<String, u8>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The extensions this client understands, with their local ids.
+    pub fn supported() -> Self {
+        let mut registry = Self::new();
+        for (name, id) in SUPPORTED_EXTENSIONS {
+            registry.register(*name, *id);
+        }
+        registry
+    }
+
+    /// Registers an extension under `id`. An id of 0 disables the extension.
+    pub fn register(&mut self, name: impl Into<String>, id: u8) {
+        let name = name.into();
+
+        if id == 0 {
+            self.ids.remove(&name);
+        } else {
+            self.ids.insert(name, id);
+        }
+    }
+
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn name(&self, id: u8) -> Option<&str> {
+        self.ids
+            .iter()
+            .find(|(_, v)| **v == id)
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub fn supports(&self, name: &str) -> bool {
+        self.ids.contains_key(name)
+    }
+
+    pub fn to_map(&self) -> BTreeMap<String, i64> {
+        self.ids
+            .iter()
+            .map(|(name, id)| (name.clone(), *id as i64))
+            .collect()
+    }
+}
+
+impl From<&ExtensionHandshake> for ExtensionRegistry {
+    fn from(handshake: &ExtensionHandshake) -> Self {
+        let mut registry = Self::new();
+        for name in handshake.m.keys() {
+            if let Some(id) = handshake.extension_id(name) {
+                registry.register(name.as_str(), id);
+            }
+        }
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let mut registry = ExtensionRegistry::supported();
+        assert_eq!(registry.id(UT_METADATA), Some(1));
+        assert_eq!(registry.name(1), Some(UT_METADATA));
+
+        registry.register("lt_donthave", 7);
+        assert_eq!(registry.name(7), Some("lt_donthave"));
+
+        registry.register("lt_donthave", 0);
+        assert!(!registry.supports("lt_donthave"));
+        assert_eq!(registry.name(7), None);
+    }
+}
@@ -15,6 +15,12 @@ const MESSAGE_ID_BITFIELD: u8 = 5;
 const MESSAGE_ID_REQUEST: u8 = 6;
 const MESSAGE_ID_PIECE: u8 = 7;
 const MESSAGE_ID_CANCEL: u8 = 8;
+const MESSAGE_ID_PORT: u8 = 9;
+const MESSAGE_ID_SUGGEST_PIECE: u8 = 13;
+const MESSAGE_ID_HAVE_ALL: u8 = 14;
+const MESSAGE_ID_HAVE_NONE: u8 = 15;
+const MESSAGE_ID_REJECT_REQUEST: u8 = 16;
+const MESSAGE_ID_ALLOWED_FAST: u8 = 17;
 
 pub fn is_peer_message(id: u8) -> bool {
     matches!(
@@ -28,6 +34,12 @@ pub fn is_peer_message(id: u8) -> bool {
             | MESSAGE_ID_REQUEST
             | MESSAGE_ID_PIECE
             | MESSAGE_ID_CANCEL
+            | MESSAGE_ID_PORT
+            | MESSAGE_ID_SUGGEST_PIECE
+            | MESSAGE_ID_HAVE_ALL
+            | MESSAGE_ID_HAVE_NONE
+            | MESSAGE_ID_REJECT_REQUEST
+            | MESSAGE_ID_ALLOWED_FAST
     )
 }
 
@@ -54,6 +66,16 @@ pub enum PeerMessage {
         begin: u32,
         length: u32,
     },
+    Port(u16),
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast(u32),
 }
 
 impl PeerMessage {
@@ -64,6 +86,19 @@ impl PeerMessage {
     pub fn is_unchoke(&self) -> bool {
         matches!(self, PeerMessage::Unchoke)
     }
+
+    pub fn is_piece(&self) -> bool {
+        matches!(self, PeerMessage::Piece { .. })
+    }
+
+    /// Whether the message announces which pieces the peer has. With the Fast
+    /// Extension a peer may send `HaveAll` or `HaveNone` in place of a bitfield.
+    pub fn is_availability(&self) -> bool {
+        matches!(
+            self,
+            PeerMessage::Bitfield(_) | PeerMessage::HaveAll | PeerMessage::HaveNone
+        )
+    }
 }
 
 impl AsBytes for PeerMessage {
@@ -148,6 +183,52 @@ impl AsBytes for PeerMessage {
                 bytes.extend_from_slice(&length.to_be_bytes());
                 Bytes::from(bytes)
             }
+            PeerMessage::Port(port) => {
+                let mut bytes = Vec::with_capacity(7);
+                bytes.extend_from_slice(&3u32.to_be_bytes());
+                bytes.push(MESSAGE_ID_PORT);
+                bytes.extend_from_slice(&port.to_be_bytes());
+                Bytes::from(bytes)
+            }
+            PeerMessage::SuggestPiece(index) => {
+                let mut bytes = Vec::with_capacity(9);
+                bytes.extend_from_slice(&5u32.to_be_bytes());
+                bytes.push(MESSAGE_ID_SUGGEST_PIECE);
+                bytes.extend_from_slice(&index.to_be_bytes());
+                Bytes::from(bytes)
+            }
+            PeerMessage::HaveAll => {
+                let mut bytes = Vec::with_capacity(5);
+                bytes.extend_from_slice(&1u32.to_be_bytes());
+                bytes.push(MESSAGE_ID_HAVE_ALL);
+                Bytes::from(bytes)
+            }
+            PeerMessage::HaveNone => {
+                let mut bytes = Vec::with_capacity(5);
+                bytes.extend_from_slice(&1u32.to_be_bytes());
+                bytes.push(MESSAGE_ID_HAVE_NONE);
+                Bytes::from(bytes)
+            }
+            PeerMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                let mut bytes = Vec::with_capacity(17);
+                bytes.extend_from_slice(&13u32.to_be_bytes());
+                bytes.push(MESSAGE_ID_REJECT_REQUEST);
+                bytes.extend_from_slice(&index.to_be_bytes());
+                bytes.extend_from_slice(&begin.to_be_bytes());
+                bytes.extend_from_slice(&length.to_be_bytes());
+                Bytes::from(bytes)
+            }
+            PeerMessage::AllowedFast(index) => {
+                let mut bytes = Vec::with_capacity(9);
+                bytes.extend_from_slice(&5u32.to_be_bytes());
+                bytes.push(MESSAGE_ID_ALLOWED_FAST);
+                bytes.extend_from_slice(&index.to_be_bytes());
+                Bytes::from(bytes)
+            }
         };
 
         Ok(bytes)
@@ -216,6 +297,42 @@ impl TryFrom<&[u8]> for PeerMessage {
                     length,
                 }
             }
+            MESSAGE_ID_PORT => {
+                ensure!(payload.len() == 2, "Invalid Port message payload length");
+                PeerMessage::Port(u16::from_be_bytes([payload[0], payload[1]]))
+            }
+            MESSAGE_ID_SUGGEST_PIECE => {
+                ensure!(
+                    payload.len() == 4,
+                    "Invalid Suggest Piece message payload length"
+                );
+                PeerMessage::SuggestPiece(u32_from_bytes(payload))
+            }
+            MESSAGE_ID_HAVE_ALL => PeerMessage::HaveAll,
+            MESSAGE_ID_HAVE_NONE => PeerMessage::HaveNone,
+            MESSAGE_ID_REJECT_REQUEST => {
+                ensure!(
+                    payload.len() == 12,
+                    "Invalid Reject Request message payload length"
+                );
+
+                let index = u32_from_bytes(&payload[..4]);
+                let begin = u32_from_bytes(&payload[4..8]);
+                let length = u32_from_bytes(&payload[8..12]);
+
+                PeerMessage::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                }
+            }
+            MESSAGE_ID_ALLOWED_FAST => {
+                ensure!(
+                    payload.len() == 4,
+                    "Invalid Allowed Fast message payload length"
+                );
+                PeerMessage::AllowedFast(u32_from_bytes(payload))
+            }
             _ => bail!("Unknown message ID: {id}"),
         };
 
@@ -228,6 +345,7 @@ impl KeyHash for PeerMessage {
         let (index, begin) = match self {
             Self::Request { index, begin, .. } => (*index, *begin),
             Self::Piece { index, begin, .. } => (*index, *begin),
+            Self::RejectRequest { index, begin, .. } => (*index, *begin),
             _ => return Bytes20::from(&[0u8; 20][..]),
         };
 
@@ -243,3 +361,33 @@ fn u32_from_bytes(bytes: &[u8]) -> u32 {
     array.copy_from_slice(&bytes[0..4]);
     u32::from_be_bytes(array)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_round_trip() {
+        let messages = vec![
+            PeerMessage::Port(6881),
+            PeerMessage::SuggestPiece(3),
+            PeerMessage::HaveAll,
+            PeerMessage::HaveNone,
+            PeerMessage::RejectRequest {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            PeerMessage::AllowedFast(7),
+        ];
+
+        for msg in messages {
+            let bytes = msg.as_bytes().unwrap();
+            let length = u32_from_bytes(&bytes[..4]) as usize;
+            assert_eq!(length, bytes.len() - 4);
+
+            let decoded = PeerMessage::try_from(&bytes[4..]).unwrap();
+            assert_eq!(decoded, msg);
+        }
+    }
+}
@@ -0,0 +1,322 @@
+use crate::{
+    BitTorrentError, Result,
+    util::{Bytes20, Bytes32},
+};
+
+use super::{Extension, PeerStream};
+
+use bytes::{Bytes, BytesMut};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+pub const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+macro_rules! err {
+    ($($arg:tt)*) => {
+        BitTorrentError::Other(format!($($arg)*))
+    };
+}
+
+/// Collects the pieces of an info dictionary fetched with ut_metadata (BEP 9).
+#[derive(Debug, Clone)]
+pub struct MetadataPieces {
+    info_hash: Bytes20,
+    size: usize,
+    pieces: Vec<Option<Bytes>>,
+}
+
+impl MetadataPieces {
+    pub fn new(info_hash: Bytes20, size: usize) -> Result<Self> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return Err(BitTorrentError::ProtocolViolation(format!(
+                "Invalid metadata size {size}"
+            )));
+        }
+
+        Ok(Self {
+            info_hash,
+            size,
+            pieces: vec![None; size.div_ceil(METADATA_PIECE_SIZE)],
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.len()
+    }
+
+    pub fn piece_length(&self, piece: usize) -> usize {
+        std::cmp::min(METADATA_PIECE_SIZE, self.size - piece * METADATA_PIECE_SIZE)
+    }
+
+    pub fn missing(&self) -> Vec<usize> {
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter_map(|(i, p)| p.is_none().then_some(i))
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(Option::is_some)
+    }
+
+    pub fn insert(&mut self, piece: usize, data: Bytes) -> Result<()> {
+        if piece >= self.num_pieces() {
+            return Err(BitTorrentError::ProtocolViolation(format!(
+                "Metadata piece {piece} out of range"
+            )));
+        }
+
+        let expected = self.piece_length(piece);
+        if data.len() != expected {
+            return Err(BitTorrentError::ProtocolViolation(format!(
+                "Metadata piece {piece} has length {}, expected {expected}",
+                data.len()
+            )));
+        }
+
+        self.pieces[piece] = Some(data);
+        Ok(())
+    }
+
+    /// Joins the pieces and checks that they hash to the expected info hash,
+    /// which is the truncated SHA-256 for v2 torrents.
+    pub fn finish(self) -> Result<Bytes> {
+        let mut bytes = BytesMut::with_capacity(self.size);
+
+        for (i, piece) in self.pieces.into_iter().enumerate() {
+            let piece = piece.ok_or_else(|| err!("Metadata piece {i} is missing"))?;
+            bytes.extend_from_slice(&piece);
+        }
+
+        let hash = Bytes20::sha1_hash(&bytes);
+        if hash != self.info_hash && Bytes32::sha256_hash(&bytes).truncated() != self.info_hash {
+            return Err(err!(
+                "Metadata hash mismatch. Expected {}, got {}.",
+                self.info_hash.hex_encoded(),
+                hash.hex_encoded()
+            ));
+        }
+
+        Ok(bytes.freeze())
+    }
+}
+
+/// Builds the reply to a peer's metadata request, rejecting it when we do not
+/// have the info dictionary or the piece is out of range.
+pub fn metadata_response(metadata: Option<&Bytes>, ext_id: u8, piece: u32) -> Extension {
+    let start = piece as usize * METADATA_PIECE_SIZE;
+
+    match metadata {
+        Some(metadata) if start < metadata.len() => {
+            let end = std::cmp::min(start + METADATA_PIECE_SIZE, metadata.len());
+
+            Extension::Metadata {
+                ext_id,
+                piece,
+                total_size: metadata.len(),
+                data: metadata.slice(start..end),
+            }
+        }
+        _ => Extension::Rejected { ext_id, piece },
+    }
+}
+
+/// Downloads the info dictionary from every peer that supports ut_metadata,
+/// spreading the pieces over them and falling back to another peer when one
+/// rejects or fails a request.
+pub async fn fetch_metadata(streams: &mut [PeerStream], info_hash: Bytes20) -> Result<Bytes> {
+    let mut sources: Vec<(usize, u8)> = Vec::new();
+    let mut metadata: Option<MetadataPieces> = None;
+
+    for (i, stream) in streams.iter_mut().enumerate() {
+        let handshake = match stream.extension_handshake().await {
+            Ok(handshake) => handshake,
+            Err(err) => {
+                warn!("Extension handshake failed: {err}");
+                continue;
+            }
+        };
+
+        let (Some(ext_id), Some(size)) = (handshake.metadata_ext_id(), handshake.metadata_size())
+        else {
+            debug!("Peer does not serve metadata");
+            continue;
+        };
+
+        match &metadata {
+            Some(m) if m.size() != size => {
+                warn!("Peer reported metadata size {size}, expected {}", m.size());
+                continue;
+            }
+            Some(_) => {}
+            None => match MetadataPieces::new(info_hash, size) {
+                Ok(m) => metadata = Some(m),
+                Err(err) => {
+                    warn!("{err}");
+                    continue;
+                }
+            },
+        }
+
+        sources.push((i, ext_id));
+    }
+
+    let mut metadata =
+        metadata.ok_or_else(|| err!("Peer did not advertise ut_metadata extension"))?;
+
+    for (n, piece) in metadata.missing().into_iter().enumerate() {
+        let mut fetched = false;
+
+        for attempt in 0..sources.len() {
+            let (i, ext_id) = sources[(n + attempt) % sources.len()];
+
+            match request_metadata_piece(&mut streams[i], ext_id, piece).await {
+                Ok(Some((total_size, data))) if total_size == metadata.size() => {
+                    match metadata.insert(piece, data) {
+                        Ok(()) => {
+                            fetched = true;
+                            break;
+                        }
+                        Err(err) => warn!("{err}"),
+                    }
+                }
+                Ok(Some((total_size, _))) => {
+                    warn!(
+                        "Peer sent metadata of size {total_size}, expected {}",
+                        metadata.size()
+                    );
+                }
+                Ok(None) => debug!("Peer rejected metadata piece {piece}"),
+                Err(err) => warn!("Failed to fetch metadata piece {piece}: {err}"),
+            }
+        }
+
+        if !fetched {
+            return Err(err!(
+                "Failed to retrieve metadata piece {piece} from any peer"
+            ));
+        }
+    }
+
+    metadata.finish()
+}
+
+async fn request_metadata_piece(
+    stream: &mut PeerStream,
+    ext_id: u8,
+    piece: usize,
+) -> Result<Option<(usize, Bytes)>> {
+    stream
+        .send_message(Extension::RequestMetadata {
+            ext_id,
+            piece: piece as u32,
+        })
+        .await?;
+
+    let response = async {
+        loop {
+            match stream.wait_extention().await? {
+                Extension::Metadata {
+                    piece: p,
+                    total_size,
+                    data,
+                    ..
+                } if p as usize == piece => return Ok(Some((total_size, data))),
+                Extension::Rejected { piece: p, .. } if p as usize == piece => return Ok(None),
+                Extension::RequestMetadata { piece: p, .. } => {
+                    stream
+                        .send_message(metadata_response(None, ext_id, p))
+                        .await?;
+                }
+                _ => continue,
+            }
+        }
+    };
+
+    tokio::time::timeout(REQUEST_TIMEOUT, response)
+        .await
+        .map_err(|_| err!("Timed out waiting for metadata piece {piece}"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_assemble_multi_piece_metadata() {
+        let data = metadata(METADATA_PIECE_SIZE * 2 + 100);
+        let info_hash = Bytes20::sha1_hash(&data);
+
+        let mut pieces = MetadataPieces::new(info_hash, data.len()).unwrap();
+        assert_eq!(pieces.num_pieces(), 3);
+        assert_eq!(pieces.piece_length(2), 100);
+
+        let bytes = Bytes::from(data.clone());
+        for piece in [2, 0, 1] {
+            let start = piece * METADATA_PIECE_SIZE;
+            let end = std::cmp::min(start + METADATA_PIECE_SIZE, data.len());
+
+            assert!(!pieces.is_complete());
+            pieces.insert(piece, bytes.slice(start..end)).unwrap();
+        }
+
+        assert!(pieces.missing().is_empty());
+        assert_eq!(pieces.finish().unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_reject_invalid_metadata() {
+        let data = metadata(100);
+
+        assert!(MetadataPieces::new(Bytes20::default(), 0).is_err());
+        assert!(MetadataPieces::new(Bytes20::default(), MAX_METADATA_SIZE + 1).is_err());
+
+        let mut pieces = MetadataPieces::new(Bytes20::sha1_hash(&data), 100).unwrap();
+        assert!(pieces.insert(1, Bytes::from(data.clone())).is_err());
+        assert!(pieces.insert(0, Bytes::from(data[..99].to_vec())).is_err());
+
+        let mut pieces = MetadataPieces::new(Bytes20::default(), 100).unwrap();
+        pieces.insert(0, Bytes::from(data)).unwrap();
+        assert!(pieces.finish().is_err());
+    }
+
+    #[test]
+    fn test_metadata_response() {
+        let data = Bytes::from(metadata(METADATA_PIECE_SIZE + 10));
+
+        assert_eq!(
+            metadata_response(Some(&data), 2, 1),
+            Extension::Metadata {
+                ext_id: 2,
+                piece: 1,
+                total_size: data.len(),
+                data: data.slice(METADATA_PIECE_SIZE..),
+            }
+        );
+        assert_eq!(
+            metadata_response(Some(&data), 2, 2),
+            Extension::Rejected {
+                ext_id: 2,
+                piece: 2
+            }
+        );
+        assert_eq!(
+            metadata_response(None, 2, 0),
+            Extension::Rejected {
+                ext_id: 2,
+                piece: 0
+            }
+        );
+    }
+}
@@ -1,8 +1,37 @@
 pub mod broker;
+pub mod dht;
+mod fast;
+pub mod lsd;
 mod message;
+mod metadata;
+pub mod mse;
 mod peer;
+mod pex;
+mod picker;
 mod piece;
+mod score;
+mod swarm;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod utp;
+pub mod webseed;
 
-pub use message::{AsBytes, Extension, Message, MessageDecoder, PeerMessage};
-pub use peer::{PEER_BYTE_SIZE, Peer, PeerStream};
+pub use fast::{ALLOWED_FAST_SET_SIZE, allowed_fast_set};
+pub use message::{
+    AsBytes, CLIENT_VERSION, DEFAULT_MAX_MESSAGE_LENGTH, DEFAULT_REQQ, Extension,
+    ExtensionHandshake, ExtensionRegistry, MAX_BLOCK_LENGTH, Message, MessageDecoder, PeerMessage,
+    PexFlags, PexMessage, UT_METADATA, UT_PEX,
+};
+pub use metadata::{
+    MAX_METADATA_SIZE, METADATA_PIECE_SIZE, MetadataPieces, fetch_metadata, metadata_response,
+};
+pub use peer::{
+    PEER_BYTE_SIZE, PEER_V6_BYTE_SIZE, Peer, PeerStream, ReadTransport, WriteTransport,
+};
+pub use pex::{MAX_PEX_PEERS, PEX_INTERVAL, PexTracker};
+pub use picker::{
+    DEFAULT_PIECE_DEADLINE, DEFAULT_READ_AHEAD, PiecePicker, Priority, piece_priorities,
+};
 pub use piece::{Blocks, Piece, PieceManager};
+pub use score::{HASH_FAILURE_PENALTY, PROTOCOL_VIOLATION_PENALTY, PeerScores};
+pub use swarm::{ConnectionLimit, DEFAULT_MAX_CONNECTIONS, Swarm};
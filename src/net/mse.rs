@@ -0,0 +1,464 @@
+use crate::{BitTorrentError, Result, util::Bytes20};
+
+use bytes::BytesMut;
+use num_bigint::BigUint;
+use std::fmt;
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+mod rc4;
+mod stream;
+
+pub use rc4::Rc4;
+pub use stream::MseStream;
+
+/// The Diffie-Hellman prime shared by all peers.
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+const KEY_SIZE: usize = 96;
+const PRIVATE_KEY_SIZE: usize = 20;
+
+const MAX_PAD: usize = 512;
+const VC: [u8; 8] = [0; 8];
+const KEYSTREAM_DISCARD: usize = 1024;
+
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+// What a plaintext BitTorrent handshake starts with.
+const PROTOCOL_HEADER: &[u8; 20] = b"\x13BitTorrent protocol";
+
+macro_rules! err {
+    ($($arg:tt)*) => {
+        BitTorrentError::ProtocolViolation(format!($($arg)*))
+    };
+}
+
+/// Whether connections use Message Stream Encryption.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    /// Plaintext only.
+    #[default]
+    Disabled,
+    /// Encrypt when the peer supports it, fall back to plaintext otherwise.
+    Enabled,
+    /// Encrypted connections only.
+    Forced,
+}
+
+impl EncryptionPolicy {
+    fn crypto_provide(&self) -> u32 {
+        match self {
+            Self::Disabled => CRYPTO_PLAINTEXT,
+            Self::Enabled => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            Self::Forced => CRYPTO_RC4,
+        }
+    }
+
+    fn crypto_select(&self, provided: u32) -> Option<u32> {
+        [CRYPTO_RC4, CRYPTO_PLAINTEXT]
+            .into_iter()
+            .find(|method| provided & self.crypto_provide() & method != 0)
+    }
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = BitTorrentError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "disabled" => Ok(Self::Disabled),
+            "enabled" => Ok(Self::Enabled),
+            "forced" | "required" => Ok(Self::Forced),
+            _ => Err(BitTorrentError::Other(format!(
+                "Invalid encryption policy {s}: expected disabled, enabled or forced"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for EncryptionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Disabled => "disabled",
+            Self::Enabled => "enabled",
+            Self::Forced => "forced",
+        };
+        f.write_str(s)
+    }
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| BitTorrentError::Other(format!("Failed to get random bytes: {e}")))?;
+    Ok(bytes)
+}
+
+fn random_pad() -> Result<Vec<u8>> {
+    let len = random_bytes(2)?;
+    random_bytes(u16::from_be_bytes([len[0], len[1]]) as usize % (MAX_PAD + 1))
+}
+
+fn to_key_bytes(n: &BigUint) -> [u8; KEY_SIZE] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0u8; KEY_SIZE];
+    key[KEY_SIZE - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn hash(parts: &[&[u8]]) -> Bytes20 {
+    Bytes20::sha1_hash(&parts.concat())
+}
+
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_SIZE],
+}
+
+impl KeyPair {
+    fn generate() -> Result<Self> {
+        let prime = prime();
+        let private = BigUint::from_bytes_be(&random_bytes(PRIVATE_KEY_SIZE)?);
+        let public = to_key_bytes(&BigUint::from(GENERATOR).modpow(&private, &prime));
+        Ok(Self { private, public })
+    }
+
+    fn secret(&self, remote: &[u8]) -> Result<[u8; KEY_SIZE]> {
+        let prime = prime();
+        let remote = BigUint::from_bytes_be(remote);
+        if remote <= BigUint::from(1u32) || remote >= prime {
+            return Err(err!("Invalid Diffie-Hellman public key"));
+        }
+        Ok(to_key_bytes(&remote.modpow(&self.private, &prime)))
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME.as_bytes(), 16).expect("valid prime")
+}
+
+// The ciphers for each direction: the initiator encrypts with keyA and the
+// receiver with keyB.
+fn ciphers(secret: &[u8], skey: &Bytes20) -> (Rc4, Rc4) {
+    let mut a = Rc4::new(hash(&[b"keyA", secret, skey.as_ref()]).as_ref());
+    let mut b = Rc4::new(hash(&[b"keyB", secret, skey.as_ref()]).as_ref());
+    a.discard(KEYSTREAM_DISCARD);
+    b.discard(KEYSTREAM_DISCARD);
+    (a, b)
+}
+
+// Reads until the stream has produced `pattern`, giving up after `max`
+// bytes. Reads one byte at a time so nothing past the pattern is consumed.
+async fn sync_on<S: AsyncRead + Unpin>(stream: &mut S, pattern: &[u8], max: usize) -> Result<()> {
+    let mut window = Vec::with_capacity(max);
+
+    while window.len() < max {
+        window.push(stream.read_u8().await?);
+        if window.ends_with(pattern) {
+            return Ok(());
+        }
+    }
+
+    Err(err!("Encryption handshake did not synchronize"))
+}
+
+async fn read_pad<S: AsyncRead + Unpin>(stream: &mut S, cipher: &mut Rc4) -> Result<()> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    cipher.apply(&mut len);
+
+    let len = u16::from_be_bytes(len) as usize;
+    if len > MAX_PAD {
+        return Err(err!("Encryption handshake padding too long: {len}"));
+    }
+
+    let mut pad = vec![0u8; len];
+    stream.read_exact(&mut pad).await?;
+    cipher.apply(&mut pad);
+    Ok(())
+}
+
+/// Runs the encryption handshake on an outgoing connection for the torrent
+/// `info_hash`. The peer protocol handshake follows on the returned stream.
+pub async fn initiate<S>(
+    mut stream: S,
+    info_hash: Bytes20,
+    policy: EncryptionPolicy,
+) -> Result<MseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let keys = KeyPair::generate()?;
+    stream
+        .write_all(&[keys.public.as_slice(), &random_pad()?].concat())
+        .await?;
+
+    let mut remote = [0u8; KEY_SIZE];
+    stream.read_exact(&mut remote).await?;
+    let secret = keys.secret(&remote)?;
+
+    let (mut encrypt, mut decrypt) = ciphers(&secret, &info_hash);
+
+    let req1 = hash(&[b"req1", &secret]);
+    let req2 = hash(&[b"req2", info_hash.as_ref()]);
+    let req3 = hash(&[b"req3", &secret]);
+    let skey_hash = req2.iter().zip(req3.iter()).map(|(a, b)| a ^ b);
+
+    let mut message = VC.to_vec();
+    message.extend(policy.crypto_provide().to_be_bytes());
+    message.extend(0u16.to_be_bytes()); // len(PadC)
+    message.extend(0u16.to_be_bytes()); // len(IA)
+    encrypt.apply(&mut message);
+
+    let mut out = req1.to_vec();
+    out.extend(skey_hash);
+    out.extend(message);
+    stream.write_all(&out).await?;
+
+    // The answer starts with VC, encrypted, somewhere after PadB.
+    let mut vc = VC;
+    decrypt.clone().apply(&mut vc);
+    sync_on(&mut stream, &vc, MAX_PAD + VC.len()).await?;
+    decrypt.apply(&mut [0u8; 8]);
+
+    let mut select = [0u8; 4];
+    stream.read_exact(&mut select).await?;
+    decrypt.apply(&mut select);
+    let select = u32::from_be_bytes(select);
+
+    read_pad(&mut stream, &mut decrypt).await?;
+
+    match select {
+        CRYPTO_RC4 if policy != EncryptionPolicy::Disabled => Ok(MseStream::new(
+            stream,
+            Some(encrypt),
+            Some(decrypt),
+            BytesMut::new(),
+        )),
+        CRYPTO_PLAINTEXT if policy != EncryptionPolicy::Forced => {
+            Ok(MseStream::new(stream, None, None, BytesMut::new()))
+        }
+        _ => Err(err!("Peer selected unsupported crypto method {select:#x}")),
+    }
+}
+
+/// Answers an incoming connection, which is either a plaintext peer
+/// handshake or an encryption handshake for one of `info_hashes`. Returns
+/// the torrent it was for when the connection is encrypted.
+pub async fn accept<S>(
+    mut stream: S,
+    info_hashes: &[Bytes20],
+    policy: EncryptionPolicy,
+) -> Result<(Option<Bytes20>, MseStream<S>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut remote = [0u8; KEY_SIZE];
+    stream
+        .read_exact(&mut remote[..PROTOCOL_HEADER.len()])
+        .await?;
+
+    if remote.starts_with(PROTOCOL_HEADER) {
+        if policy == EncryptionPolicy::Forced {
+            return Err(err!("Refusing plaintext connection"));
+        }
+
+        let initial = BytesMut::from(&remote[..PROTOCOL_HEADER.len()]);
+        return Ok((None, MseStream::new(stream, None, None, initial)));
+    }
+
+    if policy == EncryptionPolicy::Disabled {
+        return Err(err!("Refusing encrypted connection"));
+    }
+
+    stream
+        .read_exact(&mut remote[PROTOCOL_HEADER.len()..])
+        .await?;
+
+    let keys = KeyPair::generate()?;
+    let secret = keys.secret(&remote)?;
+    stream
+        .write_all(&[keys.public.as_slice(), &random_pad()?].concat())
+        .await?;
+
+    let req1 = hash(&[b"req1", &secret]);
+    sync_on(&mut stream, req1.as_ref(), MAX_PAD + req1.len()).await?;
+
+    let mut skey_hash = [0u8; 20];
+    stream.read_exact(&mut skey_hash).await?;
+
+    let req3 = hash(&[b"req3", &secret]);
+    for (byte, mask) in skey_hash.iter_mut().zip(req3.iter()) {
+        *byte ^= mask;
+    }
+
+    let info_hash = *info_hashes
+        .iter()
+        .find(|h| *hash(&[b"req2", h.as_ref()]) == skey_hash)
+        .ok_or_else(|| err!("Encrypted connection for an unknown torrent"))?;
+
+    let (mut decrypt, mut encrypt) = ciphers(&secret, &info_hash);
+
+    let mut header = [0u8; 12];
+    stream.read_exact(&mut header).await?;
+    decrypt.apply(&mut header);
+
+    if header[..8] != VC {
+        return Err(err!("Invalid verification constant"));
+    }
+    let provide = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+
+    read_pad(&mut stream, &mut decrypt).await?;
+
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    decrypt.apply(&mut len);
+
+    let mut initial = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut initial).await?;
+    decrypt.apply(&mut initial);
+
+    let select = policy
+        .crypto_select(provide)
+        .ok_or_else(|| err!("No common crypto method in {provide:#x}"))?;
+
+    let mut message = VC.to_vec();
+    message.extend(select.to_be_bytes());
+    message.extend(0u16.to_be_bytes()); // len(PadD)
+    encrypt.apply(&mut message);
+    stream.write_all(&message).await?;
+
+    let initial = BytesMut::from(initial.as_slice());
+    let stream = match select {
+        CRYPTO_RC4 => MseStream::new(stream, Some(encrypt), Some(decrypt), initial),
+        _ => MseStream::new(stream, None, None, initial),
+    };
+
+    Ok((Some(info_hash), stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::duplex;
+
+    fn info_hash() -> Bytes20 {
+        Bytes20::sha1_hash(b"torrent")
+    }
+
+    async fn negotiate(
+        outgoing: EncryptionPolicy,
+        incoming: EncryptionPolicy,
+    ) -> Result<(
+        MseStream<tokio::io::DuplexStream>,
+        MseStream<tokio::io::DuplexStream>,
+    )> {
+        let (a, b) = duplex(4096);
+        let hashes = [Bytes20::sha1_hash(b"other"), info_hash()];
+
+        let (initiated, accepted) = tokio::join!(
+            initiate(a, info_hash(), outgoing),
+            accept(b, &hashes, incoming)
+        );
+
+        let (found, accepted) = accepted?;
+        assert_eq!(found, Some(info_hash()));
+        Ok((initiated?, accepted))
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!(
+            "enabled".parse::<EncryptionPolicy>().unwrap(),
+            EncryptionPolicy::Enabled
+        );
+        assert_eq!(
+            "required".parse::<EncryptionPolicy>().unwrap(),
+            EncryptionPolicy::Forced
+        );
+        assert_eq!(EncryptionPolicy::Forced.to_string(), "forced");
+        assert!("always".parse::<EncryptionPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_key_exchange_agrees() {
+        let a = KeyPair::generate().unwrap();
+        let b = KeyPair::generate().unwrap();
+
+        assert_eq!(a.secret(&b.public).unwrap(), b.secret(&a.public).unwrap());
+        assert!(a.secret(&[0u8; KEY_SIZE]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_round_trip() {
+        let (mut a, mut b) = negotiate(EncryptionPolicy::Enabled, EncryptionPolicy::Enabled)
+            .await
+            .unwrap();
+        assert!(a.is_encrypted() && b.is_encrypted());
+
+        a.write_all(b"hello over rc4").await.unwrap();
+        let mut buf = [0u8; 14];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello over rc4");
+
+        b.write_all(b"reply").await.unwrap();
+        let mut buf = [0u8; 5];
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"reply");
+    }
+
+    #[tokio::test]
+    async fn test_policy_negotiation() {
+        let (a, b) = negotiate(EncryptionPolicy::Enabled, EncryptionPolicy::Forced)
+            .await
+            .unwrap();
+        assert!(a.is_encrypted() && b.is_encrypted());
+
+        // A peer that only offers RC4 can't talk to one that refuses it.
+        let (a, b) = duplex(4096);
+        let hashes = [info_hash()];
+        let (initiated, accepted) = tokio::join!(
+            initiate(a, info_hash(), EncryptionPolicy::Forced),
+            accept(b, &hashes, EncryptionPolicy::Disabled)
+        );
+        assert!(accepted.is_err());
+        assert!(initiated.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_passthrough() {
+        let (mut a, b) = duplex(4096);
+        a.write_all(b"\x13BitTorrent protocol rest").await.unwrap();
+
+        let (found, mut stream) = accept(b, &[info_hash()], EncryptionPolicy::Enabled)
+            .await
+            .unwrap();
+        assert_eq!(found, None);
+        assert!(!stream.is_encrypted());
+
+        let mut buf = [0u8; 25];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"\x13BitTorrent protocol rest");
+
+        let (mut a, b) = duplex(4096);
+        a.write_all(PROTOCOL_HEADER).await.unwrap();
+        assert!(
+            accept(b, &[info_hash()], EncryptionPolicy::Forced)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_torrent_is_refused() {
+        let (a, b) = duplex(4096);
+        let hashes = [Bytes20::sha1_hash(b"other")];
+        let (_, accepted) = tokio::join!(
+            initiate(a, info_hash(), EncryptionPolicy::Enabled),
+            accept(b, &hashes, EncryptionPolicy::Enabled)
+        );
+        assert!(accepted.is_err());
+    }
+}
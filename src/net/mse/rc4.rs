@@ -0,0 +1,67 @@
+/// The RC4 stream cipher, as used by protocol encryption.
+#[derive(Clone)]
+pub struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut s = [0u8; 256];
+        for (i, v) in s.iter_mut().enumerate() {
+            *v = i as u8;
+        }
+
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+
+        Self { s, i: 0, j: 0 }
+    }
+
+    /// Encrypts or decrypts `data` in place.
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+
+            let k = self.s[self.i as usize].wrapping_add(self.s[self.j as usize]);
+            *byte ^= self.s[k as usize];
+        }
+    }
+
+    /// Drops the first `n` bytes of keystream.
+    pub fn discard(&mut self, n: usize) {
+        self.apply(&mut vec![0u8; n]);
+    }
+}
+
+impl std::fmt::Debug for Rc4 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Rc4")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_vectors() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(hex::encode(data), "bbf316e8d940af0ad3");
+
+        let mut data = *b"Attack at dawn";
+        Rc4::new(b"Secret").apply(&mut data);
+        assert_eq!(hex::encode(data), "45a01f645fc35b383552544b9bf5");
+
+        let mut cipher = Rc4::new(b"Secret");
+        cipher.apply(&mut data);
+        assert_eq!(&data, b"Attack at dawn");
+    }
+}
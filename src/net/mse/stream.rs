@@ -0,0 +1,140 @@
+use super::rc4::Rc4;
+
+use bytes::{Buf, BytesMut};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Writes are encrypted in chunks of at most this size.
+const MAX_WRITE: usize = 16 * 1024;
+
+/// A stream after the protocol encryption handshake. Data is RC4 encrypted
+/// when that was negotiated and passed through as is otherwise; bytes read
+/// during the handshake that belong to the payload are returned first.
+#[derive(Debug)]
+pub struct MseStream<S> {
+    inner: S,
+    encrypt: Option<Rc4>,
+    decrypt: Option<Rc4>,
+    read_buf: BytesMut,
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    write_len: usize,
+}
+
+impl<S> MseStream<S> {
+    pub(super) fn new(
+        inner: S,
+        encrypt: Option<Rc4>,
+        decrypt: Option<Rc4>,
+        initial: BytesMut,
+    ) -> Self {
+        Self {
+            inner,
+            encrypt,
+            decrypt,
+            read_buf: initial,
+            write_buf: Vec::new(),
+            write_pos: 0,
+            write_len: 0,
+        }
+    }
+
+    /// A stream that skipped the encryption handshake.
+    pub fn plaintext(inner: S) -> Self {
+        Self::new(inner, None, None, BytesMut::new())
+    }
+
+    /// Whether the payload is encrypted rather than plaintext.
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.write_buf[self.write_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+
+        self.write_buf.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.read_buf.is_empty() {
+            let n = buf.remaining().min(this.read_buf.len());
+            buf.put_slice(&this.read_buf[..n]);
+            this.read_buf.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        if let Some(cipher) = &mut this.decrypt {
+            cipher.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    // The keystream moves on as soon as data is encrypted, so encrypted bytes
+    // are kept until they are all written and the write is only reported then.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.encrypt.is_none() && this.write_buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        if this.write_buf.is_empty() {
+            let n = buf.len().min(MAX_WRITE);
+            this.write_buf.extend_from_slice(&buf[..n]);
+            if let Some(cipher) = &mut this.encrypt {
+                cipher.apply(&mut this.write_buf);
+            }
+            this.write_len = n;
+        }
+
+        ready!(this.poll_drain(cx))?;
+        Poll::Ready(Ok(this.write_len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
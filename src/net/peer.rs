@@ -1,46 +1,149 @@
 use crate::{BitTorrentError, Result, util::Bytes20};
 
-use super::message::{AsBytes, Extension, Message, MessageDecoder, PeerMessage, extension};
+use super::message::{
+    AsBytes, Extension, ExtensionHandshake, ExtensionRegistry, Message, MessageDecoder,
+    PeerMessage, UT_METADATA, UT_PEX,
+};
+use super::mse::{self, EncryptionPolicy, MseStream};
+use super::utp::UtpSocket;
 
 use std::fmt;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::ops::{Deref, DerefMut};
 use std::str::FromStr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{
-    TcpStream,
-    tcp::{OwnedReadHalf, OwnedWriteHalf},
-};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
 use tokio_stream::StreamExt;
 use tokio_util::codec::FramedRead;
+use tracing::debug;
 
 pub const PEER_BYTE_SIZE: usize = 6;
+pub const PEER_V6_BYTE_SIZE: usize = 18;
 const HANDSHAKE_SIZE: usize = 68;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct Peer(SocketAddrV4);
+// Reserved bytes advertising the extension protocol (BEP 10) and the Fast
+// Extension (BEP 6).
+const RESERVED: [u8; 8] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x04];
+const RESERVED_FAST_BYTE: usize = 7;
+const RESERVED_FAST_BIT: u8 = 0x04;
+const RESERVED_EXTENSION_BYTE: usize = 5;
+const RESERVED_EXTENSION_BIT: u8 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Peer(SocketAddr);
 
 impl Peer {
-    pub async fn connect(&self, info_hash: Bytes20, peer_id: Bytes20) -> Result<PeerStream> {
-        let mut stream = TcpStream::connect(self.0).await?;
+    pub fn new(addr: SocketAddr) -> Self {
+        Self(addr)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.0
+    }
+
+    /// Whether the peer is on the local network (private, loopback or
+    /// link-local address).
+    pub fn is_local(&self) -> bool {
+        match self.0.ip() {
+            IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+            IpAddr::V6(ip) => {
+                ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local()
+            }
+        }
+    }
 
-        let msg = Handshake::new(info_hash, peer_id);
-        stream.write_all(msg.as_bytes()).await?;
+    /// Encodes the address in the compact form used by trackers and PEX: the
+    /// IP followed by the port, both in network byte order.
+    pub fn to_compact(&self) -> Vec<u8> {
+        let mut bytes = match self.0.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        bytes.extend_from_slice(&self.0.port().to_be_bytes());
+        bytes
+    }
+
+    pub async fn connect(&self, info_hash: Bytes20, peer_id: Bytes20) -> Result<PeerStream> {
+        self.connect_with(info_hash, peer_id, EncryptionPolicy::Disabled)
+            .await
+    }
 
-        let mut resp = Handshake::default();
-        stream.read_exact(resp.as_mut()).await?;
+    /// Connects over TCP, encrypting the connection as `policy` asks.
+    pub async fn connect_with(
+        &self,
+        info_hash: Bytes20,
+        peer_id: Bytes20,
+        policy: EncryptionPolicy,
+    ) -> Result<PeerStream> {
+        let open = || async { Ok(TcpStream::connect(self.0).await?) };
+        self.establish(open, info_hash, peer_id, policy).await
+    }
 
-        let peer_id = resp.peer_id();
+    /// Connects over uTP from `socket` instead of TCP.
+    pub async fn connect_utp(
+        &self,
+        socket: &UtpSocket,
+        info_hash: Bytes20,
+        peer_id: Bytes20,
+        policy: EncryptionPolicy,
+    ) -> Result<PeerStream> {
+        let open = || socket.connect(self.0);
+        self.establish(open, info_hash, peer_id, policy).await
+    }
 
-        Ok(PeerStream::new(peer_id, stream))
+    // Runs the encryption handshake when `policy` asks for it, reconnecting in
+    // plaintext if it was optional and failed, then the peer handshake.
+    async fn establish<S, F, Fut>(
+        &self,
+        open: F,
+        info_hash: Bytes20,
+        peer_id: Bytes20,
+        policy: EncryptionPolicy,
+    ) -> Result<PeerStream>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + fmt::Debug + 'static,
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<S>>,
+    {
+        let mut stream = match policy {
+            EncryptionPolicy::Disabled => MseStream::plaintext(open().await?),
+            _ => match mse::initiate(open().await?, info_hash, policy).await {
+                Ok(stream) => stream,
+                Err(err) if policy == EncryptionPolicy::Enabled => {
+                    debug!("Encrypted connection to {self} failed, retrying in plaintext: {err}");
+                    MseStream::plaintext(open().await?)
+                }
+                Err(err) => return Err(err),
+            },
+        };
+
+        let resp = handshake(&mut stream, info_hash, peer_id).await?;
+
+        let mut stream = PeerStream::from_transport(resp.peer_id(), Some(self.0), stream);
+        stream.set_handshake(&resp);
+        stream.outbound = true;
+        Ok(stream)
     }
 }
 
+async fn handshake<S>(stream: &mut S, info_hash: Bytes20, peer_id: Bytes20) -> Result<Handshake>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let msg = Handshake::new(info_hash, peer_id);
+    stream.write_all(msg.as_bytes()).await?;
+
+    let mut resp = Handshake::default();
+    stream.read_exact(resp.as_mut()).await?;
+    Ok(resp)
+}
+
 impl FromStr for Peer {
     type Err = BitTorrentError;
 
     fn from_str(s: &str) -> Result<Self> {
-        let socket_addr: SocketAddrV4 = s.parse()?;
+        let socket_addr: SocketAddr = s.parse()?;
         Ok(Peer(socket_addr))
     }
 }
@@ -49,19 +152,25 @@ impl TryFrom<Vec<u8>> for Peer {
     type Error = BitTorrentError;
 
     fn try_from(v: Vec<u8>) -> Result<Self> {
-        if v.len() != PEER_BYTE_SIZE {
-            return Err(BitTorrentError::DeserdeError(format!(
-                "Invalid length for Peer: expected {}, got {}",
-                PEER_BYTE_SIZE,
-                v.len()
-            )));
-        }
-
-        let ip = Ipv4Addr::new(v[0], v[1], v[2], v[3]);
-        let port = u16::from_be_bytes([v[4], v[5]]);
-        let socket_addr = SocketAddrV4::new(ip, port);
+        let ip = match v.len() {
+            PEER_BYTE_SIZE => IpAddr::V4(Ipv4Addr::new(v[0], v[1], v[2], v[3])),
+            PEER_V6_BYTE_SIZE => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&v[..16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => {
+                return Err(BitTorrentError::DeserdeError(format!(
+                    "Invalid length for Peer: expected {} or {}, got {}",
+                    PEER_BYTE_SIZE,
+                    PEER_V6_BYTE_SIZE,
+                    v.len()
+                )));
+            }
+        };
 
-        Ok(Peer(socket_addr))
+        let port = u16::from_be_bytes([v[v.len() - 2], v[v.len() - 1]]);
+        Ok(Peer(SocketAddr::new(ip, port)))
     }
 }
 
@@ -79,7 +188,7 @@ impl Handshake {
         let mut bytes = [0u8; HANDSHAKE_SIZE];
         bytes[0] = 19; // Length of protocol string
         bytes[1..20].copy_from_slice(b"BitTorrent protocol");
-        bytes[20..28].copy_from_slice(b"\x00\x00\x00\x00\x00\x10\x00\x00");
+        bytes[20..28].copy_from_slice(&RESERVED);
         bytes[28..48].copy_from_slice(info_hash.as_ref());
         bytes[48..68].copy_from_slice(peer_id.as_ref());
         Self(bytes)
@@ -89,9 +198,25 @@ impl Handshake {
         &self.0
     }
 
+    fn is_valid(&self) -> bool {
+        self.0[0] == 19 && &self.0[1..20] == b"BitTorrent protocol"
+    }
+
+    fn info_hash(&self) -> Bytes20 {
+        Bytes20::from(&self.0[28..48])
+    }
+
     fn peer_id(&self) -> Bytes20 {
         Bytes20::from(&self.0[48..68])
     }
+
+    fn supports_fast_extension(&self) -> bool {
+        self.0[20 + RESERVED_FAST_BYTE] & RESERVED_FAST_BIT != 0
+    }
+
+    fn supports_extension_protocol(&self) -> bool {
+        self.0[20 + RESERVED_EXTENSION_BYTE] & RESERVED_EXTENSION_BIT != 0
+    }
 }
 
 impl Deref for Handshake {
@@ -114,35 +239,152 @@ impl fmt::Display for Peer {
     }
 }
 
+/// The read half of whatever transport carries a peer connection.
+pub trait ReadTransport: AsyncRead + Send + Unpin + fmt::Debug {}
+impl<T: AsyncRead + Send + Unpin + fmt::Debug> ReadTransport for T {}
+
+/// The write half of whatever transport carries a peer connection.
+pub trait WriteTransport: AsyncWrite + Send + Unpin + fmt::Debug {}
+impl<T: AsyncWrite + Send + Unpin + fmt::Debug> WriteTransport for T {}
+
+pub(crate) type BoxedReader = Box<dyn ReadTransport>;
+pub(crate) type BoxedWriter = Box<dyn WriteTransport>;
+
+/// A connection to a peer after the handshake, over TCP or uTP.
 #[derive(Debug)]
 pub struct PeerStream {
     peer_id: Bytes20,
-    pub(crate) reader: FramedRead<OwnedReadHalf, MessageDecoder>,
-    pub(crate) writer: OwnedWriteHalf,
+    addr: Option<SocketAddr>,
+    pub(crate) reader: FramedRead<BoxedReader, MessageDecoder>,
+    pub(crate) writer: BoxedWriter,
     get_bitfield: bool,
     sent_interested: bool,
     get_unchoked: bool,
+    fast_extension: bool,
+    extension_protocol: bool,
+    extended: bool,
+    outbound: bool,
+    peer_extensions: ExtensionRegistry,
 }
 
 impl PeerStream {
     pub fn new(peer_id: Bytes20, stream: TcpStream) -> Self {
+        let addr = stream.peer_addr().ok();
         let (read_half, write_half) = stream.into_split();
-        let reader = FramedRead::new(read_half, MessageDecoder);
+        Self::from_parts(peer_id, addr, Box::new(read_half), Box::new(write_half))
+    }
+
+    /// Wraps any stream the peer protocol can run over, such as a
+    /// [`UtpStream`](super::utp::UtpStream).
+    pub fn from_transport<T>(peer_id: Bytes20, addr: Option<SocketAddr>, transport: T) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + fmt::Debug + 'static,
+    {
+        let (read_half, write_half) = tokio::io::split(transport);
+        Self::from_parts(peer_id, addr, Box::new(read_half), Box::new(write_half))
+    }
+
+    /// Answers an incoming connection for one of `info_hashes`, encrypted or
+    /// not as `policy` allows. Returns the torrent the peer asked for.
+    pub async fn accept<T>(
+        transport: T,
+        addr: Option<SocketAddr>,
+        info_hashes: &[Bytes20],
+        peer_id: Bytes20,
+        policy: EncryptionPolicy,
+    ) -> Result<(Bytes20, Self)>
+    where
+        T: AsyncRead + AsyncWrite + Send + Unpin + fmt::Debug + 'static,
+    {
+        let (skey, mut stream) = mse::accept(transport, info_hashes, policy).await?;
+
+        let mut req = Handshake::default();
+        stream.read_exact(req.as_mut()).await?;
+
+        let info_hash = req.info_hash();
+        if !req.is_valid()
+            || !info_hashes.contains(&info_hash)
+            || skey.is_some_and(|skey| skey != info_hash)
+        {
+            return Err(BitTorrentError::ProtocolViolation(
+                "Handshake for an unknown torrent".into(),
+            ));
+        }
+
+        stream
+            .write_all(Handshake::new(info_hash, peer_id).as_bytes())
+            .await?;
 
+        let mut peer_stream = Self::from_transport(req.peer_id(), addr, stream);
+        peer_stream.set_handshake(&req);
+        Ok((info_hash, peer_stream))
+    }
+
+    fn from_parts(
+        peer_id: Bytes20,
+        addr: Option<SocketAddr>,
+        reader: BoxedReader,
+        writer: BoxedWriter,
+    ) -> Self {
         Self {
             peer_id,
-            reader,
-            writer: write_half,
+            addr,
+            reader: FramedRead::new(reader, MessageDecoder::default()),
+            writer,
             get_bitfield: false,
             sent_interested: false,
             get_unchoked: false,
+            fast_extension: false,
+            extension_protocol: false,
+            extended: false,
+            outbound: false,
+            peer_extensions: ExtensionRegistry::new(),
         }
     }
 
+    fn set_handshake(&mut self, handshake: &Handshake) {
+        self.fast_extension = handshake.supports_fast_extension();
+        self.extension_protocol = handshake.supports_extension_protocol();
+    }
+
     pub fn peer_id(&self) -> Bytes20 {
         self.peer_id
     }
 
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+
+    pub fn set_num_pieces(&mut self, num_pieces: usize) {
+        self.reader.decoder_mut().set_num_pieces(num_pieces);
+    }
+
+    pub fn set_max_message_length(&mut self, max_length: usize) {
+        self.reader.decoder_mut().set_max_length(max_length);
+    }
+
+    /// Sets the extensions we advertise in the extension handshake and decode
+    /// from the peer.
+    pub fn set_extensions(&mut self, extensions: ExtensionRegistry) {
+        self.reader.decoder_mut().set_extensions(extensions);
+    }
+
+    /// Whether both sides advertised the Fast Extension during the handshake.
+    pub fn supports_fast_extension(&self) -> bool {
+        self.fast_extension
+    }
+
+    /// Whether the peer advertised the extension protocol (BEP 10) during the
+    /// handshake.
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.extension_protocol
+    }
+
+    /// Whether the extension handshake has already been exchanged.
+    pub fn is_extended(&self) -> bool {
+        self.extended
+    }
+
     pub async fn ready(&mut self) -> Result<()> {
         if !self.get_bitfield {
             self.wait_bitfield().await?;
@@ -161,8 +403,41 @@ impl PeerStream {
 
     pub async fn extension_handshake(&mut self) -> Result<Extension> {
         self.wait_bitfield().await?;
-        self.send_message(extension::handshake()).await?;
-        self.wait_extention().await
+
+        let handshake = ExtensionHandshake {
+            yourip: self.addr.map(|addr| addr.ip()),
+            ..ExtensionHandshake::new(self.reader.decoder().extensions())
+        };
+        self.send_message(Extension::Handshake(handshake)).await?;
+
+        let ext = self.wait_extention().await?;
+        if let Extension::Handshake(handshake) = &ext {
+            self.peer_extensions = ExtensionRegistry::from(handshake);
+        }
+        self.extended = true;
+
+        Ok(ext)
+    }
+
+    /// The extension ids the peer advertised, used when sending it extended
+    /// messages.
+    pub fn peer_extensions(&self) -> &ExtensionRegistry {
+        &self.peer_extensions
+    }
+
+    /// Whether we connected to the peer, which shows it accepts incoming
+    /// connections.
+    pub fn is_outbound(&self) -> bool {
+        self.outbound
+    }
+
+    /// The id the peer assigned to ut_metadata in its extension handshake.
+    pub fn metadata_ext_id(&self) -> Option<u8> {
+        self.peer_extensions.id(UT_METADATA)
+    }
+
+    pub fn pex_ext_id(&self) -> Option<u8> {
+        self.peer_extensions.id(UT_PEX)
     }
 
     pub async fn send_message<T: AsBytes>(&mut self, msg: T) -> Result<()> {
@@ -173,7 +448,10 @@ impl PeerStream {
 
     pub async fn wait_bitfield(&mut self) -> Result<Message> {
         let msg = self
-            .wait_message(|msg| msg.as_peer_message().is_some_and(PeerMessage::is_bitfield))
+            .wait_message(|msg| {
+                msg.as_peer_message()
+                    .is_some_and(PeerMessage::is_availability)
+            })
             .await?;
         self.get_bitfield = true;
         Ok(msg)
@@ -214,3 +492,127 @@ impl PeerStream {
         Err(BitTorrentError::ConnectionClosed)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::TcpListener;
+
+    // Accepts `attempts` connections with `policy`, returning the result of the
+    // last one.
+    async fn serve(
+        policy: EncryptionPolicy,
+        attempts: usize,
+    ) -> (Peer, tokio::task::JoinHandle<Result<PeerStream>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = Peer::new(listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let mut result = Err(BitTorrentError::ConnectionClosed);
+            for _ in 0..attempts {
+                let (socket, addr) = listener.accept().await?;
+                let hashes = [Bytes20::sha1_hash(b"info")];
+                result =
+                    PeerStream::accept(socket, Some(addr), &hashes, Bytes20::default(), policy)
+                        .await
+                        .map(|(_, stream)| stream);
+            }
+            result
+        });
+
+        (peer, server)
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_connection() {
+        let (peer, server) = serve(EncryptionPolicy::Forced, 1).await;
+        let info_hash = Bytes20::sha1_hash(b"info");
+
+        let mut stream = peer
+            .connect_with(
+                info_hash,
+                Bytes20::sha1_hash(b"client"),
+                EncryptionPolicy::Enabled,
+            )
+            .await
+            .unwrap();
+        assert!(stream.supports_extension_protocol());
+        assert!(stream.is_outbound());
+
+        let mut remote = server.await.unwrap().unwrap();
+        assert_eq!(remote.peer_id(), Bytes20::sha1_hash(b"client"));
+        assert!(!remote.is_outbound());
+
+        stream.send_message(PeerMessage::Interested).await.unwrap();
+        let msg = remote
+            .wait_message(|msg| msg.as_peer_message().is_some())
+            .await
+            .unwrap();
+        assert_eq!(msg.as_peer_message(), Some(&PeerMessage::Interested));
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_plaintext() {
+        let (peer, server) = serve(EncryptionPolicy::Disabled, 2).await;
+        let info_hash = Bytes20::sha1_hash(b"info");
+
+        peer.connect_with(info_hash, Bytes20::default(), EncryptionPolicy::Enabled)
+            .await
+            .unwrap();
+        assert!(server.await.unwrap().is_ok());
+
+        let (peer, _server) = serve(EncryptionPolicy::Disabled, 1).await;
+        let forced = peer
+            .connect_with(info_hash, Bytes20::default(), EncryptionPolicy::Forced)
+            .await;
+        assert!(forced.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refuses_unknown_torrent() {
+        let (peer, server) = serve(EncryptionPolicy::Enabled, 1).await;
+
+        let result = peer
+            .connect(Bytes20::sha1_hash(b"other"), Bytes20::default())
+            .await;
+        assert!(result.is_err());
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_advertises_only_set_extensions() {
+        use tokio::io::AsyncReadExt;
+        use tokio::net::TcpStream;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let mut stream = PeerStream::new(Bytes20::default(), client.unwrap());
+        let mut remote = server.unwrap().0;
+
+        let mut extensions = ExtensionRegistry::supported();
+        extensions.register(UT_PEX, 0);
+        stream.set_extensions(extensions);
+
+        let handshake =
+            Extension::Handshake(ExtensionHandshake::new(&ExtensionRegistry::supported()));
+        remote
+            .write_all(&PeerMessage::HaveAll.as_bytes().unwrap())
+            .await
+            .unwrap();
+        remote
+            .write_all(&handshake.as_bytes().unwrap())
+            .await
+            .unwrap();
+
+        stream.extension_handshake().await.unwrap();
+        assert!(stream.pex_ext_id().is_some());
+
+        let mut buf = vec![0u8; 1024];
+        let n = remote.read(&mut buf).await.unwrap();
+        let sent = String::from_utf8_lossy(&buf[..n]);
+        assert!(sent.contains(UT_METADATA));
+        assert!(!sent.contains(UT_PEX));
+    }
+}
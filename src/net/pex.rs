@@ -0,0 +1,159 @@
+use super::{Peer, PexFlags, PexMessage};
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+pub const MAX_PEX_PEERS: usize = 50;
+
+// Incoming messages are allowed a little earlier than PEX_INTERVAL to tolerate
+// timer jitter on the sending side.
+const MIN_INCOMING_INTERVAL: Duration = Duration::from_secs(45);
+
+/// Tracks the PEX exchange with a single peer: which peers we have already
+/// advertised to it and when messages were last sent and received.
+#[derive(Debug, Default)]
+pub struct PexTracker {
+    advertised: HashSet<Peer>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PexTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the next message from the peers we are connected to, each with
+    /// the flags to advertise it with. Returns `None` when a message was sent
+    /// less than a minute ago or nothing changed.
+    pub fn next_message(
+        &mut self,
+        connected: &HashMap<Peer, PexFlags>,
+        now: Instant,
+    ) -> Option<PexMessage> {
+        if self
+            .last_sent
+            .is_some_and(|sent| now.duration_since(sent) < PEX_INTERVAL)
+        {
+            return None;
+        }
+
+        let added = connected
+            .iter()
+            .filter(|(peer, _)| !self.advertised.contains(peer))
+            .take(MAX_PEX_PEERS)
+            .map(|(peer, flags)| (*peer, *flags))
+            .collect::<Vec<_>>();
+
+        let dropped = self
+            .advertised
+            .iter()
+            .filter(|peer| !connected.contains_key(peer))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect::<Vec<Peer>>();
+
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        for (peer, _) in &added {
+            self.advertised.insert(*peer);
+        }
+
+        for peer in &dropped {
+            self.advertised.remove(peer);
+        }
+
+        self.last_sent = Some(now);
+
+        Some(PexMessage { added, dropped })
+    }
+
+    /// Whether a message received now should be processed. Peers flooding us
+    /// with PEX messages have the extra ones ignored.
+    pub fn accept_incoming(&mut self, now: Instant) -> bool {
+        if self
+            .last_received
+            .is_some_and(|received| now.duration_since(received) < MIN_INCOMING_INTERVAL)
+        {
+            return false;
+        }
+
+        self.last_received = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn peers(addrs: &[&str]) -> HashMap<Peer, PexFlags> {
+        addrs
+            .iter()
+            .map(|a| (Peer::from_str(a).unwrap(), PexFlags::REACHABLE))
+            .collect()
+    }
+
+    #[test]
+    fn test_next_message_is_rate_limited() {
+        let mut tracker = PexTracker::new();
+        let now = Instant::now();
+
+        let connected = peers(&["10.0.0.1:6881", "10.0.0.2:6881"]);
+        let msg = tracker.next_message(&connected, now).unwrap();
+        assert_eq!(msg.added.len(), 2);
+        assert!(msg.dropped.is_empty());
+
+        let mut connected = peers(&["10.0.0.1:6881"]);
+        connected.insert(
+            Peer::from_str("10.0.0.3:6881").unwrap(),
+            PexFlags::default(),
+        );
+        assert!(tracker.next_message(&connected, now).is_none());
+
+        let msg = tracker
+            .next_message(&connected, now + PEX_INTERVAL)
+            .unwrap();
+        assert_eq!(
+            msg.added,
+            vec![(
+                Peer::from_str("10.0.0.3:6881").unwrap(),
+                PexFlags::default()
+            )]
+        );
+        assert_eq!(msg.dropped, vec![Peer::from_str("10.0.0.2:6881").unwrap()]);
+
+        assert!(
+            tracker
+                .next_message(&connected, now + PEX_INTERVAL * 2)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_next_message_caps_peers() {
+        let mut tracker = PexTracker::new();
+        let connected = (0..MAX_PEX_PEERS + 10)
+            .map(|i| Peer::from_str(&format!("10.0.0.{i}:6881")).unwrap())
+            .map(|peer| (peer, PexFlags::default()))
+            .collect::<HashMap<_, _>>();
+
+        let msg = tracker.next_message(&connected, Instant::now()).unwrap();
+        assert_eq!(msg.added.len(), MAX_PEX_PEERS);
+    }
+
+    #[test]
+    fn test_accept_incoming() {
+        let mut tracker = PexTracker::new();
+        let now = Instant::now();
+
+        assert!(tracker.accept_incoming(now));
+        assert!(!tracker.accept_incoming(now + Duration::from_secs(10)));
+        assert!(tracker.accept_incoming(now + PEX_INTERVAL));
+    }
+}
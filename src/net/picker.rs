@@ -0,0 +1,398 @@
+use crate::{BitTorrentError, meta::Info};
+
+use std::cmp::Reverse;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// How many pieces a sequential download requests past the playback
+/// position by default.
+pub const DEFAULT_READ_AHEAD: usize = 8;
+
+/// How long a piece near the playback position may take before it is asked
+/// of another source.
+pub const DEFAULT_PIECE_DEADLINE: Duration = Duration::from_secs(10);
+
+/// How much we want a file, or a piece. Skipped files are not downloaded and
+/// pieces are picked from the highest priority down.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = BitTorrentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "skip" => Ok(Self::Skip),
+            "low" => Ok(Self::Low),
+            "normal" => Ok(Self::Normal),
+            "high" => Ok(Self::High),
+            _ => Err(BitTorrentError::Other(format!("Unknown priority {s}"))),
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Skip => "skip",
+            Self::Low => "low",
+            Self::Normal => "normal",
+            Self::High => "high",
+        };
+        f.write_str(s)
+    }
+}
+
+/// The priority of every piece given the priority of every file: a piece
+/// gets the highest priority of the files it holds data of, so a piece is
+/// only skipped when all of its files are. Padding files do not count.
+pub fn piece_priorities(info: &Info, file_priorities: &[Priority]) -> Vec<Priority> {
+    let files = info.files();
+    let piece_length = info.piece_length as u64;
+
+    (0..info.num_pieces())
+        .map(|index| {
+            info.file_ranges(index as u64 * piece_length, info.piece_length(index) as u64)
+                .iter()
+                .filter(|r| files[r.file].has_data())
+                .map(|r| file_priorities.get(r.file).copied().unwrap_or_default())
+                .max()
+                .unwrap_or(Priority::Skip)
+        })
+        .collect()
+}
+
+/// Decides which piece to download next: the highest priority piece not yet
+/// picked, pieces peers suggested and then the lowest index first among equals.
+///
+/// Pieces with a deadline come before all others, the earliest deadline
+/// first. In sequential mode the pieces are picked in order from the playback
+/// position on, ignoring priorities other than [`Priority::Skip`], and no
+/// more than the read-ahead window are in flight at once, each due within
+/// [`DEFAULT_PIECE_DEADLINE`] unless it has a deadline already.
+#[derive(Debug, Clone)]
+pub struct PiecePicker {
+    priorities: Vec<Priority>,
+    picked: Vec<bool>,
+    done: Vec<bool>,
+    suggested: Vec<bool>,
+    deadlines: Vec<Option<Instant>>,
+    read_ahead: Option<usize>,
+    position: usize,
+}
+
+impl PiecePicker {
+    /// A picker wanting every piece equally.
+    pub fn new(num_pieces: usize) -> Self {
+        Self::with_priorities(vec![Priority::Normal; num_pieces])
+    }
+
+    pub fn with_priorities(priorities: Vec<Priority>) -> Self {
+        let num_pieces = priorities.len();
+        Self {
+            priorities,
+            picked: vec![false; num_pieces],
+            done: vec![false; num_pieces],
+            suggested: vec![false; num_pieces],
+            deadlines: vec![None; num_pieces],
+            read_ahead: None,
+            position: 0,
+        }
+    }
+
+    /// Picks pieces in order, keeping up to `read_ahead` pieces in flight.
+    pub fn set_sequential(&mut self, read_ahead: usize) {
+        self.read_ahead = Some(read_ahead.max(1));
+    }
+
+    pub fn is_sequential(&self) -> bool {
+        self.read_ahead.is_some()
+    }
+
+    /// Moves the playback position, from which sequential picking goes on.
+    /// Pieces before it are picked last.
+    pub fn set_position(&mut self, index: usize) {
+        self.position = index.min(self.priorities.len().saturating_sub(1));
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Asks for piece `index` by `deadline`, ahead of pieces without one.
+    pub fn set_deadline(&mut self, index: usize, deadline: Instant) {
+        if let Some(d) = self.deadlines.get_mut(index) {
+            *d = Some(deadline);
+        }
+    }
+
+    /// Prefers piece `index` over others of the same priority, as a peer
+    /// suggested (BEP 6). Sequential picking is not affected.
+    pub fn suggest(&mut self, index: usize) {
+        if let Some(s) = self.suggested.get_mut(index) {
+            *s = true;
+        }
+    }
+
+    /// When piece `index`, picked at `now`, is due: by its deadline, or in
+    /// sequential mode within [`DEFAULT_PIECE_DEADLINE`].
+    pub fn due(&self, index: usize, now: Instant) -> Option<Instant> {
+        self.deadlines
+            .get(index)
+            .copied()
+            .flatten()
+            .or_else(|| self.is_sequential().then(|| now + DEFAULT_PIECE_DEADLINE))
+    }
+
+    pub fn clear_deadline(&mut self, index: usize) {
+        if let Some(d) = self.deadlines.get_mut(index) {
+            *d = None;
+        }
+    }
+
+    /// How many picked pieces may be downloading at once.
+    pub fn max_in_flight(&self) -> usize {
+        self.read_ahead.unwrap_or(usize::MAX)
+    }
+
+    /// A picker for a torrent whose files have the given priorities.
+    pub fn for_files(info: &Info, file_priorities: &[Priority]) -> Self {
+        Self::with_priorities(piece_priorities(info, file_priorities))
+    }
+
+    pub fn priority(&self, index: usize) -> Priority {
+        self.priorities
+            .get(index)
+            .copied()
+            .unwrap_or(Priority::Skip)
+    }
+
+    pub fn set_priority(&mut self, index: usize, priority: Priority) {
+        if let Some(p) = self.priorities.get_mut(index) {
+            *p = priority;
+        }
+    }
+
+    /// The number of pieces to download in all.
+    pub fn num_wanted(&self) -> usize {
+        self.priorities
+            .iter()
+            .filter(|p| **p != Priority::Skip)
+            .count()
+    }
+
+    pub fn num_done(&self) -> usize {
+        self.done.iter().filter(|d| **d).count()
+    }
+
+    pub fn is_done(&self, index: usize) -> bool {
+        self.done.get(index).copied().unwrap_or_default()
+    }
+
+    /// Whether the piece was picked and is still being downloaded.
+    pub fn is_picked(&self, index: usize) -> bool {
+        self.picked.get(index).copied().unwrap_or_default() && !self.is_done(index)
+    }
+
+    /// Whether every wanted piece is done.
+    pub fn is_complete(&self) -> bool {
+        self.priorities
+            .iter()
+            .zip(&self.done)
+            .all(|(p, done)| *p == Priority::Skip || *done)
+    }
+
+    /// Picks the next piece to request.
+    pub fn pick(&mut self) -> Option<usize> {
+        let index = (0..self.priorities.len())
+            .filter(|i| !self.picked[*i] && !self.done[*i])
+            .filter(|i| self.priorities[*i] != Priority::Skip)
+            .min_by_key(|i| self.rank(*i))?;
+
+        self.picked[index] = true;
+        Some(index)
+    }
+
+    // Pieces with a deadline sort first, the earliest first.
+    fn rank(&self, index: usize) -> (bool, Option<Instant>, Reverse<Priority>, bool, usize) {
+        let deadline = self.deadlines[index];
+
+        if self.is_sequential() {
+            let n = self.priorities.len();
+            let distance = (index + n - self.position) % n;
+            (
+                deadline.is_none(),
+                deadline,
+                Reverse(Priority::Normal),
+                false,
+                distance,
+            )
+        } else {
+            (
+                deadline.is_none(),
+                deadline,
+                Reverse(self.priorities[index]),
+                !self.suggested[index],
+                index,
+            )
+        }
+    }
+
+    /// Marks a piece as downloaded and verified.
+    pub fn done(&mut self, index: usize) {
+        if let Some(done) = self.done.get_mut(index) {
+            *done = true;
+        }
+        self.clear_deadline(index);
+    }
+
+    /// Returns a picked piece that failed, so that it is picked again.
+    pub fn failed(&mut self, index: usize) {
+        if let Some(picked) = self.picked.get_mut(index) {
+            *picked = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::FileInfo;
+
+    fn info() -> Info {
+        let file = |length, path: &str| FileInfo {
+            length,
+            path: vec![path.into()],
+            ..FileInfo::default()
+        };
+
+        Info {
+            attr: None,
+            file_tree: None,
+            files: Some(vec![file(6, "a"), file(5, "b"), file(5, "c")]),
+            length: None,
+            meta_version: None,
+            name: "dir".into(),
+            piece_length: 4,
+            pieces: vec![Default::default(); 4].into(),
+            private: None,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn test_piece_priorities_from_files() {
+        use Priority::*;
+
+        let priorities = piece_priorities(&info(), &[Skip, High, Skip]);
+        // Piece 1 holds the end of `a` and the start of `b`.
+        assert_eq!(priorities, [Skip, High, High, Skip]);
+
+        let priorities = piece_priorities(&info(), &[Low, Skip, Normal]);
+        assert_eq!(priorities, [Low, Low, Normal, Normal]);
+    }
+
+    #[test]
+    fn test_picks_by_priority_then_index() {
+        use Priority::*;
+
+        let mut picker = PiecePicker::with_priorities(vec![Low, High, Skip, Normal, High]);
+        assert_eq!(picker.num_wanted(), 4);
+
+        assert_eq!(picker.pick(), Some(1));
+        assert_eq!(picker.pick(), Some(4));
+        picker.failed(1);
+        assert_eq!(picker.pick(), Some(1));
+        assert_eq!(picker.pick(), Some(3));
+        assert_eq!(picker.pick(), Some(0));
+        assert_eq!(picker.pick(), None);
+
+        for index in [0, 1, 3] {
+            picker.done(index);
+        }
+        assert!(!picker.is_complete());
+        picker.done(4);
+        assert!(picker.is_complete());
+    }
+
+    #[test]
+    fn test_suggested_pieces_come_first_among_equals() {
+        use Priority::*;
+
+        let mut picker = PiecePicker::with_priorities(vec![Normal, Normal, Low, High]);
+        picker.suggest(2);
+        picker.suggest(1);
+
+        assert_eq!(picker.pick(), Some(3));
+        assert_eq!(picker.pick(), Some(1));
+        assert_eq!(picker.pick(), Some(0));
+        assert_eq!(picker.pick(), Some(2));
+    }
+
+    #[test]
+    fn test_parse_priority() {
+        assert_eq!("HIGH".parse::<Priority>().unwrap(), Priority::High);
+        assert_eq!(Priority::Skip.to_string(), "skip");
+        assert!("urgent".parse::<Priority>().is_err());
+    }
+
+    #[test]
+    fn test_sequential_from_position() {
+        use Priority::*;
+
+        let mut picker =
+            PiecePicker::with_priorities(vec![High, Normal, Skip, Low, Normal, Normal]);
+        picker.set_sequential(2);
+        assert_eq!(picker.max_in_flight(), 2);
+
+        picker.set_position(3);
+        assert_eq!(picker.pick(), Some(3));
+        assert_eq!(picker.pick(), Some(4));
+
+        // Seeking back picks from the new position on.
+        picker.set_position(1);
+        assert_eq!(picker.pick(), Some(1));
+        assert_eq!(picker.pick(), Some(5));
+        assert_eq!(picker.pick(), Some(0));
+        assert_eq!(picker.pick(), None);
+    }
+
+    #[test]
+    fn test_deadlines_come_first() {
+        let now = Instant::now();
+        let mut picker = PiecePicker::new(5);
+        picker.set_deadline(3, now + Duration::from_secs(2));
+        picker.set_deadline(4, now + Duration::from_secs(1));
+
+        assert_eq!(picker.pick(), Some(4));
+        assert_eq!(picker.pick(), Some(3));
+        assert_eq!(picker.pick(), Some(0));
+
+        picker.done(3);
+        picker.failed(3);
+        picker.set_deadline(1, now);
+        picker.clear_deadline(1);
+        assert_eq!(picker.pick(), Some(1));
+        assert!(picker.is_picked(1) && !picker.is_picked(3));
+    }
+
+    #[test]
+    fn test_pieces_are_due_by_their_deadline_or_in_sequence() {
+        let now = Instant::now();
+        let mut picker = PiecePicker::new(3);
+        picker.set_deadline(1, now);
+        assert_eq!(picker.due(0, now), None);
+        assert_eq!(picker.due(1, now), Some(now));
+
+        picker.set_sequential(2);
+        assert_eq!(picker.due(0, now), Some(now + DEFAULT_PIECE_DEADLINE));
+        assert_eq!(picker.due(1, now), Some(now));
+    }
+}
@@ -1,16 +1,22 @@
 use crate::{BitTorrentError, Result};
 
 use std::collections::HashMap;
-use tokio::sync::mpsc::Sender;
+use std::net::SocketAddr;
 use tracing::debug;
 
 type Index = usize;
 type Offset = usize;
 
+/// The size of the blocks pieces are requested in; the last block of a piece
+/// may be shorter.
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Piece {
     pub index: Index,
     pub data: Vec<u8>,
+    /// The peer that sent the piece, unless it came from a web seed.
+    pub peer: Option<SocketAddr>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -37,6 +43,13 @@ impl Blocks {
         self.len() == self.length
     }
 
+    /// Whether a block at `begin` of `length` bytes is one we request.
+    pub fn is_requested(&self, begin: Offset, length: usize) -> bool {
+        begin.is_multiple_of(BLOCK_SIZE)
+            && begin < self.length
+            && length == BLOCK_SIZE.min(self.length - begin)
+    }
+
     fn len(&self) -> usize {
         self.blocks.values().map(|b| b.len()).sum()
     }
@@ -54,29 +67,39 @@ impl Blocks {
         Some(Piece {
             index: self.index,
             data,
+            peer: None,
         })
     }
 }
 
+#[derive(Debug, Default)]
 pub struct PieceManager {
     blocks: HashMap<Index, Blocks>,
-    sender: Sender<Piece>,
 }
 
 impl PieceManager {
-    pub fn new(sender: Sender<Piece>) -> Self {
-        Self {
-            blocks: HashMap::new(),
-            sender,
-        }
+    pub fn new() -> Self {
+        Self::default()
     }
 
     pub fn new_block(&mut self, index: Index, piece_length: usize) {
         self.blocks.insert(index, Blocks::new(index, piece_length));
     }
 
-    pub async fn insert_block(&mut self, index: Index, begin: Offset, data: Vec<u8>) -> Result<()> {
+    pub fn insert_block(
+        &mut self,
+        index: Index,
+        begin: Offset,
+        data: Vec<u8>,
+    ) -> Result<Option<Piece>> {
         if let Some(blocks) = self.blocks.get_mut(&index) {
+            if !blocks.is_requested(begin, data.len()) {
+                return Err(BitTorrentError::ProtocolViolation(format!(
+                    "Block at {begin} with length {} of piece {index} was not requested",
+                    data.len()
+                )));
+            }
+
             blocks.insert_block(begin, data);
         }
 
@@ -84,13 +107,75 @@ impl PieceManager {
             && let Some(piece) = self.blocks.remove(&index).and_then(|b| b.into_piece())
         {
             debug!("Piece {index} completed.");
+            return Ok(Some(piece));
+        }
+
+        Ok(None)
+    }
+
+    pub fn contains(&self, index: Index) -> bool {
+        self.blocks.contains_key(&index)
+    }
+
+    /// Drops an unfinished piece, returning its length.
+    pub fn remove(&mut self, index: Index) -> Option<usize> {
+        self.blocks.remove(&index).map(|blocks| blocks.length)
+    }
+
+    /// Removes every unfinished piece, returning their indices and lengths so
+    /// they can be requested from another peer.
+    pub fn take_pending(&mut self) -> Vec<(Index, usize)> {
+        let mut pending = self
+            .blocks
+            .drain()
+            .map(|(index, blocks)| (index, blocks.length))
+            .collect::<Vec<_>>();
+        pending.sort();
+        pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assembles_requested_blocks() {
+        let mut pieces = PieceManager::new();
+        pieces.new_block(1, BLOCK_SIZE + 10);
+
+        let tail = pieces.insert_block(1, BLOCK_SIZE, vec![2; 10]).unwrap();
+        assert!(tail.is_none());
+
+        let piece = pieces
+            .insert_block(1, 0, vec![1; BLOCK_SIZE])
+            .unwrap()
+            .unwrap();
+        assert_eq!(piece.index, 1);
+        assert_eq!(piece.data[..BLOCK_SIZE], [1; BLOCK_SIZE]);
+        assert_eq!(piece.data[BLOCK_SIZE..], [2; 10]);
+
+        // Blocks of pieces not asked for are ignored.
+        assert!(pieces.insert_block(7, 0, vec![0; 10]).unwrap().is_none());
+    }
 
-            self.sender
-                .send(piece)
-                .await
-                .map_err(|_| BitTorrentError::ChannelClosed)?;
+    #[test]
+    fn test_rejects_blocks_not_requested() {
+        let mut pieces = PieceManager::new();
+        pieces.new_block(0, 2 * BLOCK_SIZE);
+
+        for (begin, length) in [
+            (1, BLOCK_SIZE),
+            (0, BLOCK_SIZE - 1),
+            (0, 2 * BLOCK_SIZE),
+            (2 * BLOCK_SIZE, 1),
+        ] {
+            let result = pieces.insert_block(0, begin, vec![0; length]);
+            assert!(matches!(result, Err(BitTorrentError::ProtocolViolation(_))));
         }
 
-        Ok(())
+        pieces.insert_block(0, 0, vec![1; BLOCK_SIZE]).unwrap();
+        let piece = pieces.insert_block(0, BLOCK_SIZE, vec![1; BLOCK_SIZE]);
+        assert!(piece.unwrap().is_some());
     }
 }
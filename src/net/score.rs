@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub const PROTOCOL_VIOLATION_PENALTY: i64 = 100;
+/// A piece failing its hash check may be a fluke, so it takes two to ban.
+pub const HASH_FAILURE_PENALTY: i64 = 50;
+const BAN_THRESHOLD: i64 = -100;
+
+#[derive(Debug, Clone, Default)]
+pub struct PeerScores {
+    scores: Arc<Mutex<HashMap<SocketAddr, i64>>>,
+}
+
+impl PeerScores {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn penalize(&self, addr: SocketAddr, penalty: i64) {
+        let mut scores = self.scores.lock().await;
+        *scores.entry(addr).or_default() -= penalty;
+    }
+
+    pub async fn score(&self, addr: SocketAddr) -> i64 {
+        self.scores.lock().await.get(&addr).copied().unwrap_or(0)
+    }
+
+    pub async fn is_banned(&self, addr: SocketAddr) -> bool {
+        self.score(addr).await <= BAN_THRESHOLD
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_peer_scores() {
+        let scores = PeerScores::new();
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+
+        assert_eq!(scores.score(addr).await, 0);
+        assert!(!scores.is_banned(addr).await);
+
+        scores.penalize(addr, PROTOCOL_VIOLATION_PENALTY).await;
+
+        assert_eq!(scores.score(addr).await, -PROTOCOL_VIOLATION_PENALTY);
+        assert!(scores.is_banned(addr).await);
+    }
+}
@@ -0,0 +1,701 @@
+use crate::{
+    BitTorrentError, Result,
+    util::{Bytes20, RotationPool},
+};
+
+use super::{
+    ALLOWED_FAST_SET_SIZE, ExtensionRegistry, Peer, PeerScores, PeerStream, PexFlags, Piece,
+    UT_PEX, allowed_fast_set,
+    broker::{self, Broker, Event},
+    mse::EncryptionPolicy,
+    utp::UtpSocket,
+    webseed::{WebSeed, WebSeedWorker},
+};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 50;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+const EXTENSION_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a late piece asked of another source has before it is asked again.
+const LATE_PIECE_RETRY: Duration = Duration::from_secs(5);
+
+macro_rules! err {
+    ($($arg:tt)*) => {
+        BitTorrentError::Other(format!($($arg)*))
+    };
+}
+
+/// A limit on the number of connections shared by several swarms.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimit(Arc<Semaphore>);
+
+impl ConnectionLimit {
+    pub fn new(max_connections: usize) -> Self {
+        Self(Arc::new(Semaphore::new(max_connections)))
+    }
+
+    /// The number of connections that can still be made.
+    pub fn available(&self) -> usize {
+        self.0.available_permits()
+    }
+
+    fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.0).try_acquire_owned().ok()
+    }
+}
+
+/// The peers a download is working with. Pieces are spread over the connected
+/// peers, and peers learned along the way (over ut_pex or from other sources
+/// through `peer_sender`) are connected to in the background and join the
+/// download once they unchoke us. Peers on the local network are connected to
+/// first. With a uTP socket set, peers are tried over uTP before TCP. Web
+/// seeds take their share of the pieces next to the peers. Private swarms do
+/// not exchange peers. Pieces requested while the first peers are still
+/// connecting wait for them. A piece with a deadline that has not arrived by
+/// then is requested again from another peer or a web seed. Dropping the
+/// swarm closes all of its connections.
+pub struct Swarm {
+    info_hash: Bytes20,
+    peer_id: Bytes20,
+    num_pieces: usize,
+    config: broker::Config,
+    scores: PeerScores,
+    brokers: RotationPool<Broker>,
+    web_seeds: Vec<WebSeedWorker>,
+    turn: usize,
+    known: HashSet<Peer>,
+    candidates: VecDeque<Peer>,
+    connecting: usize,
+    max_connections: usize,
+    limit: Option<ConnectionLimit>,
+    // A permit of the shared limit for every connection, made or being made.
+    permits: Vec<OwnedSemaphorePermit>,
+    waiting: Vec<(usize, usize)>,
+    utp: Option<UtpSocket>,
+    encryption: EncryptionPolicy,
+    private: bool,
+    // Verified pieces we hold, offered to peers as allowed fast.
+    have: Vec<bool>,
+    suggested: Vec<usize>,
+    // The deadline and length of pieces wanted by a certain time.
+    deadlines: HashMap<usize, (Instant, usize)>,
+    // Peers being connected to or waited on, dropped with the swarm like the
+    // brokers' connections.
+    tasks: Vec<JoinHandle<()>>,
+    event_tx: Sender<Event>,
+    event_rx: Receiver<Event>,
+    stream_tx: Sender<Option<PeerStream>>,
+    stream_rx: Receiver<Option<PeerStream>>,
+    incoming_tx: Sender<PeerStream>,
+    incoming_rx: Receiver<PeerStream>,
+    peer_tx: Sender<Peer>,
+    peer_rx: Receiver<Peer>,
+    returned_tx: Sender<(usize, usize)>,
+    returned_rx: Receiver<(usize, usize)>,
+}
+
+impl Drop for Swarm {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Swarm {
+    pub fn new(
+        info_hash: Bytes20,
+        peer_id: Bytes20,
+        num_pieces: usize,
+        config: broker::Config,
+    ) -> Self {
+        let (event_tx, event_rx) = mpsc::channel(100);
+        let (stream_tx, stream_rx) = mpsc::channel(10);
+        let (incoming_tx, incoming_rx) = mpsc::channel(10);
+        let (peer_tx, peer_rx) = mpsc::channel(100);
+        let (returned_tx, returned_rx) = mpsc::channel(100);
+
+        Self {
+            info_hash,
+            peer_id,
+            num_pieces,
+            config,
+            scores: PeerScores::new(),
+            brokers: RotationPool::new(Vec::new()),
+            web_seeds: Vec::new(),
+            turn: 0,
+            known: HashSet::new(),
+            candidates: VecDeque::new(),
+            connecting: 0,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            limit: None,
+            permits: Vec::new(),
+            waiting: Vec::new(),
+            utp: None,
+            encryption: EncryptionPolicy::default(),
+            private: false,
+            have: vec![false; num_pieces],
+            suggested: Vec::new(),
+            deadlines: HashMap::new(),
+            tasks: Vec::new(),
+            event_tx,
+            event_rx,
+            stream_tx,
+            stream_rx,
+            incoming_tx,
+            incoming_rx,
+            peer_tx,
+            peer_rx,
+            returned_tx,
+            returned_rx,
+        }
+    }
+
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    /// Counts the swarm's connections against a limit shared with other
+    /// swarms as well.
+    pub fn set_connection_limit(&mut self, limit: ConnectionLimit) {
+        self.limit = Some(limit);
+    }
+
+    pub fn set_utp_socket(&mut self, socket: UtpSocket) {
+        self.utp = Some(socket);
+    }
+
+    pub fn set_encryption_policy(&mut self, policy: EncryptionPolicy) {
+        self.encryption = policy;
+    }
+
+    /// Turns off ut_pex for a private torrent (BEP 27): it is not advertised
+    /// to new peers, and peers are neither sent nor taken from it.
+    pub fn set_private(&mut self, private: bool) {
+        self.private = private;
+    }
+
+    /// A channel for feeding peers found elsewhere, e.g. on the local network,
+    /// into the swarm while it downloads.
+    pub fn peer_sender(&self) -> Sender<Peer> {
+        self.peer_tx.clone()
+    }
+
+    /// A channel for handing incoming connections to the swarm. They join
+    /// once they unchoke us, like the peers the swarm connects to itself.
+    pub fn incoming_sender(&self) -> Sender<PeerStream> {
+        self.incoming_tx.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.brokers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.brokers.is_empty()
+    }
+
+    /// Adds a web seed to download pieces from.
+    pub fn add_web_seed(&mut self, seed: WebSeed) {
+        debug!("Adding web seed {}", seed.url());
+        let worker = WebSeedWorker::spawn(seed, self.event_tx.clone(), self.returned_tx.clone());
+        self.web_seeds.push(worker);
+    }
+
+    pub fn scores(&self) -> &PeerScores {
+        &self.scores
+    }
+
+    /// Records that we hold verified piece `index`, telling the peers whose
+    /// allowed fast set has it.
+    pub async fn have(&mut self, index: usize) {
+        if let Some(have) = self.have.get_mut(index) {
+            *have = true;
+        }
+        for broker in self.brokers.iter() {
+            if let Err(err) = broker.allow_fast(index).await {
+                debug!("Failed to offer piece {index} as allowed fast: {err}");
+            }
+        }
+    }
+
+    /// The pieces peers suggested since the last call, for the picker.
+    pub fn take_suggestions(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.suggested)
+    }
+
+    /// Wants requested piece `index` by `deadline`. Until it arrives, it is
+    /// asked of another source whenever it is late.
+    pub fn set_deadline(&mut self, index: usize, length: usize, deadline: Instant) {
+        self.deadlines.insert(index, (deadline, length));
+    }
+
+    /// Adds an already connected peer, waiting until it unchokes us.
+    pub async fn add_stream(&mut self, mut stream: PeerStream) -> Result<()> {
+        if let Some(addr) = stream.peer_addr() {
+            self.known.insert(Peer::new(addr));
+        }
+
+        self.release_permits();
+        if !self.acquire_permit() {
+            return Err(err!("Connection limit reached"));
+        }
+
+        let ready = prepare(&mut stream, self.num_pieces, self.private).await;
+        if let Err(err) = ready {
+            self.release_permits();
+            return Err(err);
+        }
+
+        self.add_ready_stream(stream);
+        Ok(())
+    }
+
+    /// Adds a connected peer in the background, like an incoming one. It joins
+    /// once it unchokes us, unless there are too many connections already.
+    pub fn add_incoming(&mut self, mut stream: PeerStream) {
+        if let Some(addr) = stream.peer_addr() {
+            self.known.insert(Peer::new(addr));
+        }
+
+        self.release_permits();
+        if self.connecting + self.brokers.len() >= self.max_connections || !self.acquire_permit() {
+            debug!("Connection limit reached, dropping incoming peer");
+            return;
+        }
+        self.connecting += 1;
+
+        let num_pieces = self.num_pieces;
+        let private = self.private;
+        let stream_tx = self.stream_tx.clone();
+
+        self.spawn(async move {
+            let ready =
+                tokio::time::timeout(READY_TIMEOUT, prepare(&mut stream, num_pieces, private));
+            let stream = match ready.await {
+                Ok(Ok(())) => Some(stream),
+                Ok(Err(err)) => {
+                    debug!("Incoming peer is not ready: {err}");
+                    None
+                }
+                Err(_) => None,
+            };
+            let _ = stream_tx.send(stream).await;
+        });
+    }
+
+    fn spawn(&mut self, task: impl Future<Output = ()> + Send + 'static) {
+        self.tasks.retain(|task| !task.is_finished());
+        self.tasks.push(tokio::spawn(task));
+    }
+
+    fn acquire_permit(&mut self) -> bool {
+        let Some(limit) = &self.limit else {
+            return true;
+        };
+
+        match limit.try_acquire() {
+            Some(permit) => {
+                self.permits.push(permit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Gives back the permits of connections that are gone.
+    fn release_permits(&mut self) {
+        self.brokers.retain(Broker::is_connected);
+        self.permits.truncate(self.connecting + self.brokers.len());
+    }
+
+    /// Queues `peer` for connection unless it is already known or banned. Local
+    /// peers go to the front of the queue. The peer joins the swarm during a
+    /// later `next_piece`.
+    pub async fn add_peer(&mut self, peer: Peer) {
+        if self.known.contains(&peer) || self.scores.is_banned(peer.addr()).await {
+            return;
+        }
+
+        self.known.insert(peer);
+
+        if peer.is_local() {
+            self.candidates.push_front(peer);
+        } else {
+            self.candidates.push_back(peer);
+        }
+
+        self.connect_candidates();
+    }
+
+    fn connect_candidates(&mut self) {
+        while self.connecting + self.brokers.len() < self.max_connections {
+            let Some(peer) = self.candidates.pop_front() else {
+                break;
+            };
+
+            self.release_permits();
+            if !self.acquire_permit() {
+                self.candidates.push_front(peer);
+                break;
+            }
+            self.connecting += 1;
+
+            let info_hash = self.info_hash;
+            let peer_id = self.peer_id;
+            let num_pieces = self.num_pieces;
+            let stream_tx = self.stream_tx.clone();
+            let utp = self.utp.clone();
+            let encryption = self.encryption;
+            let private = self.private;
+
+            self.spawn(async move {
+                let connect = async {
+                    let mut stream =
+                        connect(peer, utp.as_ref(), info_hash, peer_id, encryption).await?;
+
+                    tokio::time::timeout(READY_TIMEOUT, prepare(&mut stream, num_pieces, private))
+                        .await
+                        .map_err(|_| err!("Timed out waiting for unchoke"))??;
+
+                    Ok::<PeerStream, BitTorrentError>(stream)
+                };
+
+                let stream = match connect.await {
+                    Ok(stream) => {
+                        debug!("Connected to peer {peer}");
+                        Some(stream)
+                    }
+                    Err(err) => {
+                        debug!("Failed to connect to peer {peer}: {err}");
+                        None
+                    }
+                };
+
+                let _ = stream_tx.send(stream).await;
+            });
+        }
+    }
+
+    /// Requests a piece from the next peer or ready web seed in turn. Web seeds
+    /// that are backing off only get pieces when no peer is left, and peers
+    /// that rejected the piece are not asked again.
+    pub async fn request_piece(&mut self, index: usize, length: usize) -> Result<()> {
+        self.web_seeds.retain(|s| !s.is_stopped());
+
+        if self.brokers.is_empty() && self.web_seeds.is_empty() && self.is_connecting() {
+            self.waiting.push((index, length));
+            return Ok(());
+        }
+
+        let ready = self.web_seeds.iter().filter(|s| s.is_ready()).count();
+        let turn = self.turn % (self.brokers.len() + ready).max(1);
+        self.turn = self.turn.wrapping_add(1);
+
+        let seed = turn
+            .checked_sub(self.brokers.len())
+            .and_then(|n| self.web_seeds.iter().filter(|s| s.is_ready()).nth(n));
+        if let Some(seed) = seed {
+            return seed.request_piece(index, length);
+        }
+
+        let mut tries = self.brokers.len();
+        while tries > 0 && !self.brokers.is_empty() {
+            tries -= 1;
+
+            let broker = self.brokers.get_item();
+            if broker.has_rejected(index).await {
+                continue;
+            }
+            match broker.request_piece(index, length).await {
+                Ok(()) => return Ok(()),
+                Err(BitTorrentError::ConnectionClosed) => self.brokers.retain(Broker::is_connected),
+                Err(err) => return Err(err),
+            }
+        }
+
+        match self.web_seeds.first() {
+            Some(seed) => seed.request_piece(index, length),
+            // A peer still connecting may have it.
+            None if self.is_connecting() => {
+                self.waiting.push((index, length));
+                Ok(())
+            }
+            None if self.brokers.is_empty() => Err(err!("No connected peers left")),
+            None => Err(err!("No connected peer has piece {index}")),
+        }
+    }
+
+    fn has_sources(&self) -> bool {
+        !self.brokers.is_empty()
+            || self.web_seeds.iter().any(|s| !s.is_stopped())
+            || self.is_connecting()
+    }
+
+    fn is_connecting(&self) -> bool {
+        self.connecting > 0 || !self.candidates.is_empty()
+    }
+
+    /// Waits for the next downloaded piece. Meanwhile the pieces of any peer
+    /// that disconnects or web seed that fails are moved over to the remaining
+    /// sources, exchanged peers are connected to, and our own peer list is sent
+    /// to the peers over ut_pex.
+    pub async fn next_piece(&mut self) -> Result<Option<Piece>> {
+        loop {
+            let next_deadline = self.next_deadline();
+            tokio::select! {
+                event = self.event_rx.recv() => {
+                    let Some(event) = event else {
+                        return Ok(None);
+                    };
+
+                    match event {
+                        Event::Piece(piece) => {
+                            self.deadlines.remove(&piece.index);
+                            self.send_pex().await;
+                            return Ok(Some(piece));
+                        }
+                        Event::Disconnected { pending } => {
+                            self.release_permits();
+                            debug!(
+                                "Peer disconnected, reassigning {} pieces to {} peers",
+                                pending.len(),
+                                self.brokers.len()
+                            );
+
+                            for (index, length) in pending {
+                                self.request_piece(index, length).await?;
+                            }
+
+                            if !self.has_sources() {
+                                return Err(err!("No connected peers left"));
+                            }
+
+                            self.connect_candidates();
+                        }
+                        Event::Rejected { index, length } => {
+                            debug!("Piece {index} was rejected, asking another peer");
+                            self.request_piece(index, length).await?;
+                        }
+                        Event::Suggest(index) => self.suggested.push(index),
+                        Event::Peers(_) if self.private => {}
+                        Event::Peers(peers) => {
+                            for peer in peers {
+                                self.add_peer(peer).await;
+                            }
+                        }
+                    }
+                }
+                Some(stream) = self.stream_rx.recv() => {
+                    self.connecting -= 1;
+
+                    if let Some(stream) = stream {
+                        self.add_ready_stream(stream);
+                    }
+
+                    self.connect_candidates();
+                    self.release_permits();
+
+                    for (index, length) in std::mem::take(&mut self.waiting) {
+                        self.request_piece(index, length).await?;
+                    }
+                }
+                Some(stream) = self.incoming_rx.recv() => {
+                    self.add_incoming(stream);
+                }
+                Some(peer) = self.peer_rx.recv() => {
+                    self.add_peer(peer).await;
+                }
+                Some((index, length)) = self.returned_rx.recv() => {
+                    self.request_piece(index, length).await?;
+                }
+                _ = tokio::time::sleep_until(next_deadline.into()), if !self.deadlines.is_empty() => {
+                    self.request_late_pieces().await?;
+                }
+            }
+        }
+    }
+
+    fn next_deadline(&self) -> Instant {
+        self.deadlines
+            .values()
+            .map(|(deadline, _)| *deadline)
+            .min()
+            .unwrap_or_else(Instant::now)
+    }
+
+    async fn request_late_pieces(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let late = self
+            .deadlines
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(index, (_, length))| (*index, *length))
+            .collect::<Vec<_>>();
+
+        for (index, length) in late {
+            debug!("Piece {index} is late, asking another source");
+            self.request_elsewhere(index, length).await?;
+            self.deadlines
+                .insert(index, (now + LATE_PIECE_RETRY, length));
+        }
+
+        Ok(())
+    }
+
+    // Asks a peer that is not downloading the piece yet, or else one of the
+    // ready web seeds, which take turns.
+    async fn request_elsewhere(&mut self, index: usize, length: usize) -> Result<()> {
+        for _ in 0..self.brokers.len() {
+            let broker = self.brokers.get_item();
+            if broker.is_downloading(index).await || broker.has_rejected(index).await {
+                continue;
+            }
+            match broker.request_piece(index, length).await {
+                Ok(()) => return Ok(()),
+                Err(BitTorrentError::ConnectionClosed) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        let ready = self
+            .web_seeds
+            .iter()
+            .filter(|s| s.is_ready())
+            .collect::<Vec<_>>();
+        if ready.is_empty() {
+            debug!("No other source for piece {index}");
+            return Ok(());
+        }
+        let seed = ready[self.turn % ready.len()];
+        self.turn = self.turn.wrapping_add(1);
+        seed.request_piece(index, length)
+    }
+
+    fn add_ready_stream(&mut self, stream: PeerStream) {
+        let fast_set = self.fast_set(&stream);
+        let (mut broker, mut rx) =
+            broker::create_with_config(stream, self.scores.clone(), self.config.clone());
+        broker.offer_allowed_fast(fast_set, &self.have);
+        let tx = self.event_tx.clone();
+
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                if tx.send(event).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        self.brokers.retain(Broker::is_connected);
+        self.brokers.push(broker);
+    }
+
+    async fn send_pex(&self) {
+        if self.private {
+            return;
+        }
+
+        let connected = self.connected_peers();
+
+        for broker in self.brokers.iter().filter(|b| b.is_connected()) {
+            if let Err(err) = broker.send_pex(&connected).await {
+                warn!("Failed to send PEX message: {err}");
+            }
+        }
+    }
+
+    // The allowed fast set of BEP 6, which is only defined for IPv4 peers
+    // with the fast extension.
+    fn fast_set(&self, stream: &PeerStream) -> Vec<usize> {
+        let Some(IpAddr::V4(ip)) = stream.peer_addr().map(|addr| addr.ip()) else {
+            return Vec::new();
+        };
+        if !stream.supports_fast_extension() || self.num_pieces == 0 {
+            return Vec::new();
+        }
+
+        let num_pieces = self.num_pieces as u32;
+        allowed_fast_set(ALLOWED_FAST_SET_SIZE, num_pieces, self.info_hash, ip)
+            .into_iter()
+            .map(|index| index as usize)
+            .collect()
+    }
+
+    // Only peers we connected to are known to accept incoming connections.
+    fn connected_peers(&self) -> HashMap<Peer, PexFlags> {
+        self.brokers
+            .iter()
+            .filter(|b| b.is_connected())
+            .filter_map(|b| {
+                let flags = if b.is_outbound() {
+                    PexFlags::REACHABLE
+                } else {
+                    PexFlags::default()
+                };
+                b.peer().map(|peer| (peer, flags))
+            })
+            .collect()
+    }
+}
+
+// Tries uTP first when a socket is available, falling back to TCP.
+async fn connect(
+    peer: Peer,
+    utp: Option<&UtpSocket>,
+    info_hash: Bytes20,
+    peer_id: Bytes20,
+    encryption: EncryptionPolicy,
+) -> Result<PeerStream> {
+    if let Some(socket) = utp {
+        let connect = peer.connect_utp(socket, info_hash, peer_id, encryption);
+        match tokio::time::timeout(UTP_CONNECT_TIMEOUT, connect).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(err)) => debug!("uTP connection to {peer} failed, trying TCP: {err}"),
+            Err(_) => debug!("uTP connection to {peer} timed out, trying TCP"),
+        }
+    }
+
+    let connect = peer.connect_with(info_hash, peer_id, encryption);
+    tokio::time::timeout(CONNECT_TIMEOUT, connect)
+        .await
+        .map_err(|_| err!("Timed out connecting"))?
+}
+
+/// Runs the extension handshake when the peer supports it, so that ut_pex can
+/// be used unless the torrent is private, and waits until the peer unchokes us.
+async fn prepare(stream: &mut PeerStream, num_pieces: usize, private: bool) -> Result<()> {
+    stream.set_num_pieces(num_pieces);
+
+    if private && !stream.is_extended() {
+        let mut extensions = ExtensionRegistry::supported();
+        extensions.register(UT_PEX, 0);
+        stream.set_extensions(extensions);
+    }
+
+    if stream.supports_extension_protocol() && !stream.is_extended() {
+        let handshake =
+            tokio::time::timeout(EXTENSION_HANDSHAKE_TIMEOUT, stream.extension_handshake());
+
+        if let Err(err) = handshake
+            .await
+            .map_err(|_| err!("Timed out"))
+            .and_then(|r| r)
+        {
+            debug!("Extension handshake failed: {err}");
+        }
+    }
+
+    stream.ready().await
+}
@@ -0,0 +1,113 @@
+//! Helpers for tests that download a torrent from a local web seed.
+
+use crate::{
+    meta::{FileInfo, Info},
+    util::Bytes20,
+};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Serves `files` by path over HTTP with range support, counting requests
+/// and failing the first `fail_first` of them.
+pub(crate) async fn serve(
+    files: HashMap<String, Vec<u8>>,
+    fail_first: usize,
+) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let count = Arc::new(AtomicUsize::new(0));
+    let files = Arc::new(files);
+
+    let requests = Arc::clone(&count);
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let files = Arc::clone(&files);
+            let n = requests.fetch_add(1, Ordering::Relaxed);
+
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 4096];
+                let mut len = 0;
+                while !buf[..len].ends_with(b"\r\n\r\n") {
+                    let read = socket.read(&mut buf[len..]).await.unwrap();
+                    if read == 0 {
+                        return;
+                    }
+                    len += read;
+                }
+
+                let request = String::from_utf8_lossy(&buf[..len]).to_string();
+                let path = request.split(' ').nth(1).unwrap().to_string();
+                let range = request.lines().find_map(|l| {
+                    l.to_lowercase()
+                        .strip_prefix("range: bytes=")
+                        .map(String::from)
+                });
+
+                let resp = match files.get(&path) {
+                    Some(_) if n < fail_first => {
+                        b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n".to_vec()
+                    }
+                    Some(data) => {
+                        let (start, end) = range
+                            .and_then(|r| {
+                                let (s, e) = r.split_once('-')?;
+                                Some((s.parse::<usize>().ok()?, e.parse::<usize>().ok()?))
+                            })
+                            .unwrap();
+                        let body = &data[start..=end];
+                        let mut resp = format!(
+                            "HTTP/1.1 206 Partial Content\r\ncontent-length: {}\r\n\r\n",
+                            body.len()
+                        )
+                        .into_bytes();
+                        resp.extend(body);
+                        resp
+                    }
+                    None => b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n".to_vec(),
+                };
+
+                let _ = socket.write_all(&resp).await;
+            });
+        }
+    });
+
+    (url, count)
+}
+
+pub(crate) fn multi_file_info(files: &[(&str, &[u8])], piece_length: u32) -> Info {
+    let data = files
+        .iter()
+        .flat_map(|(_, d)| d.iter().copied())
+        .collect::<Vec<u8>>();
+    let pieces = data
+        .chunks(piece_length as usize)
+        .map(Bytes20::sha1_hash)
+        .collect::<Vec<_>>();
+
+    Info {
+        attr: None,
+        files: Some(
+            files
+                .iter()
+                .map(|(path, d)| FileInfo {
+                    length: d.len() as u64,
+                    path: path.split('/').map(String::from).collect(),
+                    ..FileInfo::default()
+                })
+                .collect(),
+        ),
+        length: None,
+        meta_version: None,
+        file_tree: None,
+        name: "dir".into(),
+        piece_length,
+        pieces: pieces.into(),
+        private: None,
+        source: None,
+    }
+}
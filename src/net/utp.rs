@@ -0,0 +1,345 @@
+use crate::{BitTorrentError, Result};
+
+use super::dht::random_id;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::{Mutex as AsyncMutex, mpsc, oneshot};
+use tokio::task::AbortHandle;
+use tracing::debug;
+
+mod congestion;
+mod connection;
+mod packet;
+mod stream;
+
+pub use congestion::{Ledbat, RttEstimator, TARGET_DELAY};
+pub use connection::MAX_PAYLOAD;
+pub use packet::{Packet, PacketType};
+pub use stream::UtpStream;
+
+use connection::{Connection, ConnectionKey, Connections, Driver};
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const INCOMING_QUEUE: usize = 256;
+const ACCEPT_QUEUE: usize = 32;
+const READ_QUEUE: usize = 64;
+const WRITE_QUEUE: usize = 16;
+
+struct Inner {
+    udp: Arc<UdpSocket>,
+    connections: Connections,
+    accepts: AsyncMutex<mpsc::Receiver<UtpStream>>,
+    receiver: AbortHandle,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+/// A UDP socket carrying uTP connections (BEP 29), both the ones we open and
+/// the ones peers open to us.
+#[derive(Clone)]
+pub struct UtpSocket {
+    inner: Arc<Inner>,
+}
+
+// Registers the connection under `key` and wires it to a new stream.
+fn open(
+    udp: &Arc<UdpSocket>,
+    connections: &Connections,
+    key: ConnectionKey,
+    conn: Connection,
+) -> Result<(UtpStream, Driver)> {
+    let (incoming_tx, incoming) = mpsc::channel(INCOMING_QUEUE);
+    let (reads_tx, reads) = mpsc::channel(READ_QUEUE);
+    let (writes, writes_rx) = mpsc::channel(WRITE_QUEUE);
+
+    connections
+        .lock()
+        .map_err(|_| BitTorrentError::Other("uTP connection table poisoned".into()))?
+        .insert(key, incoming_tx);
+
+    let stream = UtpStream::new(udp.local_addr()?, key.0, reads, writes);
+    let driver = Driver {
+        conn,
+        incoming,
+        writes: writes_rx,
+        reads: Some(reads_tx),
+        connected: None,
+        connections: Arc::clone(connections),
+        key,
+    };
+
+    Ok((stream, driver))
+}
+
+fn random_u16() -> u16 {
+    let id = random_id();
+    u16::from_be_bytes([id[0], id[1]])
+}
+
+impl UtpSocket {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let udp = Arc::new(UdpSocket::bind(addr).await?);
+        let connections = Connections::default();
+        let (accepts_tx, accepts) = mpsc::channel(ACCEPT_QUEUE);
+
+        let receiver = tokio::spawn(receive(
+            Arc::clone(&udp),
+            Arc::clone(&connections),
+            accepts_tx,
+        ));
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                udp,
+                connections,
+                accepts: AsyncMutex::new(accepts),
+                receiver: receiver.abort_handle(),
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.inner.udp.local_addr()?)
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> Result<UtpStream> {
+        let recv_id = {
+            let connections = self
+                .inner
+                .connections
+                .lock()
+                .map_err(|_| BitTorrentError::Other("uTP connection table poisoned".into()))?;
+
+            loop {
+                let id = random_u16();
+                if !connections.contains_key(&(addr, id)) {
+                    break id;
+                }
+            }
+        };
+
+        let mut conn = Connection::initiator(Arc::clone(&self.inner.udp), addr, recv_id);
+        conn.send_syn(recv_id).await;
+
+        let (stream, mut driver) = open(
+            &self.inner.udp,
+            &self.inner.connections,
+            (addr, recv_id),
+            conn,
+        )?;
+
+        let (connected_tx, connected) = oneshot::channel();
+        driver.connected = Some(connected_tx);
+        tokio::spawn(driver.run());
+
+        connected
+            .await
+            .map_err(|_| BitTorrentError::ChannelClosed)??;
+        Ok(stream)
+    }
+
+    /// Waits for a peer to open a connection to us.
+    pub async fn accept(&self) -> Result<UtpStream> {
+        self.inner
+            .accepts
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(BitTorrentError::ChannelClosed)
+    }
+}
+
+async fn receive(udp: Arc<UdpSocket>, connections: Connections, accepts: mpsc::Sender<UtpStream>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (len, from) = match udp.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("uTP receive failed: {e}");
+                continue;
+            }
+        };
+
+        let packet = match Packet::decode(&buf[..len]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("Invalid uTP packet from {from}: {e}");
+                continue;
+            }
+        };
+
+        // A SYN carries the id the initiator receives on; we receive on the
+        // next one.
+        let id = match packet.packet_type {
+            PacketType::Syn => packet.connection_id.wrapping_add(1),
+            _ => packet.connection_id,
+        };
+
+        let existing = connections
+            .lock()
+            .ok()
+            .and_then(|c| c.get(&(from, id)).cloned());
+
+        if let Some(tx) = existing {
+            // Like the network would, drop packets the connection can't keep
+            // up with.
+            let _ = tx.try_send(packet);
+            continue;
+        }
+
+        if packet.packet_type != PacketType::Syn {
+            debug!("uTP packet from {from} for unknown connection {id}");
+            continue;
+        }
+
+        let conn = Connection::acceptor(Arc::clone(&udp), from, &packet, random_u16());
+        let (stream, driver) = match open(&udp, &connections, (from, id), conn) {
+            Ok(opened) => opened,
+            Err(e) => {
+                debug!("Failed to accept uTP connection from {from}: {e}");
+                continue;
+            }
+        };
+
+        if accepts.try_send(stream).is_err() {
+            debug!("Refusing uTP connection from {from}: accept queue full");
+            if let Ok(mut connections) = connections.lock() {
+                connections.remove(&(from, id));
+            }
+            continue;
+        }
+
+        tokio::spawn(driver.run());
+    }
+}
+
+impl std::fmt::Debug for UtpSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UtpSocket")
+            .field("local_addr", &self.inner.udp.local_addr().ok())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn pair(
+        server: &UtpSocket,
+        client: &UtpSocket,
+        addr: SocketAddr,
+    ) -> (UtpStream, UtpStream) {
+        let (accepted, connected) = tokio::join!(server.accept(), client.connect(addr));
+        (accepted.unwrap(), connected.unwrap())
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn test_transfer_both_ways() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let (mut accepted, mut connected) = pair(&server, &client, addr).await;
+        assert_eq!(connected.peer_addr(), addr);
+
+        let sent = data(1 << 20);
+        let expected = sent.clone();
+
+        let writer = tokio::spawn(async move {
+            connected.write_all(&sent).await.unwrap();
+            connected.shutdown().await.unwrap();
+
+            let mut reply = Vec::new();
+            connected.read_to_end(&mut reply).await.unwrap();
+            reply
+        });
+
+        let mut received = Vec::new();
+        accepted.read_to_end(&mut received).await.unwrap();
+        assert!(received == expected);
+
+        accepted.write_all(b"thanks").await.unwrap();
+        drop(accepted);
+
+        let reply = tokio::time::timeout(Duration::from_secs(10), writer)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply, b"thanks");
+    }
+
+    // Forwards datagrams between a client and `server`, dropping every
+    // `drop_every`th one in each direction.
+    async fn lossy_proxy(server: SocketAddr, drop_every: usize) -> SocketAddr {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = socket.local_addr().unwrap();
+        let count = AtomicUsize::new(0);
+
+        tokio::spawn(async move {
+            let mut client = None;
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let to = if from == server {
+                    match client {
+                        Some(client) => client,
+                        None => continue,
+                    }
+                } else {
+                    client = Some(from);
+                    server
+                };
+
+                if count.fetch_add(1, Ordering::Relaxed) % drop_every == drop_every - 1 {
+                    continue;
+                }
+                let _ = socket.send_to(&buf[..len], to).await;
+            }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_transfer_with_packet_loss() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let proxy = lossy_proxy(server.local_addr().unwrap(), 7).await;
+
+        let (mut accepted, mut connected) = pair(&server, &client, proxy).await;
+
+        let sent = data(256 * 1024);
+        let expected = sent.clone();
+
+        tokio::spawn(async move {
+            connected.write_all(&sent).await.unwrap();
+            connected.shutdown().await.unwrap();
+            let _ = connected.read_to_end(&mut Vec::new()).await;
+        });
+
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(30), accepted.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(received == expected);
+    }
+}
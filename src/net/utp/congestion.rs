@@ -0,0 +1,178 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// LEDBAT target queuing delay in microseconds.
+pub const TARGET_DELAY: u32 = 100_000;
+
+const MAX_CWND_INCREASE_PER_RTT: f64 = 3000.0;
+
+// Base delay is the minimum one-way delay seen over the last two minutes,
+// kept as one minimum per minute.
+const BASE_DELAY_SLOT: Duration = Duration::from_secs(60);
+const BASE_DELAY_SLOTS: usize = 2;
+
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// LEDBAT congestion control: grows the window while the queuing delay stays
+/// under [`TARGET_DELAY`] and backs off as soon as it rises above it.
+#[derive(Debug, Clone)]
+pub struct Ledbat {
+    max_window: usize,
+    min_window: usize,
+    base_delays: VecDeque<(Instant, u32)>,
+}
+
+impl Ledbat {
+    pub fn new(initial_window: usize, min_window: usize) -> Self {
+        Self {
+            max_window: initial_window.max(min_window),
+            min_window,
+            base_delays: VecDeque::new(),
+        }
+    }
+
+    pub fn window(&self) -> usize {
+        self.max_window
+    }
+
+    fn base_delay(&self) -> Option<u32> {
+        self.base_delays.iter().map(|(_, d)| *d).min()
+    }
+
+    fn record_delay(&mut self, delay: u32, now: Instant) {
+        match self.base_delays.back_mut() {
+            Some((start, min)) if now.duration_since(*start) < BASE_DELAY_SLOT => {
+                *min = (*min).min(delay);
+            }
+            _ => {
+                self.base_delays.push_back((now, delay));
+                if self.base_delays.len() > BASE_DELAY_SLOTS {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+    }
+
+    /// Updates the window for `bytes_acked` newly acknowledged bytes, given the
+    /// one-way `delay` the peer measured for our packets.
+    pub fn on_ack(&mut self, bytes_acked: usize, delay: u32, now: Instant) {
+        if bytes_acked == 0 {
+            return;
+        }
+
+        self.record_delay(delay, now);
+        let our_delay = delay.saturating_sub(self.base_delay().unwrap_or(delay));
+
+        let off_target = (TARGET_DELAY as f64 - our_delay as f64) / TARGET_DELAY as f64;
+        let off_target = off_target.clamp(-1.0, 1.0);
+        let window_factor = bytes_acked.min(self.max_window) as f64 / self.max_window as f64;
+        let gain = MAX_CWND_INCREASE_PER_RTT * off_target * window_factor;
+
+        let window = (self.max_window as f64 + gain).max(self.min_window as f64);
+        self.max_window = window as usize;
+    }
+
+    /// A packet was lost but later packets got through.
+    pub fn on_loss(&mut self) {
+        self.max_window = (self.max_window / 2).max(self.min_window);
+    }
+
+    /// Nothing was acknowledged before the retransmission timeout.
+    pub fn on_timeout(&mut self) {
+        self.max_window = self.min_window;
+    }
+}
+
+/// Round-trip time estimate and retransmission timeout, as in TCP.
+#[derive(Debug, Clone)]
+pub struct RttEstimator {
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    timeout: Duration,
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self {
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            timeout: INITIAL_TIMEOUT,
+        }
+    }
+}
+
+impl RttEstimator {
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(rtt);
+                self.rtt_var = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rtt_var = self.rtt_var * 3 / 4 + delta / 4;
+                self.rtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+
+        let srtt = self.rtt.unwrap_or(rtt);
+        self.timeout = (srtt + self.rtt_var * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    /// Doubles the timeout after it expired.
+    pub fn backoff(&mut self) {
+        self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_follows_delay() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new(10_000, 1000);
+
+        ledbat.on_ack(10_000, 20_000, now);
+        for _ in 0..10 {
+            ledbat.on_ack(10_000, 30_000, now);
+        }
+        let grown = ledbat.window();
+        assert!(grown > 10_000);
+
+        for _ in 0..10 {
+            ledbat.on_ack(grown, 20_000 + 2 * TARGET_DELAY, now);
+        }
+        assert!(ledbat.window() < grown);
+
+        ledbat.on_loss();
+        assert!(ledbat.window() >= 1000);
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window(), 1000);
+    }
+
+    #[test]
+    fn test_retransmission_timeout() {
+        let mut rtt = RttEstimator::default();
+        assert_eq!(rtt.timeout(), INITIAL_TIMEOUT);
+
+        rtt.sample(Duration::from_millis(200));
+        assert_eq!(rtt.timeout(), Duration::from_millis(600));
+
+        rtt.backoff();
+        assert_eq!(rtt.timeout(), Duration::from_millis(1200));
+
+        for _ in 0..20 {
+            rtt.sample(Duration::from_millis(1));
+        }
+        assert_eq!(rtt.timeout(), MIN_TIMEOUT);
+    }
+}
@@ -0,0 +1,580 @@
+use crate::{BitTorrentError, Result};
+
+use super::congestion::{Ledbat, RttEstimator};
+use super::packet::{Packet, PacketType};
+
+use bytes::{Bytes, BytesMut};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::debug;
+
+/// Largest payload carried by a single packet, small enough to fit the
+/// minimum IPv6 MTU.
+pub const MAX_PAYLOAD: usize = 1200;
+
+const INITIAL_WINDOW: usize = 16 * MAX_PAYLOAD;
+const RECV_BUFFER: usize = 1 << 20;
+const SEND_BUFFER: usize = 256 * 1024;
+
+// Out of order packets further ahead than this are dropped, and the
+// selective ACK covers at most this many bytes worth of packets.
+const REORDER_LIMIT: u16 = 1024;
+const MAX_SACK_BYTES: usize = 32;
+
+const DUPLICATE_ACKS: u32 = 3;
+const MAX_TIMEOUTS: u32 = 8;
+const MAX_SYN_TIMEOUTS: u32 = 3;
+
+pub(super) type ConnectionKey = (SocketAddr, u16);
+pub(super) type Connections = Arc<Mutex<HashMap<ConnectionKey, mpsc::Sender<Packet>>>>;
+
+// Microseconds on a clock shared by all connections; only differences of
+// these timestamps are meaningful, so wrapping is fine.
+fn now_micros() -> u32 {
+    static START: OnceLock<std::time::Instant> = OnceLock::new();
+    START
+        .get_or_init(std::time::Instant::now)
+        .elapsed()
+        .as_micros() as u32
+}
+
+// Whether `a` comes before or is `b` in wrapping sequence number order.
+fn seq_le(a: u16, b: u16) -> bool {
+    b.wrapping_sub(a) < 0x8000
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+}
+
+#[derive(Debug)]
+struct Sent {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    sacked: bool,
+    fast_resent: bool,
+}
+
+/// The state of one uTP connection: sequence numbers, packets in flight and
+/// out of order packets received.
+#[derive(Debug)]
+pub(super) struct Connection {
+    udp: Arc<UdpSocket>,
+    remote: SocketAddr,
+    send_id: u16,
+    state: State,
+    seq_nr: u16,
+    ack_nr: u16,
+    in_flight: VecDeque<Sent>,
+    send_buffer: BytesMut,
+    write_closed: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    reorder: HashMap<u16, Packet>,
+    reorder_bytes: usize,
+    eof_received: bool,
+    pending_reads: VecDeque<Bytes>,
+    pending_bytes: usize,
+    peer_wnd: usize,
+    ledbat: Ledbat,
+    rtt: RttEstimator,
+    reply_micro: u32,
+    last_ack: u16,
+    dup_acks: u32,
+    timeouts: u32,
+    deadline: Option<Instant>,
+    need_ack: bool,
+}
+
+impl Connection {
+    fn new(udp: Arc<UdpSocket>, remote: SocketAddr, send_id: u16, state: State) -> Self {
+        Self {
+            udp,
+            remote,
+            send_id,
+            state,
+            seq_nr: 1,
+            ack_nr: 0,
+            in_flight: VecDeque::new(),
+            send_buffer: BytesMut::new(),
+            write_closed: false,
+            fin_sent: false,
+            fin_acked: false,
+            reorder: HashMap::new(),
+            reorder_bytes: 0,
+            eof_received: false,
+            pending_reads: VecDeque::new(),
+            pending_bytes: 0,
+            peer_wnd: RECV_BUFFER,
+            ledbat: Ledbat::new(INITIAL_WINDOW, MAX_PAYLOAD),
+            rtt: RttEstimator::default(),
+            reply_micro: 0,
+            last_ack: 0,
+            dup_acks: 0,
+            timeouts: 0,
+            deadline: None,
+            need_ack: false,
+        }
+    }
+
+    /// An outgoing connection. Packets are received on `recv_id` and sent
+    /// with `recv_id + 1`.
+    pub(super) fn initiator(udp: Arc<UdpSocket>, remote: SocketAddr, recv_id: u16) -> Self {
+        Self::new(udp, remote, recv_id.wrapping_add(1), State::SynSent)
+    }
+
+    /// An incoming connection answering `syn`, starting at sequence number
+    /// `seq_nr`.
+    pub(super) fn acceptor(
+        udp: Arc<UdpSocket>,
+        remote: SocketAddr,
+        syn: &Packet,
+        seq_nr: u16,
+    ) -> Self {
+        let mut conn = Self::new(udp, remote, syn.connection_id, State::Connected);
+        conn.seq_nr = seq_nr;
+        conn.ack_nr = syn.seq_nr;
+        conn.peer_wnd = syn.wnd_size as usize;
+        conn.reply_micro = now_micros().wrapping_sub(syn.timestamp);
+        conn.need_ack = true;
+        conn
+    }
+
+    fn recv_window(&self) -> u32 {
+        RECV_BUFFER.saturating_sub(self.pending_bytes + self.reorder_bytes) as u32
+    }
+
+    fn has_unacked(&self) -> bool {
+        self.in_flight.iter().any(|s| !s.sacked)
+    }
+
+    fn in_flight_bytes(&self) -> usize {
+        self.in_flight
+            .iter()
+            .filter(|s| !s.sacked)
+            .map(|s| s.packet.payload.len())
+            .sum()
+    }
+
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        let offsets = self
+            .reorder
+            .keys()
+            .map(|seq| seq.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize)
+            .filter(|offset| *offset < MAX_SACK_BYTES * 8)
+            .collect::<Vec<usize>>();
+
+        let max = offsets.iter().max()?;
+        let mut mask = vec![0u8; (max / 32 + 1) * 4];
+        for offset in offsets {
+            mask[offset / 8] |= 1 << (offset % 8);
+        }
+        Some(mask)
+    }
+
+    async fn transmit(&self, mut packet: Packet) {
+        packet.timestamp = now_micros();
+        packet.timestamp_diff = self.reply_micro;
+        packet.wnd_size = self.recv_window();
+        if packet.packet_type != PacketType::Syn {
+            packet.ack_nr = self.ack_nr;
+        }
+
+        if let Err(e) = self.udp.send_to(&packet.encode(), self.remote).await {
+            debug!("Failed to send uTP packet to {}: {e}", self.remote);
+        }
+    }
+
+    async fn send_packet(&mut self, packet: Packet) {
+        let now = Instant::now();
+
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit(packet.clone()).await;
+        self.need_ack = false;
+
+        self.in_flight.push_back(Sent {
+            packet,
+            sent_at: now,
+            transmissions: 1,
+            sacked: false,
+            fast_resent: false,
+        });
+
+        if self.deadline.is_none() {
+            self.deadline = Some(now + self.rtt.timeout());
+        }
+    }
+
+    async fn resend(&mut self, index: usize, now: Instant) {
+        let sent = &mut self.in_flight[index];
+        sent.transmissions += 1;
+        sent.sent_at = now;
+
+        let packet = sent.packet.clone();
+        debug!("Resending uTP packet {} to {}", packet.seq_nr, self.remote);
+        self.transmit(packet).await;
+    }
+
+    /// Opens the connection; the SYN is acked like any other packet.
+    pub(super) async fn send_syn(&mut self, recv_id: u16) {
+        let packet = Packet::new(PacketType::Syn, recv_id, self.seq_nr, 0);
+        self.send_packet(packet).await;
+    }
+
+    async fn send_ack(&mut self) {
+        let packet = Packet {
+            selective_ack: self.selective_ack(),
+            ..Packet::new(PacketType::State, self.send_id, self.seq_nr, self.ack_nr)
+        };
+        self.transmit(packet).await;
+        self.need_ack = false;
+    }
+
+    async fn send_reset(&self) {
+        let packet = Packet::new(PacketType::Reset, self.send_id, self.seq_nr, self.ack_nr);
+        self.transmit(packet).await;
+    }
+
+    /// Sends buffered data as far as the congestion and receive windows
+    /// allow, then the FIN once the write side is closed.
+    async fn fill_window(&mut self) {
+        if self.state != State::Connected || self.fin_sent {
+            return;
+        }
+
+        loop {
+            if self.send_buffer.is_empty() {
+                if self.write_closed {
+                    let packet = Packet::new(PacketType::Fin, self.send_id, self.seq_nr, 0);
+                    self.send_packet(packet).await;
+                    self.fin_sent = true;
+                }
+                return;
+            }
+
+            let len = self.send_buffer.len().min(MAX_PAYLOAD);
+            let window = self.ledbat.window().min(self.peer_wnd);
+
+            // A single packet is always allowed so a zero window gets probed.
+            if self.has_unacked() && self.in_flight_bytes() + len > window {
+                return;
+            }
+
+            let packet = Packet {
+                payload: self.send_buffer.split_to(len).freeze(),
+                ..Packet::new(PacketType::Data, self.send_id, self.seq_nr, 0)
+            };
+            self.send_packet(packet).await;
+        }
+    }
+
+    async fn on_packet(&mut self, packet: Packet) -> Result<()> {
+        self.reply_micro = now_micros().wrapping_sub(packet.timestamp);
+
+        match packet.packet_type {
+            PacketType::Reset => return Err(BitTorrentError::ConnectionClosed),
+            // The initiator did not get our answer and resent its SYN.
+            PacketType::Syn => {
+                self.need_ack = true;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        if self.state == State::SynSent {
+            if packet.packet_type != PacketType::State {
+                return Ok(());
+            }
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            self.state = State::Connected;
+        }
+
+        self.peer_wnd = packet.wnd_size as usize;
+        self.on_ack(&packet).await;
+
+        if matches!(packet.packet_type, PacketType::Data | PacketType::Fin) {
+            self.on_data(packet);
+            self.need_ack = true;
+        }
+
+        Ok(())
+    }
+
+    async fn on_ack(&mut self, packet: &Packet) {
+        let now = Instant::now();
+        let ack = packet.ack_nr;
+
+        let mut bytes_acked = 0;
+        let mut progressed = false;
+
+        while let Some(sent) = self.in_flight.front() {
+            if !seq_le(sent.packet.seq_nr, ack) {
+                break;
+            }
+
+            let Some(sent) = self.in_flight.pop_front() else {
+                break;
+            };
+
+            if !sent.sacked {
+                bytes_acked += sent.packet.payload.len();
+                if sent.transmissions == 1 {
+                    self.rtt.sample(now.duration_since(sent.sent_at));
+                }
+            }
+
+            if sent.packet.packet_type == PacketType::Fin {
+                self.fin_acked = true;
+            }
+            progressed = true;
+        }
+
+        for sent in self.in_flight.iter_mut() {
+            if !sent.sacked && packet.sacks(sent.packet.seq_nr) {
+                sent.sacked = true;
+                bytes_acked += sent.packet.payload.len();
+                progressed = true;
+            }
+        }
+
+        if progressed {
+            self.timeouts = 0;
+            self.deadline = self.has_unacked().then(|| now + self.rtt.timeout());
+        }
+
+        if bytes_acked > 0 && packet.timestamp_diff != 0 {
+            self.ledbat.on_ack(bytes_acked, packet.timestamp_diff, now);
+        }
+
+        if ack != self.last_ack {
+            self.dup_acks = 0;
+        } else if packet.packet_type == PacketType::State && !progressed && self.has_unacked() {
+            self.dup_acks += 1;
+        }
+        self.last_ack = ack;
+
+        // A packet is lost when three duplicate acks arrive for the one before
+        // it, or when at least three packets after it were selectively acked.
+        let mut lost = Vec::new();
+        let mut sacked_after = 0;
+
+        for (i, sent) in self.in_flight.iter().enumerate().rev() {
+            if sent.sacked {
+                sacked_after += 1;
+            } else if sacked_after >= DUPLICATE_ACKS && !sent.fast_resent {
+                lost.push(i);
+            }
+        }
+
+        if self.dup_acks >= DUPLICATE_ACKS
+            && let Some(first) = self.in_flight.front()
+            && !first.sacked
+            && !first.fast_resent
+            && !lost.contains(&0)
+        {
+            lost.push(0);
+        }
+
+        if lost.is_empty() {
+            return;
+        }
+
+        self.ledbat.on_loss();
+        for i in lost.into_iter().rev() {
+            self.in_flight[i].fast_resent = true;
+            self.resend(i, now).await;
+        }
+    }
+
+    fn on_data(&mut self, packet: Packet) {
+        let seq = packet.seq_nr;
+        if self.eof_received || seq_le(seq, self.ack_nr) {
+            return;
+        }
+
+        let ahead = seq.wrapping_sub(self.ack_nr);
+        if ahead > REORDER_LIMIT {
+            return;
+        }
+
+        if ahead > 1 {
+            if !self.reorder.contains_key(&seq) {
+                self.reorder_bytes += packet.payload.len();
+                self.reorder.insert(seq, packet);
+            }
+            return;
+        }
+
+        self.deliver(packet);
+
+        while !self.eof_received {
+            let Some(packet) = self.reorder.remove(&self.ack_nr.wrapping_add(1)) else {
+                break;
+            };
+            self.reorder_bytes -= packet.payload.len();
+            self.deliver(packet);
+        }
+    }
+
+    fn deliver(&mut self, packet: Packet) {
+        self.ack_nr = packet.seq_nr;
+
+        match packet.packet_type {
+            PacketType::Fin => {
+                self.eof_received = true;
+                self.reorder.clear();
+                self.reorder_bytes = 0;
+            }
+            _ if !packet.payload.is_empty() => {
+                self.pending_bytes += packet.payload.len();
+                self.pending_reads.push_back(packet.payload);
+            }
+            _ => {}
+        }
+    }
+
+    async fn on_timeout(&mut self) -> Result<()> {
+        let now = Instant::now();
+
+        self.timeouts += 1;
+        let max = match self.state {
+            State::SynSent => MAX_SYN_TIMEOUTS,
+            State::Connected => MAX_TIMEOUTS,
+        };
+        if self.timeouts > max {
+            return Err(io::Error::from(io::ErrorKind::TimedOut).into());
+        }
+
+        self.rtt.backoff();
+        self.ledbat.on_timeout();
+
+        for sent in self.in_flight.iter_mut() {
+            sent.fast_resent = false;
+        }
+
+        if let Some(i) = self.in_flight.iter().position(|s| !s.sacked) {
+            self.resend(i, now).await;
+        }
+
+        self.deadline = self.has_unacked().then(|| now + self.rtt.timeout());
+
+        Ok(())
+    }
+}
+
+/// Runs a connection: moves data between its [`super::UtpStream`] and the
+/// network until both sides are closed.
+pub(super) struct Driver {
+    pub(super) conn: Connection,
+    pub(super) incoming: mpsc::Receiver<Packet>,
+    pub(super) writes: mpsc::Receiver<Bytes>,
+    pub(super) reads: Option<mpsc::Sender<io::Result<Bytes>>>,
+    pub(super) connected: Option<oneshot::Sender<Result<()>>>,
+    pub(super) connections: Connections,
+    pub(super) key: ConnectionKey,
+}
+
+async fn reserve(
+    reads: &Option<mpsc::Sender<io::Result<Bytes>>>,
+) -> Option<mpsc::Permit<'_, io::Result<Bytes>>> {
+    reads.as_ref()?.reserve().await.ok()
+}
+
+impl Driver {
+    pub(super) async fn run(mut self) {
+        if let Err(e) = self.drive().await {
+            debug!("uTP connection to {} closed: {e}", self.conn.remote);
+
+            self.conn.send_reset().await;
+            if let Some(reads) = &self.reads {
+                let _ = reads.try_send(Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    e.to_string(),
+                )));
+            }
+            if let Some(connected) = self.connected.take() {
+                let _ = connected.send(Err(e));
+            }
+        }
+
+        if let Ok(mut connections) = self.connections.lock() {
+            connections.remove(&self.key);
+        }
+    }
+
+    async fn drive(&mut self) -> Result<()> {
+        loop {
+            if self.conn.state == State::Connected
+                && let Some(connected) = self.connected.take()
+            {
+                let _ = connected.send(Ok(()));
+            }
+
+            self.conn.fill_window().await;
+            if self.conn.need_ack {
+                self.conn.send_ack().await;
+            }
+
+            let reads_closed = self.reads.as_ref().is_none_or(|r| r.is_closed());
+            if reads_closed {
+                self.conn.pending_reads.clear();
+                self.conn.pending_bytes = 0;
+            }
+
+            if self.conn.eof_received && self.conn.pending_reads.is_empty() {
+                self.reads = None;
+            }
+
+            if self.conn.fin_acked && (reads_closed || self.reads.is_none()) {
+                return Ok(());
+            }
+
+            let accept_writes = self.conn.state == State::Connected
+                && !self.conn.write_closed
+                && self.conn.send_buffer.len() < SEND_BUFFER;
+            let deadline = self.conn.deadline;
+
+            tokio::select! {
+                packet = self.incoming.recv() => match packet {
+                    Some(packet) => self.conn.on_packet(packet).await?,
+                    None => return Err(BitTorrentError::ChannelClosed),
+                },
+                data = self.writes.recv(), if accept_writes => match data {
+                    Some(data) => self.conn.send_buffer.extend_from_slice(&data),
+                    None => self.conn.write_closed = true,
+                },
+                permit = reserve(&self.reads), if !self.conn.pending_reads.is_empty() => {
+                    if let (Some(permit), Some(data)) = (permit, self.conn.pending_reads.pop_front()) {
+                        self.conn.pending_bytes -= data.len();
+                        permit.send(Ok(data));
+                    }
+                },
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.conn.on_timeout().await?;
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_numbers_wrap() {
+        assert!(seq_le(1, 1));
+        assert!(seq_le(1, 2));
+        assert!(!seq_le(2, 1));
+        assert!(seq_le(u16::MAX, 0));
+        assert!(!seq_le(0, u16::MAX));
+    }
+}
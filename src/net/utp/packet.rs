@@ -0,0 +1,215 @@
+use crate::{BitTorrentError, Result};
+
+use bytes::Bytes;
+
+pub const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 20;
+
+const EXTENSION_NONE: u8 = 0;
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+
+macro_rules! violation {
+    ($($arg:tt)*) => {
+        BitTorrentError::ProtocolViolation(format!($($arg)*))
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = BitTorrentError;
+
+    fn try_from(v: u8) -> Result<Self> {
+        match v {
+            0 => Ok(Self::Data),
+            1 => Ok(Self::Fin),
+            2 => Ok(Self::State),
+            3 => Ok(Self::Reset),
+            4 => Ok(Self::Syn),
+            _ => Err(violation!("Unknown uTP packet type {v}")),
+        }
+    }
+}
+
+/// A uTP packet (BEP 29). The selective ACK bitmask, when present, acks
+/// `ack_nr + 2 + i` for every bit `i` set, least significant bit first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    pub timestamp: u32,
+    pub timestamp_diff: u32,
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Bytes,
+}
+
+impl Packet {
+    pub fn new(packet_type: PacketType, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            packet_type,
+            connection_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            wnd_size: 0,
+            seq_nr,
+            ack_nr,
+            selective_ack: None,
+            payload: Bytes::new(),
+        }
+    }
+
+    /// Whether the selective ACK acks packet `seq_nr`.
+    pub fn sacks(&self, seq_nr: u16) -> bool {
+        let Some(mask) = &self.selective_ack else {
+            return false;
+        };
+
+        let offset = seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+        offset < mask.len() * 8 && mask[offset / 8] & (1 << (offset % 8)) != 0
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+
+        let extension = match self.selective_ack {
+            Some(_) => EXTENSION_SELECTIVE_ACK,
+            None => EXTENSION_NONE,
+        };
+
+        bytes.push(((self.packet_type as u8) << 4) | VERSION);
+        bytes.push(extension);
+        bytes.extend(self.connection_id.to_be_bytes());
+        bytes.extend(self.timestamp.to_be_bytes());
+        bytes.extend(self.timestamp_diff.to_be_bytes());
+        bytes.extend(self.wnd_size.to_be_bytes());
+        bytes.extend(self.seq_nr.to_be_bytes());
+        bytes.extend(self.ack_nr.to_be_bytes());
+
+        if let Some(mask) = &self.selective_ack {
+            bytes.push(EXTENSION_NONE);
+            bytes.push(mask.len() as u8);
+            bytes.extend(mask);
+        }
+
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(violation!("uTP packet too short: {} bytes", bytes.len()));
+        }
+
+        let version = bytes[0] & 0x0f;
+        if version != VERSION {
+            return Err(violation!("Unsupported uTP version {version}"));
+        }
+
+        let packet_type = PacketType::try_from(bytes[0] >> 4)?;
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at =
+            |i: usize| u32::from_be_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+
+        let mut packet = Self {
+            packet_type,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack: None,
+            payload: Bytes::new(),
+        };
+
+        // Walk the extension chain, keeping the selective ACK and skipping the
+        // extensions we do not know.
+        let mut extension = bytes[1];
+        let mut pos = HEADER_SIZE;
+
+        while extension != EXTENSION_NONE {
+            if pos + 2 > bytes.len() {
+                return Err(violation!("Truncated uTP extension header"));
+            }
+
+            let next = bytes[pos];
+            let len = bytes[pos + 1] as usize;
+            pos += 2;
+
+            if pos + len > bytes.len() {
+                return Err(violation!("Truncated uTP extension"));
+            }
+
+            if extension == EXTENSION_SELECTIVE_ACK {
+                packet.selective_ack = Some(bytes[pos..pos + len].to_vec());
+            }
+
+            extension = next;
+            pos += len;
+        }
+
+        packet.payload = Bytes::copy_from_slice(&bytes[pos..]);
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_round_trip() {
+        let packet = Packet {
+            timestamp: 123456,
+            timestamp_diff: 789,
+            wnd_size: 1 << 20,
+            selective_ack: Some(vec![0b0000_0101, 0, 0, 0]),
+            payload: Bytes::from_static(b"hello"),
+            ..Packet::new(PacketType::Data, 0x1234, 10, 7)
+        };
+
+        let bytes = packet.encode();
+        assert_eq!(bytes[0], 0x01);
+        assert_eq!(bytes[1], EXTENSION_SELECTIVE_ACK);
+        assert_eq!(Packet::decode(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_selective_ack_bits() {
+        let packet = Packet {
+            selective_ack: Some(vec![0b0000_0101, 0, 0, 0b1000_0000]),
+            ..Packet::new(PacketType::State, 1, 0, 100)
+        };
+
+        assert!(!packet.sacks(101));
+        assert!(packet.sacks(102));
+        assert!(!packet.sacks(103));
+        assert!(packet.sacks(104));
+        assert!(packet.sacks(102 + 31));
+        assert!(!packet.sacks(102 + 32));
+    }
+
+    #[test]
+    fn test_skip_unknown_extensions() {
+        let mut bytes = Packet::new(PacketType::State, 1, 2, 3).encode();
+        bytes[1] = 2;
+        bytes.extend([EXTENSION_NONE, 2, 0xaa, 0xbb]);
+
+        let packet = Packet::decode(&bytes).unwrap();
+        assert_eq!(packet.selective_ack, None);
+        assert!(packet.payload.is_empty());
+
+        assert!(Packet::decode(&bytes[..HEADER_SIZE + 3]).is_err());
+        assert!(Packet::decode(&[0x02; HEADER_SIZE]).is_err());
+    }
+}
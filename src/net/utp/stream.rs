@@ -0,0 +1,103 @@
+use bytes::Bytes;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio_util::sync::PollSender;
+
+// Writes are handed to the connection in chunks of at most this size.
+const MAX_WRITE: usize = 16 * 1024;
+
+/// A uTP connection, read and written like a `TcpStream`. Dropping it sends a
+/// FIN once everything written has been delivered.
+#[derive(Debug)]
+pub struct UtpStream {
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    reads: mpsc::Receiver<io::Result<Bytes>>,
+    read_buf: Bytes,
+    writes: PollSender<Bytes>,
+}
+
+impl UtpStream {
+    pub(super) fn new(
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        reads: mpsc::Receiver<io::Result<Bytes>>,
+        writes: mpsc::Sender<Bytes>,
+    ) -> Self {
+        Self {
+            local_addr,
+            peer_addr,
+            reads,
+            read_buf: Bytes::new(),
+            writes: PollSender::new(writes),
+        }
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+fn broken_pipe() -> io::Error {
+    io::Error::from(io::ErrorKind::BrokenPipe)
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.read_buf.is_empty() {
+            match ready!(this.reads.poll_recv(cx)) {
+                Some(Ok(data)) => this.read_buf = data,
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let n = buf.remaining().min(this.read_buf.len());
+        buf.put_slice(&this.read_buf.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let this = self.get_mut();
+        ready!(this.writes.poll_reserve(cx)).map_err(|_| broken_pipe())?;
+
+        let n = buf.len().min(MAX_WRITE);
+        this.writes
+            .send_item(Bytes::copy_from_slice(&buf[..n]))
+            .map_err(|_| broken_pipe())?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().writes.close();
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,506 @@
+use crate::{
+    BitTorrentError, Result,
+    meta::{FileSpan, Info, PieceLayers},
+};
+
+use super::{Piece, broker::Event};
+
+use reqwest::{StatusCode, Url, header};
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Sender, UnboundedSender};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// Consecutive failures after which a web seed is given up.
+pub const MAX_FAILURES: u32 = 5;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+macro_rules! err {
+    ($($arg:tt)*) => {
+        BitTorrentError::Other(format!($($arg)*))
+    };
+}
+
+/// An HTTP server holding the torrent's files (BEP 19), from which pieces are
+/// fetched with range requests.
+#[derive(Debug, Clone)]
+pub struct WebSeed {
+    url: Url,
+    info: Arc<Info>,
+    piece_layers: Arc<PieceLayers>,
+    client: reqwest::Client,
+}
+
+impl WebSeed {
+    pub fn new(url: &str, info: Arc<Info>) -> Result<Self> {
+        let url = Url::parse(url).map_err(|e| err!("Invalid web seed URL {url}: {e}"))?;
+        if url.cannot_be_a_base() {
+            return Err(err!("Invalid web seed URL {url}"));
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        Ok(Self {
+            url,
+            info,
+            piece_layers: Arc::default(),
+            client,
+        })
+    }
+
+    /// Sets the piece layers that pieces of v2-only torrents are checked
+    /// against.
+    pub fn with_piece_layers(mut self, piece_layers: Arc<PieceLayers>) -> Self {
+        self.piece_layers = piece_layers;
+        self
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// The URL of `file`. The seed URL names the file itself for single-file
+    /// torrents, unless it ends with a slash; otherwise the file's path is
+    /// appended to it.
+    pub fn file_url(&self, file: &FileSpan) -> Url {
+        if !self.info.is_multi_file() && !self.url.path().ends_with('/') {
+            return self.url.clone();
+        }
+
+        let mut url = self.url.clone();
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().extend(&file.path);
+        }
+        url
+    }
+
+    /// Downloads piece `index` and checks it against its hash.
+    pub async fn fetch_piece(&self, index: usize) -> Result<Piece> {
+        if index >= self.info.num_pieces() {
+            return Err(err!("Piece {index} out of range"));
+        }
+
+        let files = self.info.files();
+        let mut data = Vec::with_capacity(self.info.piece_length(index));
+
+        for range in self.info.piece_file_ranges(index) {
+            // Padding files (BEP 47) are zeros and not on the server.
+            if !files[range.file].has_data() {
+                data.resize(data.len() + range.length as usize, 0);
+                continue;
+            }
+
+            let url = self.file_url(&files[range.file]);
+            data.extend(self.fetch_range(url, range.offset, range.length).await?);
+        }
+
+        if !self.info.verify_piece(index, &data, &self.piece_layers) {
+            return Err(err!("Piece {index} from {} failed hash check", self.url));
+        }
+
+        Ok(Piece {
+            index,
+            data,
+            peer: None,
+        })
+    }
+
+    async fn fetch_range(&self, url: Url, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let range = format!("bytes={}-{}", offset, offset + length - 1);
+        let mut resp = self
+            .client
+            .get(url.clone())
+            .header(header::RANGE, range)
+            .send()
+            .await?;
+
+        let status = resp.status();
+        let start = match status {
+            StatusCode::PARTIAL_CONTENT => 0,
+            // The server ignored the range and sends the whole file.
+            StatusCode::OK => offset,
+            _ => return Err(err!("Web seed {url} answered {status}")),
+        };
+        let end = start + length;
+
+        // Only the requested window is kept, and a whole file is read no
+        // further than its end.
+        let mut data = Vec::with_capacity(length as usize);
+        let mut received = 0u64;
+        while let Some(chunk) = resp.chunk().await? {
+            let chunk_start = received;
+            received += chunk.len() as u64;
+            if status == StatusCode::PARTIAL_CONTENT && received > length {
+                return Err(err!("Web seed {url} sent more than {length} bytes"));
+            }
+
+            let from = start.clamp(chunk_start, received) - chunk_start;
+            let to = end.clamp(chunk_start, received) - chunk_start;
+            data.extend_from_slice(&chunk[from as usize..to as usize]);
+
+            if received >= end {
+                break;
+            }
+        }
+
+        if data.len() as u64 != length {
+            return Err(err!(
+                "Web seed {url} sent {} bytes, expected {length}",
+                data.len()
+            ));
+        }
+
+        Ok(data)
+    }
+}
+
+/// Exponential backoff for a failing web seed.
+#[derive(Debug, Clone, Default)]
+pub struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Records a failure and returns how long to wait before the next try.
+    pub fn failed(&mut self) -> Duration {
+        let delay = INITIAL_BACKOFF.saturating_mul(1 << self.failures.min(16));
+        self.failures += 1;
+        delay.min(MAX_BACKOFF)
+    }
+
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    retry_at: Mutex<Option<Instant>>,
+    stopped: AtomicBool,
+}
+
+/// Downloads the pieces it is given from a web seed, one after the other.
+/// When a fetch fails the piece and everything still queued are handed back
+/// so peers can take them, and the seed backs off; it is given up after
+/// [`MAX_FAILURES`] failures in a row.
+#[derive(Debug)]
+pub struct WebSeedWorker {
+    url: Url,
+    requests: UnboundedSender<(usize, usize)>,
+    state: Arc<State>,
+}
+
+impl WebSeedWorker {
+    pub fn spawn(seed: WebSeed, events: Sender<Event>, returned: Sender<(usize, usize)>) -> Self {
+        let (requests, rx) = mpsc::unbounded_channel();
+        let state = Arc::new(State::default());
+
+        let worker = Self {
+            url: seed.url().clone(),
+            requests,
+            state: Arc::clone(&state),
+        };
+
+        tokio::spawn(async move {
+            let mut rx = rx;
+            let mut backoff = Backoff::default();
+
+            while let Some((index, length)) = rx.recv().await {
+                let retry_at = *state.retry_at.lock().unwrap_or_else(|e| e.into_inner());
+                if let Some(at) = retry_at {
+                    tokio::time::sleep_until(at).await;
+                }
+
+                match seed.fetch_piece(index).await {
+                    Ok(piece) => {
+                        backoff.succeeded();
+                        if events.send(Event::Piece(piece)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        let delay = backoff.failed();
+                        warn!("Web seed {} failed: {err}", seed.url());
+
+                        let _ = returned.send((index, length)).await;
+                        while let Ok(request) = rx.try_recv() {
+                            let _ = returned.send(request).await;
+                        }
+
+                        if backoff.failures() >= MAX_FAILURES {
+                            debug!("Giving up web seed {}", seed.url());
+                            break;
+                        }
+
+                        *state.retry_at.lock().unwrap_or_else(|e| e.into_inner()) =
+                            Some(Instant::now() + delay);
+                    }
+                }
+            }
+
+            state.stopped.store(true, Ordering::Relaxed);
+            rx.close();
+            while let Some(request) = rx.recv().await {
+                let _ = returned.send(request).await;
+            }
+        });
+
+        worker
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Whether the seed was given up.
+    pub fn is_stopped(&self) -> bool {
+        self.state.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Whether the seed is working and not backing off.
+    pub fn is_ready(&self) -> bool {
+        let retry_at = *self
+            .state
+            .retry_at
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        !self.is_stopped() && retry_at.is_none_or(|at| at <= Instant::now())
+    }
+
+    pub fn request_piece(&self, index: usize, length: usize) -> Result<()> {
+        self.requests
+            .send((index, length))
+            .map_err(|_| BitTorrentError::ChannelClosed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::net::testing::{multi_file_info, serve};
+    use crate::util::Bytes20;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_file_urls() {
+        let info = Arc::new(multi_file_info(&[("a b/c.txt", b"abc")], 4));
+        let seed = WebSeed::new("http://example.com/files", Arc::clone(&info)).unwrap();
+        assert_eq!(
+            seed.file_url(&info.files()[0]).as_str(),
+            "http://example.com/files/dir/a%20b/c.txt"
+        );
+
+        let single = Arc::new(Info {
+            files: None,
+            length: Some(3),
+            ..(*info).clone()
+        });
+        let seed = WebSeed::new("http://example.com/file.iso", Arc::clone(&single)).unwrap();
+        assert_eq!(
+            seed.file_url(&single.files()[0]).as_str(),
+            "http://example.com/file.iso"
+        );
+
+        let seed = WebSeed::new("http://example.com/files/", Arc::clone(&single)).unwrap();
+        assert_eq!(
+            seed.file_url(&single.files()[0]).as_str(),
+            "http://example.com/files/dir"
+        );
+    }
+
+    #[test]
+    fn test_backoff_grows_and_resets() {
+        let mut backoff = Backoff::default();
+        assert_eq!(backoff.failed(), INITIAL_BACKOFF);
+        assert_eq!(backoff.failed(), INITIAL_BACKOFF * 2);
+        for _ in 0..20 {
+            backoff.failed();
+        }
+        assert_eq!(backoff.failed(), MAX_BACKOFF);
+
+        backoff.succeeded();
+        assert_eq!(backoff.failures(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_piece_across_files() {
+        let a = (0..10u8).collect::<Vec<u8>>();
+        let b = (10..25u8).collect::<Vec<u8>>();
+        let info = Arc::new(multi_file_info(&[("a", &a), ("sub/b", &b)], 8));
+
+        let files = HashMap::from([
+            ("/dir/a".to_string(), a.clone()),
+            ("/dir/sub/b".to_string(), b.clone()),
+        ]);
+        let (url, _) = serve(files, 0).await;
+        let seed = WebSeed::new(&url, Arc::clone(&info)).unwrap();
+
+        let piece = seed.fetch_piece(1).await.unwrap();
+        assert_eq!(piece.data, (8..16u8).collect::<Vec<u8>>());
+
+        let last = seed.fetch_piece(3).await.unwrap();
+        assert_eq!(last.data, vec![24]);
+
+        let corrupt = HashMap::from([
+            ("/dir/a".to_string(), vec![0; 10]),
+            ("/dir/sub/b".to_string(), b),
+        ]);
+        let (url, _) = serve(corrupt, 0).await;
+        let seed = WebSeed::new(&url, info).unwrap();
+        assert!(seed.fetch_piece(0).await.is_err());
+        assert!(seed.fetch_piece(2).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_piece_zero_fills_padding() {
+        let a = (1..11u8).collect::<Vec<u8>>();
+        let b = (11..19u8).collect::<Vec<u8>>();
+        let mut info = multi_file_info(&[("a", &a), ("pad", &[0; 6]), ("b", &b)], 8);
+        info.files.as_mut().unwrap()[1].attr = Some("p".into());
+        let info = Arc::new(info);
+
+        // The server only has the real files.
+        let files = HashMap::from([
+            ("/dir/a".to_string(), a.clone()),
+            ("/dir/b".to_string(), b.clone()),
+        ]);
+        let (url, requests) = serve(files, 0).await;
+        let seed = WebSeed::new(&url, info).unwrap();
+
+        let piece = seed.fetch_piece(1).await.unwrap();
+        assert_eq!(piece.data, [9, 10, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        let piece = seed.fetch_piece(2).await.unwrap();
+        assert_eq!(piece.data, b);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_range_from_whole_file() {
+        let data = (0..16u8).collect::<Vec<u8>>();
+        let info = Arc::new(multi_file_info(&[("a", &data)], 8));
+
+        // Answers 200 for a huge file and never sends past its first bytes.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let body = data.clone();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = socket.read(&mut buf).await.unwrap();
+            let mut resp = b"HTTP/1.1 200 OK\r\ncontent-length: 1073741824\r\n\r\n".to_vec();
+            resp.extend(&body);
+            socket.write_all(&resp).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let seed = WebSeed::new(&url, info).unwrap();
+        let piece = tokio::time::timeout(Duration::from_secs(5), seed.fetch_piece(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(piece.data, (8..16u8).collect::<Vec<u8>>());
+    }
+
+    #[tokio::test]
+    async fn test_worker_hands_back_failed_pieces() {
+        let data = (0..16u8).collect::<Vec<u8>>();
+        let info = Arc::new(multi_file_info(&[("a", &data)], 8));
+        let (url, _) = serve(HashMap::from([("/dir/a".to_string(), data)]), 1).await;
+
+        let (events_tx, _events) = mpsc::channel(10);
+        let (returned_tx, mut returned) = mpsc::channel(10);
+        let worker =
+            WebSeedWorker::spawn(WebSeed::new(&url, info).unwrap(), events_tx, returned_tx);
+
+        worker.request_piece(0, 8).unwrap();
+        let back = tokio::time::timeout(Duration::from_secs(5), returned.recv())
+            .await
+            .unwrap();
+        assert_eq!(back, Some((0, 8)));
+        assert!(!worker.is_ready());
+        assert!(!worker.is_stopped());
+    }
+
+    #[tokio::test]
+    async fn test_swarm_downloads_from_web_seed_alone() {
+        let data = (0..40u8).collect::<Vec<u8>>();
+        let info = Arc::new(multi_file_info(&[("a", &data)], 16));
+        let (url, _) = serve(HashMap::from([("/dir/a".to_string(), data.clone())]), 0).await;
+
+        let mut swarm = crate::net::Swarm::new(
+            Bytes20::new([0; 20]),
+            Bytes20::new([1; 20]),
+            info.num_pieces(),
+            Default::default(),
+        );
+        swarm.add_web_seed(WebSeed::new(&url, Arc::clone(&info)).unwrap());
+
+        for index in 0..info.num_pieces() {
+            swarm
+                .request_piece(index, info.piece_length(index))
+                .await
+                .unwrap();
+        }
+
+        let mut pieces = Vec::new();
+        while pieces.len() < info.num_pieces() {
+            pieces.push(swarm.next_piece().await.unwrap().unwrap());
+        }
+        pieces.sort_by_key(|p| p.index);
+
+        let downloaded = pieces.into_iter().flat_map(|p| p.data).collect::<Vec<u8>>();
+        assert_eq!(downloaded, data);
+    }
+
+    #[tokio::test]
+    async fn test_swarm_asks_another_web_seed_for_late_pieces() {
+        let data = (0..16u8).collect::<Vec<u8>>();
+        let info = Arc::new(multi_file_info(&[("a", &data)], 16));
+        let (url, _) = serve(HashMap::from([("/dir/a".to_string(), data.clone())]), 0).await;
+
+        // A web seed that takes requests and never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalled = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (_socket, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let mut swarm = crate::net::Swarm::new(
+            Bytes20::new([0; 20]),
+            Bytes20::new([1; 20]),
+            info.num_pieces(),
+            Default::default(),
+        );
+        swarm.add_web_seed(WebSeed::new(&stalled, Arc::clone(&info)).unwrap());
+        swarm.add_web_seed(WebSeed::new(&url, Arc::clone(&info)).unwrap());
+
+        swarm.request_piece(0, 16).await.unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_millis(100);
+        swarm.set_deadline(0, 16, deadline);
+
+        let piece = tokio::time::timeout(Duration::from_secs(5), swarm.next_piece())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(piece.data, data);
+    }
+}
@@ -0,0 +1,434 @@
+//! A small HTTP/1.1 server for the files of a streaming download, so that
+//! media players and the like can read them while they download. Every file
+//! is served at its path in the torrent, with support for byte ranges.
+
+use crate::{
+    BitTorrentError, Result,
+    stream::{FileStream, StreamingDownload},
+};
+
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tracing::{debug, warn};
+
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+macro_rules! err {
+    ($($arg:tt)*) => {
+        BitTorrentError::Other(format!($($arg)*))
+    };
+}
+
+pub struct FileServer {
+    listener: TcpListener,
+    download: StreamingDownload,
+}
+
+impl FileServer {
+    pub async fn bind<A: ToSocketAddrs>(addr: A, download: StreamingDownload) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self { listener, download })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serves connections until an error accepting one. Every connection
+    /// carries one request.
+    pub async fn run(self) -> Result<()> {
+        loop {
+            let (socket, addr) = self.listener.accept().await?;
+            let download = self.download.clone();
+
+            tokio::spawn(async move {
+                if let Err(err) = handle(socket, &download).await {
+                    debug!("Request from {addr} failed: {err}");
+                }
+            });
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+}
+
+async fn handle(mut socket: TcpStream, download: &StreamingDownload) -> Result<()> {
+    let request = read_request(&mut socket).await?;
+    debug!("{} {}", request.method, request.path);
+
+    let head = match request.method.as_str() {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return respond(&mut socket, "405 Method Not Allowed", &[], b"").await,
+    };
+
+    if request.path == "/" {
+        let index = index_page(download);
+        let headers = [("Content-Type", "text/html; charset=utf-8".to_string())];
+        let body = if head { &b""[..] } else { index.as_bytes() };
+        return respond(&mut socket, "200 OK", &headers, body).await;
+    }
+
+    let Some(file) = find_file(download, &request.path) else {
+        return respond(&mut socket, "404 Not Found", &[], b"").await;
+    };
+    let mut stream = download.file(file)?;
+    let length = stream.len();
+
+    let (status, start, end) = match request.range.as_deref().map(|r| parse_range(r, length)) {
+        None | Some(Range::Ignored) => ("200 OK", 0, length),
+        Some(Range::Bytes(start, end)) => ("206 Partial Content", start, end),
+        Some(Range::Unsatisfiable) => {
+            let headers = [("Content-Range", format!("bytes */{length}"))];
+            return respond(&mut socket, "416 Range Not Satisfiable", &headers, b"").await;
+        }
+    };
+
+    let mut headers = vec![
+        ("Content-Type", content_type(&request.path).to_string()),
+        ("Content-Length", (end - start).to_string()),
+        ("Accept-Ranges", "bytes".to_string()),
+    ];
+    if status.starts_with("206") {
+        headers.push((
+            "Content-Range",
+            format!("bytes {start}-{}/{length}", end - 1),
+        ));
+    }
+    write_head(&mut socket, status, &headers).await?;
+
+    if !head {
+        send_body(&mut socket, &mut stream, start, end).await?;
+    }
+    socket.shutdown().await?;
+    Ok(())
+}
+
+async fn read_request(socket: &mut TcpStream) -> Result<Request> {
+    let mut buf = vec![0u8; MAX_REQUEST_SIZE];
+    let mut len = 0;
+
+    while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+        if len == buf.len() {
+            return Err(err!("Request too large"));
+        }
+        let read = socket.read(&mut buf[len..]).await?;
+        if read == 0 {
+            return Err(BitTorrentError::ConnectionClosed);
+        }
+        len += read;
+    }
+
+    let text = String::from_utf8_lossy(&buf[..len]);
+    let mut lines = text.split("\r\n");
+
+    let mut parts = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(err!("Malformed request line"));
+    };
+    let path = target.split(['?', '#']).next().unwrap_or_default();
+
+    let range = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("range")
+            .then(|| value.trim().to_string())
+    });
+
+    Ok(Request {
+        method: method.to_string(),
+        path: percent_decode(path).ok_or_else(|| err!("Malformed path {path}"))?,
+        range,
+    })
+}
+
+async fn write_head(
+    socket: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, String)],
+) -> Result<()> {
+    let mut head = format!("HTTP/1.1 {status}\r\nConnection: close\r\n");
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+
+    socket.write_all(head.as_bytes()).await?;
+    Ok(())
+}
+
+async fn respond(
+    socket: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> Result<()> {
+    let mut headers = headers.to_vec();
+    if !headers.iter().any(|(name, _)| *name == "Content-Length") {
+        headers.push(("Content-Length", body.len().to_string()));
+    }
+
+    write_head(socket, status, &headers).await?;
+    socket.write_all(body).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+async fn send_body(
+    socket: &mut TcpStream,
+    stream: &mut FileStream,
+    start: u64,
+    end: u64,
+) -> Result<()> {
+    stream.seek(SeekFrom::Start(start)).await?;
+    let copied = tokio::io::copy(&mut stream.take(end - start), socket).await?;
+
+    if copied != end - start {
+        warn!("Sent {copied} of {} bytes", end - start);
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum Range {
+    /// The half-open range of bytes to send.
+    Bytes(u64, u64),
+    Unsatisfiable,
+    /// Ranges we do not support, like multiple ones, get the whole file.
+    Ignored,
+}
+
+fn parse_range(range: &str, length: u64) -> Range {
+    let Some(spec) = range.strip_prefix("bytes=") else {
+        return Range::Ignored;
+    };
+    if spec.contains(',') {
+        return Range::Ignored;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Range::Ignored;
+    };
+
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.saturating_add(1).min(length)),
+        (Ok(start), Err(_)) if end.is_empty() => (start, length),
+        (Err(_), Ok(suffix)) if start.is_empty() => (length.saturating_sub(suffix), length),
+        _ => return Range::Ignored,
+    };
+
+    match range {
+        (start, end) if start < end => Range::Bytes(start, end),
+        _ => Range::Unsatisfiable,
+    }
+}
+
+// Files are found by their path in the torrent, name of the torrent first.
+fn find_file(download: &StreamingDownload, path: &str) -> Option<usize> {
+    let path = path.trim_start_matches('/');
+
+    download
+        .storage()
+        .files()
+        .iter()
+        .position(|file| file.has_data() && file.path.join("/") == path)
+}
+
+fn index_page(download: &StreamingDownload) -> String {
+    let mut page = String::from("<!DOCTYPE html>\n<ul>\n");
+
+    for file in download.storage().files().iter().filter(|f| f.has_data()) {
+        let path = file.path.join("/");
+        let href = file
+            .path
+            .iter()
+            .map(|p| url::form_urlencoded::byte_serialize(p.as_bytes()).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("/")
+            .replace('+', "%20");
+        page.push_str(&format!(
+            "<li><a href=\"/{href}\">{}</a> ({} bytes)</li>\n",
+            escape_html(&path),
+            file.length
+        ));
+    }
+
+    page.push_str("</ul>\n");
+    page
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map(|(_, e)| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("mp4" | "m4v") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        Some("webm") => "video/webm",
+        Some("avi") => "video/x-msvideo",
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("ogg") => "audio/ogg",
+        Some("wav") => "audio/wav",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("txt" | "log" | "srt") => "text/plain; charset=utf-8",
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("json") => "application/json",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        meta::PieceLayers,
+        net::{
+            PiecePicker, Swarm,
+            testing::{multi_file_info, serve},
+            webseed::WebSeed,
+        },
+        storage::Storage,
+        util::Bytes20,
+    };
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    async fn server(files: &[(&str, &[u8])]) -> (String, tempfile::TempDir) {
+        let info = Arc::new(multi_file_info(files, 16));
+        let served = files
+            .iter()
+            .map(|(path, data)| (format!("/dir/{}", path.replace(' ', "%20")), data.to_vec()))
+            .collect::<HashMap<_, _>>();
+        let (url, _) = serve(served, 0).await;
+
+        let mut swarm = Swarm::new(
+            Bytes20::new([0; 20]),
+            Bytes20::new([1; 20]),
+            info.num_pieces(),
+            Default::default(),
+        );
+        swarm.add_web_seed(WebSeed::new(&url, Arc::clone(&info)).unwrap());
+
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(Arc::clone(&info), dir.path().join("dir")).unwrap();
+        let picker = PiecePicker::new(info.num_pieces());
+        let download = StreamingDownload::start(swarm, storage, PieceLayers::default(), picker);
+
+        let server = FileServer::bind("127.0.0.1:0", download).await.unwrap();
+        let url = format!("http://{}", server.local_addr().unwrap());
+        tokio::spawn(server.run());
+
+        (url, dir)
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-9", 100), Range::Bytes(0, 10));
+        assert_eq!(parse_range("bytes=90-", 100), Range::Bytes(90, 100));
+        assert_eq!(parse_range("bytes=-10", 100), Range::Bytes(90, 100));
+        assert_eq!(parse_range("bytes=50-500", 100), Range::Bytes(50, 100));
+        assert_eq!(parse_range("bytes=100-", 100), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), Range::Ignored);
+        assert_eq!(parse_range("items=0-1", 100), Range::Ignored);
+        assert_eq!(parse_range("bytes=5-1", 100), Range::Ignored);
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("/a%20b/c").as_deref(), Some("/a b/c"));
+        assert_eq!(percent_decode("/%zz"), None);
+        assert_eq!(percent_decode("/%4"), None);
+    }
+
+    #[tokio::test]
+    async fn test_serves_files_with_ranges() {
+        let movie = (0..100u8).collect::<Vec<u8>>();
+        let notes = b"some notes".to_vec();
+        let (url, _dir) = server(&[("movie.mp4", &movie), ("sub/my notes.txt", &notes)]).await;
+        let client = reqwest::Client::new();
+
+        let index = client.get(&url).send().await.unwrap().text().await.unwrap();
+        assert!(index.contains("href=\"/dir/sub/my%20notes.txt\""));
+
+        let resp = client
+            .get(format!("{url}/dir/movie.mp4"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()["content-type"], "video/mp4");
+        assert_eq!(resp.headers()["accept-ranges"], "bytes");
+        assert_eq!(resp.bytes().await.unwrap(), movie);
+
+        let resp = client
+            .get(format!("{url}/dir/movie.mp4"))
+            .header("Range", "bytes=40-59")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 206);
+        assert_eq!(resp.headers()["content-range"], "bytes 40-59/100");
+        assert_eq!(resp.bytes().await.unwrap(), movie[40..60]);
+
+        let resp = client
+            .get(format!("{url}/dir/sub/my%20notes.txt"))
+            .header("Range", "bytes=-5")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.bytes().await.unwrap(), b"notes"[..]);
+
+        let resp = client
+            .get(format!("{url}/dir/movie.mp4"))
+            .header("Range", "bytes=100-")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 416);
+        assert_eq!(resp.headers()["content-range"], "bytes */100");
+
+        let resp = client
+            .head(format!("{url}/dir/movie.mp4"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()["content-length"], "100");
+
+        let resp = client.get(format!("{url}/nope")).send().await.unwrap();
+        assert_eq!(resp.status(), 404);
+    }
+}
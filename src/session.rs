@@ -0,0 +1,1521 @@
+//! Running many torrents side by side.
+//!
+//! A [`Session`] owns a listen socket shared by its torrents, and a uTP
+//! socket on the same port, routing every incoming connection to the torrent
+//! it asks for. Swarms try the uTP socket first when connecting to peers. A
+//! limit caps the connections of all torrents together. Peers come from the
+//! trackers, the peers named by magnet links, web seeds and, for public
+//! torrents, local service discovery announcing the listen port; the session
+//! does not use the DHT. Pieces are not uploaded, so a torrent is done once
+//! it is complete.
+//!
+//! With a state file configured, the session keeps its torrents there, so
+//! they come back after a restart, paused ones still paused.
+
+use crate::{
+    BitTorrentError, Result,
+    bencode::{Deserializer, Serializer},
+    meta::{Info, MagnetLink, Meta, PieceLayers, TrackerEvent, TrackerRequest},
+    net::{
+        ConnectionLimit, DEFAULT_MAX_CONNECTIONS, HASH_FAILURE_PENALTY, Peer, PeerStream,
+        PiecePicker, Swarm, broker, fetch_metadata,
+        lsd::{Lsd, LsdConfig},
+        mse::EncryptionPolicy,
+        utp::UtpSocket,
+        webseed::WebSeed,
+    },
+    storage::Storage,
+    util::Bytes20,
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, lookup_host};
+use tokio::sync::{mpsc::Sender, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{debug, warn};
+
+/// Connections of all torrents of a session together, unless configured
+/// otherwise.
+pub const DEFAULT_MAX_SESSION_CONNECTIONS: usize = 200;
+
+const PEER_ID_PREFIX: &[u8; 8] = b"-CT0001-";
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// What trackers are told is left while a magnet link's size is unknown.
+const UNKNOWN_LEFT: u64 = 999;
+
+macro_rules! err {
+    ($($arg:tt)*) => {
+        BitTorrentError::Other(format!($($arg)*))
+    };
+}
+
+/// A peer id with our client prefix and a random tail, printable so it can
+/// go to trackers as is.
+pub fn generate_peer_id() -> Bytes20 {
+    const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    let mut id = [0u8; 20];
+    id[..8].copy_from_slice(PEER_ID_PREFIX);
+    getrandom::getrandom(&mut id[8..]).expect("Failed to generate a peer id");
+    for b in &mut id[8..] {
+        *b = ALPHABET[*b as usize % ALPHABET.len()];
+    }
+
+    Bytes20::new(id)
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub listen_addr: SocketAddr,
+    /// Where the torrents' files go, each under its own name.
+    pub download_dir: PathBuf,
+    pub max_connections: usize,
+    pub max_connections_per_torrent: usize,
+    pub peer_id: Bytes20,
+    pub encryption: EncryptionPolicy,
+    /// Where the torrents and whether they are paused are kept across
+    /// restarts.
+    pub state_file: Option<PathBuf>,
+    /// Local service discovery, announcing the session's port rather than the
+    /// one configured. `None` turns it off.
+    pub local_discovery: Option<LsdConfig>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], 6881)),
+            download_dir: PathBuf::from("."),
+            max_connections: DEFAULT_MAX_SESSION_CONNECTIONS,
+            max_connections_per_torrent: DEFAULT_MAX_CONNECTIONS,
+            peer_id: generate_peer_id(),
+            encryption: EncryptionPolicy::default(),
+            state_file: None,
+            local_discovery: Some(LsdConfig::default()),
+        }
+    }
+}
+
+/// A torrent to add to a session.
+#[derive(Debug, Clone)]
+pub enum TorrentSource {
+    Meta(Box<Meta>),
+    Magnet(MagnetLink),
+}
+
+impl From<Meta> for TorrentSource {
+    fn from(meta: Meta) -> Self {
+        Self::Meta(Box::new(meta))
+    }
+}
+
+impl From<MagnetLink> for TorrentSource {
+    fn from(link: MagnetLink) -> Self {
+        Self::Magnet(link)
+    }
+}
+
+impl TorrentSource {
+    pub fn info_hash(&self) -> Result<Bytes20> {
+        match self {
+            Self::Meta(meta) => meta.info.hash(),
+            Self::Magnet(link) => Ok(link.info_hash()),
+        }
+    }
+
+    fn name(&self) -> Option<String> {
+        match self {
+            Self::Meta(meta) => Some(meta.info.name.clone()),
+            Self::Magnet(link) => link.name().map(String::from),
+        }
+    }
+
+    fn trackers(&self) -> Vec<&str> {
+        match self {
+            Self::Meta(meta) => meta.trackers(),
+            Self::Magnet(link) => link.trackers().iter().map(String::as_str).collect(),
+        }
+    }
+
+    fn web_seeds(&self) -> &[String] {
+        match self {
+            Self::Meta(meta) => &meta.url_list,
+            Self::Magnet(link) => link.web_seeds(),
+        }
+    }
+
+    fn piece_layers(&self) -> PieceLayers {
+        match self {
+            Self::Meta(meta) => meta.piece_layers.clone(),
+            // Magnet links whose metadata needs piece layers are refused.
+            Self::Magnet(_) => PieceLayers::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Verifying the data already on disk.
+    Checking,
+    /// Fetching the info dictionary of a magnet link from peers.
+    DownloadingMetadata,
+    Downloading,
+    /// All pieces are on disk. Nothing is uploaded, so the torrent is done.
+    Complete,
+    Paused,
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentStatus {
+    pub state: TorrentState,
+    /// The torrent's name, unknown for magnet links without one until the
+    /// metadata arrives.
+    pub name: Option<String>,
+    pub num_pieces: usize,
+    pub pieces_done: usize,
+    /// Bytes still to download, known once the data on disk is checked.
+    pub bytes_left: Option<u64>,
+}
+
+/// A torrent of a session. Handles stay usable after the torrent is removed,
+/// showing its last status.
+#[derive(Debug, Clone)]
+pub struct TorrentHandle {
+    inner: Arc<TorrentInner>,
+}
+
+#[derive(Debug)]
+struct TorrentInner {
+    info_hash: Bytes20,
+    // Magnet links give way to the torrent once the metadata is in.
+    source: Mutex<TorrentSource>,
+    status: watch::Sender<TorrentStatus>,
+    // Where incoming connections go while the torrent has a swarm.
+    incoming: Mutex<Option<Sender<PeerStream>>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl TorrentHandle {
+    fn new(info_hash: Bytes20, source: TorrentSource) -> Self {
+        let status = TorrentStatus {
+            state: TorrentState::Checking,
+            name: source.name(),
+            num_pieces: 0,
+            pieces_done: 0,
+            bytes_left: None,
+        };
+
+        Self {
+            inner: Arc::new(TorrentInner {
+                info_hash,
+                source: Mutex::new(source),
+                status: watch::Sender::new(status),
+                incoming: Mutex::new(None),
+                task: Mutex::new(None),
+            }),
+        }
+    }
+
+    pub fn info_hash(&self) -> Bytes20 {
+        self.inner.info_hash
+    }
+
+    pub fn status(&self) -> TorrentStatus {
+        self.inner.status.borrow().clone()
+    }
+
+    pub fn state(&self) -> TorrentState {
+        self.inner.status.borrow().state.clone()
+    }
+
+    pub fn name(&self) -> Option<String> {
+        self.inner.status.borrow().name.clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<TorrentStatus> {
+        self.inner.status.subscribe()
+    }
+
+    /// Waits until the status satisfies `f` and returns it.
+    pub async fn wait_for<F>(&self, mut f: F) -> TorrentStatus
+    where
+        F: FnMut(&TorrentStatus) -> bool,
+    {
+        let mut status = self.subscribe();
+        // The sender lives in the handle, so the channel cannot close.
+        let status = status.wait_for(|s| f(s)).await.expect("status sender gone");
+        status.clone()
+    }
+
+    fn source(&self) -> TorrentSource {
+        lock(&self.inner.source).clone()
+    }
+
+    fn set_source(&self, source: TorrentSource) {
+        *lock(&self.inner.source) = source;
+    }
+
+    fn set_state(&self, state: TorrentState) {
+        self.inner.status.send_modify(|s| s.state = state);
+    }
+
+    fn incoming(&self) -> Option<Sender<PeerStream>> {
+        lock(&self.inner.incoming).clone()
+    }
+
+    fn set_incoming(&self, incoming: Option<Sender<PeerStream>>) {
+        *lock(&self.inner.incoming) = incoming;
+    }
+
+    fn abort(&self) -> Option<JoinHandle<()>> {
+        self.set_incoming(None);
+        let task = lock(&self.inner.task).take()?;
+        task.abort();
+        Some(task)
+    }
+
+    // Aborts the task and waits for it, so it no longer touches the status.
+    // Pieces are written as they arrive, so only the ones in flight are lost.
+    async fn stop(&self) {
+        if let Some(task) = self.abort() {
+            let _ = task.await;
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+type Torrents = Arc<Mutex<HashMap<Bytes20, TorrentHandle>>>;
+
+/// Downloads any number of torrents at once. Dropping the session
+/// stops all of them.
+pub struct Session {
+    config: SessionConfig,
+    local_addr: SocketAddr,
+    limit: ConnectionLimit,
+    torrents: Torrents,
+    utp: Option<UtpSocket>,
+    lsd: Option<Lsd>,
+    accept_task: JoinHandle<()>,
+    utp_task: Option<JoinHandle<()>>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+        if let Some(task) = &self.utp_task {
+            task.abort();
+        }
+        for torrent in lock(&self.torrents).values() {
+            torrent.abort();
+        }
+    }
+}
+
+impl Session {
+    /// Starts a session listening on the configured address, with the
+    /// torrents of its state file.
+    pub async fn new(config: SessionConfig) -> Result<Self> {
+        let listener = TcpListener::bind(config.listen_addr).await?;
+        let local_addr = listener.local_addr()?;
+        let torrents = Torrents::default();
+
+        let accept_task = tokio::spawn(accept_peers(
+            listener,
+            Arc::clone(&torrents),
+            config.peer_id,
+            config.encryption,
+        ));
+
+        // Without uTP the session still works over TCP alone.
+        let utp = match UtpSocket::bind(local_addr).await {
+            Ok(socket) => Some(socket),
+            Err(err) => {
+                warn!("uTP unavailable on {local_addr}: {err}");
+                None
+            }
+        };
+        let utp_task = utp.clone().map(|socket| {
+            tokio::spawn(accept_utp_peers(
+                socket,
+                Arc::clone(&torrents),
+                config.peer_id,
+                config.encryption,
+            ))
+        });
+
+        // Nor does it need local service discovery.
+        let lsd = match &config.local_discovery {
+            Some(lsd_config) => {
+                let lsd_config = LsdConfig {
+                    port: Some(local_addr.port()),
+                    ..lsd_config.clone()
+                };
+                match Lsd::bind(lsd_config).await {
+                    Ok(lsd) => Some(lsd),
+                    Err(err) => {
+                        warn!("Local service discovery unavailable: {err}");
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let session = Self {
+            limit: ConnectionLimit::new(config.max_connections),
+            config,
+            local_addr,
+            torrents,
+            utp,
+            lsd,
+            accept_task,
+            utp_task,
+        };
+        if let Some(path) = &session.config.state_file {
+            session.load_state(path)?;
+        }
+
+        Ok(session)
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Adds a torrent and starts it, checking its data on disk first.
+    pub fn add(&self, source: impl Into<TorrentSource>) -> Result<TorrentHandle> {
+        let handle = self.insert(source.into(), false)?;
+        self.save_state()?;
+        Ok(handle)
+    }
+
+    /// Pauses a torrent: disconnects its peers and tells its trackers it
+    /// stopped.
+    pub async fn pause(&self, info_hash: Bytes20) -> Result<()> {
+        let handle = self.get(info_hash)?;
+        if handle.state() == TorrentState::Paused {
+            return Ok(());
+        }
+
+        self.stop(&handle).await;
+        handle.set_state(TorrentState::Paused);
+        self.save_state()?;
+        self.announce_stopped(&handle).await;
+        Ok(())
+    }
+
+    /// Resumes a paused torrent, or retries one that failed, checking its
+    /// data again before reconnecting.
+    pub fn resume(&self, info_hash: Bytes20) -> Result<()> {
+        let handle = self.get(info_hash)?;
+        if !matches!(
+            handle.state(),
+            TorrentState::Paused | TorrentState::Error(_)
+        ) {
+            return Ok(());
+        }
+
+        self.start(&handle);
+        self.save_state()
+    }
+
+    /// Stops a torrent and drops it from the session, deleting its files as
+    /// well if `delete_data` is set.
+    pub async fn remove(&self, info_hash: Bytes20, delete_data: bool) -> Result<TorrentHandle> {
+        let handle = lock(&self.torrents)
+            .remove(&info_hash)
+            .ok_or_else(|| err!("No torrent {} in the session", info_hash.hex_encoded()))?;
+
+        let paused = handle.state() == TorrentState::Paused;
+        self.stop(&handle).await;
+        self.save_state()?;
+        if !paused {
+            self.announce_stopped(&handle).await;
+        }
+
+        // Without metadata nothing was written.
+        if let (true, TorrentSource::Meta(meta)) = (delete_data, handle.source()) {
+            storage(&self.config, Arc::new(meta.info))?.delete()?;
+        }
+
+        Ok(handle)
+    }
+
+    pub fn torrent(&self, info_hash: Bytes20) -> Option<TorrentHandle> {
+        lock(&self.torrents).get(&info_hash).cloned()
+    }
+
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        lock(&self.torrents).values().cloned().collect()
+    }
+
+    fn get(&self, info_hash: Bytes20) -> Result<TorrentHandle> {
+        self.torrent(info_hash)
+            .ok_or_else(|| err!("No torrent {} in the session", info_hash.hex_encoded()))
+    }
+
+    fn insert(&self, source: TorrentSource, paused: bool) -> Result<TorrentHandle> {
+        let info_hash = source.info_hash()?;
+
+        let mut torrents = lock(&self.torrents);
+        if torrents.contains_key(&info_hash) {
+            return Err(err!(
+                "Torrent {} is already in the session",
+                info_hash.hex_encoded()
+            ));
+        }
+
+        let handle = TorrentHandle::new(info_hash, source);
+        if paused {
+            handle.set_state(TorrentState::Paused);
+        } else {
+            self.start(&handle);
+        }
+
+        torrents.insert(info_hash, handle.clone());
+        Ok(handle)
+    }
+
+    fn start(&self, handle: &TorrentHandle) {
+        handle.set_state(TorrentState::Checking);
+        let ctx = Context {
+            source: handle.source(),
+            handle: handle.clone(),
+            config: self.config.clone(),
+            port: self.local_addr.port(),
+            limit: self.limit.clone(),
+            torrents: Arc::clone(&self.torrents),
+            utp: self.utp.clone(),
+            lsd: self.lsd.clone(),
+        };
+        *lock(&handle.inner.task) = Some(tokio::spawn(run_torrent(ctx)));
+    }
+
+    async fn stop(&self, handle: &TorrentHandle) {
+        handle.stop().await;
+        if let Some(lsd) = &self.lsd {
+            lsd.remove_torrent(&handle.info_hash()).await;
+        }
+    }
+
+    async fn announce_stopped(&self, handle: &TorrentHandle) {
+        let (source, info_hash) = (handle.source(), handle.info_hash());
+        let port = self.local_addr.port();
+        let left = handle.status().bytes_left.unwrap_or(UNKNOWN_LEFT);
+        announce(
+            &source,
+            info_hash,
+            &self.config,
+            port,
+            left,
+            TrackerEvent::Stopped,
+        )
+        .await;
+    }
+
+    fn save_state(&self) -> Result<()> {
+        match &self.config.state_file {
+            Some(path) => save_state(path, &self.torrents),
+            None => Ok(()),
+        }
+    }
+
+    // Adds the torrents of the state file, if there is one yet. Torrents that
+    // cannot be read back are skipped.
+    fn load_state(&self, path: &Path) -> Result<()> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let state = SavedSession::deserialize(&mut Deserializer::new(bytes.as_slice()))?;
+
+        for saved in state.torrents {
+            let paused = saved.paused != 0;
+            if let Err(err) = saved
+                .into_source()
+                .and_then(|source| self.insert(source, paused))
+            {
+                warn!("Skipping saved torrent: {err}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// The state file: every torrent, as a torrent once its metadata is known.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedSession {
+    #[serde(default)]
+    torrents: Vec<SavedTorrent>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedTorrent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    magnet: Option<String>,
+    #[serde(default)]
+    paused: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    torrent: Option<Meta>,
+}
+
+impl SavedTorrent {
+    fn new(handle: &TorrentHandle) -> Self {
+        let (magnet, torrent) = match handle.source() {
+            TorrentSource::Meta(meta) => (None, Some(*meta)),
+            TorrentSource::Magnet(link) => (Some(link.to_string()), None),
+        };
+
+        Self {
+            magnet,
+            paused: (handle.state() == TorrentState::Paused).into(),
+            torrent,
+        }
+    }
+
+    fn into_source(self) -> Result<TorrentSource> {
+        match (self.torrent, self.magnet) {
+            (Some(meta), _) => Ok(meta.into()),
+            (None, Some(uri)) => Ok(uri.parse::<MagnetLink>()?.into()),
+            (None, None) => Err(err!("Saved torrent has neither metadata nor a magnet link")),
+        }
+    }
+}
+
+// Writes the state to a temporary file first, so a crash leaves the old
+// state intact.
+fn save_state(path: &Path, torrents: &Torrents) -> Result<()> {
+    let state = SavedSession {
+        torrents: lock(torrents).values().map(SavedTorrent::new).collect(),
+    };
+    let mut bytes = Vec::new();
+    state.serialize(&mut Serializer::new(&mut bytes))?;
+
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+// Torrents go under their name in the download directory.
+fn storage(config: &SessionConfig, info: Arc<Info>) -> Result<Storage> {
+    let output = config.download_dir.join(&info.name);
+    Storage::new(info, output)
+}
+
+// Hands incoming connections to the torrents they ask for.
+async fn accept_peers(
+    listener: TcpListener,
+    torrents: Torrents,
+    peer_id: Bytes20,
+    encryption: EncryptionPolicy,
+) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!("Failed to accept a connection: {err}");
+                continue;
+            }
+        };
+
+        let torrents = Arc::clone(&torrents);
+        tokio::spawn(async move {
+            if let Err(err) = accept_peer(socket, addr, &torrents, peer_id, encryption).await {
+                debug!("Dropping incoming peer {addr}: {err}");
+            }
+        });
+    }
+}
+
+// The same for connections over uTP.
+async fn accept_utp_peers(
+    socket: UtpSocket,
+    torrents: Torrents,
+    peer_id: Bytes20,
+    encryption: EncryptionPolicy,
+) {
+    loop {
+        let stream = match socket.accept().await {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Failed to accept a uTP connection: {err}");
+                continue;
+            }
+        };
+
+        let addr = stream.peer_addr();
+        let torrents = Arc::clone(&torrents);
+        tokio::spawn(async move {
+            if let Err(err) = accept_peer(stream, addr, &torrents, peer_id, encryption).await {
+                debug!("Dropping incoming uTP peer {addr}: {err}");
+            }
+        });
+    }
+}
+
+async fn accept_peer<T>(
+    transport: T,
+    addr: SocketAddr,
+    torrents: &Torrents,
+    peer_id: Bytes20,
+    encryption: EncryptionPolicy,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Send + Unpin + std::fmt::Debug + 'static,
+{
+    let info_hashes = lock(torrents)
+        .values()
+        .filter(|t| t.incoming().is_some())
+        .map(TorrentHandle::info_hash)
+        .collect::<Vec<_>>();
+
+    let accept = PeerStream::accept(transport, Some(addr), &info_hashes, peer_id, encryption);
+    let (info_hash, stream) = tokio::time::timeout(HANDSHAKE_TIMEOUT, accept)
+        .await
+        .map_err(|_| err!("Handshake timed out"))??;
+
+    let incoming = lock(torrents)
+        .get(&info_hash)
+        .and_then(TorrentHandle::incoming);
+    incoming
+        .ok_or_else(|| err!("Torrent {} is not running", info_hash.hex_encoded()))?
+        .try_send(stream)
+        .map_err(|_| err!("Too many incoming peers for {}", info_hash.hex_encoded()))
+}
+
+// What a torrent's task needs from the session.
+struct Context {
+    source: TorrentSource,
+    handle: TorrentHandle,
+    config: SessionConfig,
+    port: u16,
+    limit: ConnectionLimit,
+    torrents: Torrents,
+    utp: Option<UtpSocket>,
+    lsd: Option<Lsd>,
+}
+
+async fn run_torrent(ctx: Context) {
+    if let Err(err) = download(&ctx).await {
+        warn!(
+            "Torrent {} failed: {err}",
+            ctx.handle.info_hash().hex_encoded()
+        );
+        ctx.handle.set_state(TorrentState::Error(err.to_string()));
+    }
+    ctx.handle.set_incoming(None);
+    if let Some(lsd) = &ctx.lsd {
+        lsd.remove_torrent(&ctx.handle.info_hash()).await;
+    }
+}
+
+async fn download(ctx: &Context) -> Result<()> {
+    let info_hash = ctx.handle.info_hash();
+
+    let (info, metadata, streams, peers) = match &ctx.source {
+        TorrentSource::Meta(meta) => (meta.info.clone(), None, Vec::new(), None),
+        TorrentSource::Magnet(link) => {
+            let mut peers = find_peers(ctx).await;
+            ctx.handle.set_state(TorrentState::DownloadingMetadata);
+            let mut streams = connect(ctx, &peers).await;
+            let metadata = fetch_metadata(&mut streams, info_hash).await?;
+            let info = Info::deserialize(&mut Deserializer::new(metadata.as_ref()))?;
+            info.check_without_piece_layers()?;
+
+            // Resuming or restarting need not fetch the metadata again.
+            let torrent = link.to_torrent(&metadata)?;
+            let meta = Meta::deserialize(&mut Deserializer::new(torrent.as_slice()))?;
+            ctx.handle.set_source(meta.into());
+            if let Some(path) = &ctx.config.state_file
+                && let Err(err) = save_state(path, &ctx.torrents)
+            {
+                warn!("Failed to save the session: {err}");
+            }
+
+            // The streams are handed to the swarm, not reconnected to.
+            let connected = streams
+                .iter()
+                .filter_map(PeerStream::peer_addr)
+                .collect::<Vec<_>>();
+            peers.retain(|peer| !connected.contains(&peer.addr()));
+            (info, Some(metadata), streams, Some(peers))
+        }
+    };
+
+    let info = Arc::new(info);
+    ctx.handle.inner.status.send_modify(|s| {
+        s.name = Some(info.name.clone());
+        s.num_pieces = info.num_pieces();
+    });
+
+    let storage = storage(&ctx.config, Arc::clone(&info))?;
+    let piece_layers = Arc::new(ctx.source.piece_layers());
+    let mut picker = check(ctx, &storage, &piece_layers).await?;
+
+    // Torrents with their metadata announce once they know what is left.
+    let peers = match peers {
+        Some(peers) => peers,
+        None => find_peers(ctx).await,
+    };
+
+    if picker.is_complete() {
+        storage.finish()?;
+        ctx.handle.set_state(TorrentState::Complete);
+        return Ok(());
+    }
+
+    let mut swarm = swarm(ctx, &info, Arc::clone(&piece_layers), metadata);
+    ctx.handle.set_incoming(Some(swarm.incoming_sender()));
+    if let Some(lsd) = &ctx.lsd
+        && !info.is_private()
+    {
+        lsd.add_torrent(info_hash, swarm.peer_sender()).await;
+    }
+    ctx.handle.set_state(TorrentState::Downloading);
+    for index in (0..info.num_pieces()).filter(|index| picker.is_done(*index)) {
+        swarm.have(index).await;
+    }
+    for stream in streams {
+        swarm.add_incoming(stream);
+    }
+    for peer in peers {
+        swarm.add_peer(peer).await;
+    }
+
+    let mut in_flight = 0;
+    while !picker.is_complete() {
+        for index in swarm.take_suggestions() {
+            picker.suggest(index);
+        }
+        while in_flight < picker.max_in_flight() {
+            let Some(index) = picker.pick() else {
+                break;
+            };
+            swarm.request_piece(index, info.piece_length(index)).await?;
+            in_flight += 1;
+        }
+
+        let piece = swarm
+            .next_piece()
+            .await?
+            .ok_or(BitTorrentError::ChannelClosed)?;
+        if picker.is_done(piece.index) {
+            continue;
+        }
+        in_flight -= 1;
+
+        // A bad piece is picked again, and its peer loses some trust.
+        if !info.verify_piece(piece.index, &piece.data, &piece_layers) {
+            warn!("Piece {} failed verification", piece.index);
+            picker.failed(piece.index);
+            if let Some(addr) = piece.peer {
+                swarm.scores().penalize(addr, HASH_FAILURE_PENALTY).await;
+            }
+            continue;
+        }
+
+        storage.write_piece(piece.index, &piece.data)?;
+        picker.done(piece.index);
+        swarm.have(piece.index).await;
+        ctx.handle.inner.status.send_modify(|s| {
+            s.pieces_done += 1;
+            s.bytes_left = s
+                .bytes_left
+                .map(|l| l.saturating_sub(piece.data.len() as u64));
+        });
+    }
+
+    storage.finish()?;
+    ctx.handle.set_state(TorrentState::Complete);
+    announce(
+        &ctx.source,
+        info_hash,
+        &ctx.config,
+        ctx.port,
+        0,
+        TrackerEvent::Completed,
+    )
+    .await;
+    Ok(())
+}
+
+// Verifies the pieces already on disk, marking the good ones done. Pieces are
+// read and hashed on the blocking pool, one at a time, so stopping the torrent
+// takes effect between them.
+async fn check(
+    ctx: &Context,
+    storage: &Storage,
+    piece_layers: &Arc<PieceLayers>,
+) -> Result<PiecePicker> {
+    ctx.handle.set_state(TorrentState::Checking);
+    // Resuming checks again, counting from scratch.
+    ctx.handle.inner.status.send_modify(|s| s.pieces_done = 0);
+
+    let info = storage.info();
+    let shared = Arc::new(storage.clone());
+    let mut picker = PiecePicker::new(info.num_pieces());
+    for index in 0..info.num_pieces() {
+        let (storage, piece_layers) = (Arc::clone(&shared), Arc::clone(piece_layers));
+        let verified = tokio::task::spawn_blocking(move || -> Result<bool> {
+            let Some(data) = storage.read_piece(index)? else {
+                return Ok(false);
+            };
+            Ok(storage.info().verify_piece(index, &data, &piece_layers))
+        })
+        .await
+        .map_err(|err| err!("Checking piece {index} failed: {err}"))??;
+
+        if verified {
+            picker.done(index);
+            ctx.handle.inner.status.send_modify(|s| s.pieces_done += 1);
+        }
+    }
+
+    let left = (0..info.num_pieces())
+        .filter(|index| !picker.is_done(*index))
+        .map(|index| info.piece_length(index) as u64)
+        .sum();
+    ctx.handle
+        .inner
+        .status
+        .send_modify(|s| s.bytes_left = Some(left));
+
+    Ok(picker)
+}
+
+fn swarm(
+    ctx: &Context,
+    info: &Arc<Info>,
+    piece_layers: Arc<PieceLayers>,
+    metadata: Option<Bytes>,
+) -> Swarm {
+    let config = broker::Config {
+        metadata,
+        ..broker::Config::default()
+    };
+    let mut swarm = Swarm::new(
+        ctx.handle.info_hash(),
+        ctx.config.peer_id,
+        info.num_pieces(),
+        config,
+    );
+    swarm.set_private(info.is_private());
+    swarm.set_max_connections(ctx.config.max_connections_per_torrent);
+    swarm.set_connection_limit(ctx.limit.clone());
+    swarm.set_encryption_policy(ctx.config.encryption);
+    if let Some(socket) = &ctx.utp {
+        swarm.set_utp_socket(socket.clone());
+    }
+
+    for url in ctx.source.web_seeds() {
+        match WebSeed::new(url, Arc::clone(info)) {
+            Ok(seed) => swarm.add_web_seed(seed.with_piece_layers(Arc::clone(&piece_layers))),
+            Err(err) => warn!("Skipping web seed: {err}"),
+        }
+    }
+
+    swarm
+}
+
+// Asks every tracker for peers, along with the ones a magnet link names.
+async fn find_peers(ctx: &Context) -> Vec<Peer> {
+    let mut peers = Vec::new();
+
+    if let TorrentSource::Magnet(link) = &ctx.source {
+        for addr in link.peers() {
+            match lookup_host(addr.as_str()).await {
+                Ok(addrs) => peers.extend(addrs.map(Peer::new)),
+                Err(err) => warn!("Skipping peer {addr}: {err}"),
+            }
+        }
+    }
+
+    let info_hash = ctx.handle.info_hash();
+    let left = ctx.handle.status().bytes_left.unwrap_or(UNKNOWN_LEFT);
+    let event = TrackerEvent::Started;
+    peers.extend(announce(&ctx.source, info_hash, &ctx.config, ctx.port, left, event).await);
+
+    peers.sort_by_key(Peer::addr);
+    peers.dedup();
+    peers
+}
+
+// Announces `event` to every tracker of the torrent and returns the peers
+// they know. Failing trackers are skipped; the swarm fails later if no source
+// is left.
+async fn announce(
+    source: &TorrentSource,
+    info_hash: Bytes20,
+    config: &SessionConfig,
+    port: u16,
+    left: u64,
+    event: TrackerEvent,
+) -> Vec<Peer> {
+    let peer_id = String::from_utf8_lossy(config.peer_id.as_ref()).into_owned();
+
+    let mut peers = Vec::new();
+    for tracker in source.trackers() {
+        let request = TrackerRequest::builder()
+            .url(tracker)
+            .info_hash(info_hash)
+            .peer_id(peer_id.clone())
+            .port(port)
+            .left(left)
+            .event(event)
+            .build();
+
+        match async { request?.send().await }.await {
+            Ok(resp) => peers.extend(resp.peers),
+            Err(err) => warn!("Tracker {tracker} failed: {err}"),
+        }
+    }
+
+    peers
+}
+
+// Connects to peers for the metadata, a few at a time.
+async fn connect(ctx: &Context, peers: &[Peer]) -> Vec<PeerStream> {
+    let info_hash = ctx.handle.info_hash();
+    let mut tasks = JoinSet::new();
+    for peer in peers.iter().take(ctx.config.max_connections_per_torrent) {
+        let (peer, peer_id, policy) = (*peer, ctx.config.peer_id, ctx.config.encryption);
+        tasks.spawn(async move {
+            let connect = peer.connect_with(info_hash, peer_id, policy);
+            match tokio::time::timeout(CONNECT_TIMEOUT, connect).await {
+                Ok(Ok(stream)) => Some(stream),
+                Ok(Err(err)) => {
+                    debug!("Failed to connect to peer {peer}: {err}");
+                    None
+                }
+                Err(_) => {
+                    debug!("Timed out connecting to peer {peer}");
+                    None
+                }
+            }
+        });
+    }
+
+    tasks.join_all().await.into_iter().flatten().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::{
+        FileEntry, FileTree, FileTreeNode,
+        merkle::{self, BLOCK_SIZE},
+    };
+    use crate::net::{
+        Extension, ExtensionHandshake, ExtensionRegistry, Message, PeerMessage, UT_METADATA,
+        metadata_response,
+        testing::{multi_file_info, serve},
+    };
+    use std::collections::BTreeMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::mpsc;
+
+    fn config(dir: &tempfile::TempDir) -> SessionConfig {
+        SessionConfig {
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            download_dir: dir.path().to_path_buf(),
+            local_discovery: None,
+            ..SessionConfig::default()
+        }
+    }
+
+    async fn seeded_meta(name: &str, files: &[(&str, &[u8])]) -> Meta {
+        failing_meta(name, files, 0).await
+    }
+
+    // A torrent whose web seed fails the first `fail_first` requests.
+    async fn failing_meta(name: &str, files: &[(&str, &[u8])], fail_first: usize) -> Meta {
+        let mut info = multi_file_info(files, 16);
+        info.name = name.to_string();
+        let served = files
+            .iter()
+            .map(|(path, data)| (format!("/{name}/{path}"), data.to_vec()))
+            .collect();
+        let (url, _) = serve(served, fail_first).await;
+
+        Meta {
+            announce: String::new(),
+            announce_list: Vec::new(),
+            info,
+            url_list: vec![url],
+            piece_layers: PieceLayers::default(),
+        }
+    }
+
+    // A tracker that knows no peers, passing on the event and the bytes left
+    // of every announce.
+    async fn tracker() -> (String, mpsc::UnboundedReceiver<(String, u64)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (events, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 4096];
+                let n = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                let param = |name: &str| {
+                    request
+                        .split(['?', '&', ' '])
+                        .find_map(|pair| pair.strip_prefix(name))
+                        .unwrap_or_default()
+                        .to_string()
+                };
+                let left = param("left=").parse().unwrap();
+                let _ = events.send((param("event="), left));
+
+                let body = "d8:intervali60e5:peers0:e";
+                let resp = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = socket.write_all(resp.as_bytes()).await;
+            }
+        });
+
+        (url, rx)
+    }
+
+    async fn next_event(events: &mut mpsc::UnboundedReceiver<(String, u64)>) -> (String, u64) {
+        tokio::time::timeout(Duration::from_secs(10), events.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn wait_done(handle: &TorrentHandle) -> TorrentStatus {
+        let done =
+            handle.wait_for(|s| matches!(s.state, TorrentState::Complete | TorrentState::Error(_)));
+        tokio::time::timeout(Duration::from_secs(10), done)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_peer_id_is_printable() {
+        let id = generate_peer_id();
+        assert!(id.starts_with(PEER_ID_PREFIX));
+        assert!(id[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_ne!(id, generate_peer_id());
+    }
+
+    #[tokio::test]
+    async fn test_downloads_torrents_side_by_side() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::new(config(&dir)).await.unwrap();
+
+        let a = (0..50u8).collect::<Vec<u8>>();
+        let b = (50..90u8).collect::<Vec<u8>>();
+        let first = session
+            .add(seeded_meta("first", &[("a", &a)]).await)
+            .unwrap();
+        let second = session
+            .add(seeded_meta("second", &[("b", &b)]).await)
+            .unwrap();
+        assert_eq!(session.torrents().len(), 2);
+
+        let status = wait_done(&first).await;
+        assert_eq!(status.state, TorrentState::Complete);
+        assert_eq!(status.pieces_done, 4);
+        assert_eq!(status.bytes_left, Some(0));
+        assert_eq!(wait_done(&second).await.state, TorrentState::Complete);
+
+        assert_eq!(std::fs::read(dir.path().join("first/a")).unwrap(), a);
+        assert_eq!(std::fs::read(dir.path().join("second/b")).unwrap(), b);
+    }
+
+    #[tokio::test]
+    async fn test_checks_existing_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = (0..40u8).collect::<Vec<u8>>();
+        let meta = seeded_meta("dir", &[("a", &data)]).await;
+
+        std::fs::create_dir(dir.path().join("dir")).unwrap();
+        std::fs::write(dir.path().join("dir/a"), &data).unwrap();
+
+        // Nothing to download from, so all pieces must come from disk.
+        let meta = Meta {
+            url_list: Vec::new(),
+            ..meta
+        };
+        let session = Session::new(config(&dir)).await.unwrap();
+        let handle = session.add(meta).unwrap();
+
+        let status = wait_done(&handle).await;
+        assert_eq!(status.state, TorrentState::Complete);
+        assert_eq!(status.pieces_done, 3);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_duplicates_and_removes() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::new(config(&dir)).await.unwrap();
+
+        let meta = seeded_meta("dir", &[("a", b"abc")]).await;
+        let handle = session.add(meta.clone()).unwrap();
+        assert!(session.add(meta).is_err());
+
+        session.remove(handle.info_hash(), false).await.unwrap();
+        assert!(session.torrent(handle.info_hash()).is_none());
+        assert!(session.remove(handle.info_hash(), false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fails_without_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::new(config(&dir)).await.unwrap();
+
+        let meta = Meta {
+            url_list: Vec::new(),
+            ..seeded_meta("dir", &[("a", b"abc")]).await
+        };
+        let status = wait_done(&session.add(meta).unwrap()).await;
+        assert!(matches!(status.state, TorrentState::Error(_)));
+
+        let link = MagnetLink::builder()
+            .info_hash(Bytes20::new([7; 20]))
+            .build()
+            .unwrap();
+        let status = wait_done(&session.add(link).unwrap()).await;
+        assert!(matches!(status.state, TorrentState::Error(_)));
+    }
+
+    #[tokio::test]
+    async fn test_refuses_magnets_that_need_piece_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::new(config(&dir)).await.unwrap();
+
+        // v2-only metadata with a file of two pieces.
+        let data = vec![1; 2 * BLOCK_SIZE];
+        let entry = FileEntry {
+            length: data.len() as u64,
+            pieces_root: Some(merkle::file_root(&data)),
+            ..FileEntry::default()
+        };
+        let info = Info {
+            file_tree: Some(FileTree(BTreeMap::from([(
+                "a".into(),
+                FileTreeNode::File(entry),
+            )]))),
+            files: None,
+            meta_version: Some(2),
+            pieces: Default::default(),
+            ..multi_file_info(&[], BLOCK_SIZE as u32)
+        };
+        let mut metadata = Vec::new();
+        info.serialize(&mut Serializer::new(&mut metadata)).unwrap();
+        let metadata = Bytes::from(metadata);
+        let info_hash = info.hash_v2().unwrap();
+
+        // A peer that serves the metadata.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, addr) = listener.accept().await.unwrap();
+            let (_, mut stream) = PeerStream::accept(
+                socket,
+                Some(addr),
+                &[info_hash.truncated()],
+                Bytes20::new([9; 20]),
+                EncryptionPolicy::default(),
+            )
+            .await
+            .unwrap();
+            stream
+                .send_message(PeerMessage::Bitfield(vec![0]))
+                .await
+                .unwrap();
+            let Extension::Handshake(handshake) = stream.wait_extention().await.unwrap() else {
+                panic!("Expected an extension handshake");
+            };
+            let ext_id = handshake.extension_id(UT_METADATA).unwrap();
+            let ours = ExtensionHandshake {
+                metadata_size: Some(metadata.len()),
+                ..ExtensionHandshake::new(&ExtensionRegistry::supported())
+            };
+            stream
+                .send_message(Extension::Handshake(ours))
+                .await
+                .unwrap();
+            while let Ok(Extension::RequestMetadata { piece, .. }) = stream.wait_extention().await {
+                let response = metadata_response(Some(&metadata), ext_id, piece);
+                stream.send_message(response).await.unwrap();
+            }
+        });
+
+        let link = MagnetLink::builder()
+            .info_hash_v2(info_hash)
+            .build()
+            .unwrap();
+        let link = format!("{link}&x.pe={addr}").parse::<MagnetLink>().unwrap();
+        let status = wait_done(&session.add(link).unwrap()).await;
+        let TorrentState::Error(err) = status.state else {
+            panic!("Expected an error, got {:?}", status.state);
+        };
+        assert!(err.contains("piece layers"));
+    }
+
+    #[tokio::test]
+    async fn test_routes_incoming_peers_by_info_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::new(config(&dir)).await.unwrap();
+
+        // A web seed that keeps failing holds the torrent in its swarm.
+        let meta = failing_meta("dir", &[("a", &[1; 64])], usize::MAX).await;
+        let handle = session.add(meta).unwrap();
+        handle
+            .wait_for(|s| s.state == TorrentState::Downloading)
+            .await;
+
+        let peer = Peer::new(session.local_addr());
+        let stream = peer
+            .connect(handle.info_hash(), Bytes20::new([9; 20]))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_id(), session.config().peer_id);
+
+        let unknown = peer
+            .connect(Bytes20::new([3; 20]), Bytes20::new([9; 20]))
+            .await;
+        assert!(unknown.is_err());
+
+        let socket = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stream = peer
+            .connect_utp(
+                &socket,
+                handle.info_hash(),
+                Bytes20::new([9; 20]),
+                EncryptionPolicy::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_id(), session.config().peer_id);
+    }
+
+    #[tokio::test]
+    async fn test_finds_local_peers_and_announces_itself() {
+        let dir = tempfile::tempdir().unwrap();
+        let meta = failing_meta("dir", &[("a", &[1; 64])], usize::MAX).await;
+        let info_hash = meta.info.hash().unwrap();
+
+        // A neighbour on the local network, listening for the torrent.
+        let unicast = |listen, group, port| {
+            Lsd::bind(LsdConfig {
+                listen,
+                group,
+                port,
+            })
+        };
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+        let watcher = unicast(localhost, localhost, None).await.unwrap();
+        let (tx, mut found) = mpsc::channel(10);
+        watcher.add_torrent(info_hash, tx).await;
+
+        let lsd_addr = std::net::UdpSocket::bind(localhost)
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = SessionConfig {
+            local_discovery: Some(LsdConfig {
+                listen: lsd_addr,
+                group: watcher.local_addr().unwrap(),
+                port: None,
+            }),
+            ..config(&dir)
+        };
+        let session = Session::new(config).await.unwrap();
+        session.add(meta).unwrap();
+
+        let announced = tokio::time::timeout(Duration::from_secs(5), found.recv())
+            .await
+            .unwrap();
+        assert_eq!(
+            announced,
+            Some(Peer::new(SocketAddr::from((
+                [127, 0, 0, 1],
+                session.local_addr().port()
+            ))))
+        );
+
+        // A local peer the session hears of is connected to, over TCP once
+        // uTP gives up.
+        let listener = TcpListener::bind(localhost).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let announcer = unicast(localhost, lsd_addr, Some(port)).await.unwrap();
+        let (tx, _found) = mpsc::channel(10);
+        announcer.add_torrent(info_hash, tx).await;
+        tokio::time::timeout(Duration::from_secs(20), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::new(config(&dir)).await.unwrap();
+        let (url, mut events) = tracker().await;
+
+        // Only the first piece is on disk already.
+        let mut data = vec![0; 64];
+        data[..16].fill(1);
+        std::fs::create_dir(dir.path().join("dir")).unwrap();
+        std::fs::write(dir.path().join("dir/a"), &data).unwrap();
+
+        let meta = Meta {
+            announce: url,
+            ..failing_meta("dir", &[("a", &[1; 64])], usize::MAX).await
+        };
+        let handle = session.add(meta).unwrap();
+        let info_hash = handle.info_hash();
+        assert_eq!(next_event(&mut events).await, ("started".to_string(), 48));
+        let status = handle
+            .wait_for(|s| s.state == TorrentState::Downloading)
+            .await;
+        assert_eq!(status.pieces_done, 1);
+
+        session.pause(info_hash).await.unwrap();
+        assert_eq!(handle.state(), TorrentState::Paused);
+        assert_eq!(next_event(&mut events).await, ("stopped".to_string(), 48));
+
+        let peer = Peer::new(session.local_addr());
+        assert!(
+            peer.connect(info_hash, Bytes20::new([9; 20]))
+                .await
+                .is_err()
+        );
+
+        session.resume(info_hash).unwrap();
+        assert_eq!(next_event(&mut events).await, ("started".to_string(), 48));
+        let status = handle
+            .wait_for(|s| s.state == TorrentState::Downloading)
+            .await;
+        assert_eq!(status.pieces_done, 1);
+        assert!(peer.connect(info_hash, Bytes20::new([9; 20])).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_pause_closes_peer_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::new(config(&dir)).await.unwrap();
+
+        let meta = failing_meta("dir", &[("a", &[1; 64])], usize::MAX).await;
+        let handle = session.add(meta).unwrap();
+        handle
+            .wait_for(|s| s.state == TorrentState::Downloading)
+            .await;
+
+        let peer = Peer::new(session.local_addr());
+        let mut remote = peer
+            .connect(handle.info_hash(), Bytes20::new([9; 20]))
+            .await
+            .unwrap();
+        remote
+            .send_message(PeerMessage::Bitfield(vec![0xf0]))
+            .await
+            .unwrap();
+        remote.wait_message(Message::is_extension).await.unwrap();
+        let handshake = ExtensionHandshake::new(&ExtensionRegistry::supported());
+        remote
+            .send_message(Extension::Handshake(handshake))
+            .await
+            .unwrap();
+        remote
+            .wait_message(|m| m.as_peer_message() == Some(&PeerMessage::Interested))
+            .await
+            .unwrap();
+        remote.send_message(PeerMessage::Unchoke).await.unwrap();
+        // Let the swarm take the peer on.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        session.pause(handle.info_hash()).await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(5), remote.wait_message(|_| false))
+            .await
+            .unwrap();
+        assert!(closed.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_restores_torrents_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SessionConfig {
+            state_file: Some(dir.path().join("session.dat")),
+            ..config(&dir)
+        };
+
+        let data = (0..40u8).collect::<Vec<u8>>();
+        let session = Session::new(config.clone()).await.unwrap();
+        let paused = session
+            .add(failing_meta("paused", &[("a", &data)], usize::MAX).await)
+            .unwrap();
+        session.pause(paused.info_hash()).await.unwrap();
+        let done = session
+            .add(seeded_meta("done", &[("b", &data)]).await)
+            .unwrap();
+        assert_eq!(wait_done(&done).await.state, TorrentState::Complete);
+        let link = MagnetLink::builder()
+            .info_hash(Bytes20::new([7; 20]))
+            .build()
+            .unwrap();
+        let magnet = session.add(link).unwrap();
+        drop(session);
+
+        let session = Session::new(config.clone()).await.unwrap();
+        assert_eq!(session.torrents().len(), 3);
+        let restored = session.torrent(paused.info_hash()).unwrap();
+        assert_eq!(restored.state(), TorrentState::Paused);
+        assert_eq!(restored.name().as_deref(), Some("paused"));
+        let restored = session.torrent(done.info_hash()).unwrap();
+        assert_eq!(wait_done(&restored).await.state, TorrentState::Complete);
+        assert!(session.torrent(magnet.info_hash()).is_some());
+
+        session.remove(magnet.info_hash(), false).await.unwrap();
+        drop(session);
+
+        let session = Session::new(config).await.unwrap();
+        assert_eq!(session.torrents().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_remove_deletes_data_on_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::new(config(&dir)).await.unwrap();
+        let meta = seeded_meta("dir", &[("a", b"abc"), ("sub/b", b"def")]).await;
+
+        let handle = session.add(meta.clone()).unwrap();
+        assert_eq!(wait_done(&handle).await.state, TorrentState::Complete);
+        session.remove(handle.info_hash(), false).await.unwrap();
+        assert!(dir.path().join("dir/sub/b").exists());
+
+        let handle = session.add(meta).unwrap();
+        assert_eq!(wait_done(&handle).await.state, TorrentState::Complete);
+        session.remove(handle.info_hash(), true).await.unwrap();
+        assert!(!dir.path().join("dir").exists());
+    }
+}
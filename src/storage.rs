@@ -0,0 +1,478 @@
+use crate::{
+    BitTorrentError, Result,
+    meta::{FileSpan, Info},
+    net::Priority,
+};
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::debug;
+
+macro_rules! err {
+    ($($arg:tt)*) => {
+        BitTorrentError::Other(format!($($arg)*))
+    };
+}
+
+// Keeps the data of skipped files from pieces shared with wanted files.
+const PARTFILE_SUFFIX: &str = ".parts";
+
+/// Writes downloaded pieces to the torrent's files. Padding files are never
+/// written, and symlinks and file attributes (BEP 47) are applied once the
+/// download is complete. Skipped files are not created: the parts of them
+/// that share a piece with a wanted file go to a partfile instead.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    info: Arc<Info>,
+    root: PathBuf,
+    files: Vec<FileSpan>,
+    skipped: Vec<bool>,
+}
+
+impl Storage {
+    /// Stores the data under `output`, which is the file itself for single-file
+    /// torrents and stands in for the torrent's directory otherwise.
+    pub fn new(info: Arc<Info>, output: impl Into<PathBuf>) -> Result<Self> {
+        let files = info.files();
+
+        for file in &files {
+            if let Some(part) = file.path.iter().find(|p| !is_safe_component(p)) {
+                return Err(err!("Unsafe path component {part:?} in torrent"));
+            }
+        }
+
+        Ok(Self {
+            info,
+            root: output.into(),
+            skipped: vec![false; files.len()],
+            files,
+        })
+    }
+
+    /// Skips the files whose priority is [`Priority::Skip`].
+    pub fn set_file_priorities(&mut self, priorities: &[Priority]) {
+        for (i, skipped) in self.skipped.iter_mut().enumerate() {
+            *skipped = priorities.get(i) == Some(&Priority::Skip);
+        }
+    }
+
+    pub fn is_skipped(&self, file: usize) -> bool {
+        self.skipped.get(file).copied().unwrap_or_default()
+    }
+
+    /// The partfile, next to the torrent's data. Data of skipped files sits
+    /// at its offset within the torrent.
+    pub fn partfile_path(&self) -> PathBuf {
+        let mut name = self.root.file_name().unwrap_or_default().to_os_string();
+        name.push(PARTFILE_SUFFIX);
+        self.root.with_file_name(name)
+    }
+
+    pub fn info(&self) -> &Info {
+        &self.info
+    }
+
+    pub fn files(&self) -> &[FileSpan] {
+        &self.files
+    }
+
+    /// Where file `index` goes on disk.
+    pub fn file_path(&self, index: usize) -> PathBuf {
+        if !self.info.is_multi_file() {
+            return self.root.clone();
+        }
+
+        self.files[index]
+            .path
+            .iter()
+            .skip(1)
+            .fold(self.root.clone(), |path, part| path.join(part))
+    }
+
+    /// Writes the data of piece `index` to the files it covers.
+    pub fn write_piece(&self, index: usize, data: &[u8]) -> Result<()> {
+        let start = index as u64 * self.info.piece_length as u64;
+
+        for range in self.info.file_ranges(start, data.len() as u64) {
+            let file = &self.files[range.file];
+            if !file.has_data() {
+                continue;
+            }
+
+            let pos = (file.offset + range.offset - start) as usize;
+            let data = &data[pos..pos + range.length as usize];
+
+            if self.skipped[range.file] {
+                write_at(&self.partfile_path(), file.offset + range.offset, data)?;
+            } else {
+                write_at(&self.file_path(range.file), range.offset, data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads data of file `index` at `offset` into `buf`, from the partfile
+    /// if the file is skipped. The data must have been written already.
+    pub fn read_at(&self, index: usize, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let file = &self.files[index];
+        let len = buf.len().min(file.length.saturating_sub(offset) as usize);
+
+        let (path, pos) = if self.skipped[index] {
+            (self.partfile_path(), file.offset + offset)
+        } else {
+            (self.file_path(index), offset)
+        };
+
+        let mut f = fs::File::open(path)?;
+        f.seek(SeekFrom::Start(pos))?;
+        f.read_exact(&mut buf[..len])?;
+        Ok(len)
+    }
+
+    /// Reads piece `index` back, or `None` when some of it is missing on disk.
+    /// The data still has to be verified.
+    pub fn read_piece(&self, index: usize) -> Result<Option<Vec<u8>>> {
+        let start = index as u64 * self.info.piece_length as u64;
+        let mut data = vec![0; self.info.piece_length(index)];
+
+        for range in self.info.file_ranges(start, data.len() as u64) {
+            if !self.files[range.file].has_data() {
+                continue;
+            }
+
+            let pos = (self.files[range.file].offset + range.offset - start) as usize;
+            let buf = &mut data[pos..pos + range.length as usize];
+
+            match self.read_at(range.file, range.offset, buf) {
+                Ok(_) => {}
+                Err(BitTorrentError::IoError(err))
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::NotFound | io::ErrorKind::UnexpectedEof
+                    ) =>
+                {
+                    return Ok(None);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(Some(data))
+    }
+
+    /// Completes the files on disk: creates empty files and symlinks and
+    /// applies the executable and hidden attributes.
+    pub fn finish(&self) -> Result<()> {
+        for (index, file) in self.files.iter().enumerate() {
+            if file.attributes.padding || self.skipped[index] {
+                continue;
+            }
+
+            let path = self.file_path(index);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            if file.attributes.symlink {
+                let target = file
+                    .symlink_path
+                    .as_ref()
+                    .ok_or_else(|| err!("Symlink {} has no target", path.display()))?;
+                self.create_symlink(&path, file.path.len(), target)?;
+                continue;
+            }
+
+            let f = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            if f.metadata()?.len() != file.length {
+                f.set_len(file.length)?;
+            }
+
+            if file.attributes.executable {
+                set_executable(&path)?;
+            }
+            // Hidden files are a Windows attribute; elsewhere a leading dot in
+            // the name is what hides a file.
+            if file.attributes.hidden {
+                debug!("Not marking {} hidden on this platform", path.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the torrent's files and partfile, and the directories they
+    /// leave empty. Other files in the torrent's directory are kept.
+    pub fn delete(&self) -> Result<()> {
+        let mut dirs = Vec::new();
+        for index in 0..self.files.len() {
+            let path = self.file_path(index);
+            remove_file(&path)?;
+
+            if self.info.is_multi_file() {
+                let parents = path.ancestors().skip(1);
+                dirs.extend(
+                    parents
+                        .take_while(|dir| dir.starts_with(&self.root))
+                        .map(Path::to_path_buf),
+                );
+            }
+        }
+        remove_file(&self.partfile_path())?;
+
+        // Deepest first, so parents are empty by the time they come up.
+        dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+        dirs.dedup();
+        for dir in dirs {
+            if fs::remove_dir(&dir).is_err() {
+                debug!("Keeping directory {}", dir.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Links to the target relative to the torrent's directory, so the link
+    // keeps working when the directory is moved.
+    fn create_symlink(&self, path: &Path, depth: usize, target: &[String]) -> Result<()> {
+        if let Some(part) = target.iter().find(|p| !is_safe_component(p)) {
+            return Err(err!("Unsafe symlink target component {part:?}"));
+        }
+
+        let up = depth.saturating_sub(2);
+        let target = std::iter::repeat_n("..", up)
+            .chain(target.iter().map(String::as_str))
+            .collect::<PathBuf>();
+
+        if fs::symlink_metadata(path).is_ok() {
+            fs::remove_file(path)?;
+        }
+
+        symlink(&target, path)
+    }
+}
+
+fn write_at(path: &Path, offset: u64, data: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+fn is_safe_component(part: &str) -> bool {
+    !part.is_empty() && part != "." && part != ".." && !part.contains(['/', '\\'])
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(permissions.mode() | 0o111);
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn symlink(target: &Path, path: &Path) -> Result<()> {
+    tracing::warn!(
+        "Cannot create symlink {} -> {} on this platform",
+        path.display(),
+        target.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::FileInfo;
+    use crate::util::Bytes20;
+
+    fn info(files: Vec<FileInfo>, data: &[u8], piece_length: u32) -> Info {
+        Info {
+            attr: None,
+            file_tree: None,
+            files: Some(files),
+            length: None,
+            meta_version: None,
+            name: "dir".into(),
+            piece_length,
+            pieces: data
+                .chunks(piece_length as usize)
+                .map(Bytes20::sha1_hash)
+                .collect::<Vec<_>>()
+                .into(),
+            private: None,
+            source: None,
+        }
+    }
+
+    fn file(attr: Option<&str>, length: u64, path: &str) -> FileInfo {
+        FileInfo {
+            attr: attr.map(String::from),
+            length,
+            path: path.split('/').map(String::from).collect(),
+            ..FileInfo::default()
+        }
+    }
+
+    #[test]
+    fn test_writes_files_skipping_padding() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data = b"hello".to_vec();
+        data.extend([0; 3]);
+        data.extend(b"bin!");
+
+        let files = vec![
+            file(None, 5, "a.txt"),
+            file(Some("p"), 3, ".pad/3"),
+            file(Some("x"), 4, "sub/run"),
+            file(None, 0, "empty"),
+        ];
+        let storage = Storage::new(Arc::new(info(files, &data, 4)), dir.path()).unwrap();
+
+        for (index, piece) in data.chunks(4).enumerate() {
+            storage.write_piece(index, piece).unwrap();
+        }
+        storage.finish().unwrap();
+
+        assert_eq!(fs::read(dir.path().join("a.txt")).unwrap(), b"hello");
+        assert_eq!(storage.read_piece(1).unwrap().unwrap(), data[4..8]);
+        assert_eq!(fs::read(dir.path().join("sub/run")).unwrap(), b"bin!");
+        assert!(dir.path().join("empty").exists());
+        assert!(!dir.path().join(".pad").exists());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.path().join("sub/run"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o111, 0o111);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_creates_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = b"target".to_vec();
+
+        let link = FileInfo {
+            symlink_path: Some(vec!["real".into()]),
+            ..file(Some("l"), 0, "sub/link")
+        };
+        let files = vec![file(None, 6, "real"), link];
+        let storage = Storage::new(Arc::new(info(files, &data, 8)), dir.path()).unwrap();
+
+        storage.write_piece(0, &data).unwrap();
+        storage.finish().unwrap();
+
+        let link = dir.path().join("sub/link");
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("../real"));
+        assert_eq!(fs::read(&link).unwrap(), b"target");
+    }
+
+    #[test]
+    fn test_rejects_unsafe_paths() {
+        let files = vec![file(None, 1, "../escape")];
+        assert!(Storage::new(Arc::new(info(files, b"x", 1)), "out").is_err());
+    }
+
+    #[test]
+    fn test_skipped_files_go_to_partfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("dir");
+        let data = b"aaaaaabbbbbccccc".to_vec();
+
+        let files = vec![file(None, 6, "a"), file(None, 5, "b"), file(None, 5, "c")];
+        let mut storage = Storage::new(Arc::new(info(files, &data, 4)), &output).unwrap();
+        storage.set_file_priorities(&[Priority::Skip, Priority::Normal, Priority::Skip]);
+
+        // Pieces 1 and 2 hold all of `b` and parts of `a` and `c`.
+        storage.write_piece(1, &data[4..8]).unwrap();
+        storage.write_piece(2, &data[8..12]).unwrap();
+        storage.finish().unwrap();
+
+        assert_eq!(fs::read(output.join("b")).unwrap(), b"bbbbb");
+        assert!(!output.join("a").exists());
+        assert!(!output.join("c").exists());
+
+        let partfile = fs::read(dir.path().join("dir.parts")).unwrap();
+        assert_eq!(&partfile[4..6], b"aa");
+        assert_eq!(&partfile[11..12], b"c");
+
+        let mut buf = [0; 8];
+        assert_eq!(storage.read_at(1, 1, &mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"bbbb");
+        assert_eq!(storage.read_at(0, 4, &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"aa");
+
+        assert_eq!(storage.read_piece(2).unwrap().unwrap(), data[8..12]);
+        assert_eq!(storage.read_piece(3).unwrap(), None);
+    }
+
+    #[test]
+    fn test_deletes_only_the_torrents_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("dir");
+        let data = b"aaaabbbbcccc".to_vec();
+
+        let files = vec![
+            file(None, 4, "a"),
+            file(None, 4, "sub/b"),
+            file(None, 4, "c"),
+        ];
+        let mut storage = Storage::new(Arc::new(info(files, &data, 4)), &output).unwrap();
+        storage.set_file_priorities(&[Priority::Normal, Priority::Normal, Priority::Skip]);
+        for (index, piece) in data.chunks(4).enumerate() {
+            storage.write_piece(index, piece).unwrap();
+        }
+        storage.finish().unwrap();
+        fs::write(output.join("mine"), b"keep").unwrap();
+
+        storage.delete().unwrap();
+        assert!(!output.join("a").exists());
+        assert!(!output.join("sub").exists());
+        assert!(!dir.path().join("dir.parts").exists());
+        assert_eq!(fs::read(output.join("mine")).unwrap(), b"keep");
+
+        fs::remove_file(output.join("mine")).unwrap();
+        storage.delete().unwrap();
+        assert!(!output.exists());
+    }
+}
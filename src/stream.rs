@@ -0,0 +1,439 @@
+//! Reading the files of a torrent while it downloads.
+
+use crate::{
+    BitTorrentError, Result,
+    meta::{Info, PieceLayers},
+    net::{DEFAULT_PIECE_DEADLINE, DEFAULT_READ_AHEAD, PiecePicker, Priority, Swarm},
+    storage::Storage,
+};
+
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_stream::{Stream, wrappers::WatchStream};
+use tracing::{debug, warn};
+
+macro_rules! err {
+    ($($arg:tt)*) => {
+        BitTorrentError::Other(format!($($arg)*))
+    };
+}
+
+/// A download whose files can be read before it completes. Pieces are picked
+/// sequentially, and a piece a reader waits for is moved to the front with a
+/// deadline, after which it is asked of another source. The download keeps running while it or any of its readers is
+/// around, so that pieces of skipped files can still be fetched on demand.
+#[derive(Clone)]
+pub struct StreamingDownload {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    storage: Arc<Storage>,
+    have: watch::Receiver<Vec<bool>>,
+    reads: mpsc::UnboundedSender<usize>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl StreamingDownload {
+    /// Starts downloading the pieces `picker` wants into `storage`, checking
+    /// them against `piece_layers` for v2 torrents. A picker that is not
+    /// sequential yet gets the default read-ahead.
+    pub fn start(
+        swarm: Swarm,
+        storage: Storage,
+        piece_layers: PieceLayers,
+        mut picker: PiecePicker,
+    ) -> Self {
+        if !picker.is_sequential() {
+            picker.set_sequential(DEFAULT_READ_AHEAD);
+        }
+
+        let storage = Arc::new(storage);
+        let (have_tx, have) = watch::channel(vec![false; storage.info().num_pieces()]);
+        let (reads, reads_rx) = mpsc::unbounded_channel();
+
+        let task = tokio::spawn(run(
+            swarm,
+            Arc::clone(&storage),
+            piece_layers,
+            picker,
+            reads_rx,
+            have_tx,
+        ));
+
+        Self {
+            inner: Arc::new(Inner {
+                storage,
+                have,
+                reads,
+                task,
+            }),
+        }
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.inner.storage
+    }
+
+    /// The number of pieces downloaded so far.
+    pub fn num_done(&self) -> usize {
+        self.inner.have.borrow().iter().filter(|h| **h).count()
+    }
+
+    /// A reader of file `index`, which must hold data.
+    pub fn file(&self, index: usize) -> Result<FileStream> {
+        let span = self
+            .inner
+            .storage
+            .files()
+            .get(index)
+            .ok_or_else(|| err!("No file {index}"))?;
+        if !span.has_data() {
+            return Err(err!("File {index} holds no data"));
+        }
+
+        Ok(FileStream {
+            download: self.clone(),
+            file: index,
+            offset: span.offset,
+            length: span.length,
+            pos: 0,
+            have: self.inner.have.borrow().clone(),
+            updates: WatchStream::from_changes(self.inner.have.clone()),
+            requested: None,
+            reading: None,
+        })
+    }
+}
+
+// Downloads pieces until the picker is complete, then waits for pieces that
+// readers still need. Reads move the playback position and get a deadline.
+async fn run(
+    mut swarm: Swarm,
+    storage: Arc<Storage>,
+    piece_layers: PieceLayers,
+    mut picker: PiecePicker,
+    mut reads: mpsc::UnboundedReceiver<usize>,
+    have: watch::Sender<Vec<bool>>,
+) {
+    let info = storage.info();
+    let mut in_flight = 0;
+    let mut finished = false;
+
+    loop {
+        while let Ok(index) = reads.try_recv() {
+            prioritise(&mut picker, &mut swarm, info, index);
+        }
+        for index in swarm.take_suggestions() {
+            picker.suggest(index);
+        }
+
+        while in_flight < picker.max_in_flight() {
+            let Some(index) = picker.pick() else {
+                break;
+            };
+            let length = info.piece_length(index);
+            if let Err(err) = swarm.request_piece(index, length).await {
+                warn!("Streaming download stopped: {err}");
+                return;
+            }
+            if let Some(due) = picker.due(index, Instant::now()) {
+                swarm.set_deadline(index, length, due);
+            }
+            in_flight += 1;
+        }
+
+        // Pieces only arrive while some are in flight, otherwise there is
+        // nothing to do until a reader needs one. The files are completed
+        // again after pieces readers needed were written.
+        if in_flight == 0 {
+            if !finished {
+                if let Err(err) = storage.finish() {
+                    warn!("Failed to complete the files: {err}");
+                }
+                finished = true;
+            }
+
+            match reads.recv().await {
+                Some(index) => prioritise(&mut picker, &mut swarm, info, index),
+                None => return,
+            }
+            continue;
+        }
+
+        let piece = match swarm.next_piece().await {
+            Ok(Some(piece)) => piece,
+            Ok(None) => {
+                warn!("Streaming download stopped: the swarm is gone");
+                return;
+            }
+            Err(err) => {
+                warn!("Streaming download stopped: {err}");
+                return;
+            }
+        };
+        if picker.is_done(piece.index) {
+            continue;
+        }
+        in_flight -= 1;
+
+        // A bad piece goes back to the picker and is requested again.
+        if !info.verify_piece(piece.index, &piece.data, &piece_layers) {
+            warn!("Piece {} failed verification", piece.index);
+            picker.failed(piece.index);
+            continue;
+        }
+
+        if let Err(err) = storage.write_piece(piece.index, &piece.data) {
+            warn!("Streaming download stopped: {err}");
+            return;
+        }
+        finished = false;
+        picker.done(piece.index);
+        swarm.have(piece.index).await;
+        have.send_modify(|have| have[piece.index] = true);
+        debug!("Downloaded piece {}", piece.index);
+    }
+}
+
+// Makes a piece a reader waits for the next one to pick, fetching it even
+// when it belongs to skipped files only. A piece already in flight is asked of
+// another source if it is late.
+fn prioritise(picker: &mut PiecePicker, swarm: &mut Swarm, info: &Info, index: usize) {
+    if picker.is_done(index) {
+        return;
+    }
+    if picker.priority(index) == Priority::Skip {
+        picker.set_priority(index, Priority::Normal);
+    }
+    picker.set_position(index);
+
+    let deadline = Instant::now() + DEFAULT_PIECE_DEADLINE;
+    picker.set_deadline(index, deadline);
+    if picker.is_picked(index) {
+        swarm.set_deadline(index, info.piece_length(index), deadline);
+    }
+}
+
+/// A file of a [`StreamingDownload`], read as it downloads. Reads wait for
+/// the piece they fall into and only ever return verified data, which is read
+/// from disk off the runtime.
+pub struct FileStream {
+    download: StreamingDownload,
+    file: usize,
+    offset: u64,
+    length: u64,
+    pos: u64,
+    have: Vec<bool>,
+    updates: WatchStream<Vec<bool>>,
+    requested: Option<usize>,
+    // Reading from disk at the position, on the blocking pool.
+    reading: Option<JoinHandle<io::Result<Vec<u8>>>>,
+}
+
+impl FileStream {
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+}
+
+impl AsyncRead for FileStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos >= this.length || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let piece_length = this.download.storage().info().piece_length as u64;
+        let start = this.offset + this.pos;
+        let piece = (start / piece_length) as usize;
+
+        while !this.have[piece] {
+            if this.requested != Some(piece) {
+                // The download only goes away along with this reader.
+                let _ = this.download.inner.reads.send(piece);
+                this.requested = Some(piece);
+            }
+
+            match Pin::new(&mut this.updates).poll_next(cx) {
+                Poll::Ready(Some(have)) => this.have = have,
+                Poll::Ready(None) => {
+                    return Poll::Ready(Err(io::Error::other(format!(
+                        "Download stopped before piece {piece}"
+                    ))));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let reading = this.reading.get_or_insert_with(|| {
+            let piece_end = (piece as u64 + 1) * piece_length;
+            let len = (buf.remaining() as u64)
+                .min(this.length - this.pos)
+                .min(piece_end - start) as usize;
+
+            let storage = Arc::clone(&this.download.inner.storage);
+            let (file, pos) = (this.file, this.pos);
+            tokio::task::spawn_blocking(move || {
+                let mut data = vec![0; len];
+                let n = storage
+                    .read_at(file, pos, &mut data)
+                    .map_err(io::Error::other)?;
+                data.truncate(n);
+                Ok(data)
+            })
+        });
+
+        let data = match Pin::new(reading).poll(cx) {
+            Poll::Ready(data) => data,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.reading = None;
+
+        // The buffer may have shrunk since the read started.
+        let data = data.map_err(io::Error::other)??;
+        let n = data.len().min(buf.remaining());
+        buf.put_slice(&data[..n]);
+        this.pos += n as u64;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for FileStream {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+
+        let pos = match position {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => this.length.checked_add_signed(delta),
+            SeekFrom::Current(delta) => this.pos.checked_add_signed(delta),
+        };
+        this.pos = pos
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start"))?;
+        // A read in progress was for the old position.
+        this.reading = None;
+
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::{
+        testing::{multi_file_info, serve},
+        webseed::WebSeed,
+    };
+    use crate::util::Bytes20;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    async fn download(
+        files: &[(&str, &[u8])],
+        piece_length: u32,
+        priorities: &[Priority],
+    ) -> (StreamingDownload, tempfile::TempDir) {
+        let info = Arc::new(multi_file_info(files, piece_length));
+        let served = files
+            .iter()
+            .map(|(path, data)| (format!("/dir/{path}"), data.to_vec()))
+            .collect::<HashMap<_, _>>();
+        let (url, _) = serve(served, 0).await;
+
+        let mut swarm = Swarm::new(
+            Bytes20::new([0; 20]),
+            Bytes20::new([1; 20]),
+            info.num_pieces(),
+            Default::default(),
+        );
+        swarm.add_web_seed(WebSeed::new(&url, Arc::clone(&info)).unwrap());
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::new(Arc::clone(&info), dir.path().join("dir")).unwrap();
+        storage.set_file_priorities(priorities);
+        let mut picker = PiecePicker::for_files(&info, priorities);
+        picker.set_sequential(2);
+
+        let download = StreamingDownload::start(swarm, storage, PieceLayers::default(), picker);
+        (download, dir)
+    }
+
+    #[tokio::test]
+    async fn test_reads_and_seeks_across_pieces() {
+        let a = (0..50u8).collect::<Vec<u8>>();
+        let b = (50..90u8).collect::<Vec<u8>>();
+        let (download, _dir) = download(&[("a", &a), ("b", &b)], 16, &[]).await;
+
+        let mut file = download.file(1).unwrap();
+        assert_eq!(file.len(), 40);
+
+        let mut data = Vec::new();
+        tokio::time::timeout(Duration::from_secs(10), file.read_to_end(&mut data))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(data, b);
+
+        file.seek(SeekFrom::End(-5)).await.unwrap();
+        let mut tail = [0; 5];
+        file.read_exact(&mut tail).await.unwrap();
+        assert_eq!(tail, b[35..]);
+
+        let mut file = download.file(0).unwrap();
+        file.seek(SeekFrom::Start(30)).await.unwrap();
+        let mut middle = [0; 10];
+        file.read_exact(&mut middle).await.unwrap();
+        assert_eq!(middle, a[30..40]);
+
+        assert!(file.seek(SeekFrom::Current(-100)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reads_pieces_of_skipped_files_on_demand() {
+        let a = (0..20u8).collect::<Vec<u8>>();
+        let b = (20..40u8).collect::<Vec<u8>>();
+        let priorities = [Priority::Normal, Priority::Skip];
+        let (download, dir) = download(&[("a", &a), ("b", &b)], 8, &priorities).await;
+
+        let mut data = Vec::new();
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            download.file(1).unwrap().read_to_end(&mut data),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(data, b);
+        assert!(!dir.path().join("dir/b").exists());
+    }
+}
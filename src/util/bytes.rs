@@ -1,8 +1,12 @@
 use crate::BitTorrentError;
+use serde::{de, ser};
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::fmt;
 use std::ops::Deref;
 
 pub const HASH_SIZE: usize = 20;
+pub const HASH_V2_SIZE: usize = 32;
 
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
 pub struct Bytes20([u8; HASH_SIZE]);
@@ -68,3 +72,118 @@ impl Deref for Bytes20 {
         &self.0
     }
 }
+
+/// A SHA-256 hash, as used by v2 torrents (BEP 52).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Bytes32([u8; HASH_V2_SIZE]);
+
+impl From<&[u8]> for Bytes32 {
+    fn from(slice: &[u8]) -> Self {
+        let mut array = [0u8; HASH_V2_SIZE];
+        array.copy_from_slice(&slice[0..HASH_V2_SIZE]);
+        Bytes32(array)
+    }
+}
+
+impl TryFrom<Vec<u8>> for Bytes32 {
+    type Error = BitTorrentError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        if value.len() != HASH_V2_SIZE {
+            return Err(BitTorrentError::DeserdeError(format!(
+                "Invalid length for Bytes32: expected {}, got {}",
+                HASH_V2_SIZE,
+                value.len()
+            )));
+        }
+        Ok(Bytes32::from(&value[..]))
+    }
+}
+
+impl Bytes32 {
+    pub fn new(bytes: [u8; HASH_V2_SIZE]) -> Self {
+        Bytes32(bytes)
+    }
+
+    pub fn hex_encoded(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    pub fn sha256_hash(data: &[u8]) -> Self {
+        let digest = Sha256::digest(data);
+        Bytes32::from(digest.as_ref())
+    }
+
+    /// The first 20 bytes, which stand in for a v2 info hash wherever the
+    /// protocol has room for a SHA-1 hash only.
+    pub fn truncated(&self) -> Bytes20 {
+        Bytes20::from(&self.0[..])
+    }
+}
+
+impl AsRef<[u8]> for Bytes32 {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Deref for Bytes32 {
+    type Target = [u8; HASH_V2_SIZE];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl ser::Serialize for Bytes32 {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> de::Deserialize<'de> for Bytes32 {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = Bytes32;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{HASH_V2_SIZE} bytes")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+                if v.len() != HASH_V2_SIZE {
+                    return Err(E::invalid_length(v.len(), &self));
+                }
+                Ok(Bytes32::from(v))
+            }
+        }
+
+        deserializer.deserialize_bytes(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256_truncated() {
+        let hash = Bytes32::sha256_hash(b"abc");
+        assert_eq!(
+            hash.hex_encoded(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hash.truncated().hex_encoded(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a3"
+        );
+    }
+}
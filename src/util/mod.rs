@@ -2,6 +2,6 @@ mod bytes;
 mod pool;
 mod throttle;
 
-pub use bytes::{Bytes20, HASH_SIZE};
+pub use bytes::{Bytes20, Bytes32, HASH_SIZE, HASH_V2_SIZE};
 pub use pool::{Pool, RotationPool};
 pub use throttle::{KeyHash, ThrottleQueue};
@@ -97,6 +97,33 @@ impl<T> RotationPool<T> {
         self.index = next_index;
         item
     }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn push(&mut self, item: T) {
+        self.items.push(item);
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.items.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&T) -> bool,
+    {
+        self.items.retain(f);
+
+        if self.index >= self.items.len() {
+            self.index = 0;
+        }
+    }
 }
 
 impl<T> FromIterator<T> for RotationPool<T> {
@@ -124,6 +151,22 @@ mod tests {
         assert_eq!(*item4, 1);
     }
 
+    #[test]
+    fn test_rotation_pool_retain() {
+        let mut pool = RotationPool::from_iter(vec![1, 2, 3]);
+        assert_eq!(*pool.get_item(), 1);
+        assert_eq!(*pool.get_item(), 2);
+        assert_eq!(*pool.get_item(), 3);
+
+        pool.retain(|v| *v != 2);
+        assert_eq!(pool.len(), 2);
+        assert_eq!(*pool.get_item(), 1);
+        assert_eq!(*pool.get_item(), 3);
+
+        pool.retain(|_| false);
+        assert!(pool.is_empty());
+    }
+
     #[tokio::test]
     async fn test_pool_should_be_locked() {
         let items = vec![1];
@@ -52,6 +52,11 @@ where
         }
     }
 
+    /// Keeps only the waiting items for which `f` returns true.
+    pub fn retain_waiting(&mut self, f: impl FnMut(&T) -> bool) {
+        self.waitings.retain(f);
+    }
+
     fn is_full(&self) -> bool {
         self.processings.len() >= self.capacity
     }